@@ -1,13 +1,13 @@
 use crate::{
-    dydx::{msg::DydxMsg, query::DydxQueryWrapper},
+    dydx::{msg::DydxMsg, querier::DydxQuerier, query::DydxQueryWrapper},
     error::{ContractError, ContractResult},
-    msg::{ExecuteMsg, InstantiateMsg, QueryMsg},
-    state::{State, STATE},
+    migrations::{parse_version, pending_migrations},
+    msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg},
+    state::{State, LP_SNAPSHOTS_START_HEIGHT, STATE},
 };
 use cosmwasm_std::{
-    to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdError, StdResult,
+    to_json_binary, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Response, StdResult,
 };
-use cw20_base::msg::MigrateMsg;
 
 const CONTRACT_NAME: &str = env!("CARGO_PKG_NAME");
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -22,19 +22,53 @@ pub fn instantiate(
 ) -> ContractResult<Response<DydxMsg>> {
     // assert sender is the contract deployer
     let owner = deps.api.addr_validate(&msg.owner)?;
-    if owner != &info.sender {
+    if owner != info.sender {
         return Err(ContractError::InvalidOwnerDuringInstantiation { owner });
     }
 
+    // the collateral must be a dYdX asset whose quantums are base units of the denom
+    let collateral = msg.collateral;
+    let asset = DydxQuerier::new(&deps.querier)
+        .query_asset(collateral.asset_id)?
+        .asset;
+    if asset.denom != collateral.denom {
+        return Err(ContractError::InvalidCollateral {
+            reason: format!(
+                "asset id {} has denom {}, not {}",
+                collateral.asset_id, asset.denom, collateral.denom
+            ),
+        });
+    }
+    if collateral.decimals > Decimal::DECIMAL_PLACES
+        || asset.atomic_resolution != -(collateral.decimals as i32)
+        || asset.denom_exponent != asset.atomic_resolution
+    {
+        return Err(ContractError::InvalidCollateral {
+            reason: format!(
+                "{} decimals do not match the atomic resolution {} and denom exponent {} of asset id {}",
+                collateral.decimals,
+                asset.atomic_resolution,
+                asset.denom_exponent,
+                collateral.asset_id
+            ),
+        });
+    }
+
     let state = State {
         admin: owner.clone(),
         trader: owner,
         contract: env.contract.address,
+        msg_encoding: msg.msg_encoding.unwrap_or_default(),
+        collateral,
     };
     STATE.save(deps.storage, &state)?;
+    LP_SNAPSHOTS_START_HEIGHT.save(deps.storage, &env.block.height)?;
     cw2::set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
-    Ok(Response::new().add_attribute("method", "instantiate"))
+    Ok(Response::new()
+        .add_attribute("method", "instantiate")
+        .add_attribute("msg_encoding", state.msg_encoding.to_string())
+        .add_attribute("collateral_denom", state.collateral.denom))
 }
 
 pub fn execute(
@@ -45,50 +79,85 @@ pub fn execute(
 ) -> ContractResult<Response<DydxMsg>> {
     match msg {
         ExecuteMsg::SetTrader { new_trader } => {
-            crate::execute::admin::set_trader(deps, info, new_trader).map_err(Into::into)
+            crate::execute::admin::set_trader(deps, info, new_trader)
         }
-        ExecuteMsg::CreateVault { perp_id } => {
-            crate::execute::admin::create_vault(deps, env, info, perp_id).map_err(Into::into)
-        }
-        ExecuteMsg::ModifyVaultFee { perp_id } => {
-            crate::execute::admin::modify_vault_fee(deps, env, info, perp_id).map_err(Into::into)
+        ExecuteMsg::CreateVault {
+            vault_id,
+            perp_id,
+            subaccount_number,
+        } => crate::execute::admin::create_vault(
+            deps,
+            env,
+            info,
+            vault_id,
+            perp_id,
+            subaccount_number,
+        ),
+        ExecuteMsg::UpdateVaultConfig {
+            vault_id,
+            lockup_seconds,
+            instant_withdrawals,
+            withdrawal_rate_limit,
+            share_price_interval_seconds,
+        } => crate::execute::admin::update_vault_config(
+            deps,
+            info,
+            vault_id,
+            lockup_seconds,
+            instant_withdrawals,
+            withdrawal_rate_limit,
+            share_price_interval_seconds,
+        ),
+        ExecuteMsg::RebalanceVaults {
+            from_vault_id,
+            to_vault_id,
+            amount,
+        } => crate::execute::admin::rebalance_vaults(
+            deps,
+            env,
+            info,
+            from_vault_id,
+            to_vault_id,
+            amount,
+        ),
+        ExecuteMsg::ModifyVaultFee { vault_id } => {
+            crate::execute::admin::modify_vault_fee(deps, env, info, vault_id)
         }
-        ExecuteMsg::CollectFeesFromVault { perp_id } => {
-            crate::execute::admin::collect_fees_from_vault(deps, env, info, perp_id)
-                .map_err(Into::into)
+        ExecuteMsg::CollectFeesFromVault { vault_id } => {
+            crate::execute::admin::collect_fees_from_vault(deps, env, info, vault_id)
         }
-        ExecuteMsg::DepositIntoVault { perp_id } => {
-            crate::execute::deposit_withdraw::deposit_into_vault(deps, env, info, perp_id)
-                .map_err(Into::into)
+        ExecuteMsg::DepositIntoVault { vault_id, referrer } => {
+            crate::execute::deposit_withdraw::deposit_into_vault(
+                deps, env, info, vault_id, referrer,
+            )
         }
         ExecuteMsg::RequestWithdrawal {
             usdc_amount,
-            perp_id,
+            vault_id,
+            recipient,
         } => crate::execute::deposit_withdraw::request_withdrawal(
             deps,
             env,
             info,
             usdc_amount,
-            perp_id,
-        )
-        .map_err(Into::into),
-        ExecuteMsg::CancelWithdrawalRequests { perp_id } => {
-            crate::execute::deposit_withdraw::cancel_withdrawal_requests(deps, env, info, perp_id)
-                .map_err(Into::into)
+            vault_id,
+            recipient,
+        ),
+        ExecuteMsg::CancelWithdrawalRequests { vault_id } => {
+            crate::execute::deposit_withdraw::cancel_withdrawal_requests(deps, env, info, vault_id)
         }
         ExecuteMsg::ProcessWithdrawals {
-            perp_id,
+            vault_id,
             max_num_withdrawals,
         } => crate::execute::deposit_withdraw::process_withdrawals(
             deps,
             env,
             info,
-            perp_id,
+            vault_id,
             max_num_withdrawals,
-        )
-        .map_err(Into::into),
+        ),
         ExecuteMsg::MarketMake {
-            subaccount_number,
+            vault_id,
             clob_pair_id,
             new_orders,
             cancel_client_ids,
@@ -97,63 +166,248 @@ pub fn execute(
             deps,
             env,
             info,
-            subaccount_number,
+            vault_id,
             clob_pair_id,
             new_orders,
             cancel_client_ids,
             cancel_good_til_block_time,
-        )
-        .map_err(Into::into),
+        ),
+        ExecuteMsg::SettleVault { vault_id, limit } => {
+            crate::execute::lifecycle::settle_vault(deps, env, info, vault_id, limit)
+        }
+        ExecuteMsg::CloseVault {
+            vault_id,
+            cancel_client_ids,
+            cancel_good_til_block_time,
+        } => crate::execute::lifecycle::close_vault(
+            deps,
+            env,
+            info,
+            vault_id,
+            cancel_client_ids,
+            cancel_good_til_block_time,
+        ),
+        ExecuteMsg::ArchiveVault { vault_id, limit } => {
+            crate::execute::lifecycle::archive_vault(deps, env, info, vault_id, limit)
+        }
+        ExecuteMsg::FundRewards {
+            vault_id,
+            start_time,
+            end_time,
+        } => crate::execute::rewards::fund_rewards(deps, env, info, vault_id, start_time, end_time),
+        ExecuteMsg::ClaimRewards { vault_id } => {
+            crate::execute::rewards::claim_rewards(deps, env, info, vault_id)
+        }
+        ExecuteMsg::SetReferralFeeShare {
+            referrer,
+            fee_share,
+        } => crate::execute::admin::set_referral_fee_share(deps, info, referrer, fee_share),
+        ExecuteMsg::AddPermissionedKey {
+            vault_id,
+            public_key,
+            clob_pair_ids,
+            message_types,
+        } => crate::execute::permissioned_keys::add_permissioned_key(
+            deps,
+            info,
+            vault_id,
+            public_key,
+            clob_pair_ids,
+            message_types,
+        ),
+        ExecuteMsg::RemovePermissionedKey {
+            vault_id,
+            authenticator_id,
+        } => crate::execute::permissioned_keys::remove_permissioned_key(
+            deps,
+            env,
+            info,
+            vault_id,
+            authenticator_id,
+        ),
+        ExecuteMsg::BatchCancel {
+            vault_id,
+            order_batches,
+            good_til_block,
+        } => crate::execute::market_make::batch_cancel(
+            deps,
+            env,
+            info,
+            vault_id,
+            order_batches,
+            good_til_block,
+        ),
     }
 }
 
-pub fn query(deps: Deps<DydxQueryWrapper>, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps<DydxQueryWrapper>, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     use QueryMsg::*;
     match msg {
         Trader => to_json_binary(&crate::query::trader(deps)?),
+        Collateral => to_json_binary(&crate::query::collateral(deps)?),
         Vaults => to_json_binary(&crate::query::vaults(deps)?),
-        VaultOwnership { perp_id, depositor } => {
-            to_json_binary(&crate::query::vault_ownership(deps, perp_id, depositor)?)
+        Vault { vault_id } => to_json_binary(&crate::query::vault(deps, vault_id)?),
+        VaultOwnership {
+            vault_id,
+            depositor,
+        } => to_json_binary(&crate::query::vault_ownership(deps, vault_id, depositor)?),
+        VaultSummary { vault_id } => to_json_binary(&crate::query::vault_summary(deps, vault_id)?),
+        AllVaultSummaries { start_after, limit } => to_json_binary(
+            &crate::query::all_vault_summaries(deps, start_after, limit)?,
+        ),
+        SimulateDeposit { vault_id, amount } => {
+            to_json_binary(&crate::query::simulate_deposit(deps, vault_id, amount)?)
         }
+        SimulateWithdrawal {
+            vault_id,
+            user,
+            amount,
+        } => to_json_binary(&crate::query::simulate_withdrawal(
+            deps, env, vault_id, user, amount,
+        )?),
+        SharePriceHistory {
+            vault_id,
+            start_after,
+            limit,
+        } => to_json_binary(&crate::query::share_price_history(
+            deps,
+            vault_id,
+            start_after,
+            limit,
+        )?),
+        VaultApr {
+            vault_id,
+            window_seconds,
+        } => to_json_binary(&crate::query::vault_apr(deps, vault_id, window_seconds)?),
+        UserPnl { vault_id, user } => {
+            to_json_binary(&crate::query::user_pnl(deps, vault_id, user)?)
+        }
+        ReferralStats { referrer } => {
+            to_json_binary(&crate::query::referral_stats(deps, referrer)?)
+        }
+        RewardStreams { vault_id } => {
+            to_json_binary(&crate::query::reward_streams(deps, env, vault_id)?)
+        }
+        PendingRewards { vault_id, user } => {
+            to_json_binary(&crate::query::pending_rewards(deps, env, vault_id, user)?)
+        }
+        UserPortfolio { user } => to_json_binary(&crate::query::user_portfolio(deps, env, user)?),
         DydxSubaccount { owner, number } => {
             to_json_binary(&crate::query::dydx_subaccount(deps, owner, number)?)
         }
         LiquidityTiers => to_json_binary(&crate::query::liquidity_tiers(deps)?),
-        Withdrawals { perp_id } => to_json_binary(&crate::query::withdrawals(deps, perp_id)?),
-        UserLpTokens { perp_id, user } => {
-            to_json_binary(&crate::query::lp_balance(deps, perp_id, user)?)
+        Asset { id } => to_json_binary(&crate::query::asset(deps, id)?),
+        MarketParam { id } => to_json_binary(&crate::query::market_param(deps, id)?),
+        AllPerpetuals => to_json_binary(&crate::query::all_perpetuals(deps)?),
+        AllClobPairs => to_json_binary(&crate::query::all_clob_pairs(deps)?),
+        PerpetualFeeParams => to_json_binary(&crate::query::perpetual_fee_params(deps)?),
+        BlockRateLimitConfiguration => {
+            to_json_binary(&crate::query::block_rate_limit_config(deps)?)
+        }
+        Withdrawals {
+            vault_id,
+            start_after,
+            limit,
+        } => to_json_binary(&crate::query::withdrawals(
+            deps,
+            vault_id,
+            start_after,
+            limit,
+        )?),
+        UserWithdrawals { user, vault_id } => {
+            to_json_binary(&crate::query::user_withdrawals(deps, user, vault_id)?)
+        }
+        BalanceAtHeight {
+            vault_id,
+            user,
+            height,
+        } => to_json_binary(&crate::query::balance_at_height(
+            deps, vault_id, user, height,
+        )?),
+        TotalSupplyAtHeight { vault_id, height } => to_json_binary(
+            &crate::query::total_supply_at_height(deps, vault_id, height)?,
+        ),
+        UserLpTokens { vault_id, user } => {
+            to_json_binary(&crate::query::lp_balance(deps, vault_id, user)?)
+        }
+        VaultConfig { vault_id } => to_json_binary(&crate::query::vault_config(deps, vault_id)?),
+        UnlockSchedule { vault_id, user } => {
+            to_json_binary(&crate::query::unlock_schedule(deps, env, vault_id, user)?)
+        }
+        PermissionedKeys { vault_id } => {
+            to_json_binary(&crate::query::permissioned_keys(deps, env, vault_id)?)
+        }
+        WithdrawalCapacity { vault_id } => {
+            to_json_binary(&crate::query::withdrawal_capacity(deps, env, vault_id)?)
         }
     }
 }
 
+/// Migrates the contract from an older version of the same contract.
+/// Runs the state migrations of every version in between in order, then applies the overrides of the migrate message.
 pub fn migrate(
     deps: DepsMut<DydxQueryWrapper>,
-    _env: Env,
-    _msg: MigrateMsg,
-) -> StdResult<Response> {
+    env: Env,
+    msg: MigrateMsg,
+) -> ContractResult<Response<DydxMsg>> {
     let ver = cw2::get_contract_version(deps.storage)?;
+    let cannot_migrate = |reason: String| ContractError::CannotMigrate {
+        from_version: ver.version.clone(),
+        to_version: CONTRACT_VERSION.to_string(),
+        reason,
+    };
     // ensure we are migrating from an allowed contract
     if ver.contract != CONTRACT_NAME {
-        return Err(StdError::generic_err("Can only upgrade from same type").into());
+        return Err(cannot_migrate(format!(
+            "can only upgrade from the same contract type, not {}",
+            ver.contract
+        )));
     }
-    // note: better to do proper semver compare, but string compare *usually* works
-    if ver.version >= CONTRACT_VERSION.to_string() {
-        return Err(StdError::generic_err(format!(
-            "Cannot upgrade from a newer version {} -> {}",
-            ver.version, CONTRACT_VERSION
-        ))
-        .into());
+    let from_version = parse_version(&ver.version)?;
+    let to_version = parse_version(CONTRACT_VERSION)?;
+    if from_version >= to_version {
+        return Err(cannot_migrate(
+            "can only upgrade from an older version".to_string(),
+        ));
     }
-    // set the new version
-    cw2::set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
     // See https://medium.com/cosmwasm/cosmwasm-for-ctos-ii-advanced-usage-ee04ce95d1d0 for migration details
-    // and note that migrate is called on the new version of the code
-    // As long as the state in state.rs is the same between the new and old contract, it will be copied over automatically.
-    // Otherwise, see https://github.com/CosmWasm/cosmwasm/blob/a0cf296c43aa092b81457d96a9c6bc2ab223f6d3/contracts/hackatom/src/contract.rs#L37-L48
-    // for an example of migration where state does not match
+    // and note that migrate is called on the new version of the code.
+    // Since the smart contract address is the same, migration of funds in dYdX subaccounts is not necessary.
+    let migrations = pending_migrations(&from_version, &to_version)?;
+    for migration in migrations.iter() {
+        (migration.migrate)(deps.storage, &env)?;
+    }
 
-    // since the smart contract address is the same, migration of funds in dYdX subaccounts is not necessary
+    // fields added to the state since the old version are saved with their defaults
+    let mut state = STATE.load(deps.storage)?;
+    if let Some(new_admin) = msg.new_admin {
+        state.admin = deps.api.addr_validate(&new_admin)?;
+    }
+    if let Some(new_trader) = msg.new_trader {
+        state.trader = deps.api.addr_validate(&new_trader)?;
+    }
+    if let Some(msg_encoding) = msg.msg_encoding {
+        state.msg_encoding = msg_encoding;
+    }
+    STATE.save(deps.storage, &state)?;
 
-    Ok(Response::default())
+    // set the new version
+    cw2::set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", ver.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute(
+            "migrations",
+            migrations
+                .iter()
+                .map(|migration| migration.version)
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+        .add_attribute("admin", state.admin)
+        .add_attribute("trader", state.trader)
+        .add_attribute("msg_encoding", state.msg_encoding.to_string()))
 }
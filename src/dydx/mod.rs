@@ -3,3 +3,4 @@ pub mod proto_structs;
 pub mod querier;
 pub mod query;
 pub mod serializable_int;
+pub mod stargate;
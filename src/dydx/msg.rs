@@ -1,4 +1,4 @@
-use cosmwasm_std::{CosmosMsg, CustomMsg, Event};
+use cosmwasm_std::{Binary, CosmosMsg, CustomMsg, Event};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
@@ -100,6 +100,13 @@ impl Order {
     }
 }
 
+/// Client ids of short-term orders to cancel in a clob pair.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct OrderBatch {
+    pub clob_pair_id: u32,
+    pub client_ids: Vec<u32>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum GoodTilOneof {
@@ -107,6 +114,46 @@ pub enum GoodTilOneof {
     GoodTilBlockTime(u32),
 }
 
+/// How the contract sends the dYdX messages that have a Stargate encoding.
+/// Messages without one are always sent as custom bindings.
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema, EnumString, Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum DydxMsgEncoding {
+    /// Custom `DydxMsg` bindings, only available on chains running the wasmbinding feature branch.
+    #[default]
+    Bindings,
+    /// Protobuf encoded `CosmosMsg::Stargate` messages.
+    Stargate,
+}
+
+/// Authenticator types of dYdX's x/accountplus module.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema, EnumString, Display)]
+pub enum AuthenticatorType {
+    /// Verifies the signature of the transaction against a public key.
+    SignatureVerification,
+    /// Only allows the comma separated message type urls in its config.
+    MessageFilter,
+    /// Only allows orders in the comma separated clob pair ids in its config.
+    ClobPairIdFilter,
+    /// Only allows messages for the comma separated subaccount numbers in its config.
+    SubaccountFilter,
+    /// Passes if all of its sub-authenticators pass.
+    AllOf,
+    /// Passes if any of its sub-authenticators pass.
+    AnyOf,
+}
+
+/// A sub-authenticator of an `AllOf` or `AnyOf` authenticator. Its config is the data the authenticator would be added with.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct SubAuthenticatorInitData {
+    #[serde(rename = "type")]
+    pub authenticator_type: AuthenticatorType,
+    pub config: Binary,
+}
+
 #[non_exhaustive]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -144,6 +191,35 @@ pub enum DydxMsg {
         clob_pair_id: u32,
         good_til_block_time: u32,
     },
+    BatchCancelV1 {
+        subaccount_number: u32,
+        short_term_cancels: Vec<OrderBatch>,
+        good_til_block: u32,
+    },
+    AddAuthenticatorV1 {
+        authenticator_type: AuthenticatorType,
+        data: Binary,
+    },
+    RemoveAuthenticatorV1 {
+        id: u64,
+    },
+    TransferV1 {
+        sender: SubaccountId,
+        recipient: SubaccountId,
+        asset_id: u32,
+        amount: u64,
+    },
+}
+
+impl From<Transfer> for DydxMsg {
+    fn from(transfer: Transfer) -> Self {
+        DydxMsg::TransferV1 {
+            sender: transfer.sender,
+            recipient: transfer.recipient,
+            asset_id: transfer.asset_id,
+            amount: transfer.amount,
+        }
+    }
 }
 
 impl From<DydxMsg> for CosmosMsg<DydxMsg> {
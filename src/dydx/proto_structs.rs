@@ -1,3 +1,4 @@
+use cosmwasm_std::Binary;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
@@ -180,9 +181,9 @@ pub struct LiquidityTier {
     /// bid/ask prices and its recommended value is 500 USDC / initial margin
     /// fraction.
     /// - Impact bid price = average execution price for a market sell of the
-    /// impact notional value.
+    ///   impact notional value.
     /// - Impact ask price = average execution price for a market buy of the
-    /// impact notional value.
+    ///   impact notional value.
     #[serde(default)]
     pub impact_notional: u64,
     /// Lower cap for Open Interest Margin Fraction (OIMF), in quote quantums.
@@ -195,3 +196,141 @@ pub struct LiquidityTier {
     #[serde(default)]
     pub open_interest_upper_cap: u64,
 }
+
+/// Asset defines a single exchangable asset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct Asset {
+    /// Unique, sequentially-generated.
+    #[serde(default)]
+    pub id: u32,
+    /// The human readable symbol of the `Asset` (e.g. `USDC`, `ATOM`).
+    #[serde(default)]
+    pub symbol: String,
+    /// Denomination of the `Asset` on the dYdX chain.
+    #[serde(default)]
+    pub denom: String,
+    /// The exponent of converting one unit of `denom` to a full coin.
+    /// For example, `denom=uatom, denom_exponent=-6` defines that
+    /// `1 uatom = 10^(-6) ATOM`.
+    #[serde(default)]
+    pub denom_exponent: i32,
+    /// `true` if this `Asset` has a valid `market_id` value.
+    #[serde(default)]
+    pub has_market: bool,
+    /// The `Id` of the `Market` associated with this `Asset`. It acts as the
+    /// oracle price for the purposes of calculating collateral
+    /// and margin requirements.
+    #[serde(default)]
+    pub market_id: u32,
+    /// The exponent for converting an atomic amount (1 'quantum')
+    /// to a full coin. For example, if `atomic_resolution = -8`
+    /// then an `asset_position` with `base_quantums = 1e8` is equivalent to
+    /// a position size of one full coin.
+    #[serde(default)]
+    pub atomic_resolution: i32,
+}
+
+/// MarketParam represents the x/prices configuration for markets, including
+/// the pair and the exchange config used to compute the index price.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct MarketParam {
+    /// Unique, sequentially-generated value.
+    #[serde(default)]
+    pub id: u32,
+    /// The human-readable name of the market pair (e.g. `BTC-USD`).
+    #[serde(default)]
+    pub pair: String,
+    /// Static value. The exponent of the price.
+    /// For example if `Exponent == -5` then a `Value` of `1,000,000,000`
+    /// represents ``$10,000`. Therefore `10 ^ Exponent` represents the smallest
+    /// price step (in dollars) that can be recorded.
+    #[serde(default)]
+    pub exponent: i32,
+    /// The minimum number of exchanges that should be reporting a live price for
+    /// a price update to be considered valid.
+    #[serde(default)]
+    pub min_exchanges: u32,
+    /// The minimum allowable change in `price` value that would cause a price
+    /// update on the network. Measured as `1e-6` (parts per million).
+    #[serde(default)]
+    pub min_price_change_ppm: u32,
+    /// A string of json that encodes the configuration for resolving the price
+    /// of this market on various exchanges.
+    #[serde(default)]
+    pub exchange_config_json: String,
+}
+
+/// A fee tier for perpetuals.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PerpetualFeeTier {
+    /// Human-readable name of the tier, e.g. "Gold".
+    #[serde(default)]
+    pub name: String,
+    /// The trader's absolute volume requirement in quote quantums.
+    #[serde(default)]
+    pub absolute_volume_requirement: u64,
+    /// The total volume share requirement.
+    #[serde(default)]
+    pub total_volume_share_requirement_ppm: u32,
+    /// The maker volume share requirement.
+    #[serde(default)]
+    pub maker_volume_share_requirement_ppm: u32,
+    /// The maker fee once this tier is reached.
+    #[serde(default)]
+    pub maker_fee_ppm: i32,
+    /// The taker fee once this tier is reached.
+    #[serde(default)]
+    pub taker_fee_ppm: i32,
+}
+
+/// Fee parameters for perpetuals.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PerpetualFeeParams {
+    /// Sorted fee tiers (lowest requirements first).
+    #[serde(default)]
+    pub tiers: Vec<PerpetualFeeTier>,
+}
+
+/// Defines a rate limit over a specific number of blocks.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct MaxPerNBlocksRateLimit {
+    /// How many blocks the rate limit is over.
+    #[serde(default)]
+    pub num_blocks: u32,
+    /// What the limit is for `num_blocks`.
+    #[serde(default)]
+    pub limit: u32,
+}
+
+/// Stores the block rate limit configuration of the clob module.
+/// Each list may contain several rate limits, all of which have to be respected.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct BlockRateLimitConfiguration {
+    /// How many short term order attempts (successful and failed) are allowed
+    /// for an account per N blocks.
+    #[serde(default)]
+    pub max_short_term_orders_per_n_blocks: Vec<MaxPerNBlocksRateLimit>,
+    /// How many stateful order attempts (successful and failed) are allowed
+    /// for an account per N blocks.
+    #[serde(default)]
+    pub max_stateful_orders_per_n_blocks: Vec<MaxPerNBlocksRateLimit>,
+    /// How many short term order cancellation attempts (successful and failed)
+    /// are allowed for an account per N blocks.
+    #[serde(default)]
+    pub max_short_term_order_cancellations_per_n_blocks: Vec<MaxPerNBlocksRateLimit>,
+    /// How many short term order place and cancel attempts (successful and
+    /// failed) are allowed for an account per N blocks.
+    #[serde(default)]
+    pub max_short_term_orders_and_cancels_per_n_blocks: Vec<MaxPerNBlocksRateLimit>,
+}
+
+/// An authenticator registered for an account in x/accountplus.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct AccountAuthenticator {
+    #[serde(default)]
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub authenticator_type: String,
+    #[serde(default)]
+    pub config: Binary,
+}
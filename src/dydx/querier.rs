@@ -1,10 +1,15 @@
 use cosmwasm_std::{QuerierWrapper, StdResult};
 
 use super::{
-    proto_structs::{LiquidityTier, MarketPrice, PerpetualClobDetails, Subaccount},
+    proto_structs::{
+        AccountAuthenticator, Asset, BlockRateLimitConfiguration, ClobPair, LiquidityTier,
+        MarketParam, MarketPrice, Perpetual, PerpetualClobDetails, PerpetualFeeParams, Subaccount,
+    },
     query::{
-        DydxQuery, DydxQueryWrapper, DydxRoute, LiquidityTiersResponse, MarketPriceResponse,
-        PerpetualClobDetailsResponse, SubaccountResponse,
+        AllClobPairsResponse, AllPerpetualsResponse, AssetResponse, AuthenticatorsResponse,
+        BlockRateLimitConfigurationResponse, DydxQuery, DydxQueryWrapper, DydxRoute,
+        LiquidityTiersResponse, MarketParamResponse, MarketPriceResponse,
+        PerpetualClobDetailsResponse, PerpetualFeeParamsResponse, SubaccountResponse,
     },
 };
 
@@ -32,10 +37,7 @@ impl<'a> DydxQuerier<'a> {
     pub fn query_subaccount(&self, owner: String, number: u32) -> StdResult<SubaccountResponse> {
         let request = DydxQueryWrapper {
             route: DydxRoute::Subaccount,
-            query_data: DydxQuery::Subaccount {
-                owner: owner,
-                number: number,
-            },
+            query_data: DydxQuery::Subaccount { owner, number },
         }
         .into();
 
@@ -76,4 +78,87 @@ impl<'a> DydxQuerier<'a> {
             liquidity_tiers: result?,
         })
     }
+
+    pub fn query_asset(&self, asset_id: u32) -> StdResult<AssetResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::Asset,
+            query_data: DydxQuery::Asset { id: asset_id },
+        }
+        .into();
+
+        let result: StdResult<Asset> = self.querier.query(&request);
+        result.map(|asset| AssetResponse { asset })
+    }
+
+    pub fn query_market_param(&self, market_id: u32) -> StdResult<MarketParamResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::MarketParam,
+            query_data: DydxQuery::MarketParam { id: market_id },
+        }
+        .into();
+
+        let result: StdResult<MarketParam> = self.querier.query(&request);
+        result.map(|market_param| MarketParamResponse { market_param })
+    }
+
+    pub fn query_all_perpetuals(&self) -> StdResult<AllPerpetualsResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::AllPerpetuals,
+            query_data: DydxQuery::AllPerpetuals,
+        }
+        .into();
+
+        let result: StdResult<Vec<Perpetual>> = self.querier.query(&request);
+        result.map(|perpetuals| AllPerpetualsResponse { perpetuals })
+    }
+
+    pub fn query_all_clob_pairs(&self) -> StdResult<AllClobPairsResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::AllClobPairs,
+            query_data: DydxQuery::AllClobPairs,
+        }
+        .into();
+
+        let result: StdResult<Vec<ClobPair>> = self.querier.query(&request);
+        result.map(|clob_pairs| AllClobPairsResponse { clob_pairs })
+    }
+
+    pub fn query_perpetual_fee_params(&self) -> StdResult<PerpetualFeeParamsResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::PerpetualFeeParams,
+            query_data: DydxQuery::PerpetualFeeParams,
+        }
+        .into();
+
+        let result: StdResult<PerpetualFeeParams> = self.querier.query(&request);
+        result.map(|params| PerpetualFeeParamsResponse { params })
+    }
+
+    pub fn query_block_rate_limit_config(&self) -> StdResult<BlockRateLimitConfigurationResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::BlockRateLimitConfiguration,
+            query_data: DydxQuery::BlockRateLimitConfiguration,
+        }
+        .into();
+
+        let result: StdResult<BlockRateLimitConfiguration> = self.querier.query(&request);
+        result.map(
+            |block_rate_limit_config| BlockRateLimitConfigurationResponse {
+                block_rate_limit_config,
+            },
+        )
+    }
+
+    pub fn query_authenticators(&self, account: String) -> StdResult<AuthenticatorsResponse> {
+        let request = DydxQueryWrapper {
+            route: DydxRoute::Authenticators,
+            query_data: DydxQuery::Authenticators { account },
+        }
+        .into();
+
+        let result: StdResult<Vec<AccountAuthenticator>> = self.querier.query(&request);
+        result.map(|account_authenticators| AuthenticatorsResponse {
+            account_authenticators,
+        })
+    }
 }
@@ -2,7 +2,10 @@ use cosmwasm_std::CustomQuery;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use super::proto_structs::{LiquidityTier, MarketPrice, PerpetualClobDetails, Subaccount};
+use super::proto_structs::{
+    AccountAuthenticator, Asset, BlockRateLimitConfiguration, ClobPair, LiquidityTier, MarketParam,
+    MarketPrice, Perpetual, PerpetualClobDetails, PerpetualFeeParams, Subaccount,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -11,6 +14,13 @@ pub enum DydxRoute {
     Subaccount,
     PerpetualClobDetails,
     LiquidityTiers,
+    Asset,
+    MarketParam,
+    AllPerpetuals,
+    AllClobPairs,
+    PerpetualFeeParams,
+    BlockRateLimitConfiguration,
+    Authenticators,
 }
 
 /// SeiQueryWrapper is an override of QueryRequest::Custom to access Sei-specific modules
@@ -32,6 +42,13 @@ pub enum DydxQuery {
     Subaccount { owner: String, number: u32 },
     PerpetualClobDetails { id: u32 },
     LiquidityTiers,
+    Asset { id: u32 },
+    MarketParam { id: u32 },
+    AllPerpetuals,
+    AllClobPairs,
+    PerpetualFeeParams,
+    BlockRateLimitConfiguration,
+    Authenticators { account: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -53,3 +70,38 @@ pub struct PerpetualClobDetailsResponse {
 pub struct LiquidityTiersResponse {
     pub liquidity_tiers: Vec<LiquidityTier>,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetResponse {
+    pub asset: Asset,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MarketParamResponse {
+    pub market_param: MarketParam,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AllPerpetualsResponse {
+    pub perpetuals: Vec<Perpetual>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AllClobPairsResponse {
+    pub clob_pairs: Vec<ClobPair>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PerpetualFeeParamsResponse {
+    pub params: PerpetualFeeParams,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BlockRateLimitConfigurationResponse {
+    pub block_rate_limit_config: BlockRateLimitConfiguration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AuthenticatorsResponse {
+    pub account_authenticators: Vec<AccountAuthenticator>,
+}
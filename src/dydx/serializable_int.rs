@@ -1,8 +1,11 @@
+use cosmwasm_std::{ConversionOverflowError, Int256, SignedDecimal, SignedDecimal256};
 use num_bigint::BigInt;
+use num_traits::Signed;
 use schemars::schema::{InstanceType, Schema, SchemaObject};
 use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
+use std::ops::{Add, AddAssign, Mul, MulAssign, Neg, Sub, SubAssign};
 
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct SerializableInt {
@@ -16,6 +19,154 @@ impl SerializableInt {
     pub fn to_big_int(&self) -> &BigInt {
         &self.i
     }
+
+    pub fn is_negative(&self) -> bool {
+        self.i.is_negative()
+    }
+
+    /// Converts to an `Int256`, failing instead of truncating if the value does not fit.
+    pub fn to_int256(&self) -> Result<Int256, ConversionOverflowError> {
+        let bytes = self.i.to_signed_bytes_be();
+        if bytes.len() > 32 {
+            return Err(self.overflow_error("Int256"));
+        }
+        // sign-extend the two's complement bytes to 32 bytes
+        let fill = if self.is_negative() { 0xff } else { 0x00 };
+        let mut data = [fill; 32];
+        data[32 - bytes.len()..].copy_from_slice(&bytes);
+        Ok(Int256::from_be_bytes(data))
+    }
+
+    /// Interprets the integer as atomics with `decimal_places` decimals, e.g. quantums of an asset.
+    pub fn to_signed_decimal_256(
+        &self,
+        decimal_places: u32,
+    ) -> Result<SignedDecimal256, ConversionOverflowError> {
+        SignedDecimal256::from_atomics(self.to_int256()?, decimal_places)
+            .map_err(|_| self.overflow_error("SignedDecimal256"))
+    }
+
+    /// Same as `to_signed_decimal_256`, but also fails if the value does not fit a `SignedDecimal`.
+    pub fn to_signed_decimal(
+        &self,
+        decimal_places: u32,
+    ) -> Result<SignedDecimal, ConversionOverflowError> {
+        SignedDecimal::try_from(self.to_signed_decimal_256(decimal_places)?)
+            .map_err(|_| self.overflow_error("SignedDecimal"))
+    }
+
+    fn overflow_error(&self, target_type: &'static str) -> ConversionOverflowError {
+        ConversionOverflowError::new("SerializableInt", target_type, self.i.to_string())
+    }
+}
+
+impl fmt::Display for SerializableInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.i.fmt(f)
+    }
+}
+
+impl From<BigInt> for SerializableInt {
+    fn from(i: BigInt) -> Self {
+        Self::new(i)
+    }
+}
+
+impl From<i128> for SerializableInt {
+    fn from(i: i128) -> Self {
+        Self::new(BigInt::from(i))
+    }
+}
+
+impl From<Int256> for SerializableInt {
+    fn from(i: Int256) -> Self {
+        Self::new(BigInt::from_signed_bytes_be(&i.to_be_bytes()))
+    }
+}
+
+impl TryFrom<&SerializableInt> for Int256 {
+    type Error = ConversionOverflowError;
+
+    fn try_from(value: &SerializableInt) -> Result<Self, Self::Error> {
+        value.to_int256()
+    }
+}
+
+impl TryFrom<SerializableInt> for Int256 {
+    type Error = ConversionOverflowError;
+
+    fn try_from(value: SerializableInt) -> Result<Self, Self::Error> {
+        value.to_int256()
+    }
+}
+
+/// Implements a binary operator for all combinations of owned and borrowed operands.
+macro_rules! impl_serializable_int_op {
+    ($trait:ident, $method:ident, $assign_trait:ident, $assign_method:ident) => {
+        impl $trait<&SerializableInt> for &SerializableInt {
+            type Output = SerializableInt;
+
+            fn $method(self, rhs: &SerializableInt) -> SerializableInt {
+                SerializableInt::new((&self.i).$method(&rhs.i))
+            }
+        }
+
+        impl $trait<SerializableInt> for SerializableInt {
+            type Output = SerializableInt;
+
+            fn $method(self, rhs: SerializableInt) -> SerializableInt {
+                (&self).$method(&rhs)
+            }
+        }
+
+        impl $trait<&SerializableInt> for SerializableInt {
+            type Output = SerializableInt;
+
+            fn $method(self, rhs: &SerializableInt) -> SerializableInt {
+                (&self).$method(rhs)
+            }
+        }
+
+        impl $trait<SerializableInt> for &SerializableInt {
+            type Output = SerializableInt;
+
+            fn $method(self, rhs: SerializableInt) -> SerializableInt {
+                self.$method(&rhs)
+            }
+        }
+
+        impl $assign_trait<&SerializableInt> for SerializableInt {
+            fn $assign_method(&mut self, rhs: &SerializableInt) {
+                self.i.$assign_method(&rhs.i);
+            }
+        }
+
+        impl $assign_trait<SerializableInt> for SerializableInt {
+            fn $assign_method(&mut self, rhs: SerializableInt) {
+                self.i.$assign_method(rhs.i);
+            }
+        }
+    };
+}
+
+impl_serializable_int_op!(Add, add, AddAssign, add_assign);
+impl_serializable_int_op!(Sub, sub, SubAssign, sub_assign);
+impl_serializable_int_op!(Mul, mul, MulAssign, mul_assign);
+
+impl Neg for SerializableInt {
+    type Output = SerializableInt;
+
+    fn neg(self) -> SerializableInt {
+        SerializableInt::new(-self.i)
+    }
+}
+
+impl Neg for &SerializableInt {
+    type Output = SerializableInt;
+
+    fn neg(self) -> SerializableInt {
+        SerializableInt::new(-&self.i)
+    }
 }
 
 impl Serialize for SerializableInt {
@@ -38,7 +189,35 @@ impl<'de> Deserialize<'de> for SerializableInt {
             type Value = SerializableInt;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a string representing a big integer")
+                formatter.write_str("a big integer or a string representing a big integer")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<SerializableInt, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(SerializableInt::new(BigInt::from(value)))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<SerializableInt, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(SerializableInt::new(BigInt::from(value)))
+            }
+
+            fn visit_i128<E>(self, value: i128) -> Result<SerializableInt, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(SerializableInt::new(BigInt::from(value)))
+            }
+
+            fn visit_u128<E>(self, value: u128) -> Result<SerializableInt, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(SerializableInt::new(BigInt::from(value)))
             }
 
             fn visit_str<E>(self, value: &str) -> Result<SerializableInt, E>
@@ -50,7 +229,7 @@ impl<'de> Deserialize<'de> for SerializableInt {
             }
         }
 
-        deserializer.deserialize_str(SerializableIntVisitor)
+        deserializer.deserialize_any(SerializableIntVisitor)
     }
 }
 
@@ -71,3 +250,75 @@ impl JsonSchema for SerializableInt {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::{from_json, Int256, SignedDecimal, SignedDecimal256};
+    use num_bigint::BigInt;
+
+    use super::SerializableInt;
+
+    #[test]
+    fn deserializes_strings_and_numbers() {
+        let from_str: SerializableInt = from_json(br#""-123456789012345678901234567890""#).unwrap();
+        assert!(from_str.i == "-123456789012345678901234567890".parse::<BigInt>().unwrap());
+
+        let from_positive: SerializableInt = from_json(b"18446744073709551615").unwrap();
+        assert!(from_positive == SerializableInt::from(u64::MAX as i128));
+        let from_negative: SerializableInt = from_json(b"-42").unwrap();
+        assert!(from_negative == SerializableInt::from(-42));
+
+        assert!(from_json::<SerializableInt>(br#""1.5""#).is_err());
+        assert!(from_json::<SerializableInt>(b"true").is_err());
+    }
+
+    #[test]
+    fn converts_to_int256_without_truncating() {
+        assert!(SerializableInt::from(-1).to_int256().unwrap() == Int256::from(-1));
+        assert!(SerializableInt::from(i128::MIN).to_int256().unwrap() == Int256::from(i128::MIN));
+        assert!(SerializableInt::from(Int256::MAX).to_int256().unwrap() == Int256::MAX);
+        assert!(SerializableInt::from(Int256::MIN).to_int256().unwrap() == Int256::MIN);
+
+        let too_large = SerializableInt::from(Int256::MAX) + SerializableInt::from(1);
+        let err = too_large.to_int256().unwrap_err();
+        assert!(err.source_type == "SerializableInt");
+        assert!(err.target_type == "Int256");
+        assert!(err.value == too_large.to_string());
+        let too_small = SerializableInt::from(Int256::MIN) - SerializableInt::from(1);
+        assert!(Int256::try_from(&too_small).is_err());
+    }
+
+    #[test]
+    fn converts_to_signed_decimals() {
+        let quantums = SerializableInt::from(-1_500_000);
+        assert!(
+            quantums.to_signed_decimal_256(6).unwrap()
+                == "-1.5".parse::<SignedDecimal256>().unwrap()
+        );
+        assert!(quantums.to_signed_decimal(6).unwrap() == "-1.5".parse::<SignedDecimal>().unwrap());
+
+        // fits an i128, but not a SignedDecimal once scaled by 10^18
+        let large = SerializableInt::from(i128::MAX / 10);
+        assert!(large.to_signed_decimal_256(0).is_ok());
+        assert!(large.to_signed_decimal(0).unwrap_err().target_type == "SignedDecimal");
+
+        let huge = SerializableInt::from(Int256::MAX);
+        assert!(huge.to_signed_decimal_256(0).unwrap_err().target_type == "SignedDecimal256");
+    }
+
+    #[test]
+    fn supports_arithmetic() {
+        let a = SerializableInt::from(7);
+        let b = SerializableInt::from(-3);
+        assert!(&a + &b == SerializableInt::from(4));
+        assert!(&a - &b == SerializableInt::from(10));
+        assert!(&a * &b == SerializableInt::from(-21));
+        assert!(-&b == SerializableInt::from(3));
+
+        let mut c = a.clone();
+        c += &b;
+        c -= SerializableInt::from(1);
+        c *= b;
+        assert!(c == SerializableInt::from(-9));
+    }
+}
@@ -0,0 +1,432 @@
+//! Protobuf encoding of dYdX messages so they can be sent as `CosmosMsg::Stargate` instead of custom bindings.
+//! Field numbers follow the v4-chain proto definitions:
+//! https://github.com/dydxprotocol/v4-chain/blob/main/proto/dydxprotocol/sending/transfer.proto
+//! https://github.com/dydxprotocol/v4-chain/blob/main/proto/dydxprotocol/clob/tx.proto
+//! https://github.com/dydxprotocol/v4-chain/blob/main/proto/dydxprotocol/clob/order.proto
+
+use cosmwasm_std::{Binary, CosmosMsg, StdError, StdResult};
+use protobuf::CodedOutputStream;
+
+use super::msg::{DydxMsg, GoodTilOneof, Order, OrderId};
+use super::proto_structs::SubaccountId;
+
+pub const MSG_DEPOSIT_TO_SUBACCOUNT_TYPE_URL: &str = "/dydxprotocol.sending.MsgDepositToSubaccount";
+pub const MSG_WITHDRAW_FROM_SUBACCOUNT_TYPE_URL: &str =
+    "/dydxprotocol.sending.MsgWithdrawFromSubaccount";
+pub const MSG_PLACE_ORDER_TYPE_URL: &str = "/dydxprotocol.clob.MsgPlaceOrder";
+pub const MSG_CANCEL_ORDER_TYPE_URL: &str = "/dydxprotocol.clob.MsgCancelOrder";
+
+/// A message that can be written in the protobuf wire format.
+/// Scalar fields with their default value are omitted as in proto3, non-nullable embedded messages are always written.
+pub trait ProtoEncode {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()>;
+
+    fn encode_to_vec(&self) -> StdResult<Vec<u8>> {
+        let mut bytes = vec![];
+        let mut os = CodedOutputStream::vec(&mut bytes);
+        self.write_fields(&mut os)
+            .and_then(|_| os.flush())
+            .map_err(|e| StdError::generic_err(format!("protobuf encoding failed: {}", e)))?;
+        drop(os);
+        Ok(bytes)
+    }
+}
+
+fn write_message<T: ProtoEncode>(
+    os: &mut CodedOutputStream,
+    field_number: u32,
+    msg: &T,
+) -> protobuf::Result<()> {
+    let mut bytes = vec![];
+    let mut inner = CodedOutputStream::vec(&mut bytes);
+    msg.write_fields(&mut inner)?;
+    inner.flush()?;
+    drop(inner);
+    os.write_bytes(field_number, &bytes)
+}
+
+fn write_string(
+    os: &mut CodedOutputStream,
+    field_number: u32,
+    value: &str,
+) -> protobuf::Result<()> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    os.write_string(field_number, value)
+}
+
+fn write_uint32(os: &mut CodedOutputStream, field_number: u32, value: u32) -> protobuf::Result<()> {
+    if value == 0 {
+        return Ok(());
+    }
+    os.write_uint32(field_number, value)
+}
+
+fn write_uint64(os: &mut CodedOutputStream, field_number: u32, value: u64) -> protobuf::Result<()> {
+    if value == 0 {
+        return Ok(());
+    }
+    os.write_uint64(field_number, value)
+}
+
+fn write_fixed32(
+    os: &mut CodedOutputStream,
+    field_number: u32,
+    value: u32,
+) -> protobuf::Result<()> {
+    if value == 0 {
+        return Ok(());
+    }
+    os.write_fixed32(field_number, value)
+}
+
+fn write_enum(os: &mut CodedOutputStream, field_number: u32, value: u32) -> protobuf::Result<()> {
+    if value == 0 {
+        return Ok(());
+    }
+    os.write_enum(field_number, value as i32)
+}
+
+fn write_bool(os: &mut CodedOutputStream, field_number: u32, value: bool) -> protobuf::Result<()> {
+    if !value {
+        return Ok(());
+    }
+    os.write_bool(field_number, value)
+}
+
+/// `good_til_oneof` is written even when zero since oneof fields track presence.
+fn write_good_til_oneof(
+    os: &mut CodedOutputStream,
+    good_til_block_field: u32,
+    good_til_oneof: &GoodTilOneof,
+) -> protobuf::Result<()> {
+    match good_til_oneof {
+        GoodTilOneof::GoodTilBlock(block) => os.write_uint32(good_til_block_field, *block),
+        GoodTilOneof::GoodTilBlockTime(time) => os.write_fixed32(good_til_block_field + 1, *time),
+    }
+}
+
+impl ProtoEncode for SubaccountId {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_string(os, 1, &self.owner)?;
+        write_uint32(os, 2, self.number)
+    }
+}
+
+impl ProtoEncode for OrderId {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_message(os, 1, &self.subaccount_id)?;
+        write_fixed32(os, 2, self.client_id)?;
+        write_uint32(os, 3, self.order_flags)?;
+        write_uint32(os, 4, self.clob_pair_id)
+    }
+}
+
+impl ProtoEncode for Order {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_message(os, 1, &self.order_id)?;
+        write_enum(os, 2, self.side.clone() as u32)?;
+        write_uint64(os, 3, self.quantums)?;
+        write_uint64(os, 4, self.subticks)?;
+        write_good_til_oneof(os, 5, &self.good_til_oneof)?;
+        write_enum(os, 7, self.time_in_force.clone() as u32)?;
+        write_bool(os, 8, self.reduce_only)?;
+        write_uint32(os, 9, self.client_metadata)?;
+        write_enum(os, 10, self.condition_type.clone() as u32)?;
+        write_uint64(os, 11, self.conditional_order_trigger_subticks)
+    }
+}
+
+/// `MsgDepositToSubaccount` of the x/sending module.
+pub struct MsgDepositToSubaccount {
+    pub sender: String,
+    pub recipient: SubaccountId,
+    pub asset_id: u32,
+    pub quantums: u64,
+}
+
+impl ProtoEncode for MsgDepositToSubaccount {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_string(os, 1, &self.sender)?;
+        write_message(os, 2, &self.recipient)?;
+        write_uint32(os, 3, self.asset_id)?;
+        write_uint64(os, 4, self.quantums)
+    }
+}
+
+/// `MsgWithdrawFromSubaccount` of the x/sending module. Its field numbers start at 2.
+pub struct MsgWithdrawFromSubaccount {
+    pub sender: SubaccountId,
+    pub recipient: String,
+    pub asset_id: u32,
+    pub quantums: u64,
+}
+
+impl ProtoEncode for MsgWithdrawFromSubaccount {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_message(os, 2, &self.sender)?;
+        write_string(os, 3, &self.recipient)?;
+        write_uint32(os, 4, self.asset_id)?;
+        write_uint64(os, 5, self.quantums)
+    }
+}
+
+/// `MsgPlaceOrder` of the x/clob module.
+pub struct MsgPlaceOrder {
+    pub order: Order,
+}
+
+impl ProtoEncode for MsgPlaceOrder {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_message(os, 1, &self.order)
+    }
+}
+
+/// `MsgCancelOrder` of the x/clob module.
+pub struct MsgCancelOrder {
+    pub order_id: OrderId,
+    pub good_til_oneof: GoodTilOneof,
+}
+
+impl ProtoEncode for MsgCancelOrder {
+    fn write_fields(&self, os: &mut CodedOutputStream) -> protobuf::Result<()> {
+        write_message(os, 1, &self.order_id)?;
+        write_good_til_oneof(os, 2, &self.good_til_oneof)
+    }
+}
+
+fn stargate_msg<T: ProtoEncode>(type_url: &str, msg: &T) -> StdResult<CosmosMsg<DydxMsg>> {
+    Ok(CosmosMsg::Stargate {
+        type_url: type_url.to_string(),
+        value: Binary::from(msg.encode_to_vec()?),
+    })
+}
+
+/// Encodes a dYdX binding message as the equivalent Stargate message sent by `contract`.
+/// Returns `None` for messages that have no Stargate encoding and must be sent as custom bindings.
+pub fn to_stargate_msg(msg: &DydxMsg, contract: &str) -> StdResult<Option<CosmosMsg<DydxMsg>>> {
+    let contract_subaccount = |number: u32| SubaccountId {
+        owner: contract.to_string(),
+        number,
+    };
+
+    let stargate = match msg.clone() {
+        DydxMsg::DepositToSubaccountV1 {
+            recipient,
+            asset_id,
+            quantums,
+        } => stargate_msg(
+            MSG_DEPOSIT_TO_SUBACCOUNT_TYPE_URL,
+            &MsgDepositToSubaccount {
+                sender: contract.to_string(),
+                recipient,
+                asset_id,
+                quantums,
+            },
+        )?,
+        DydxMsg::WithdrawFromSubaccountV1 {
+            subaccount_number,
+            recipient,
+            asset_id,
+            quantums,
+        } => stargate_msg(
+            MSG_WITHDRAW_FROM_SUBACCOUNT_TYPE_URL,
+            &MsgWithdrawFromSubaccount {
+                sender: contract_subaccount(subaccount_number),
+                recipient,
+                asset_id,
+                quantums,
+            },
+        )?,
+        DydxMsg::PlaceOrderV1 {
+            subaccount_number,
+            client_id,
+            order_flags,
+            clob_pair_id,
+            side,
+            quantums,
+            subticks,
+            good_til_block_time,
+            time_in_force,
+            reduce_only,
+            client_metadata,
+            condition_type,
+            conditional_order_trigger_subticks,
+        } => stargate_msg(
+            MSG_PLACE_ORDER_TYPE_URL,
+            &MsgPlaceOrder {
+                order: Order {
+                    order_id: OrderId {
+                        subaccount_id: contract_subaccount(subaccount_number),
+                        client_id,
+                        order_flags,
+                        clob_pair_id,
+                    },
+                    side,
+                    quantums,
+                    subticks,
+                    good_til_oneof: GoodTilOneof::GoodTilBlockTime(good_til_block_time),
+                    time_in_force,
+                    reduce_only,
+                    client_metadata,
+                    condition_type,
+                    conditional_order_trigger_subticks,
+                },
+            },
+        )?,
+        DydxMsg::CancelOrderV1 {
+            subaccount_number,
+            client_id,
+            order_flags,
+            clob_pair_id,
+            good_til_block_time,
+        } => stargate_msg(
+            MSG_CANCEL_ORDER_TYPE_URL,
+            &MsgCancelOrder {
+                order_id: OrderId {
+                    subaccount_id: contract_subaccount(subaccount_number),
+                    client_id,
+                    order_flags,
+                    clob_pair_id,
+                },
+                good_til_oneof: GoodTilOneof::GoodTilBlockTime(good_til_block_time),
+            },
+        )?,
+        _ => return Ok(None),
+    };
+    Ok(Some(stargate))
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::CosmosMsg;
+
+    use super::*;
+    use crate::dydx::msg::{OrderConditionType, OrderSide, OrderTimeInForce};
+
+    const OWNER: &str = "dydx1owner";
+
+    fn stargate_value(msg: &DydxMsg) -> (String, Vec<u8>) {
+        match to_stargate_msg(msg, OWNER).unwrap().unwrap() {
+            CosmosMsg::Stargate { type_url, value } => (type_url, value.to_vec()),
+            _ => panic!("expected a stargate message"),
+        }
+    }
+
+    /// OrderId { subaccount_id: { owner: OWNER, number: 1 }, client_id: 101, order_flags: 64, clob_pair_id: 1 }
+    fn encoded_order_id() -> Vec<u8> {
+        [
+            &[0x0a, 0x0e, 0x0a, 0x0a][..],
+            OWNER.as_bytes(),
+            &[0x10, 0x01],
+            &[0x15, 0x65, 0x00, 0x00, 0x00],
+            &[0x18, 0x40],
+            &[0x20, 0x01],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn encodes_deposit_to_subaccount() {
+        let (type_url, value) = stargate_value(&DydxMsg::DepositToSubaccountV1 {
+            recipient: SubaccountId {
+                owner: OWNER.to_string(),
+                number: 2,
+            },
+            asset_id: 0,
+            quantums: 1_000_000,
+        });
+
+        let expected = [
+            &[0x0a, 0x0a][..],
+            OWNER.as_bytes(),
+            &[0x12, 0x0e, 0x0a, 0x0a],
+            OWNER.as_bytes(),
+            &[0x10, 0x02],
+            &[0x20, 0xc0, 0x84, 0x3d],
+        ]
+        .concat();
+        assert!(type_url == "/dydxprotocol.sending.MsgDepositToSubaccount");
+        assert!(value == expected);
+    }
+
+    #[test]
+    fn encodes_withdraw_from_subaccount() {
+        let (type_url, value) = stargate_value(&DydxMsg::WithdrawFromSubaccountV1 {
+            subaccount_number: 0,
+            recipient: "dydx1user".to_string(),
+            asset_id: 0,
+            quantums: 150,
+        });
+
+        let expected = [
+            &[0x12, 0x0c, 0x0a, 0x0a][..],
+            OWNER.as_bytes(),
+            &[0x1a, 0x09],
+            b"dydx1user",
+            &[0x28, 0x96, 0x01],
+        ]
+        .concat();
+        assert!(type_url == "/dydxprotocol.sending.MsgWithdrawFromSubaccount");
+        assert!(value == expected);
+    }
+
+    #[test]
+    fn encodes_place_order() {
+        let (type_url, value) = stargate_value(&DydxMsg::PlaceOrderV1 {
+            subaccount_number: 1,
+            client_id: 101,
+            order_flags: 64,
+            clob_pair_id: 1,
+            side: OrderSide::Buy,
+            quantums: 1_000_000,
+            subticks: 100_000,
+            good_til_block_time: 1721231980,
+            time_in_force: OrderTimeInForce::PostOnly,
+            reduce_only: false,
+            client_metadata: 0,
+            condition_type: OrderConditionType::Unspecified,
+            conditional_order_trigger_subticks: 0,
+        });
+
+        let expected = [
+            &[0x0a, 0x2c, 0x0a, 0x19][..],
+            &encoded_order_id(),
+            &[0x10, 0x01],
+            &[0x18, 0xc0, 0x84, 0x3d],
+            &[0x20, 0xa0, 0x8d, 0x06],
+            &[0x35, 0x6c, 0xea, 0x97, 0x66],
+            &[0x38, 0x02],
+        ]
+        .concat();
+        assert!(type_url == "/dydxprotocol.clob.MsgPlaceOrder");
+        assert!(value == expected);
+    }
+
+    #[test]
+    fn encodes_cancel_order() {
+        let (type_url, value) = stargate_value(&DydxMsg::CancelOrderV1 {
+            subaccount_number: 1,
+            client_id: 101,
+            order_flags: 64,
+            clob_pair_id: 1,
+            good_til_block_time: 1721231980,
+        });
+
+        let expected = [
+            &[0x0a, 0x19][..],
+            &encoded_order_id(),
+            &[0x1d, 0x6c, 0xea, 0x97, 0x66],
+        ]
+        .concat();
+        assert!(type_url == "/dydxprotocol.clob.MsgCancelOrder");
+        assert!(value == expected);
+    }
+
+    #[test]
+    fn messages_without_stargate_encoding_are_skipped() {
+        let msg = DydxMsg::RemoveAuthenticatorV1 { id: 0 };
+        assert!(to_stargate_msg(&msg, OWNER).unwrap().is_none());
+    }
+}
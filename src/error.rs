@@ -1,7 +1,8 @@
-use cosmwasm_std::{Addr, StdError};
+use cosmwasm_std::{Addr, StdError, Uint128};
 use thiserror::Error;
 
 use crate::execute::market_make::NewOrder;
+use crate::state::VaultStatus;
 
 pub type ContractResult<T> = Result<T, ContractError>;
 
@@ -13,34 +14,68 @@ pub enum ContractError {
     InvalidOwnerDuringInstantiation { owner: Addr },
     #[error("{sender} is not the trader")]
     SenderIsNotTrader { sender: Addr },
+    #[error("{sender} is not the admin")]
+    SenderIsNotAdmin { sender: Addr },
     #[error("An invalid address was provided: {addr}")]
     InvalidAddress { addr: String },
     #[error("{sender} does not have permission to create vaults")]
     SenderCannotCreateVault { sender: Addr },
     #[error("{sender} does not have permission to process withdrawals")]
     SenderCannotProcessWithdrawals { sender: Addr },
-    #[error("Tried to set {new_trader} as trader of vault: {perp_id}, but they do not have permission to trade")]
-    NewVaultTraderMustBeApproved { new_trader: Addr, perp_id: u32 },
+    #[error("Tried to set {new_trader} as trader of vault: {vault_id}, but they do not have permission to trade")]
+    NewVaultTraderMustBeApproved { new_trader: Addr, vault_id: u32 },
 
-    #[error("{sender} does not have permission to place trades. Only {expected} can place trades  perp_id: {perp_id}")]
+    #[error("{sender} does not have permission to place trades. Only {expected} can place trades  vault_id: {vault_id}")]
     SenderCannotPlaceTrade {
         sender: Addr,
         expected: String,
-        perp_id: u32,
+        vault_id: u32,
     },
     #[error("Trade permissions cannot be revoked from the contract deployer")]
     CannotRemoveContractDeployerAsTrader,
 
-    #[error("Vault already initialized for perp_id: {perp_id}")]
-    VaultAlreadyInitialized { perp_id: u32 },
-    #[error("Vault with perp_id: {perp_id} is not initialized")]
-    VaultNotInitialized { perp_id: u32 },
+    #[error("Vault already initialized for vault_id: {vault_id}")]
+    VaultAlreadyInitialized { vault_id: u32 },
+    #[error("Vault with vault_id: {vault_id} is not initialized")]
+    VaultNotInitialized { vault_id: u32 },
+    #[error(
+        "Subaccount number: {subaccount_number} is already used by vault with vault_id: {vault_id}"
+    )]
+    SubaccountAlreadyUsed {
+        subaccount_number: u32,
+        vault_id: u32,
+    },
+    #[error("Invalid subaccount number: {subaccount_number} for a vault on perp market: {perp_id}: {reason}")]
+    InvalidSubaccountNumber {
+        subaccount_number: u32,
+        perp_id: u32,
+        reason: String,
+    },
+
+    #[error("Vault with vault_id: {vault_id} is not active, its status is: {status:?}")]
+    VaultNotActive { vault_id: u32, status: VaultStatus },
+    #[error("The perp market of vault with vault_id: {vault_id} is in final settlement")]
+    MarketInFinalSettlement { vault_id: u32 },
+    #[error("Cannot settle vault with vault_id: {vault_id}: {reason}")]
+    CannotSettleVault { vault_id: u32, reason: String },
+    #[error("Cannot close vault with vault_id: {vault_id}: {reason}")]
+    CannotCloseVault { vault_id: u32, reason: String },
+    #[error("Cannot migrate from version {from_version} to {to_version}: {reason}")]
+    CannotMigrate {
+        from_version: String,
+        to_version: String,
+        reason: String,
+    },
+    #[error("Invalid contract version {version}: {reason}")]
+    InvalidContractVersion { version: String, reason: String },
+    #[error("Cannot archive vault with vault_id: {vault_id}: {reason}")]
+    CannotArchiveVault { vault_id: u32, reason: String },
 
-    #[error("The subaccount for vault with perp_id: {perp_id} has more that one perp position")]
-    VaultSubaccountHasMoreThanOnePerpPosition { perp_id: u32 },
+    #[error("The subaccount for vault with vault_id: {vault_id} has more that one perp position")]
+    VaultSubaccountHasMoreThanOnePerpPosition { vault_id: u32 },
 
-    #[error("The subaccount for vault with perp_id: {perp_id} has more that one asset position")]
-    VaultSubaccountHasMoreThanOneAssetPosition { perp_id: u32 },
+    #[error("The subaccount for vault with vault_id: {vault_id} has more that one asset position")]
+    VaultSubaccountHasMoreThanOneAssetPosition { vault_id: u32 },
 
     #[error("The subaccount of the order id must be owned by this smart contract")]
     InvalidOrderIdSubaccountOwner,
@@ -61,6 +96,9 @@ pub enum ContractError {
         perp_id: u32,
     },
 
+    #[error("Invalid collateral: {reason}")]
+    InvalidCollateral { reason: String },
+
     #[error("Tried to deposit an invalid coin: {coin_type}. Only USDC is accepted")]
     InvalidCoin { coin_type: String },
 
@@ -70,11 +108,21 @@ pub enum ContractError {
     #[error("Tried to withdraw an invalid amount of: {coin_type}, {amount}")]
     InvalidWithdrawalAmount { coin_type: String, amount: u128 },
 
-    #[error("Withdrawal would increase leverage over 1x for subaccount with perp_id: {perp_id}")]
-    WithdrawalWouldIncreaseLeverageTooMuch { perp_id: u32 },
+    #[error("Withdrawal would increase leverage over 1x for subaccount with vault_id: {vault_id}")]
+    WithdrawalWouldIncreaseLeverageTooMuch { vault_id: u32 },
 
-    #[error("New orders would increase leverage over 1x for subaccount with perp_id: {perp_id}")]
-    NewOrdersWouldIncreaseLeverageTooMuch { perp_id: u32 },
+    #[error("Rebalance would increase leverage over 1x for subaccount with vault_id: {vault_id}")]
+    RebalanceWouldIncreaseLeverageTooMuch { vault_id: u32 },
+
+    #[error("Cannot rebalance from vault with vault_id: {from_vault_id} to vault with vault_id: {to_vault_id}: {reason}")]
+    InvalidRebalance {
+        from_vault_id: u32,
+        to_vault_id: u32,
+        reason: String,
+    },
+
+    #[error("New orders would increase leverage over 1x for subaccount with vault_id: {vault_id}")]
+    NewOrdersWouldIncreaseLeverageTooMuch { vault_id: u32 },
 
     #[error("Cannot place new order with an unspecified side: {new_order}")]
     MustSpecifyOrderSide { new_order: NewOrder },
@@ -85,8 +133,65 @@ pub enum ContractError {
     #[error("Trader can only cancel at most 6 orders at a time")]
     CanOnlyCancelSixOrderOrders {},
 
-    #[error("Could not find LP tokens with perp_id: {perp_id} for {user}")]
-    LpTokensNotFound { user: Addr, perp_id: u32 },
+    #[error("Trader can only cancel at most {max} orders in a batch, got {num_client_ids}")]
+    BatchCancelTooLarge { num_client_ids: usize, max: usize },
+
+    #[error("Invalid batch cancel: {reason}")]
+    InvalidBatchCancel { reason: String },
+
+    #[error("Good til block: {good_til_block} must be after the current block: {current_block} and at most {max_block}")]
+    InvalidGoodTilBlock {
+        good_til_block: u32,
+        current_block: u64,
+        max_block: u64,
+    },
+
+    #[error("Invalid permissioned key for vault with vault_id: {vault_id}: {reason}")]
+    InvalidPermissionedKey { vault_id: u32, reason: String },
+
+    #[error("Permissioned key with authenticator id: {authenticator_id} not found for vault with vault_id: {vault_id}")]
+    PermissionedKeyNotFound {
+        vault_id: u32,
+        authenticator_id: u64,
+    },
+
+    #[error("Could not find LP tokens with vault_id: {vault_id} for {user}")]
+    LpTokensNotFound { user: Addr, vault_id: u32 },
+
+    #[error("Requested {requested} LP tokens but only {balance} are held for vault with vault_id: {vault_id}")]
+    InsufficientLpTokens {
+        vault_id: u32,
+        requested: Uint128,
+        balance: Uint128,
+    },
+    #[error("Requested {requested} LP tokens but only {unlocked} are unlocked for vault with vault_id: {vault_id}. The requested amount unlocks at timestamp: {unlock_time}")]
+    LpTokensLocked {
+        vault_id: u32,
+        requested: Uint128,
+        unlocked: Uint128,
+        unlock_time: u64,
+    },
+
+    #[error("Invalid config for vault with vault_id: {vault_id}: {reason}")]
+    InvalidVaultConfig { vault_id: u32, reason: String },
+
+    #[error("LP token snapshots are only available from height: {start_height}, requested height: {height}")]
+    LpSnapshotNotAvailable { height: u64, start_height: u64 },
+
+    #[error("The APR window must be at least 1 second")]
+    InvalidAprWindow {},
+
+    #[error("{referrer} cannot refer their own deposits")]
+    SelfReferral { referrer: Addr },
+
+    #[error("Invalid referral fee share: {fee_share}. It must be at most 1")]
+    InvalidReferralFeeShare { fee_share: String },
+
+    #[error("Invalid reward stream for vault with vault_id: {vault_id}: {reason}")]
+    InvalidRewardStream { vault_id: u32, reason: String },
+
+    #[error("{user} has no rewards to claim from vault with vault_id: {vault_id}")]
+    NoRewardsToClaim { vault_id: u32, user: Addr },
 
     #[error("Only one coin type can be deposited at a time")]
     CanOnlyDepositOneCointype {},
@@ -98,12 +203,21 @@ pub enum ContractError {
     MintingCannotExceedCap {},
     #[error("Burning cannot exceed the supply")]
     BurningCannotExceedSupply {},
-    #[error("could not find LP token for vault with perp_id: {perp_id}")]
-    MissingLpToken { perp_id: u32 },
+    #[error("could not find LP token for vault with vault_id: {vault_id}")]
+    MissingLpToken { vault_id: u32 },
 
-    #[error("could not find withdrawal_queue for vault with perp_id: {perp_id}")]
-    MissingWithdrawalQueue { perp_id: u32 },
+    #[error("could not find withdrawal_queue for vault with vault_id: {vault_id}")]
+    MissingWithdrawalQueue { vault_id: u32 },
 
     #[error("This method is not implemented")]
     NotImplemented {},
 }
+
+impl From<ContractError> for StdError {
+    fn from(err: ContractError) -> Self {
+        match err {
+            ContractError::StdError(err) => err,
+            err => StdError::generic_err(err.to_string()),
+        }
+    }
+}
@@ -1,14 +1,27 @@
-use cosmwasm_std::{DepsMut, Env, Event, MessageInfo, Response, Uint128};
+use cosmwasm_std::{
+    Decimal, DepsMut, Env, Event, MessageInfo, Order, Response, SignedDecimal, StdResult, Uint128,
+};
 use cw20_base::state::{MinterData, TokenInfo};
 
 use crate::dydx::msg::DydxMsg;
+use crate::dydx::proto_structs::PerpetualMarketType;
+use crate::dydx::querier::DydxQuerier;
 use crate::dydx::query::DydxQueryWrapper;
 use crate::error::ContractResult;
-use crate::state::{LP_TOKENS, VAULTS_BY_PERP_ID, WITHDRAWAL_QUEUES};
+use crate::query::{lp_token_info, query_dydx_position, vault_usdc_value};
+use crate::state::{
+    InstantWithdrawalBuffer, InstantWithdrawalConfig, Vault, VaultConfig, VaultStatus,
+    WithdrawalRateLimit, LP_TOKENS, REFERRER_STATS, VAULTS, VAULT_CONFIGS, VAULT_LP_HOLDINGS,
+    WITHDRAWAL_QUEUES,
+};
 use crate::{error::ContractError, state::STATE};
 
-use super::helpers::{validate_addr_string, verify_sender_is_trader};
-use super::USDC_DENOM;
+use super::helpers::{
+    burn_vault_lp_tokens, decimal_to_native_round_down, decimal_to_native_round_up,
+    get_contract_subaccount_id, load_vault, mint_vault_lp_tokens, validate_addr_string,
+    vault_margin, verify_sender_is_admin, verify_sender_is_trader, verify_vault_is_active,
+};
+use super::{ISOLATED_SUBACCOUNT_START, MAX_SUBACCOUNT_NUMBER};
 
 /// Set the permissioned trader.
 /// Can only be called by the current trader.
@@ -39,29 +52,92 @@ pub fn set_trader(
 
 /// Creates a vault and the associated dYdX subaccount required for trading.
 /// Also creates an LP token and withdrawal queue for the vault.
-/// Vaults are unique for a dYdX perp market and as such use `perp_id` as their identifier throughout the contract.
+/// A market can have several vaults, but every vault trades from its own subaccount.
+/// Vaults on cross markets use a parent subaccount, which defaults to the subaccount numbered `vault_id`.
+/// Vaults on isolated markets use a child subaccount, which defaults to the first unused one.
 pub fn create_vault(
     deps: DepsMut<DydxQueryWrapper>,
     env: Env,
     info: MessageInfo,
+    vault_id: u32,
     perp_id: u32,
+    subaccount_number: Option<u32>,
 ) -> ContractResult<Response<DydxMsg>> {
     let state = STATE.load(deps.storage)?;
     verify_sender_is_trader(&info.sender, &state.trader)?;
 
-    if VAULTS_BY_PERP_ID.has(deps.storage, perp_id) {
-        return Err(ContractError::VaultAlreadyInitialized { perp_id });
+    if VAULTS.has(deps.storage, vault_id) {
+        return Err(ContractError::VaultAlreadyInitialized { vault_id });
+    }
+    let market_type = DydxQuerier::new(&deps.querier)
+        .query_perpetual_clob_details(perp_id)
+        .map_err(|_| ContractError::InvalidMarket { perp_id })?
+        .perpetual_clob_details
+        .perpetual
+        .params
+        .market_type;
+    let isolated = market_type == PerpetualMarketType::Isolated;
+
+    let vaults = VAULTS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, vault)| vault))
+        .collect::<StdResult<Vec<Vault>>>()?;
+    let subaccount_number = match subaccount_number {
+        Some(subaccount_number) => subaccount_number,
+        None if isolated => (ISOLATED_SUBACCOUNT_START..=MAX_SUBACCOUNT_NUMBER)
+            .find(|number| !vaults.iter().any(|v| v.subaccount_number == *number))
+            .ok_or(ContractError::InvalidSubaccountNumber {
+                subaccount_number: MAX_SUBACCOUNT_NUMBER,
+                perp_id,
+                reason: "no child subaccount is left".to_string(),
+            })?,
+        None => vault_id,
+    };
+    let invalid_subaccount = |reason: &str| ContractError::InvalidSubaccountNumber {
+        subaccount_number,
+        perp_id,
+        reason: reason.to_string(),
+    };
+    if isolated && subaccount_number < ISOLATED_SUBACCOUNT_START {
+        return Err(invalid_subaccount(
+            "isolated markets must be traded from a child subaccount",
+        ));
+    }
+    if !isolated && subaccount_number >= ISOLATED_SUBACCOUNT_START {
+        return Err(invalid_subaccount(
+            "cross markets must be traded from a parent subaccount",
+        ));
+    }
+    if subaccount_number > MAX_SUBACCOUNT_NUMBER {
+        return Err(invalid_subaccount("subaccount number is too large"));
+    }
+    if let Some(vault) = vaults
+        .iter()
+        .find(|vault| vault.subaccount_number == subaccount_number)
+    {
+        return Err(ContractError::SubaccountAlreadyUsed {
+            subaccount_number,
+            vault_id: vault.vault_id,
+        });
     }
 
     // save new vault
-    VAULTS_BY_PERP_ID.save(deps.storage, perp_id, &true)?;
-    WITHDRAWAL_QUEUES.save(deps.storage, perp_id, &Vec::with_capacity(10))?;
+    let vault = Vault {
+        vault_id,
+        perp_id,
+        subaccount_number,
+        isolated,
+        status: VaultStatus::Active,
+    };
+    VAULTS.save(deps.storage, vault_id, &vault)?;
+    VAULT_CONFIGS.save(deps.storage, vault_id, &VaultConfig::default())?;
+    WITHDRAWAL_QUEUES.save(deps.storage, vault_id, &Vec::with_capacity(10))?;
 
     // create LP token using cw20-base format
     let data = TokenInfo {
-        name: format!("Elixir LP Token: dYdX-{perp_id}"),
-        symbol: format!("ELXR-LP-dYdX-{perp_id}"),
-        decimals: USDC_DENOM as u8,
+        name: format!("Elixir LP Token: dYdX-{vault_id}"),
+        symbol: format!("ELXR-LP-dYdX-{vault_id}"),
+        decimals: state.collateral.decimals as u8,
         total_supply: Uint128::zero(),
         // set self as minter, so we can properly execute mint and burn
         mint: Some(MinterData {
@@ -69,26 +145,346 @@ pub fn create_vault(
             cap: None,
         }),
     };
-    LP_TOKENS.save(deps.storage, perp_id, &data)?;
+    LP_TOKENS.save(deps.storage, vault_id, &data, env.block.height)?;
 
     let event = Event::new("new_vault")
+        .add_attribute("vault_id", vault_id.to_string())
         .add_attribute("perp_id", perp_id.to_string())
-        .add_attribute("lp_name", format!("Elixir LP Token: dYdX-{perp_id}"))
-        .add_attribute("lp_symbol", format!("ELXR-LP-dYdX-{perp_id}"));
+        .add_attribute("subaccount_number", subaccount_number.to_string())
+        .add_attribute("isolated", isolated.to_string())
+        .add_attribute("lp_name", format!("Elixir LP Token: dYdX-{vault_id}"))
+        .add_attribute("lp_symbol", format!("ELXR-LP-dYdX-{vault_id}"));
 
     Ok(Response::new()
         .add_event(event)
         .add_attribute("method", "create_vault"))
 }
 
+/// Updates the config of an existing vault. Only the provided fields are changed.
+/// Instant withdrawals can be disabled by setting a zero `max_outflow_per_window`.
+/// Can only be called by the trader.
+pub fn update_vault_config(
+    deps: DepsMut<DydxQueryWrapper>,
+    info: MessageInfo,
+    vault_id: u32,
+    lockup_seconds: Option<u64>,
+    instant_withdrawals: Option<InstantWithdrawalConfig>,
+    withdrawal_rate_limit: Option<WithdrawalRateLimit>,
+    share_price_interval_seconds: Option<u64>,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_trader(&info.sender, &state.trader)?;
+
+    if !VAULTS.has(deps.storage, vault_id) {
+        return Err(ContractError::VaultNotInitialized { vault_id });
+    }
+
+    let mut config = VAULT_CONFIGS
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default();
+    if let Some(lockup_seconds) = lockup_seconds {
+        config.lockup_seconds = lockup_seconds;
+    }
+    if let Some(instant_withdrawals) = instant_withdrawals {
+        validate_instant_withdrawal_config(vault_id, &instant_withdrawals)?;
+        config.instant_withdrawals = if instant_withdrawals.max_outflow_per_window.is_zero() {
+            None
+        } else {
+            Some(instant_withdrawals)
+        };
+    }
+    if let Some(withdrawal_rate_limit) = withdrawal_rate_limit {
+        validate_withdrawal_rate_limit(vault_id, &withdrawal_rate_limit)?;
+        config.withdrawal_rate_limit = if withdrawal_rate_limit.max_nav_fraction == Decimal::one() {
+            None
+        } else {
+            Some(withdrawal_rate_limit)
+        };
+    }
+    if let Some(share_price_interval_seconds) = share_price_interval_seconds {
+        if share_price_interval_seconds == 0 {
+            return Err(ContractError::InvalidVaultConfig {
+                vault_id,
+                reason: "share price checkpoint interval must be at least 1 second".to_string(),
+            });
+        }
+        config.share_price_interval_seconds = share_price_interval_seconds;
+    }
+    VAULT_CONFIGS.save(deps.storage, vault_id, &config)?;
+
+    let event = Event::new("updated_vault_config")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("lockup_seconds", config.lockup_seconds.to_string())
+        .add_attribute(
+            "instant_withdrawals_enabled",
+            config.instant_withdrawals.is_some().to_string(),
+        )
+        .add_attribute(
+            "withdrawal_rate_limited",
+            config.withdrawal_rate_limit.is_some().to_string(),
+        )
+        .add_attribute(
+            "share_price_interval_seconds",
+            config.share_price_interval_seconds.to_string(),
+        );
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("method", "update_vault_config"))
+}
+
+fn validate_instant_withdrawal_config(
+    vault_id: u32,
+    instant_withdrawals: &InstantWithdrawalConfig,
+) -> ContractResult<()> {
+    if instant_withdrawals.window_seconds == 0 {
+        return Err(ContractError::InvalidVaultConfig {
+            vault_id,
+            reason: "instant withdrawal window must be at least 1 second".to_string(),
+        });
+    }
+    if let InstantWithdrawalBuffer::NavFraction(fraction) = instant_withdrawals.buffer {
+        if fraction > Decimal::one() {
+            return Err(ContractError::InvalidVaultConfig {
+                vault_id,
+                reason: "instant withdrawal buffer cannot exceed the vault value".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn validate_withdrawal_rate_limit(
+    vault_id: u32,
+    withdrawal_rate_limit: &WithdrawalRateLimit,
+) -> ContractResult<()> {
+    if withdrawal_rate_limit.window_seconds == 0 {
+        return Err(ContractError::InvalidVaultConfig {
+            vault_id,
+            reason: "withdrawal rate limit window must be at least 1 second".to_string(),
+        });
+    }
+    if withdrawal_rate_limit.max_nav_fraction > Decimal::one() {
+        return Err(ContractError::InvalidVaultConfig {
+            vault_id,
+            reason: "withdrawal rate limit cannot exceed the vault value".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Moves USDC from the subaccount of one vault to the subaccount of another with a dYdX transfer.
+/// LP accounting stays consistent through an internal LP swap, so the share price of both vaults is unchanged:
+/// 1. the destination vault redeems LP tokens of the source vault that it holds from earlier rebalances
+/// 2. the source vault is paid for the rest of the amount in newly minted LP tokens of the destination vault
+///
+/// To keep vault valuation one level deep, a vault cannot hold LP tokens of other vaults while its own LP tokens are held by a vault.
+/// The source vault must stay under 1x leverage after the transfer. Can only be called by the admin.
+pub fn rebalance_vaults(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    from_vault_id: u32,
+    to_vault_id: u32,
+    amount: u64,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_admin(&info.sender, &state.admin)?;
+
+    let invalid_rebalance = |reason: &str| ContractError::InvalidRebalance {
+        from_vault_id,
+        to_vault_id,
+        reason: reason.to_string(),
+    };
+    if from_vault_id == to_vault_id {
+        return Err(invalid_rebalance("cannot rebalance a vault into itself"));
+    }
+    if amount == 0 {
+        return Err(invalid_rebalance("amount must be greater than 0"));
+    }
+    let from_vault = load_vault(deps.storage, from_vault_id)?;
+    let to_vault = load_vault(deps.storage, to_vault_id)?;
+    verify_vault_is_active(&from_vault)?;
+    // settling vaults can still redeem LP tokens they hold, but must not take on new positions
+    if matches!(to_vault.status, VaultStatus::Settled | VaultStatus::Closed) {
+        return Err(ContractError::VaultNotActive {
+            vault_id: to_vault_id,
+            status: to_vault.status,
+        });
+    }
+
+    // validate health of the source vault after the transfer
+    let from_pos = query_dydx_position(deps.as_ref(), from_vault_id)?;
+    let transfer_value = Decimal::from_atomics(amount, state.collateral.decimals).unwrap();
+    let asset_value =
+        vault_margin(&from_vault, &from_pos) - SignedDecimal::try_from(transfer_value).unwrap();
+    let perp_value = from_pos.perp_usdc_value.abs_diff(SignedDecimal::zero());
+    if asset_value < SignedDecimal::zero()
+        || perp_value > asset_value.abs_diff(SignedDecimal::zero())
+    {
+        return Err(ContractError::RebalanceWouldIncreaseLeverageTooMuch {
+            vault_id: from_vault_id,
+        });
+    }
+
+    // redeem the source vault LP tokens held by the destination vault first
+    let mut remaining_value = transfer_value;
+    let held_lp_tokens = VAULT_LP_HOLDINGS
+        .may_load(deps.storage, (to_vault_id, from_vault_id))?
+        .unwrap_or_default();
+    let mut redeemed_lp_tokens = Uint128::zero();
+    if !held_lp_tokens.is_zero() {
+        let from_value = vault_usdc_value(deps.as_ref(), from_vault_id, &from_pos)?
+            .abs_diff(SignedDecimal::zero());
+        let from_lp_token_info = lp_token_info(deps.as_ref(), from_vault_id)?;
+        let lp_decimals = from_lp_token_info.decimals as u32;
+        let outstanding_lp_tokens =
+            Decimal::from_atomics(from_lp_token_info.total_supply, lp_decimals).unwrap();
+        let held_lp_tokens_decimal = Decimal::from_atomics(held_lp_tokens, lp_decimals).unwrap();
+
+        let held_value = from_value * held_lp_tokens_decimal / outstanding_lp_tokens;
+        if held_value <= remaining_value {
+            redeemed_lp_tokens = held_lp_tokens;
+            remaining_value -= held_value;
+        } else {
+            // round up in favour of the LPs that stay in the source vault
+            redeemed_lp_tokens = decimal_to_native_round_up(
+                remaining_value * outstanding_lp_tokens / from_value,
+                lp_decimals,
+            )
+            .unwrap()
+            .min(held_lp_tokens);
+            remaining_value = Decimal::zero();
+        }
+        burn_vault_lp_tokens(
+            deps.storage,
+            to_vault_id,
+            from_vault_id,
+            redeemed_lp_tokens,
+            &env.block,
+        )?;
+    }
+
+    // pay the source vault for the rest in destination vault LP tokens
+    let mut minted_lp_tokens = Uint128::zero();
+    if !decimal_to_native_round_down(remaining_value, state.collateral.decimals)
+        .unwrap()
+        .is_zero()
+    {
+        // minting LP tokens is a new position
+        verify_vault_is_active(&to_vault)?;
+        let holdings = VAULT_LP_HOLDINGS
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<((u32, u32), Uint128)>>>()?;
+        if holdings
+            .iter()
+            .any(|((_, issuer), _)| *issuer == from_vault_id)
+        {
+            return Err(invalid_rebalance(
+                "the source vault LP tokens are held by another vault",
+            ));
+        }
+        if holdings
+            .iter()
+            .any(|((holder, _), _)| *holder == to_vault_id)
+        {
+            return Err(invalid_rebalance(
+                "the destination vault holds LP tokens of another vault",
+            ));
+        }
+
+        let to_pos = query_dydx_position(deps.as_ref(), to_vault_id)?;
+        let to_value =
+            vault_usdc_value(deps.as_ref(), to_vault_id, &to_pos)?.abs_diff(SignedDecimal::zero());
+        let to_lp_token_info = lp_token_info(deps.as_ref(), to_vault_id)?;
+        let lp_decimals = to_lp_token_info.decimals as u32;
+        minted_lp_tokens = if to_lp_token_info.total_supply.is_zero() {
+            decimal_to_native_round_down(remaining_value, lp_decimals).unwrap()
+        } else if to_value.is_zero() {
+            return Err(invalid_rebalance("the destination vault has no value"));
+        } else {
+            let outstanding_lp_tokens =
+                Decimal::from_atomics(to_lp_token_info.total_supply, lp_decimals).unwrap();
+            decimal_to_native_round_down(
+                remaining_value * outstanding_lp_tokens / to_value,
+                lp_decimals,
+            )
+            .unwrap()
+        };
+        mint_vault_lp_tokens(
+            deps.storage,
+            from_vault_id,
+            to_vault_id,
+            minted_lp_tokens,
+            &env.block,
+        )?;
+    }
+
+    let transfer = DydxMsg::TransferV1 {
+        sender: get_contract_subaccount_id(&env, from_vault.subaccount_number),
+        recipient: get_contract_subaccount_id(&env, to_vault.subaccount_number),
+        asset_id: state.collateral.asset_id,
+        amount,
+    };
+
+    let event = Event::new("rebalanced_vaults")
+        .add_attribute("from_vault_id", from_vault_id.to_string())
+        .add_attribute("to_vault_id", to_vault_id.to_string())
+        .add_attribute("usdc_amount", amount.to_string())
+        .add_attribute("redeemed_lp_tokens", redeemed_lp_tokens.to_string())
+        .add_attribute("minted_lp_tokens", minted_lp_tokens.to_string());
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("method", "rebalance_vaults")
+        .add_message(transfer))
+}
+
+/// Sets the share of the performance fees of referred deposits owed to a referrer. `None` clears it.
+/// Can only be called by the admin.
+pub fn set_referral_fee_share(
+    deps: DepsMut<DydxQueryWrapper>,
+    info: MessageInfo,
+    referrer: String,
+    fee_share: Option<Decimal>,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_admin(&info.sender, &state.admin)?;
+    let referrer_addr = validate_addr_string(&deps, referrer)?;
+    if let Some(fee_share) = fee_share {
+        if fee_share > Decimal::one() {
+            return Err(ContractError::InvalidReferralFeeShare {
+                fee_share: fee_share.to_string(),
+            });
+        }
+    }
+
+    REFERRER_STATS.update(deps.storage, &referrer_addr, |stats| -> StdResult<_> {
+        let mut stats = stats.unwrap_or_default();
+        stats.fee_share = fee_share;
+        Ok(stats)
+    })?;
+
+    let event = Event::new("referral_fee_share_updated")
+        .add_attribute("referrer", referrer_addr.to_string())
+        .add_attribute(
+            "fee_share",
+            fee_share.map_or("none".to_string(), |share| share.to_string()),
+        );
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("method", "set_referral_fee_share"))
+}
+
 /// Changes the vault fee. For now this is method is unused and will throw an error if called.
 pub fn modify_vault_fee(
     _deps: DepsMut<DydxQueryWrapper>,
     _env: Env,
     _info: MessageInfo,
-    _perp_id: u32,
+    _vault_id: u32,
 ) -> ContractResult<Response<DydxMsg>> {
-    return Err(ContractError::NotImplemented {});
+    Err(ContractError::NotImplemented {})
 }
 
 /// Changes the vault fee. For now this is method is unused and will throw an error if called.
@@ -96,7 +492,7 @@ pub fn collect_fees_from_vault(
     _deps: DepsMut<DydxQueryWrapper>,
     _env: Env,
     _info: MessageInfo,
-    _perp_id: u32,
+    _vault_id: u32,
 ) -> ContractResult<Response<DydxMsg>> {
-    return Err(ContractError::NotImplemented {});
+    Err(ContractError::NotImplemented {})
 }
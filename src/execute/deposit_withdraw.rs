@@ -1,37 +1,58 @@
-use cosmwasm_std::{Decimal, DepsMut, Env, Event, MessageInfo, Response, SignedDecimal, Uint128};
+use cosmwasm_std::{
+    Addr, Decimal, Deps, DepsMut, Env, Event, MessageInfo, Response, SignedDecimal, Uint128,
+};
 
 use crate::dydx::msg::DydxMsg;
+use crate::dydx::querier::DydxQuerier;
 use crate::dydx::query::DydxQueryWrapper;
 use crate::error::ContractResult;
 use crate::execute::helpers::{
-    burn_lp_tokens, decimal_to_native_round_down, get_contract_subaccount_id, mint_lp_tokens,
+    burn_lp_tokens, checkpoint_share_price, current_instant_outflow_window,
+    decimal_to_native_round_down, encode_dydx_msg, get_contract_subaccount_id, load_vault,
+    lp_tokens_for_deposit, lp_tokens_for_withdrawal, lp_tokens_usdc_value, mint_lp_tokens,
+    record_deposit_lock, record_withdrawal_outflow, remaining_withdrawal_capacity, vault_margin,
+    verify_lp_tokens_unlocked, verify_vault_accepts_deposits, verify_vault_is_active,
+};
+use crate::query::{lp_token_info, query_dydx_position, vault_usdc_value};
+use crate::state::{
+    InstantWithdrawalBuffer, InstantWithdrawalConfig, Vault, VaultStatus, WithdrawalRequest,
+    INSTANT_WITHDRAWAL_OUTFLOWS, VAULT_CONFIGS, WITHDRAWAL_QUEUES,
 };
-use crate::execute::{USDC_COIN_TYPE, USDC_DENOM, USDC_ID};
-use crate::query::{lp_token_info, query_dydx_position};
-use crate::state::{WithdrawalRequest, VAULTS_BY_PERP_ID, WITHDRAWAL_QUEUES};
 use crate::{error::ContractError, state::STATE};
 
 use super::helpers::{
-    decimal_to_native_round_up, get_user_and_outstanding_lp_tokens,
-    transfer_lp_tokens_from_withdrawal_queue, transfer_lp_tokens_to_withdrawal_queue,
+    get_user_and_outstanding_lp_tokens, realize_withdrawal_pnl, record_deposit_cost,
+    record_referral, share_price_checkpoint_due, transfer_lp_tokens_from_withdrawal_queue,
+    transfer_lp_tokens_to_withdrawal_queue, validate_addr_string,
 };
 
 /// Processes a user to deposit into the market-making vault.
 /// This consists of minting LP tokens such that the % of LP tokens that
 /// a user owns is the same as their % of deposit value in the pool.
+/// The deposit is attributed to the depositor's referrer, which is recorded on their first deposit into the vault.
 pub fn deposit_into_vault(
     deps: DepsMut<DydxQueryWrapper>,
     env: Env,
     info: MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
+    referrer: Option<String>,
 ) -> ContractResult<Response<DydxMsg>> {
-    let subaccount_id = get_contract_subaccount_id(&env, perp_id);
+    let state = STATE.load(deps.storage)?;
+    let referrer_addr = match referrer {
+        Some(referrer) => Some(validate_addr_string(&deps, referrer)?),
+        None => None,
+    };
+    if referrer_addr.as_ref() == Some(&info.sender) {
+        return Err(ContractError::SelfReferral {
+            referrer: info.sender,
+        });
+    }
 
     // assert that user is depositing only USDC with amount > 0
     if info.funds.len() != 1 {
         return Err(ContractError::CanOnlyDepositOneCointype {});
     }
-    if info.funds[0].denom != USDC_COIN_TYPE {
+    if info.funds[0].denom != state.collateral.denom {
         return Err(ContractError::InvalidCoin {
             coin_type: info.funds[0].denom.clone(),
         });
@@ -45,29 +66,39 @@ pub fn deposit_into_vault(
         });
     }
 
-    // assert vault exists
-    if !VAULTS_BY_PERP_ID.has(deps.storage, perp_id) {
-        return Err(ContractError::VaultNotInitialized { perp_id });
-    }
+    // assert vault exists and accepts deposits
+    let vault = load_vault(deps.storage, vault_id)?;
+    verify_vault_accepts_deposits(&DydxQuerier::new(&deps.querier), &vault)?;
+    let subaccount_id = get_contract_subaccount_id(&env, vault.subaccount_number);
 
-    let pos = query_dydx_position(deps.as_ref(), perp_id)?;
-    let subaccount_value_signed = pos.asset_usdc_value + pos.perp_usdc_value;
-    let subaccount_value = subaccount_value_signed.abs_diff(SignedDecimal::zero());
-    let deposit_value = Decimal::from_atomics(amount, USDC_DENOM).unwrap();
-    let lp_token_info = lp_token_info(deps.as_ref(), perp_id)?;
+    let pos = query_dydx_position(deps.as_ref(), vault_id)?;
+    let nav = vault_usdc_value(deps.as_ref(), vault_id, &pos)?;
+    let subaccount_value = nav.abs_diff(SignedDecimal::zero());
+    let lp_token_info = lp_token_info(deps.as_ref(), vault_id)?;
+
+    // checkpoint the share price before the deposit changes the vault value and LP supply
+    if share_price_checkpoint_due(deps.storage, env.block.time, vault_id)? {
+        checkpoint_share_price(deps.storage, env.block.time, vault_id, nav, &lp_token_info)?;
+    }
 
-    // calculate the new deposit's share of total value using the following:
-    //      new_tokens / (new_tokens + outstanding_lp_tokens) = deposit_value / (deposit_value + subaccount_value).
-    let share_value_fraction = deposit_value / (deposit_value + subaccount_value);
+    // calculate the new deposit's share of total value
     let outstanding_lp_tokens =
         Decimal::from_atomics(lp_token_info.total_supply, lp_token_info.decimals as u32).unwrap();
-    let new_tokens = if share_value_fraction == Decimal::one() {
-        amount
-    } else {
-        let token_amt_decimal = (share_value_fraction * outstanding_lp_tokens)
-            / (Decimal::one() - share_value_fraction);
-        decimal_to_native_round_down(token_amt_decimal, lp_token_info.decimals as u32).unwrap()
-    };
+    let new_tokens = lp_tokens_for_deposit(
+        amount,
+        state.collateral.decimals,
+        subaccount_value,
+        outstanding_lp_tokens,
+        lp_token_info.decimals as u32,
+    );
+
+    // attribute the deposit before it is recorded, so a first deposit can be told apart
+    let referrer_addr =
+        record_referral(deps.storage, vault_id, &info.sender, referrer_addr, amount)?;
+
+    // lock the new tokens for the vault lockup period
+    record_deposit_lock(deps.storage, &env, vault_id, &info.sender, new_tokens)?;
+    record_deposit_cost(deps.storage, vault_id, &info.sender, amount, new_tokens)?;
 
     // mint tokens to depositor
     let sub_info = MessageInfo {
@@ -77,9 +108,10 @@ pub fn deposit_into_vault(
     mint_lp_tokens(
         deps,
         sub_info,
-        perp_id,
+        vault_id,
         info.sender.to_string(),
-        new_tokens.into(),
+        new_tokens,
+        &env.block,
     )
     .unwrap();
 
@@ -90,19 +122,23 @@ pub fn deposit_into_vault(
     // https://github.com/dydxprotocol/v4-chain/blob/c06db6fea945ad84fa4479df09078cee8feeba96/protocol/x/assets/types/genesis.go#L18,
     let deposit = DydxMsg::DepositToSubaccountV1 {
         recipient: subaccount_id.clone(),
-        asset_id: USDC_ID,
+        asset_id: state.collateral.asset_id,
         quantums: amount.u128() as u64,
     };
+    let deposit = encode_dydx_msg(&state, deposit)?;
 
-    let event = Event::new("new_deposit")
+    let mut event = Event::new("new_deposit")
         .add_attribute("depositor", info.sender.to_string())
-        .add_attribute("perp_id", perp_id.to_string())
+        .add_attribute("vault_id", vault_id.to_string())
         .add_attribute("usdc_amount", amount.to_string())
         .add_attribute("minted_lp_tokens", new_tokens.to_string())
         .add_attribute(
             "total_lp_tokens",
             (lp_token_info.total_supply + new_tokens).to_string(),
         );
+    if let Some(referrer_addr) = referrer_addr {
+        event = event.add_attribute("referrer", referrer_addr.to_string());
+    }
 
     Ok(Response::new()
         .add_attribute("method", "deposit_into_vault")
@@ -111,7 +147,11 @@ pub fn deposit_into_vault(
 }
 
 /// Requests a user withdrawal from the LP vault. Requires that the user has enough LP tokens to support their requested withdrawal.
+/// LP tokens minted within the vault lockup period cannot be withdrawn.
 /// If 0 is passed as the usdc_amount, the max possible withdrawal will be requested.
+/// USDC is paid to `recipient` if provided, otherwise to the sender. The sender keeps ownership of the request and is the only one who can cancel it.
+/// If the vault has instant withdrawals enabled and the withdrawal fits in the instant withdrawal buffer, it is settled right away instead of being queued.
+/// Settling vaults reject withdrawals, as settlement pays out all LP holders.
 /// Since withdrawals are processed some time in the future, a user may receive more/less USDC than they initially requested,
 /// depending on how the value of the subaccount fluctuates.
 pub fn request_withdrawal(
@@ -119,56 +159,84 @@ pub fn request_withdrawal(
     env: Env,
     info: MessageInfo,
     usdc_amount: u64,
-    perp_id: u32,
+    vault_id: u32,
+    recipient: Option<String>,
 ) -> ContractResult<Response<DydxMsg>> {
+    let recipient_addr = match recipient {
+        Some(recipient) => validate_addr_string(&deps, recipient)?,
+        None => info.sender.clone(),
+    };
+    let vault = load_vault(deps.storage, vault_id)?;
+    // settlement pays out all LP holders at the same rate, so no USDC may leave the vault before
+    if vault.status == VaultStatus::Settling {
+        return Err(ContractError::VaultNotActive {
+            vault_id,
+            status: vault.status,
+        });
+    }
+
+    let lp_token_amount =
+        withdrawal_lp_tokens(deps.as_ref(), &env, vault_id, &info.sender, usdc_amount)?;
     let (
-        user_lp_tokens,
-        user_lp_tokens_decimal,
+        _user_lp_tokens,
+        _user_lp_tokens_decimal,
         _outstanding_lp_tokens,
         outstanding_lp_tokens_decimal,
         lp_token_info,
-    ) = get_user_and_outstanding_lp_tokens(&deps, perp_id, &info.sender)?;
-
-    let lp_token_amount = if usdc_amount == 0 {
-        // withdraw all
-        user_lp_tokens
-    } else {
-        // withdraw some
-        let pos = query_dydx_position(deps.as_ref(), perp_id)?;
-        let subaccount_value_signed = pos.asset_usdc_value + pos.perp_usdc_value;
-        let subaccount_value = subaccount_value_signed.abs_diff(SignedDecimal::zero());
-        let ownership_fraction = user_lp_tokens_decimal / outstanding_lp_tokens_decimal;
+    ) = get_user_and_outstanding_lp_tokens(deps.as_ref(), vault_id, &info.sender)?;
 
-        let requested_withdraw_value = Decimal::from_atomics(usdc_amount, USDC_DENOM).unwrap();
-        let max_withdraw_value = ownership_fraction * subaccount_value;
-
-        let lp_token_ratio = requested_withdraw_value / max_withdraw_value;
-        let withdraw_lp_tokens_decimal = user_lp_tokens_decimal * lp_token_ratio;
+    if vault.status == VaultStatus::Closed {
+        return closed_vault_redemption(
+            deps,
+            env,
+            info,
+            &vault,
+            recipient_addr,
+            lp_token_amount,
+            outstanding_lp_tokens_decimal,
+            lp_token_info.decimals as u32,
+        );
+    }
 
-        if withdraw_lp_tokens_decimal > user_lp_tokens_decimal {
-            return Err(ContractError::InvalidWithdrawalAmount {
-                coin_type: USDC_COIN_TYPE.to_string(),
-                amount: usdc_amount.into(),
-            });
+    let config = VAULT_CONFIGS
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default();
+    if let Some(instant_config) = config.instant_withdrawals {
+        let instant_quantums = instant_withdrawal_quantums(
+            deps.as_ref(),
+            &env,
+            vault_id,
+            &instant_config,
+            lp_token_amount,
+            outstanding_lp_tokens_decimal,
+            lp_token_info.decimals as u32,
+        )?;
+        if let Some(withdraw_quantums) = instant_quantums {
+            return instant_withdrawal(
+                deps,
+                env,
+                info,
+                vault_id,
+                &instant_config,
+                recipient_addr,
+                lp_token_amount,
+                withdraw_quantums,
+            );
         }
-
-        let lp_tokens =
-            decimal_to_native_round_up(withdraw_lp_tokens_decimal, lp_token_info.decimals as u32)
-                .unwrap();
-        lp_tokens
-    };
+    }
 
     // put LP tokens into queue
     let withdrawal = WithdrawalRequest {
-        recipient_addr: info.sender.clone(),
+        recipient_addr: recipient_addr.clone(),
         lp_tokens: lp_token_amount,
+        owner: Some(info.sender.clone()),
     };
 
     let mut withdrawal_queue = WITHDRAWAL_QUEUES
-        .may_load(deps.storage, perp_id)?
-        .ok_or(ContractError::MissingWithdrawalQueue { perp_id })?;
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingWithdrawalQueue { vault_id })?;
     withdrawal_queue.push(withdrawal);
-    WITHDRAWAL_QUEUES.save(deps.storage, perp_id, &withdrawal_queue)?;
+    WITHDRAWAL_QUEUES.save(deps.storage, vault_id, &withdrawal_queue)?;
 
     let sub_info = MessageInfo {
         sender: env.contract.address.clone(),
@@ -179,45 +247,339 @@ pub fn request_withdrawal(
     transfer_lp_tokens_to_withdrawal_queue(
         deps,
         sub_info,
-        perp_id,
+        vault_id,
         info.sender.to_string(),
         lp_token_amount,
+        &env.block,
     )?;
 
     let event = Event::new("new_withdrawal_request")
         .add_attribute("withdrawer", info.sender.to_string())
-        .add_attribute("perp_id", perp_id.to_string())
+        .add_attribute("vault_id", vault_id.to_string())
         .add_attribute("usdc_amount", usdc_amount.to_string())
-        .add_attribute("transferred_lp_tokens", lp_token_amount.to_string());
+        .add_attribute("transferred_lp_tokens", lp_token_amount.to_string())
+        .add_attribute("recipient", recipient_addr.to_string());
 
     Ok(Response::new()
         .add_event(event)
         .add_attribute("method", "request_withdrawal"))
 }
 
-/// Cancels all user withdrawal requests from the LP vault.
+/// Returns the LP tokens that a withdrawal request of `usdc_amount` takes from the user, or all of the user's unlocked LP tokens if `usdc_amount` is 0.
+/// Fails if the user's LP tokens are worth less than `usdc_amount` or are still locked. LP tokens of closed vaults are never locked.
+pub fn withdrawal_lp_tokens(
+    deps: Deps<DydxQueryWrapper>,
+    env: &Env,
+    vault_id: u32,
+    user_addr: &Addr,
+    usdc_amount: u64,
+) -> ContractResult<Uint128> {
+    let state = STATE.load(deps.storage)?;
+    let (
+        user_lp_tokens,
+        user_lp_tokens_decimal,
+        _outstanding_lp_tokens,
+        outstanding_lp_tokens_decimal,
+        lp_token_info,
+    ) = get_user_and_outstanding_lp_tokens(deps, vault_id, user_addr)?;
+
+    // closed vaults can only be redeemed, so the lockup no longer applies
+    let closed = load_vault(deps.storage, vault_id)?.status == VaultStatus::Closed;
+    let unlocked_lp_tokens = |requested: Uint128| {
+        if closed {
+            Ok(user_lp_tokens)
+        } else {
+            verify_lp_tokens_unlocked(
+                deps.storage,
+                env,
+                vault_id,
+                user_addr,
+                user_lp_tokens,
+                requested,
+            )
+        }
+    };
+
+    if usdc_amount == 0 {
+        // withdraw all unlocked tokens, requiring at least one if the user holds any
+        return unlocked_lp_tokens(user_lp_tokens.min(Uint128::one()));
+    }
+
+    // withdraw some
+    let pos = query_dydx_position(deps, vault_id)?;
+    let subaccount_value = vault_usdc_value(deps, vault_id, &pos)?.abs_diff(SignedDecimal::zero());
+    let requested_withdraw_value =
+        Decimal::from_atomics(usdc_amount, state.collateral.decimals).unwrap();
+    let lp_tokens = lp_tokens_for_withdrawal(
+        requested_withdraw_value,
+        subaccount_value,
+        user_lp_tokens_decimal,
+        outstanding_lp_tokens_decimal,
+        lp_token_info.decimals as u32,
+    )
+    .ok_or(ContractError::InvalidWithdrawalAmount {
+        coin_type: state.collateral.denom,
+        amount: usdc_amount.into(),
+    })?;
+    unlocked_lp_tokens(lp_tokens)?;
+    Ok(lp_tokens)
+}
+
+/// Returns the USDC quantums to pay out if the withdrawal of `lp_tokens` can be settled instantly.
+/// This requires the withdrawal to fit in the instant withdrawal buffer, the remaining outflow cap of the current window
+/// and the remaining capacity of the withdrawal rate limit, and the subaccount to stay under 1x leverage after the withdrawal.
+fn instant_withdrawal_quantums(
+    deps: Deps<DydxQueryWrapper>,
+    env: &Env,
+    vault_id: u32,
+    instant_config: &InstantWithdrawalConfig,
+    lp_tokens: Uint128,
+    outstanding_lp_tokens_decimal: Decimal,
+    lp_decimals: u32,
+) -> ContractResult<Option<Uint128>> {
+    let collateral_decimals = STATE.load(deps.storage)?.collateral.decimals;
+    let vault = load_vault(deps.storage, vault_id)?;
+    let pos = query_dydx_position(deps, vault_id)?;
+    let subaccount_value = vault_usdc_value(deps, vault_id, &pos)?.abs_diff(SignedDecimal::zero());
+
+    let withdraw_value = lp_tokens_usdc_value(
+        lp_tokens,
+        lp_decimals,
+        outstanding_lp_tokens_decimal,
+        subaccount_value,
+    );
+    let withdraw_quantums =
+        decimal_to_native_round_down(withdraw_value, collateral_decimals).unwrap();
+    if withdraw_quantums.is_zero() || withdraw_quantums >= u64::MAX.into() {
+        return Ok(None);
+    }
+
+    let buffer = match &instant_config.buffer {
+        InstantWithdrawalBuffer::NavFraction(fraction) => {
+            decimal_to_native_round_down(*fraction * subaccount_value, collateral_decimals).unwrap()
+        }
+        InstantWithdrawalBuffer::Usdc(amount) => *amount,
+    };
+    if withdraw_quantums > buffer {
+        return Ok(None);
+    }
+
+    let window =
+        current_instant_outflow_window(deps.storage, env.block.time, vault_id, instant_config)?;
+    if window.usdc_outflow + withdraw_quantums > instant_config.max_outflow_per_window {
+        return Ok(None);
+    }
+
+    // instant withdrawals count against the rolling rate limit of processed withdrawals
+    let rate_limit = VAULT_CONFIGS
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default()
+        .withdrawal_rate_limit;
+    if let Some(rate_limit) = &rate_limit {
+        let (_, _, remaining_capacity) = remaining_withdrawal_capacity(
+            deps.storage,
+            env.block.time,
+            vault_id,
+            rate_limit,
+            subaccount_value,
+            collateral_decimals,
+        )?;
+        if withdraw_quantums > remaining_capacity {
+            return Ok(None);
+        }
+    }
+
+    // validate health after withdrawal
+    let asset_value = vault_margin(&vault, &pos) - SignedDecimal::try_from(withdraw_value).unwrap();
+    let perp_value = pos.perp_usdc_value.abs_diff(SignedDecimal::zero());
+    if asset_value < SignedDecimal::zero()
+        || perp_value > asset_value.abs_diff(SignedDecimal::zero())
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(withdraw_quantums))
+}
+
+/// Settles a withdrawal without queueing it. Burns the withdrawer's LP tokens and pays the recipient from the vault's subaccount.
+#[allow(clippy::too_many_arguments)]
+fn instant_withdrawal(
+    mut deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+    instant_config: &InstantWithdrawalConfig,
+    recipient_addr: Addr,
+    lp_tokens: Uint128,
+    withdraw_quantums: Uint128,
+) -> ContractResult<Response<DydxMsg>> {
+    let mut window =
+        current_instant_outflow_window(deps.storage, env.block.time, vault_id, instant_config)?;
+    window.usdc_outflow += withdraw_quantums;
+    INSTANT_WITHDRAWAL_OUTFLOWS.save(deps.storage, vault_id, &window)?;
+    let rate_limit = VAULT_CONFIGS
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default()
+        .withdrawal_rate_limit;
+    if let Some(rate_limit) = &rate_limit {
+        record_withdrawal_outflow(deps.storage, &env, vault_id, rate_limit, withdraw_quantums)?;
+    }
+
+    // checkpoint the share price before the withdrawal changes the vault value and LP supply
+    if share_price_checkpoint_due(deps.storage, env.block.time, vault_id)? {
+        let pos = query_dydx_position(deps.as_ref(), vault_id)?;
+        let nav = vault_usdc_value(deps.as_ref(), vault_id, &pos)?;
+        let lp_token_info = lp_token_info(deps.as_ref(), vault_id)?;
+        checkpoint_share_price(deps.storage, env.block.time, vault_id, nav, &lp_token_info)?;
+    }
+
+    // move LP tokens to the contract and burn them, as if the withdrawal was queued and processed
+    let sub_info = MessageInfo {
+        sender: env.contract.address.clone(),
+        funds: vec![],
+    };
+    transfer_lp_tokens_to_withdrawal_queue(
+        deps.branch(),
+        sub_info.clone(),
+        vault_id,
+        info.sender.to_string(),
+        lp_tokens,
+        &env.block,
+    )?;
+    burn_lp_tokens(&mut deps, &sub_info, vault_id, lp_tokens, &env.block)?;
+    let realized_pnl = realize_withdrawal_pnl(
+        deps.storage,
+        vault_id,
+        &info.sender,
+        lp_tokens,
+        withdraw_quantums,
+    )?;
+
+    let state = STATE.load(deps.storage)?;
+    let withdraw_message = DydxMsg::WithdrawFromSubaccountV1 {
+        subaccount_number: load_vault(deps.storage, vault_id)?.subaccount_number,
+        recipient: recipient_addr.to_string(),
+        asset_id: state.collateral.asset_id,
+        quantums: withdraw_quantums.u128() as u64,
+    };
+    let withdraw_message = encode_dydx_msg(&state, withdraw_message)?;
+
+    let event = Event::new("instant_withdrawal")
+        .add_attribute("withdrawer", info.sender.to_string())
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("recipient", recipient_addr.to_string())
+        .add_attribute("withdrawn_usdc", withdraw_quantums.to_string())
+        .add_attribute("burnt_lp_tokens", lp_tokens.to_string())
+        .add_attribute("realized_pnl", realized_pnl.to_string());
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("method", "request_withdrawal")
+        .add_message(withdraw_message))
+}
+
+/// Redeems LP tokens of a closed vault immediately, bypassing the withdrawal queue and the withdrawal limits.
+/// Closed vaults have no perp position, so the LP tokens are worth their pro-rata share of the USDC in the subaccount.
+#[allow(clippy::too_many_arguments)]
+fn closed_vault_redemption(
+    mut deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault: &Vault,
+    recipient_addr: Addr,
+    lp_tokens: Uint128,
+    outstanding_lp_tokens_decimal: Decimal,
+    lp_decimals: u32,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    let vault_id = vault.vault_id;
+    if lp_tokens.is_zero() {
+        return Err(ContractError::InvalidWithdrawalAmount {
+            coin_type: state.collateral.denom,
+            amount: 0,
+        });
+    }
+    let pos = query_dydx_position(deps.as_ref(), vault_id)?;
+    let subaccount_value =
+        vault_usdc_value(deps.as_ref(), vault_id, &pos)?.abs_diff(SignedDecimal::zero());
+    let withdraw_value = lp_tokens_usdc_value(
+        lp_tokens,
+        lp_decimals,
+        outstanding_lp_tokens_decimal,
+        subaccount_value,
+    );
+    let withdraw_quantums =
+        decimal_to_native_round_down(withdraw_value, state.collateral.decimals).unwrap();
+
+    // move LP tokens to the contract and burn them, as if the withdrawal was queued and processed
+    let sub_info = MessageInfo {
+        sender: env.contract.address.clone(),
+        funds: vec![],
+    };
+    transfer_lp_tokens_to_withdrawal_queue(
+        deps.branch(),
+        sub_info.clone(),
+        vault_id,
+        info.sender.to_string(),
+        lp_tokens,
+        &env.block,
+    )?;
+    burn_lp_tokens(&mut deps, &sub_info, vault_id, lp_tokens, &env.block)?;
+    let realized_pnl = realize_withdrawal_pnl(
+        deps.storage,
+        vault_id,
+        &info.sender,
+        lp_tokens,
+        withdraw_quantums,
+    )?;
+
+    let mut response = Response::new();
+    if !withdraw_quantums.is_zero() {
+        let withdraw_message = DydxMsg::WithdrawFromSubaccountV1 {
+            subaccount_number: vault.subaccount_number,
+            recipient: recipient_addr.to_string(),
+            asset_id: state.collateral.asset_id,
+            quantums: withdraw_quantums.u128() as u64,
+        };
+        response = response.add_message(encode_dydx_msg(&state, withdraw_message)?);
+    }
+
+    let event = Event::new("closed_vault_redemption")
+        .add_attribute("withdrawer", info.sender.to_string())
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("recipient", recipient_addr.to_string())
+        .add_attribute("withdrawn_usdc", withdraw_quantums.to_string())
+        .add_attribute("burnt_lp_tokens", lp_tokens.to_string())
+        .add_attribute("realized_pnl", realized_pnl.to_string());
+
+    Ok(response
+        .add_event(event)
+        .add_attribute("method", "request_withdrawal"))
+}
+
+/// Cancels all withdrawal requests owned by the user from the LP vault, regardless of their recipient.
 /// Returns the user's LP tokens to them.
 pub fn cancel_withdrawal_requests(
     deps: DepsMut<DydxQueryWrapper>,
     env: Env,
     info: MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
 ) -> ContractResult<Response<DydxMsg>> {
     let mut withdrawal_queue = WITHDRAWAL_QUEUES
-        .may_load(deps.storage, perp_id)?
-        .ok_or(ContractError::MissingWithdrawalQueue { perp_id })?;
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingWithdrawalQueue { vault_id })?;
 
     let mut i = 0;
     let mut restored_lp_tokens = Uint128::zero();
     while i < withdrawal_queue.len() {
-        if withdrawal_queue[i].recipient_addr == info.sender {
+        if withdrawal_queue[i].owner() == info.sender {
             restored_lp_tokens += withdrawal_queue[i].lp_tokens;
             withdrawal_queue.remove(i);
         } else {
             i += 1;
         }
     }
-    WITHDRAWAL_QUEUES.save(deps.storage, perp_id, &withdrawal_queue)?;
+    WITHDRAWAL_QUEUES.save(deps.storage, vault_id, &withdrawal_queue)?;
 
     let sub_info = MessageInfo {
         sender: env.contract.address.clone(),
@@ -229,15 +591,16 @@ pub fn cancel_withdrawal_requests(
         transfer_lp_tokens_from_withdrawal_queue(
             deps,
             sub_info,
-            perp_id,
+            vault_id,
             info.sender.to_string(),
             restored_lp_tokens,
+            &env.block,
         )?;
     }
 
     let event = Event::new("cancelled_withdrawal_requests")
         .add_attribute("withdrawer", info.sender.to_string())
-        .add_attribute("perp_id", perp_id.to_string())
+        .add_attribute("vault_id", vault_id.to_string())
         .add_attribute("restored_lp_tokens", restored_lp_tokens.to_string());
 
     Ok(Response::new()
@@ -246,28 +609,31 @@ pub fn cancel_withdrawal_requests(
 }
 
 /// Processes user withdrawal requests as long as the dYdX subaccount allows it.
-/// Burns LP tokens upon withdrawal.
+/// If the vault has a withdrawal rate limit, processing stops at the first request that exceeds the remaining capacity of the window.
+/// Burns LP tokens upon withdrawal. The vault must be active, settlement pays out the queue of a settling vault.
 /// Can only be called by the Trader
 pub fn process_withdrawals(
     mut deps: DepsMut<DydxQueryWrapper>,
     env: Env,
     info: MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
     mut max_num_withdrawals: u32,
 ) -> ContractResult<Response<DydxMsg>> {
     let state = STATE.load(deps.storage)?;
     // validate sender (must be configured trader)
-    if info.sender != &state.trader {
+    if info.sender != state.trader {
         return Err(ContractError::SenderCannotProcessWithdrawals {
             sender: info.sender,
         });
     }
 
-    let pos = query_dydx_position(deps.as_ref(), perp_id)?;
-    let mut asset_value = pos.asset_usdc_value.clone();
+    let vault = load_vault(deps.storage, vault_id)?;
+    verify_vault_is_active(&vault)?;
+    let pos = query_dydx_position(deps.as_ref(), vault_id)?;
+    let mut asset_value = vault_margin(&vault, &pos);
     let perp_value = pos.perp_usdc_value;
-    let subaccount_value_signed = pos.asset_usdc_value + pos.perp_usdc_value;
-    let mut subaccount_value = subaccount_value_signed.abs_diff(SignedDecimal::zero());
+    let nav = vault_usdc_value(deps.as_ref(), vault_id, &pos)?;
+    let mut subaccount_value = nav.abs_diff(SignedDecimal::zero());
 
     let (
         _queued_lp_tokens,
@@ -275,15 +641,40 @@ pub fn process_withdrawals(
         _outstanding_lp_tokens,
         outstanding_lp_tokens_decimal,
         lp_token_info,
-    ) = get_user_and_outstanding_lp_tokens(&deps, perp_id, &env.contract.address)?;
+    ) = get_user_and_outstanding_lp_tokens(deps.as_ref(), vault_id, &env.contract.address)?;
+
+    // checkpoint the share price before withdrawals change the vault value and LP supply
+    if share_price_checkpoint_due(deps.storage, env.block.time, vault_id)? {
+        checkpoint_share_price(deps.storage, env.block.time, vault_id, nav, &lp_token_info)?;
+    }
 
     let mut withdrawal_queue = WITHDRAWAL_QUEUES
-        .may_load(deps.storage, perp_id)?
-        .ok_or(ContractError::MissingWithdrawalQueue { perp_id })?;
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingWithdrawalQueue { vault_id })?;
+
+    let rate_limit = VAULT_CONFIGS
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default()
+        .withdrawal_rate_limit;
+    let mut remaining_capacity = match &rate_limit {
+        Some(rate_limit) => Some(
+            remaining_withdrawal_capacity(
+                deps.storage,
+                env.block.time,
+                vault_id,
+                rate_limit,
+                subaccount_value,
+                state.collateral.decimals,
+            )?
+            .2,
+        ),
+        None => None,
+    };
 
     let mut withdraw_msgs = vec![];
     let mut withdraw_events = vec![];
-    while max_num_withdrawals > 0 && withdrawal_queue.len() > 0 {
+    let mut withdrawn_quantums = Uint128::zero();
+    while max_num_withdrawals > 0 && !withdrawal_queue.is_empty() {
         let lp_amount = withdrawal_queue[0].lp_tokens;
         let lp_amount_decimal =
             Decimal::from_atomics(lp_amount, lp_token_info.decimals as u32).unwrap();
@@ -292,55 +683,89 @@ pub fn process_withdrawals(
         // make quantums
         let ownership_fraction = lp_amount_decimal / outstanding_lp_tokens_decimal;
         let withdraw_value = ownership_fraction * subaccount_value;
-        assert!(withdraw_value <= subaccount_value.into());
+        assert!(withdraw_value <= subaccount_value);
         assert!(ownership_fraction <= Decimal::one());
 
-        let withdraw_quantums = decimal_to_native_round_down(withdraw_value, USDC_DENOM).unwrap();
+        let withdraw_quantums =
+            decimal_to_native_round_down(withdraw_value, state.collateral.decimals).unwrap();
+
+        // leave the remaining requests in the queue once the rate limit is reached
+        if let Some(capacity) = remaining_capacity {
+            if withdraw_quantums > capacity {
+                withdraw_events.push(
+                    Event::new("withdrawals_rate_limited")
+                        .add_attribute("vault_id", vault_id.to_string())
+                        .add_attribute("remaining_capacity", capacity.to_string())
+                        .add_attribute("queued_withdrawals", withdrawal_queue.len().to_string()),
+                );
+                break;
+            }
+            remaining_capacity = Some(capacity - withdraw_quantums);
+        }
+        withdrawn_quantums += withdraw_quantums;
 
         subaccount_value -= withdraw_value;
         asset_value -= SignedDecimal::try_from(withdraw_value).unwrap();
         // validate withdraw amount
         if withdraw_quantums >= u64::MAX.into() {
             return Err(ContractError::InvalidWithdrawalAmount {
-                coin_type: USDC_COIN_TYPE.to_string(),
+                coin_type: state.collateral.denom.clone(),
                 amount: withdraw_quantums.into(),
             });
         };
 
         // validate health after withdrawal
         if perp_value > asset_value {
-            return Err(ContractError::WithdrawalWouldIncreaseLeverageTooMuch { perp_id });
+            return Err(ContractError::WithdrawalWouldIncreaseLeverageTooMuch { vault_id });
         };
 
         // make withdrawal message
         let withdraw_message = DydxMsg::WithdrawFromSubaccountV1 {
-            subaccount_number: perp_id,
+            subaccount_number: vault.subaccount_number,
             recipient: recipient.to_string(),
-            asset_id: USDC_ID,
+            asset_id: state.collateral.asset_id,
             quantums: withdraw_quantums.u128() as u64,
         };
-        withdraw_msgs.push(withdraw_message);
-
-        let event = Event::new("processed_withdrawal")
-            .add_attribute("recipient", recipient.to_string())
-            .add_attribute("perp_id", perp_id.to_string())
-            .add_attribute("withdrawn_usdc", withdraw_quantums.to_string())
-            .add_attribute("burnt_lp_tokens", lp_amount.to_string());
-        withdraw_events.push(event);
+        withdraw_msgs.push(encode_dydx_msg(&state, withdraw_message)?);
 
-        // burn LP tokens
+        // burn LP tokens and realize the PnL of the request owner
         let sub_info = MessageInfo {
             sender: env.contract.address.clone(),
             funds: vec![],
         };
-        burn_lp_tokens(&mut deps, &sub_info, perp_id, lp_amount)?;
+        burn_lp_tokens(&mut deps, &sub_info, vault_id, lp_amount, &env.block)?;
+        let realized_pnl = realize_withdrawal_pnl(
+            deps.storage,
+            vault_id,
+            withdrawal_queue[0].owner(),
+            lp_amount,
+            withdraw_quantums,
+        )?;
+
+        let event = Event::new("processed_withdrawal")
+            .add_attribute("recipient", recipient.to_string())
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("withdrawn_usdc", withdraw_quantums.to_string())
+            .add_attribute("burnt_lp_tokens", lp_amount.to_string())
+            .add_attribute("realized_pnl", realized_pnl.to_string());
+        withdraw_events.push(event);
 
         // pop from vec
         withdrawal_queue.remove(0);
 
         max_num_withdrawals -= 1;
     }
-    WITHDRAWAL_QUEUES.save(deps.storage, perp_id, &withdrawal_queue)?;
+    WITHDRAWAL_QUEUES.save(deps.storage, vault_id, &withdrawal_queue)?;
+
+    if let Some(rate_limit) = rate_limit {
+        record_withdrawal_outflow(
+            deps.storage,
+            &env,
+            vault_id,
+            &rate_limit,
+            withdrawn_quantums,
+        )?;
+    }
 
     Ok(Response::new()
         .add_attribute("method", "process_withdrawals")
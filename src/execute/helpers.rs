@@ -1,57 +1,120 @@
-use crate::dydx::proto_structs::SubaccountId;
+use crate::dydx::msg::{DydxMsg, DydxMsgEncoding};
+use crate::dydx::proto_structs::{Status, SubaccountId};
+use crate::dydx::querier::DydxQuerier;
 use crate::dydx::query::DydxQueryWrapper;
+use crate::dydx::stargate::to_stargate_msg;
 use crate::error::ContractError;
 use crate::error::ContractResult;
+use crate::execute::rewards::accrue_rewards;
 use crate::msg::TokenInfoResponse;
-use crate::query::lp_token_info;
-use crate::state::{LP_BALANCES, LP_TOKENS};
+use crate::query::{lp_token_info, DydxPosition};
+use crate::state::{
+    DepositLock, InstantWithdrawalConfig, Outflow, OutflowWindow, SharePriceCheckpoint, State,
+    Vault, VaultStatus, WithdrawalRateLimit, COST_BASES, DEPOSIT_LOCKS,
+    INSTANT_WITHDRAWAL_OUTFLOWS, LP_BALANCES, LP_TOKENS, PROCESSED_WITHDRAWAL_OUTFLOWS, REFERRALS,
+    REFERRER_STATS, SHARE_PRICE_CHECKPOINTS, VAULTS, VAULT_CONFIGS, VAULT_LP_HELD_BY_VAULTS,
+    VAULT_LP_HOLDINGS,
+};
 use cosmwasm_std::{
-    Addr, CheckedMultiplyFractionError, Decimal, DepsMut, Env, Fraction, MessageInfo, StdResult,
+    Addr, BlockInfo, CheckedMultiplyFractionError, CosmosMsg, Decimal, Deps, DepsMut, Env,
+    Fraction, Int128, MessageInfo, Order, SignedDecimal, StdError, StdResult, Storage, Timestamp,
     Uint128,
 };
+use num_bigint::BigInt;
 
 pub fn verify_sender_is_trader(sender: &Addr, trader: &Addr) -> ContractResult<()> {
     if sender != trader {
-        return Err(ContractError::SenderIsNotTrader {
+        Err(ContractError::SenderIsNotTrader {
             sender: sender.clone(),
-        });
+        })
     } else {
         Ok(())
     }
 }
 
+pub fn verify_sender_is_admin(sender: &Addr, admin: &Addr) -> ContractResult<()> {
+    if sender != admin {
+        Err(ContractError::SenderIsNotAdmin {
+            sender: sender.clone(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Wraps a dYdX message in the encoding the contract was instantiated with.
+pub fn encode_dydx_msg(state: &State, msg: DydxMsg) -> ContractResult<CosmosMsg<DydxMsg>> {
+    if state.msg_encoding == DydxMsgEncoding::Stargate {
+        if let Some(stargate_msg) = to_stargate_msg(&msg, state.contract.as_str())? {
+            return Ok(stargate_msg);
+        }
+    }
+    Ok(CosmosMsg::Custom(msg))
+}
+
 pub fn validate_addr_string(
     deps: &DepsMut<DydxQueryWrapper>,
     addr_string: String,
 ) -> ContractResult<Addr> {
     match deps.api.addr_validate(&addr_string) {
         Ok(a) => Ok(a),
-        Err(_) => return Err(ContractError::InvalidAddress { addr: addr_string }),
+        Err(_) => Err(ContractError::InvalidAddress { addr: addr_string }),
+    }
+}
+
+/// Loads a vault, failing if it has not been created.
+pub fn load_vault(storage: &dyn Storage, vault_id: u32) -> ContractResult<Vault> {
+    VAULTS
+        .may_load(storage, vault_id)?
+        .ok_or(ContractError::VaultNotInitialized { vault_id })
+}
+
+/// Fails unless the vault accepts deposits and trades.
+pub fn verify_vault_is_active(vault: &Vault) -> ContractResult<()> {
+    if vault.status != VaultStatus::Active {
+        return Err(ContractError::VaultNotActive {
+            vault_id: vault.vault_id,
+            status: vault.status.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the collateral that the leverage checks of a vault compare its perp value against.
+/// Cross vaults use their asset position.
+/// An isolated position can only be margined by its own subaccount, so isolated vaults use the subaccount value, which includes the position's pnl.
+pub fn vault_margin(vault: &Vault, pos: &DydxPosition) -> SignedDecimal {
+    if vault.isolated {
+        pos.asset_usdc_value + pos.perp_usdc_value
+    } else {
+        pos.asset_usdc_value
     }
 }
 
-/// Since vaults and dYdX perp markets have a 1:1 relationship, perp_id and subaccount number are interchangeable.
-pub fn get_contract_subaccount_id(env: &Env, perp_id: u32) -> SubaccountId {
+/// Returns the id of the contract-owned subaccount with the given number.
+pub fn get_contract_subaccount_id(env: &Env, subaccount_number: u32) -> SubaccountId {
     SubaccountId {
         owner: env.contract.address.to_string(),
-        number: perp_id,
+        number: subaccount_number,
     }
 }
 
 /// Mint the specified amount of LP tokens to an address.
 /// Should only be called by the smart contract during deposits.
 /// The minting process updates the LP state maps and consists of:
-/// 1. increasing the total LP token supply
-/// 2. increasing the address' LP token balance
+/// 1. accruing the vault's rewards on the previous balances
+/// 2. increasing the total LP token supply
+/// 3. increasing the address' LP token balance
 pub fn mint_lp_tokens(
     deps: DepsMut<DydxQueryWrapper>,
     info: MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
     recipient: String,
     amount: Uint128,
+    block: &BlockInfo,
 ) -> ContractResult<()> {
     let mut config = LP_TOKENS
-        .may_load(deps.storage, perp_id)?
+        .may_load(deps.storage, vault_id)?
         .ok_or(ContractError::Unauthorized {})?;
 
     if config
@@ -64,6 +127,10 @@ pub fn mint_lp_tokens(
         return Err(ContractError::Unauthorized {});
     }
 
+    // accrue rewards on the previous balances
+    let rcpt_addr = deps.api.addr_validate(&recipient)?;
+    accrue_rewards(deps.storage, block.time, vault_id, Some(&rcpt_addr))?;
+
     // update supply and enforce cap
     config.total_supply += amount;
     if let Some(limit) = config.get_cap() {
@@ -71,13 +138,13 @@ pub fn mint_lp_tokens(
             return Err(ContractError::MintingCannotExceedCap {});
         }
     }
-    LP_TOKENS.save(deps.storage, perp_id, &config)?;
+    LP_TOKENS.save(deps.storage, vault_id, &config, block.height)?;
 
     // add amount to recipient balance
-    let rcpt_addr = deps.api.addr_validate(&recipient)?;
     LP_BALANCES.update(
         deps.storage,
-        (perp_id, &rcpt_addr),
+        (vault_id, &rcpt_addr),
+        block.height,
         |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() + amount) },
     )?;
 
@@ -87,16 +154,18 @@ pub fn mint_lp_tokens(
 /// Burn the specified amount of LP tokens owned by an address.
 /// Should only be called by the smart contract during processing of withdrawals.
 /// The burn process updates the LP state maps and consists of:
-/// 1. decreasing the total LP token supply
-/// 2. decreasing the address' LP token balance
+/// 1. accruing the vault's rewards on the previous balances
+/// 2. decreasing the total LP token supply
+/// 3. decreasing the address' LP token balance
 pub fn burn_lp_tokens(
     deps: &mut DepsMut<DydxQueryWrapper>,
     info: &MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
     amount: Uint128,
+    block: &BlockInfo,
 ) -> ContractResult<()> {
     let mut config = LP_TOKENS
-        .may_load(deps.storage, perp_id)?
+        .may_load(deps.storage, vault_id)?
         .ok_or(ContractError::Unauthorized {})?;
 
     if config
@@ -112,29 +181,118 @@ pub fn burn_lp_tokens(
     if amount > config.total_supply {
         return Err(ContractError::BurningCannotExceedSupply {});
     };
+    accrue_rewards(deps.storage, block.time, vault_id, None)?;
     // update supply
     config.total_supply -= amount;
-    LP_TOKENS.save(deps.storage, perp_id, &config)?;
+    LP_TOKENS.save(deps.storage, vault_id, &config, block.height)?;
 
     // remove amount from sender balance (always the smart contract)
     LP_BALANCES.update(
         deps.storage,
-        (perp_id, &info.sender),
+        (vault_id, &info.sender),
+        block.height,
         |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() - amount) },
     )?;
 
     Ok(())
 }
 
+/// Mints LP tokens of the issuer vault to the holder vault during rebalancing.
+/// Increases the issuer's LP token supply, the holder's `VAULT_LP_HOLDINGS` balance and the issuer's `VAULT_LP_HELD_BY_VAULTS`.
+pub fn mint_vault_lp_tokens(
+    storage: &mut dyn Storage,
+    holder_vault_id: u32,
+    issuer_vault_id: u32,
+    amount: Uint128,
+    block: &BlockInfo,
+) -> ContractResult<()> {
+    if amount.is_zero() {
+        return Ok(());
+    }
+    accrue_rewards(storage, block.time, issuer_vault_id, None)?;
+    let mut config =
+        LP_TOKENS
+            .may_load(storage, issuer_vault_id)?
+            .ok_or(ContractError::MissingLpToken {
+                vault_id: issuer_vault_id,
+            })?;
+    config.total_supply += amount;
+    if let Some(limit) = config.get_cap() {
+        if config.total_supply > limit {
+            return Err(ContractError::MintingCannotExceedCap {});
+        }
+    }
+    LP_TOKENS.save(storage, issuer_vault_id, &config, block.height)?;
+
+    VAULT_LP_HOLDINGS.update(
+        storage,
+        (holder_vault_id, issuer_vault_id),
+        |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() + amount) },
+    )?;
+    VAULT_LP_HELD_BY_VAULTS.update(
+        storage,
+        issuer_vault_id,
+        |held: Option<Uint128>| -> StdResult<_> { Ok(held.unwrap_or_default() + amount) },
+    )?;
+    Ok(())
+}
+
+/// Burns LP tokens of the issuer vault held by the holder vault during rebalancing.
+/// Decreases the issuer's LP token supply, the holder's `VAULT_LP_HOLDINGS` balance and the issuer's `VAULT_LP_HELD_BY_VAULTS`.
+pub fn burn_vault_lp_tokens(
+    storage: &mut dyn Storage,
+    holder_vault_id: u32,
+    issuer_vault_id: u32,
+    amount: Uint128,
+    block: &BlockInfo,
+) -> ContractResult<()> {
+    let mut config =
+        LP_TOKENS
+            .may_load(storage, issuer_vault_id)?
+            .ok_or(ContractError::MissingLpToken {
+                vault_id: issuer_vault_id,
+            })?;
+    let held = VAULT_LP_HOLDINGS
+        .may_load(storage, (holder_vault_id, issuer_vault_id))?
+        .unwrap_or_default();
+    if amount > config.total_supply || amount > held {
+        return Err(ContractError::BurningCannotExceedSupply {});
+    }
+    accrue_rewards(storage, block.time, issuer_vault_id, None)?;
+    config.total_supply -= amount;
+    LP_TOKENS.save(storage, issuer_vault_id, &config, block.height)?;
+
+    if held == amount {
+        VAULT_LP_HOLDINGS.remove(storage, (holder_vault_id, issuer_vault_id));
+    } else {
+        VAULT_LP_HOLDINGS.save(
+            storage,
+            (holder_vault_id, issuer_vault_id),
+            &(held - amount),
+        )?;
+    }
+    let held_by_vaults = VAULT_LP_HELD_BY_VAULTS
+        .may_load(storage, issuer_vault_id)?
+        .unwrap_or_default()
+        - amount;
+    if held_by_vaults.is_zero() {
+        VAULT_LP_HELD_BY_VAULTS.remove(storage, issuer_vault_id);
+    } else {
+        VAULT_LP_HELD_BY_VAULTS.save(storage, issuer_vault_id, &held_by_vaults)?;
+    }
+    Ok(())
+}
+
 pub fn transfer_lp_tokens_to_withdrawal_queue(
     deps: DepsMut<DydxQueryWrapper>,
     info: MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
     withdrawer: String,
     amount: Uint128,
+    block: &BlockInfo,
 ) -> ContractResult<()> {
     let config = LP_TOKENS
-        .may_load(deps.storage, perp_id)?
+        .may_load(deps.storage, vault_id)?
         .ok_or(ContractError::Unauthorized {})?;
 
     if config
@@ -149,16 +307,19 @@ pub fn transfer_lp_tokens_to_withdrawal_queue(
 
     // remove amount from withdrawer's balance
     let rcpt_addr = deps.api.addr_validate(&withdrawer)?;
+    accrue_rewards(deps.storage, block.time, vault_id, Some(&rcpt_addr))?;
     LP_BALANCES.update(
         deps.storage,
-        (perp_id, &rcpt_addr),
+        (vault_id, &rcpt_addr),
+        block.height,
         |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() - amount) },
     )?;
 
     // add it to the contract's LP balance
     LP_BALANCES.update(
         deps.storage,
-        (perp_id, &info.sender), // guaranteed to be smart contract
+        (vault_id, &info.sender), // guaranteed to be smart contract
+        block.height,
         |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() + amount) },
     )?;
 
@@ -168,12 +329,13 @@ pub fn transfer_lp_tokens_to_withdrawal_queue(
 pub fn transfer_lp_tokens_from_withdrawal_queue(
     deps: DepsMut<DydxQueryWrapper>,
     info: MessageInfo,
-    perp_id: u32,
+    vault_id: u32,
     withdrawer: String,
     amount: Uint128,
+    block: &BlockInfo,
 ) -> ContractResult<()> {
     let config = LP_TOKENS
-        .may_load(deps.storage, perp_id)?
+        .may_load(deps.storage, vault_id)?
         .ok_or(ContractError::Unauthorized {})?;
 
     if config
@@ -186,41 +348,357 @@ pub fn transfer_lp_tokens_from_withdrawal_queue(
         return Err(ContractError::Unauthorized {});
     }
 
+    let withdrawer_addr = deps.api.addr_validate(&withdrawer)?;
+    accrue_rewards(deps.storage, block.time, vault_id, Some(&withdrawer_addr))?;
+
     // remove amount from contracts's balance
     LP_BALANCES.update(
         deps.storage,
-        (perp_id, &info.sender), // guaranteed to be smart contract
+        (vault_id, &info.sender), // guaranteed to be smart contract
+        block.height,
         |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() - amount) },
     )?;
 
     // add it to the withdrawer's LP balance
-    let withdrawer_addr = deps.api.addr_validate(&withdrawer)?;
     LP_BALANCES.update(
         deps.storage,
-        (perp_id, &withdrawer_addr),
+        (vault_id, &withdrawer_addr),
+        block.height,
         |balance: Option<Uint128>| -> StdResult<_> { Ok(balance.unwrap_or_default() + amount) },
     )?;
 
     Ok(())
 }
 
+/// Returns the lockup of the vault and the deposits of a user that are still locked at `now`, ordered from oldest to newest.
+pub fn active_deposit_locks(
+    storage: &dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+    user_addr: &Addr,
+) -> StdResult<(u64, Vec<DepositLock>)> {
+    let lockup_seconds = VAULT_CONFIGS
+        .may_load(storage, vault_id)?
+        .unwrap_or_default()
+        .lockup_seconds;
+    let locks = DEPOSIT_LOCKS
+        .may_load(storage, (vault_id, user_addr))?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|lock| lock.deposited_at.plus_seconds(lockup_seconds) > now)
+        .collect();
+    Ok((lockup_seconds, locks))
+}
+
+/// Records the LP tokens minted by a deposit so they cannot be withdrawn before the vault lockup has passed.
+/// Expired locks are pruned at the same time, so nothing is stored when the vault has no lockup.
+pub fn record_deposit_lock(
+    storage: &mut dyn Storage,
+    env: &Env,
+    vault_id: u32,
+    user_addr: &Addr,
+    lp_tokens: Uint128,
+) -> ContractResult<()> {
+    let (lockup_seconds, mut locks) =
+        active_deposit_locks(storage, env.block.time, vault_id, user_addr)?;
+    if lockup_seconds > 0 {
+        locks.push(DepositLock {
+            lp_tokens,
+            deposited_at: env.block.time,
+        });
+    }
+
+    if locks.is_empty() {
+        DEPOSIT_LOCKS.remove(storage, (vault_id, user_addr));
+    } else {
+        DEPOSIT_LOCKS.save(storage, (vault_id, user_addr), &locks)?;
+    }
+    Ok(())
+}
+
+/// Returns whether the checkpoint interval of the vault has passed since its last share price checkpoint.
+pub fn share_price_checkpoint_due(
+    storage: &dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+) -> StdResult<bool> {
+    let interval_seconds = VAULT_CONFIGS
+        .may_load(storage, vault_id)?
+        .unwrap_or_default()
+        .share_price_interval_seconds;
+    let last_checkpoint = SHARE_PRICE_CHECKPOINTS
+        .prefix(vault_id)
+        .keys(storage, None, None, Order::Descending)
+        .next()
+        .transpose()?;
+    Ok(match last_checkpoint {
+        Some(time) => now.seconds() >= time + interval_seconds,
+        None => true,
+    })
+}
+
+/// Records the share price of a vault at `now`. Vaults without LP tokens have no share price and are skipped.
+pub fn checkpoint_share_price(
+    storage: &mut dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+    nav: SignedDecimal,
+    lp_token_info: &TokenInfoResponse,
+) -> ContractResult<()> {
+    if lp_token_info.total_supply.is_zero() {
+        return Ok(());
+    }
+    let lp_supply =
+        Decimal::from_atomics(lp_token_info.total_supply, lp_token_info.decimals as u32)
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
+    let lp_supply =
+        SignedDecimal::try_from(lp_supply).map_err(|e| StdError::generic_err(e.to_string()))?;
+    let checkpoint = SharePriceCheckpoint {
+        time: now,
+        share_price: nav / lp_supply,
+        nav,
+        lp_supply: lp_token_info.total_supply,
+    };
+    SHARE_PRICE_CHECKPOINTS.save(storage, (vault_id, now.seconds()), &checkpoint)?;
+    Ok(())
+}
+
+/// Attributes a deposit to the referrer of the depositor and adds it to the referrer's deposit volume.
+/// A referrer is only recorded on the depositor's first deposit into the vault; later deposits are attributed to the recorded referrer.
+/// Must be called before the deposit is recorded. Returns the referrer the deposit is attributed to.
+pub fn record_referral(
+    storage: &mut dyn Storage,
+    vault_id: u32,
+    depositor: &Addr,
+    referrer: Option<Addr>,
+    usdc_quantums: Uint128,
+) -> StdResult<Option<Addr>> {
+    let referrer = match REFERRALS.may_load(storage, (vault_id, depositor))? {
+        Some(recorded) => Some(recorded),
+        None => {
+            let first_deposit = !COST_BASES.has(storage, (vault_id, depositor))
+                && LP_BALANCES
+                    .may_load(storage, (vault_id, depositor))?
+                    .unwrap_or_default()
+                    .is_zero();
+            match referrer {
+                Some(referrer) if first_deposit => {
+                    REFERRALS.save(storage, (vault_id, depositor), &referrer)?;
+                    REFERRER_STATS.update(storage, &referrer, |stats| -> StdResult<_> {
+                        let mut stats = stats.unwrap_or_default();
+                        stats.referred_depositors += 1;
+                        Ok(stats)
+                    })?;
+                    Some(referrer)
+                }
+                _ => None,
+            }
+        }
+    };
+
+    if let Some(referrer) = &referrer {
+        REFERRER_STATS.update(storage, referrer, |stats| -> StdResult<_> {
+            let mut stats = stats.unwrap_or_default();
+            stats.deposit_volume += usdc_quantums;
+            Ok(stats)
+        })?;
+    }
+    Ok(referrer)
+}
+
+/// Adds the collateral paid for newly minted LP tokens to the user's cost basis.
+pub fn record_deposit_cost(
+    storage: &mut dyn Storage,
+    vault_id: u32,
+    user_addr: &Addr,
+    usdc_quantums: Uint128,
+    lp_tokens: Uint128,
+) -> StdResult<()> {
+    COST_BASES.update(
+        storage,
+        (vault_id, user_addr),
+        |cost_basis| -> StdResult<_> {
+            let mut cost_basis = cost_basis.unwrap_or_default();
+            cost_basis.lp_tokens += lp_tokens;
+            cost_basis.cost_usdc += usdc_quantums;
+            cost_basis.deposited_usdc += usdc_quantums;
+            Ok(cost_basis)
+        },
+    )?;
+    Ok(())
+}
+
+/// Removes burnt LP tokens from the user's cost basis at their average cost and realizes the PnL of the collateral paid for them.
+/// LP tokens without a recorded cost are burnt last and do not count towards the realized PnL.
+/// Returns the realized PnL.
+pub fn realize_withdrawal_pnl(
+    storage: &mut dyn Storage,
+    vault_id: u32,
+    user_addr: &Addr,
+    burnt_lp_tokens: Uint128,
+    usdc_quantums: Uint128,
+) -> StdResult<Int128> {
+    let mut cost_basis = COST_BASES
+        .may_load(storage, (vault_id, user_addr))?
+        .unwrap_or_default();
+    cost_basis.withdrawn_usdc += usdc_quantums;
+
+    let tracked_lp_tokens = burnt_lp_tokens.min(cost_basis.lp_tokens);
+    let realized_pnl = if tracked_lp_tokens.is_zero() {
+        Int128::zero()
+    } else {
+        let cost = cost_basis
+            .cost_usdc
+            .multiply_ratio(tracked_lp_tokens, cost_basis.lp_tokens);
+        let proceeds = usdc_quantums.multiply_ratio(tracked_lp_tokens, burnt_lp_tokens);
+        cost_basis.lp_tokens -= tracked_lp_tokens;
+        cost_basis.cost_usdc -= cost;
+        signed_quantums(proceeds) - signed_quantums(cost)
+    };
+    cost_basis.realized_pnl += realized_pnl;
+
+    COST_BASES.save(storage, (vault_id, user_addr), &cost_basis)?;
+    Ok(realized_pnl)
+}
+
+/// Converts collateral quantums to a signed amount. dYdX quantums fit in a u64, so the conversion cannot overflow.
+pub fn signed_quantums(quantums: Uint128) -> Int128 {
+    Int128::new(quantums.u128() as i128)
+}
+
+/// Returns the amount of a user's LP tokens that are not locked.
+/// Errors if fewer than `requested` LP tokens are unlocked, stating when enough of them unlock.
+pub fn verify_lp_tokens_unlocked(
+    storage: &dyn Storage,
+    env: &Env,
+    vault_id: u32,
+    user_addr: &Addr,
+    user_lp_tokens: Uint128,
+    requested: Uint128,
+) -> ContractResult<Uint128> {
+    let (lockup_seconds, locks) =
+        active_deposit_locks(storage, env.block.time, vault_id, user_addr)?;
+    let locked: Uint128 = locks.iter().map(|lock| lock.lp_tokens).sum();
+    let unlocked = user_lp_tokens.saturating_sub(locked);
+    if requested <= unlocked {
+        return Ok(unlocked);
+    }
+
+    // locks are ordered oldest first, so they unlock in order
+    let mut available = unlocked;
+    let mut unlock_time = env.block.time;
+    for lock in locks {
+        if available >= requested {
+            break;
+        }
+        available += lock.lp_tokens;
+        unlock_time = lock.deposited_at.plus_seconds(lockup_seconds);
+    }
+    Err(ContractError::LpTokensLocked {
+        vault_id,
+        requested,
+        unlocked,
+        unlock_time: unlock_time.seconds(),
+    })
+}
+
+/// Returns the instant withdrawal outflows of the current window, starting a new window if the last one has ended.
+pub fn current_instant_outflow_window(
+    storage: &dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+    instant_config: &InstantWithdrawalConfig,
+) -> StdResult<OutflowWindow> {
+    let now = now.seconds();
+    let window_start = Timestamp::from_seconds(now - now % instant_config.window_seconds);
+    let window = INSTANT_WITHDRAWAL_OUTFLOWS
+        .may_load(storage, vault_id)?
+        .filter(|w| w.window_start == window_start)
+        .unwrap_or(OutflowWindow {
+            window_start,
+            usdc_outflow: Uint128::zero(),
+        });
+    Ok(window)
+}
+
+/// Returns the processed withdrawal outflows that are still inside the rolling window.
+fn recent_withdrawal_outflows(
+    storage: &dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+    rate_limit: &WithdrawalRateLimit,
+) -> StdResult<Vec<Outflow>> {
+    Ok(PROCESSED_WITHDRAWAL_OUTFLOWS
+        .may_load(storage, vault_id)?
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|outflow| outflow.time.plus_seconds(rate_limit.window_seconds) > now)
+        .collect())
+}
+
+/// Returns the maximum outflow of the rolling window, the USDC quantums already processed in it and the remaining capacity.
+/// The maximum outflow is measured against the vault value at the start of the window, i.e. the current value plus the processed outflows.
+pub fn remaining_withdrawal_capacity(
+    storage: &dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+    rate_limit: &WithdrawalRateLimit,
+    vault_value: Decimal,
+    collateral_decimals: u32,
+) -> ContractResult<(Uint128, Uint128, Uint128)> {
+    let processed: Uint128 = recent_withdrawal_outflows(storage, now, vault_id, rate_limit)?
+        .iter()
+        .map(|outflow| outflow.usdc)
+        .sum();
+    let processed_value = Decimal::from_atomics(processed, collateral_decimals).unwrap();
+    let max_outflow = decimal_to_native_round_down(
+        rate_limit.max_nav_fraction * (vault_value + processed_value),
+        collateral_decimals,
+    )
+    .unwrap();
+    Ok((
+        max_outflow,
+        processed,
+        max_outflow.saturating_sub(processed),
+    ))
+}
+
+/// Records USDC paid out by processed and instant withdrawals, pruning outflows that have left the rolling window.
+pub fn record_withdrawal_outflow(
+    storage: &mut dyn Storage,
+    env: &Env,
+    vault_id: u32,
+    rate_limit: &WithdrawalRateLimit,
+    usdc: Uint128,
+) -> ContractResult<()> {
+    let mut outflows = recent_withdrawal_outflows(storage, env.block.time, vault_id, rate_limit)?;
+    if !usdc.is_zero() {
+        outflows.push(Outflow {
+            time: env.block.time,
+            usdc,
+        });
+    }
+    PROCESSED_WITHDRAWAL_OUTFLOWS.save(storage, vault_id, &outflows)?;
+    Ok(())
+}
+
 /// Returns user and outstanding token balances.
 /// Returns both raw and decimal versions.
 pub fn get_user_and_outstanding_lp_tokens(
-    deps: &DepsMut<DydxQueryWrapper>,
-    perp_id: u32,
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
     user_addr: &Addr,
 ) -> ContractResult<(Uint128, Decimal, Uint128, Decimal, TokenInfoResponse)> {
-    let lp_token_info = lp_token_info(deps.as_ref(), perp_id)?;
+    let lp_token_info = lp_token_info(deps, vault_id)?;
     let outstanding_lp_tokens =
         Decimal::from_atomics(lp_token_info.total_supply, lp_token_info.decimals as u32).unwrap();
 
-    let ulp = match LP_BALANCES.may_load(deps.storage, (perp_id, &user_addr))? {
+    let ulp = match LP_BALANCES.may_load(deps.storage, (vault_id, user_addr))? {
         Some(x) => x,
         None => {
             return Err(ContractError::LpTokensNotFound {
                 user: user_addr.clone(),
-                perp_id,
+                vault_id,
             })
         }
     };
@@ -234,13 +712,97 @@ pub fn get_user_and_outstanding_lp_tokens(
     ))
 }
 
+/// Returns the LP tokens minted for a deposit of `amount` collateral quantums into a vault worth `vault_value`, using:
+///      new_tokens / (new_tokens + outstanding_lp_tokens) = deposit_value / (deposit_value + vault_value).
+/// A deposit into an empty vault mints one LP token per collateral quantum.
+pub fn lp_tokens_for_deposit(
+    amount: Uint128,
+    collateral_decimals: u32,
+    vault_value: Decimal,
+    outstanding_lp_tokens: Decimal,
+    lp_decimals: u32,
+) -> Uint128 {
+    let deposit_value = Decimal::from_atomics(amount, collateral_decimals).unwrap();
+    let share_value_fraction = deposit_value / (deposit_value + vault_value);
+    if share_value_fraction == Decimal::one() {
+        amount
+    } else {
+        let token_amt_decimal = (share_value_fraction * outstanding_lp_tokens)
+            / (Decimal::one() - share_value_fraction);
+        decimal_to_native_round_down(token_amt_decimal, lp_decimals).unwrap()
+    }
+}
+
+/// Returns the LP tokens, rounded up, that a withdrawal worth `withdraw_value` takes from a user holding `user_lp_tokens` of a vault worth `vault_value`.
+/// Returns `None` if the user's LP tokens are worth less than the withdrawal.
+pub fn lp_tokens_for_withdrawal(
+    withdraw_value: Decimal,
+    vault_value: Decimal,
+    user_lp_tokens: Decimal,
+    outstanding_lp_tokens: Decimal,
+    lp_decimals: u32,
+) -> Option<Uint128> {
+    let ownership_fraction = user_lp_tokens.checked_div(outstanding_lp_tokens).ok()?;
+    let max_withdraw_value = ownership_fraction * vault_value;
+
+    let lp_token_ratio = withdraw_value.checked_div(max_withdraw_value).ok()?;
+    let withdraw_lp_tokens = user_lp_tokens * lp_token_ratio;
+    if withdraw_lp_tokens > user_lp_tokens {
+        return None;
+    }
+    Some(decimal_to_native_round_up(withdraw_lp_tokens, lp_decimals).unwrap())
+}
+
+/// Returns the share of a vault worth `vault_value` that `lp_tokens` are redeemed for.
+pub fn lp_tokens_usdc_value(
+    lp_tokens: Uint128,
+    lp_decimals: u32,
+    outstanding_lp_tokens: Decimal,
+    vault_value: Decimal,
+) -> Decimal {
+    let lp_amount_decimal = Decimal::from_atomics(lp_tokens, lp_decimals).unwrap();
+    vault_value * lp_amount_decimal / outstanding_lp_tokens
+}
+
+/// Fails unless the vault is active and its perp market is not in final settlement.
+pub fn verify_vault_accepts_deposits(querier: &DydxQuerier, vault: &Vault) -> ContractResult<()> {
+    verify_vault_is_active(vault)?;
+    let clob_pair = querier
+        .query_perpetual_clob_details(vault.perp_id)?
+        .perpetual_clob_details
+        .clob_pair;
+    if clob_pair.status == Status::FinalSettlement {
+        return Err(ContractError::MarketInFinalSettlement {
+            vault_id: vault.vault_id,
+        });
+    }
+    Ok(())
+}
+
+/// Returns whether the vault subaccount has no position in the perp market of the vault.
+/// Checks the size of the position, as a position can be valued at 0.
+pub fn perp_position_is_flat(
+    querier: &DydxQuerier,
+    contract: &Addr,
+    vault: &Vault,
+) -> ContractResult<bool> {
+    let subaccount = querier
+        .query_subaccount(contract.to_string(), vault.subaccount_number)?
+        .subaccount;
+    Ok(subaccount
+        .perpetual_positions
+        .iter()
+        .filter(|p| p.perpetual_id == vault.perp_id)
+        .all(|p| *p.quantums.to_big_int() == BigInt::ZERO))
+}
+
 /// Convert a decimal to native Uint128. Rounds down.
 pub fn decimal_to_native_round_down(
     decimal: Decimal,
     denom: u32,
 ) -> Result<Uint128, CheckedMultiplyFractionError> {
     let frac = (
-        Uint128::new(10 as u128).pow(Decimal::DECIMAL_PLACES - denom),
+        Uint128::new(10_u128).pow(Decimal::DECIMAL_PLACES - denom),
         Uint128::one(),
     );
     decimal.numerator().checked_div_floor(frac)
@@ -251,7 +813,7 @@ pub fn decimal_to_native_round_up(
     denom: u32,
 ) -> Result<Uint128, CheckedMultiplyFractionError> {
     let frac = (
-        Uint128::new(10 as u128).pow(Decimal::DECIMAL_PLACES - denom),
+        Uint128::new(10_u128).pow(Decimal::DECIMAL_PLACES - denom),
         Uint128::one(),
     );
     decimal.numerator().checked_div_ceil(frac)
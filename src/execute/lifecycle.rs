@@ -0,0 +1,577 @@
+use cosmwasm_std::{
+    Addr, BankMsg, CosmosMsg, DepsMut, Env, Event, MessageInfo, Order, Response, SignedDecimal,
+    StdResult, Storage, Uint128,
+};
+
+use crate::dydx::msg::DydxMsg;
+use crate::dydx::proto_structs::Status;
+use crate::dydx::querier::DydxQuerier;
+use crate::dydx::query::DydxQueryWrapper;
+use crate::error::ContractResult;
+use crate::query::query_dydx_position;
+use crate::state::{
+    Settlement, Vault, VaultStatus, COST_BASES, DEPOSIT_LOCKS, INSTANT_WITHDRAWAL_OUTFLOWS,
+    LP_BALANCES, LP_TOKENS, PROCESSED_WITHDRAWAL_OUTFLOWS, REFERRALS, SETTLEMENTS,
+    SHARE_PRICE_CHECKPOINTS, VAULTS, VAULT_CONFIGS, VAULT_LP_HOLDINGS, WITHDRAWAL_QUEUES,
+};
+use crate::{error::ContractError, state::STATE};
+
+use super::helpers::{
+    burn_vault_lp_tokens, decimal_to_native_round_down, encode_dydx_msg,
+    get_contract_subaccount_id, load_vault, perp_position_is_flat, realize_withdrawal_pnl,
+    transfer_lp_tokens_from_withdrawal_queue, verify_sender_is_trader, verify_vault_is_active,
+};
+use super::market_make::{cancel_order_messages, MAX_CANCEL_ORDERS};
+use super::permissioned_keys::PermissionedKey;
+use super::rewards::{accrue_rewards, end_reward_streams};
+
+/// The number of LP holders paid out by a single `settle_vault` call if no limit is given.
+const DEFAULT_SETTLEMENT_PAYOUTS: u32 = 10;
+/// The maximum number of LP holders paid out by a single `settle_vault` call.
+const MAX_SETTLEMENT_PAYOUTS: u32 = 30;
+/// The number of per-user state entries removed by a single `archive_vault` call if no limit is given.
+const DEFAULT_ARCHIVED_ENTRIES: u32 = 100;
+/// The maximum number of per-user state entries removed by a single `archive_vault` call.
+const MAX_ARCHIVED_ENTRIES: u32 = 300;
+
+/// Winds down a vault whose perp market is in final settlement. Can be called by anyone.
+/// The first call moves the vault to `Settling`, which blocks deposits and trading.
+/// Once dYdX has closed the perp position, the USDC of the subaccount is paid out to all LP holders pro-rata,
+/// including queued withdrawals and vaults holding LP tokens from rebalances.
+/// Each call pays out at most `limit` LP holders, and the vault is `Settled` once all of them are paid.
+pub fn settle_vault(
+    mut deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    _info: MessageInfo,
+    vault_id: u32,
+    limit: Option<u32>,
+) -> ContractResult<Response<DydxMsg>> {
+    let mut vault = load_vault(deps.storage, vault_id)?;
+    let cannot_settle = |reason: &str| ContractError::CannotSettleVault {
+        vault_id,
+        reason: reason.to_string(),
+    };
+
+    match vault.status {
+        VaultStatus::Active => {
+            let clob_pair = DydxQuerier::new(&deps.querier)
+                .query_perpetual_clob_details(vault.perp_id)?
+                .perpetual_clob_details
+                .clob_pair;
+            if clob_pair.status != Status::FinalSettlement {
+                return Err(cannot_settle("the perp market is not in final settlement"));
+            }
+            vault.status = VaultStatus::Settling;
+            VAULTS.save(deps.storage, vault_id, &vault)?;
+        }
+        VaultStatus::Settling => {}
+        VaultStatus::Settled => return Err(cannot_settle("the vault is already settled")),
+        VaultStatus::Closed => return Err(cannot_settle("the vault is closed")),
+    }
+
+    let settlement = match SETTLEMENTS.may_load(deps.storage, vault_id)? {
+        Some(settlement) => settlement,
+        None => {
+            let querier = DydxQuerier::new(&deps.querier);
+            if !perp_position_is_flat(&querier, &env.contract.address, &vault)? {
+                let event = Event::new("settling_vault")
+                    .add_attribute("vault_id", vault_id.to_string())
+                    .add_attribute("perp_id", vault.perp_id.to_string());
+                return Ok(Response::new()
+                    .add_event(event)
+                    .add_attribute("method", "settle_vault"));
+            }
+            if VAULT_LP_HOLDINGS
+                .prefix(vault_id)
+                .range(deps.storage, None, None, Order::Ascending)
+                .next()
+                .is_some()
+            {
+                return Err(cannot_settle(
+                    "the vault holds LP tokens of other vaults, rebalance them back first",
+                ));
+            }
+            let pos = query_dydx_position(deps.as_ref(), vault_id)?;
+            new_settlement(deps.storage, vault_id, pos.asset_usdc_value)?
+        }
+    };
+
+    let limit = limit
+        .unwrap_or(DEFAULT_SETTLEMENT_PAYOUTS)
+        .min(MAX_SETTLEMENT_PAYOUTS) as usize;
+    let (messages, events, settlement) =
+        pay_out_lp_holders(&mut deps, &env, &vault, settlement, limit)?;
+
+    let event = match settlement {
+        Some(settlement) => {
+            let event = Event::new("settling_vault")
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("perp_id", vault.perp_id.to_string())
+                .add_attribute("num_payouts", events.len().to_string());
+            SETTLEMENTS.save(deps.storage, vault_id, &settlement)?;
+            event
+        }
+        None => {
+            vault.status = VaultStatus::Settled;
+            VAULTS.save(deps.storage, vault_id, &vault)?;
+            Event::new("settled_vault")
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("perp_id", vault.perp_id.to_string())
+                .add_attribute("num_payouts", events.len().to_string())
+        }
+    };
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_events(events)
+        .add_messages(messages)
+        .add_attribute("method", "settle_vault"))
+}
+
+/// Closes an active vault for good. Can only be called by the trader.
+/// The vault must have a flat perp position and no LP tokens of other vaults in or out.
+/// Cancels the given long-term orders and removes all permissioned keys of the vault subaccount, so trading stops.
+/// Queued withdrawal requests are cancelled and their LP tokens returned to their owners.
+/// Closed vaults reject deposits, and withdrawal requests are redeemed immediately and pro-rata for the USDC in the subaccount,
+/// regardless of the vault lockup.
+pub fn close_vault(
+    mut deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+    cancel_client_ids: Vec<u32>,
+    cancel_good_til_block_time: u32,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_trader(&info.sender, &state.trader)?;
+
+    let mut vault = load_vault(deps.storage, vault_id)?;
+    verify_vault_is_active(&vault)?;
+    let cannot_close = |reason: &str| ContractError::CannotCloseVault {
+        vault_id,
+        reason: reason.to_string(),
+    };
+
+    if cancel_client_ids.len() > MAX_CANCEL_ORDERS {
+        return Err(ContractError::CanOnlyCancelSixOrderOrders {});
+    }
+    let querier = DydxQuerier::new(&deps.querier);
+    if !perp_position_is_flat(&querier, &env.contract.address, &vault)? {
+        return Err(cannot_close("the perp position is not flat"));
+    }
+    if VAULT_LP_HOLDINGS
+        .prefix(vault_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some()
+    {
+        return Err(cannot_close(
+            "the vault holds LP tokens of other vaults, rebalance them back first",
+        ));
+    }
+    let holdings: Vec<(u32, u32)> = VAULT_LP_HOLDINGS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    if holdings.iter().any(|(_, issuer)| *issuer == vault_id) {
+        return Err(cannot_close(
+            "other vaults hold LP tokens of the vault, rebalance them back first",
+        ));
+    }
+
+    let clob_pair_id = querier
+        .query_perpetual_clob_details(vault.perp_id)?
+        .perpetual_clob_details
+        .clob_pair
+        .id;
+    let (mut messages, mut events) = cancel_order_messages(
+        &state,
+        &vault,
+        clob_pair_id,
+        cancel_client_ids,
+        cancel_good_til_block_time,
+    )?;
+
+    // keys would let external signers keep trading the subaccount
+    let keys: Vec<PermissionedKey> = querier
+        .query_authenticators(env.contract.address.to_string())?
+        .account_authenticators
+        .iter()
+        .filter_map(PermissionedKey::from_authenticator)
+        .filter(|key| key.subaccount_number == vault.subaccount_number)
+        .collect();
+    for key in keys {
+        messages.push(CosmosMsg::Custom(DydxMsg::RemoveAuthenticatorV1 {
+            id: key.authenticator_id,
+        }));
+        events.push(
+            Event::new("removed_permissioned_key")
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("authenticator_id", key.authenticator_id.to_string()),
+        );
+    }
+
+    // queued withdrawals are returned to their owners, who redeem them like all other LP tokens
+    let withdrawal_queue = WITHDRAWAL_QUEUES
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default();
+    let sub_info = MessageInfo {
+        sender: env.contract.address.clone(),
+        funds: vec![],
+    };
+    for request in &withdrawal_queue {
+        if request.lp_tokens.is_zero() {
+            continue;
+        }
+        transfer_lp_tokens_from_withdrawal_queue(
+            deps.branch(),
+            sub_info.clone(),
+            vault_id,
+            request.owner().to_string(),
+            request.lp_tokens,
+            &env.block,
+        )?;
+        events.push(
+            Event::new("cancelled_withdrawal_requests")
+                .add_attribute("withdrawer", request.owner().to_string())
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("restored_lp_tokens", request.lp_tokens.to_string()),
+        );
+    }
+    WITHDRAWAL_QUEUES.save(deps.storage, vault_id, &vec![])?;
+
+    vault.status = VaultStatus::Closed;
+    VAULTS.save(deps.storage, vault_id, &vault)?;
+
+    let pos = query_dydx_position(deps.as_ref(), vault_id)?;
+    let event = Event::new("closed_vault")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("perp_id", vault.perp_id.to_string())
+        .add_attribute("asset_usdc_value", pos.asset_usdc_value.to_string());
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_events(events)
+        .add_messages(messages)
+        .add_attribute("method", "close_vault"))
+}
+
+/// Removes all state of a closed or settled vault once its LP supply is zero. Can only be called by the trader.
+/// Each call removes at most `limit` entries of its per-user state, and the vault is only archived once all of them are gone.
+/// Rewards its reward streams had not distributed yet are refunded to the admin.
+/// The vault id and its subaccount can be reused by a new vault afterwards.
+pub fn archive_vault(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+    limit: Option<u32>,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_trader(&info.sender, &state.trader)?;
+
+    let vault = load_vault(deps.storage, vault_id)?;
+    let cannot_archive = |reason: &str| ContractError::CannotArchiveVault {
+        vault_id,
+        reason: reason.to_string(),
+    };
+    if !matches!(vault.status, VaultStatus::Closed | VaultStatus::Settled) {
+        return Err(cannot_archive(
+            "only closed or settled vaults can be archived",
+        ));
+    }
+    let lp_token_info = LP_TOKENS
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingLpToken { vault_id })?;
+    if !lp_token_info.total_supply.is_zero() {
+        return Err(cannot_archive("LP tokens are still outstanding"));
+    }
+
+    let limit = limit
+        .unwrap_or(DEFAULT_ARCHIVED_ENTRIES)
+        .min(MAX_ARCHIVED_ENTRIES) as usize;
+    let mut remaining = limit;
+    let holders: Vec<Addr> = LP_BALANCES
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take(remaining)
+        .collect::<StdResult<_>>()?;
+    remaining -= holders.len();
+    for holder in holders {
+        LP_BALANCES.remove(deps.storage, (vault_id, &holder), env.block.height)?;
+    }
+    let users: Vec<Addr> = COST_BASES
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take(remaining)
+        .collect::<StdResult<_>>()?;
+    remaining -= users.len();
+    for user in users {
+        COST_BASES.remove(deps.storage, (vault_id, &user));
+    }
+    let referred: Vec<Addr> = REFERRALS
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take(remaining)
+        .collect::<StdResult<_>>()?;
+    remaining -= referred.len();
+    for user in referred {
+        REFERRALS.remove(deps.storage, (vault_id, &user));
+    }
+    let locked: Vec<Addr> = DEPOSIT_LOCKS
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take(remaining)
+        .collect::<StdResult<_>>()?;
+    remaining -= locked.len();
+    for user in locked {
+        DEPOSIT_LOCKS.remove(deps.storage, (vault_id, &user));
+    }
+    let checkpoints: Vec<u64> = SHARE_PRICE_CHECKPOINTS
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .take(remaining)
+        .collect::<StdResult<_>>()?;
+    remaining -= checkpoints.len();
+    for time in checkpoints {
+        SHARE_PRICE_CHECKPOINTS.remove(deps.storage, (vault_id, time));
+    }
+    // a page that used up the limit may have left entries behind, the next call removes them
+    if remaining == 0 {
+        let event = Event::new("archiving_vault")
+            .add_attribute("vault_id", vault_id.to_string())
+            .add_attribute("perp_id", vault.perp_id.to_string())
+            .add_attribute("num_removed_entries", limit.to_string());
+        return Ok(Response::new()
+            .add_event(event)
+            .add_attribute("method", "archive_vault"));
+    }
+
+    let unreleased_rewards = end_reward_streams(deps.storage, env.block.time, vault_id)?;
+    LP_TOKENS.remove(deps.storage, vault_id, env.block.height)?;
+    WITHDRAWAL_QUEUES.remove(deps.storage, vault_id);
+    VAULT_CONFIGS.remove(deps.storage, vault_id);
+    INSTANT_WITHDRAWAL_OUTFLOWS.remove(deps.storage, vault_id);
+    PROCESSED_WITHDRAWAL_OUTFLOWS.remove(deps.storage, vault_id);
+    VAULTS.remove(deps.storage, vault_id);
+
+    let mut event = Event::new("archived_vault")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("perp_id", vault.perp_id.to_string())
+        .add_attribute("subaccount_number", vault.subaccount_number.to_string());
+    let mut response = Response::new();
+    if !unreleased_rewards.is_empty() {
+        event = event.add_attribute(
+            "refunded_rewards",
+            unreleased_rewards
+                .iter()
+                .map(|coin| coin.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        // the admin funded the streams
+        response = response.add_message(BankMsg::Send {
+            to_address: state.admin.to_string(),
+            amount: unreleased_rewards,
+        });
+    }
+
+    Ok(response
+        .add_event(event)
+        .add_attribute("method", "archive_vault"))
+}
+
+/// Freezes the payout of a vault whose perp position was closed, so later pages are paid at the same rate.
+fn new_settlement(
+    storage: &mut dyn Storage,
+    vault_id: u32,
+    asset_usdc_value: SignedDecimal,
+) -> ContractResult<Settlement> {
+    let state = STATE.load(storage)?;
+    let lp_token_info = LP_TOKENS
+        .may_load(storage, vault_id)?
+        .ok_or(ContractError::MissingLpToken { vault_id })?;
+    let available_quantums = if asset_usdc_value > SignedDecimal::zero() {
+        decimal_to_native_round_down(
+            asset_usdc_value.abs_diff(SignedDecimal::zero()),
+            state.collateral.decimals,
+        )
+        .unwrap()
+    } else {
+        Uint128::zero()
+    };
+    Ok(Settlement {
+        available_quantums,
+        total_supply: lp_token_info.total_supply,
+        num_payouts: 0,
+    })
+}
+
+/// Pays up to `limit` LP holders of a vault their share of the settlement and burns their LP tokens.
+/// Users are paid first, then queued withdrawals and last vaults holding the LP tokens.
+/// Users are paid with withdrawals and vaults with transfers to their subaccounts.
+/// Paid LP tokens are burnt, so the next call picks up where this one stopped.
+/// Returns `None` for the settlement once all LP holders are paid.
+#[allow(clippy::type_complexity)]
+fn pay_out_lp_holders(
+    deps: &mut DepsMut<DydxQueryWrapper>,
+    env: &Env,
+    vault: &Vault,
+    mut settlement: Settlement,
+    limit: usize,
+) -> ContractResult<(Vec<CosmosMsg<DydxMsg>>, Vec<Event>, Option<Settlement>)> {
+    let state = STATE.load(deps.storage)?;
+    let vault_id = vault.vault_id;
+    let mut lp_token_info = LP_TOKENS
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingLpToken { vault_id })?;
+    let payout = |lp_tokens: Uint128| {
+        if settlement.total_supply.is_zero() {
+            Uint128::zero()
+        } else {
+            settlement
+                .available_quantums
+                .multiply_ratio(lp_tokens, settlement.total_supply)
+        }
+    };
+    let mut messages = vec![];
+    let mut events = vec![];
+    let mut withdraw = |deps: &mut DepsMut<DydxQueryWrapper>,
+                        recipient: &Addr,
+                        owner: &Addr,
+                        lp_tokens: Uint128|
+     -> ContractResult<()> {
+        let quantums = payout(lp_tokens);
+        let realized_pnl =
+            realize_withdrawal_pnl(deps.storage, vault_id, owner, lp_tokens, quantums)?;
+        if !quantums.is_zero() {
+            let withdraw_message = DydxMsg::WithdrawFromSubaccountV1 {
+                subaccount_number: vault.subaccount_number,
+                recipient: recipient.to_string(),
+                asset_id: state.collateral.asset_id,
+                quantums: quantums.u128() as u64,
+            };
+            messages.push(encode_dydx_msg(&state, withdraw_message)?);
+        }
+        events.push(
+            Event::new("settlement_payout")
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("recipient", recipient.to_string())
+                .add_attribute("lp_tokens", lp_tokens.to_string())
+                .add_attribute("usdc_amount", quantums.to_string())
+                .add_attribute("realized_pnl", realized_pnl.to_string()),
+        );
+        Ok(())
+    };
+
+    // the LP tokens held by the contract belong to the withdrawal queue
+    let holders: Vec<(Addr, Uint128)> = LP_BALANCES
+        .prefix(vault_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .filter(|item| {
+            item.as_ref()
+                .map_or(true, |(addr, _)| *addr != env.contract.address)
+        })
+        .take(limit + 1)
+        .collect::<StdResult<_>>()?;
+    let mut remaining = limit;
+    let mut done = holders.len() <= limit;
+    for (holder, lp_tokens) in holders.into_iter().take(limit) {
+        // accrue the holder's rewards before their LP tokens are burnt
+        accrue_rewards(deps.storage, env.block.time, vault_id, Some(&holder))?;
+        withdraw(deps, &holder, &holder, lp_tokens)?;
+        LP_BALANCES.remove(deps.storage, (vault_id, &holder), env.block.height)?;
+        lp_token_info.total_supply -= lp_tokens;
+        remaining -= 1;
+    }
+
+    // queued withdrawals are paid to their recipients and the PnL is realized by the owners of the LP tokens
+    let mut withdrawal_queue = WITHDRAWAL_QUEUES
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default();
+    if done && !withdrawal_queue.is_empty() {
+        let unpaid = withdrawal_queue.split_off(remaining.min(withdrawal_queue.len()));
+        let mut queued_lp_tokens = Uint128::zero();
+        for request in withdrawal_queue {
+            if !request.lp_tokens.is_zero() {
+                withdraw(
+                    deps,
+                    &request.recipient_addr,
+                    request.owner(),
+                    request.lp_tokens,
+                )?;
+            }
+            queued_lp_tokens += request.lp_tokens;
+            remaining -= 1;
+        }
+        let contract_lp_tokens = LP_BALANCES
+            .may_load(deps.storage, (vault_id, &env.contract.address))?
+            .unwrap_or_default();
+        LP_BALANCES.save(
+            deps.storage,
+            (vault_id, &env.contract.address),
+            &(contract_lp_tokens - queued_lp_tokens),
+            env.block.height,
+        )?;
+        lp_token_info.total_supply -= queued_lp_tokens;
+        done = unpaid.is_empty();
+        WITHDRAWAL_QUEUES.save(deps.storage, vault_id, &unpaid)?;
+    }
+    LP_TOKENS.save(deps.storage, vault_id, &lp_token_info, env.block.height)?;
+
+    if done {
+        let holder_vaults: Vec<(u32, Uint128)> = VAULT_LP_HOLDINGS
+            .range(deps.storage, None, None, Order::Ascending)
+            .filter_map(|item| match item {
+                Ok(((holder, issuer), amount)) if issuer == vault_id => Some(Ok((holder, amount))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<StdResult<_>>()?;
+        done = holder_vaults.len() <= remaining;
+        for (holder_vault_id, lp_tokens) in holder_vaults.into_iter().take(remaining) {
+            let quantums = payout(lp_tokens);
+            if !quantums.is_zero() {
+                let holder = load_vault(deps.storage, holder_vault_id)?;
+                let transfer = DydxMsg::TransferV1 {
+                    sender: get_contract_subaccount_id(env, vault.subaccount_number),
+                    recipient: get_contract_subaccount_id(env, holder.subaccount_number),
+                    asset_id: state.collateral.asset_id,
+                    amount: quantums.u128() as u64,
+                };
+                messages.push(encode_dydx_msg(&state, transfer)?);
+            }
+            burn_vault_lp_tokens(
+                deps.storage,
+                holder_vault_id,
+                vault_id,
+                lp_tokens,
+                &env.block,
+            )?;
+            events.push(
+                Event::new("settlement_payout")
+                    .add_attribute("vault_id", vault_id.to_string())
+                    .add_attribute("recipient_vault_id", holder_vault_id.to_string())
+                    .add_attribute("lp_tokens", lp_tokens.to_string())
+                    .add_attribute("usdc_amount", quantums.to_string()),
+            );
+        }
+    }
+
+    settlement.num_payouts += events.len() as u32;
+    if !done {
+        return Ok((messages, events, Some(settlement)));
+    }
+
+    // the whole supply is burnt, only rounding dust is left in the subaccount
+    LP_BALANCES.remove(
+        deps.storage,
+        (vault_id, &env.contract.address),
+        env.block.height,
+    )?;
+    let mut lp_token_info = LP_TOKENS.load(deps.storage, vault_id)?;
+    lp_token_info.total_supply = Uint128::zero();
+    LP_TOKENS.save(deps.storage, vault_id, &lp_token_info, env.block.height)?;
+    SETTLEMENTS.remove(deps.storage, vault_id);
+
+    Ok((messages, events, None))
+}
@@ -1,19 +1,27 @@
 use core::fmt;
 
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{DepsMut, Env, Event, MessageInfo, Response, SignedDecimal};
+use cosmwasm_std::{CosmosMsg, DepsMut, Env, Event, MessageInfo, Response, SignedDecimal};
 
-use super::USDC_DENOM;
-use crate::dydx::msg::{DydxMsg, OrderConditionType, OrderSide, OrderTimeInForce};
+use super::helpers::{
+    checkpoint_share_price, encode_dydx_msg, load_vault, share_price_checkpoint_due, vault_margin,
+    verify_vault_is_active,
+};
+use crate::dydx::msg::{DydxMsg, OrderBatch, OrderConditionType, OrderSide, OrderTimeInForce};
+use crate::dydx::proto_structs::Status;
 use crate::dydx::querier::DydxQuerier;
 use crate::dydx::query::DydxQueryWrapper;
 use crate::error::ContractResult;
-use crate::query::query_dydx_position;
-use crate::state::VAULTS_BY_PERP_ID;
+use crate::query::{lp_token_info, query_dydx_position, vault_usdc_value};
+use crate::state::{State, Vault};
 use crate::{error::ContractError, state::STATE};
 
-const MAX_CANCEL_ORDERS: usize = 6;
-const MAX_NEW_ORDERS_PER_SIDE: usize = 6;
+pub const MAX_CANCEL_ORDERS: usize = 6;
+const MAX_NEW_ORDERS_PER_SIDE: usize = 3;
+/// Maximum number of client ids in a dYdX `MsgBatchCancel`.
+const MAX_BATCH_CANCEL_CLIENT_IDS: usize = 100;
+/// Number of blocks ahead of the current block that a short-term order can be good til.
+const SHORT_BLOCK_WINDOW: u64 = 20;
 
 #[cw_serde]
 pub struct NewOrder {
@@ -29,9 +37,9 @@ pub struct NewOrder {
 }
 
 impl NewOrder {
-    pub fn get_place_event(&self, perp_id: u32, clob_pair_id: u32) -> Event {
+    pub fn get_place_event(&self, vault_id: u32, clob_pair_id: u32) -> Event {
         Event::new("placed_order")
-            .add_attribute("perp_id", perp_id.to_string())
+            .add_attribute("vault_id", vault_id.to_string())
             .add_attribute("client_id", self.client_id.to_string())
             .add_attribute("clob_pair_id", clob_pair_id.to_string())
             .add_attribute("side", self.side.to_string())
@@ -65,26 +73,33 @@ pub const LONG_TERM_ORDER_FLAGS: u32 = 64;
 /// This entrypoint will only send messages passed in as arguments. This means that it can be used selectively to only place or cancel orders.
 /// Orders that would cause the subaccount's perp value to asset value are rejected, unless perp value > asset value when this function is called.
 /// In that case, the orders must decrease perp value.
+#[allow(clippy::too_many_arguments)]
 pub fn market_make(
     deps: DepsMut<DydxQueryWrapper>,
-    _env: Env,
+    env: Env,
     info: MessageInfo,
-    subaccount_number: u32,
+    vault_id: u32,
     clob_pair_id: u32,
     new_orders: Vec<NewOrder>,
     cancel_client_ids: Vec<u32>,
     cancel_good_til_block_time: u32,
 ) -> ContractResult<Response<DydxMsg>> {
-    let perp_id = subaccount_number;
-
     let state = STATE.load(deps.storage)?;
 
+    // validate vault
+    let vault = load_vault(deps.storage, vault_id)?;
+    verify_vault_is_active(&vault)?;
+    let subaccount_number = vault.subaccount_number;
+
     let querier = DydxQuerier::new(&deps.querier);
-    let perp_details = querier.query_perpetual_clob_details(perp_id)?;
-    let pos = query_dydx_position(deps.as_ref(), perp_id)?;
+    let perp_details = querier.query_perpetual_clob_details(vault.perp_id)?;
+    if perp_details.perpetual_clob_details.clob_pair.status == Status::FinalSettlement {
+        return Err(ContractError::MarketInFinalSettlement { vault_id });
+    }
+    let pos = query_dydx_position(deps.as_ref(), vault_id)?;
 
     // validate sender (must be configured trader)
-    if info.sender != &state.trader {
+    if info.sender != state.trader {
         return Err(ContractError::SenderIsNotTrader {
             sender: info.sender,
         });
@@ -95,57 +110,48 @@ pub fn market_make(
         return Err(ContractError::PerpMarketClobIdMismatch {
             queried_id: perp_details.perpetual_clob_details.clob_pair.id,
             supplied_id: clob_pair_id,
-            perp_id,
+            perp_id: vault.perp_id,
         });
     }
 
-    // validate vault
-    if !VAULTS_BY_PERP_ID.has(deps.storage, perp_id) {
-        return Err(ContractError::VaultNotInitialized { perp_id });
-    }
-
     // validate at most 6 cancelled orders
     if cancel_client_ids.len() > MAX_CANCEL_ORDERS {
         return Err(ContractError::CanOnlyCancelSixOrderOrders {});
     }
 
+    // checkpoint the share price for the vault performance history
+    if share_price_checkpoint_due(deps.storage, env.block.time, vault_id)? {
+        let nav = vault_usdc_value(deps.as_ref(), vault_id, &pos)?;
+        let lp_token_info = lp_token_info(deps.as_ref(), vault_id)?;
+        checkpoint_share_price(deps.storage, env.block.time, vault_id, nav, &lp_token_info)?;
+    }
+
     // NOOP
-    if cancel_client_ids.len() == 0 && new_orders.len() == 0 {
-        return
+    if cancel_client_ids.is_empty() && new_orders.is_empty() {
+        return Ok(Response::new().add_attribute("method", "market_make"));
     }
 
     let mut messages = vec![];
     let mut events = vec![];
 
     // first add batch cancel
-    if cancel_client_ids.len() > 0 {
-        for cancel_client_id in cancel_client_ids {
-            // let event = order_id.get_batch_cancel_event();
-            let cancel_msg = DydxMsg::CancelOrderV1 {
-                subaccount_number,
-                client_id: cancel_client_id,
-                order_flags: LONG_TERM_ORDER_FLAGS,
-                clob_pair_id,
-                good_til_block_time: cancel_good_til_block_time,
-            };
-            let cancel_event = Event::new("cancelled_order")
-                .add_attribute("perp_id", subaccount_number.to_string())
-                .add_attribute("client_id", cancel_client_id.to_string())
-                .add_attribute("clob_pair_id", clob_pair_id.to_string())
-                .add_attribute(
-                    "cancel_good_til_block_time",
-                    cancel_good_til_block_time.to_string(),
-                );
-            messages.push(cancel_msg);
-            events.push(cancel_event);
-        }
+    if !cancel_client_ids.is_empty() {
+        let (cancel_messages, cancel_events) = cancel_order_messages(
+            &state,
+            &vault,
+            clob_pair_id,
+            cancel_client_ids,
+            cancel_good_til_block_time,
+        )?;
+        messages.extend(cancel_messages);
+        events.extend(cancel_events);
     }
 
     let mut num_bids = 0;
     let mut num_asks = 0;
     let mut net_order_value = SignedDecimal::zero();
     // then add new orders
-    if new_orders.len() > 0 {
+    if !new_orders.is_empty() {
         for new_order in new_orders {
             let order_sign = match new_order.side {
                 OrderSide::Unspecified => {
@@ -161,11 +167,12 @@ pub fn market_make(
                 }
             };
             let order_value =
-                SignedDecimal::from_atomics(new_order.quantums, USDC_DENOM).unwrap() * order_sign;
+                SignedDecimal::from_atomics(new_order.quantums, state.collateral.decimals).unwrap()
+                    * order_sign;
 
             net_order_value += order_value;
 
-            let place_event = new_order.get_place_event(subaccount_number, clob_pair_id);
+            let place_event = new_order.get_place_event(vault_id, clob_pair_id);
             let place_msg = DydxMsg::PlaceOrderV1 {
                 subaccount_number,
                 client_id: new_order.client_id,
@@ -181,7 +188,7 @@ pub fn market_make(
                 condition_type: OrderConditionType::Unspecified,
                 conditional_order_trigger_subticks: new_order.conditional_order_trigger_subticks,
             };
-            messages.push(place_msg);
+            messages.push(encode_dydx_msg(&state, place_msg)?);
             events.push(place_event);
         }
     }
@@ -190,12 +197,12 @@ pub fn market_make(
     if num_bids > MAX_NEW_ORDERS_PER_SIDE || num_asks > MAX_NEW_ORDERS_PER_SIDE {
         return Err(ContractError::CanOnlyPlaceThreeOrdersPerSide {});
     }
-    let asset_usdc_value = pos.asset_usdc_value.abs_diff(SignedDecimal::zero());
+    let asset_usdc_value = vault_margin(&vault, &pos).abs_diff(SignedDecimal::zero());
     let new_perp_value = (pos.perp_usdc_value + net_order_value).abs_diff(SignedDecimal::zero());
     let leverage_increased = new_perp_value > pos.perp_usdc_value.abs_diff(SignedDecimal::zero());
 
     if new_perp_value > asset_usdc_value && leverage_increased {
-        return Err(ContractError::NewOrdersWouldIncreaseLeverageTooMuch { perp_id });
+        return Err(ContractError::NewOrdersWouldIncreaseLeverageTooMuch { vault_id });
     }
 
     Ok(Response::new()
@@ -203,3 +210,148 @@ pub fn market_make(
         .add_events(events)
         .add_messages(messages))
 }
+
+/// Builds the `CancelOrderV1` messages and events for long-term orders of a vault subaccount.
+pub fn cancel_order_messages(
+    state: &State,
+    vault: &Vault,
+    clob_pair_id: u32,
+    cancel_client_ids: Vec<u32>,
+    cancel_good_til_block_time: u32,
+) -> ContractResult<(Vec<CosmosMsg<DydxMsg>>, Vec<Event>)> {
+    let mut messages = vec![];
+    let mut events = vec![];
+    for cancel_client_id in cancel_client_ids {
+        let cancel_msg = DydxMsg::CancelOrderV1 {
+            subaccount_number: vault.subaccount_number,
+            client_id: cancel_client_id,
+            order_flags: LONG_TERM_ORDER_FLAGS,
+            clob_pair_id,
+            good_til_block_time: cancel_good_til_block_time,
+        };
+        let cancel_event = Event::new("cancelled_order")
+            .add_attribute("vault_id", vault.vault_id.to_string())
+            .add_attribute("client_id", cancel_client_id.to_string())
+            .add_attribute("clob_pair_id", clob_pair_id.to_string())
+            .add_attribute(
+                "cancel_good_til_block_time",
+                cancel_good_til_block_time.to_string(),
+            );
+        messages.push(encode_dydx_msg(state, cancel_msg)?);
+        events.push(cancel_event);
+    }
+    Ok((messages, events))
+}
+
+/// Cancels short-term orders of a vault subaccount across clob pairs in a single dYdX batch cancel.
+/// Requires the sender to be the trader and the vault to exist.
+/// The number of cancelled orders is limited by dYdX's batch size and the chain's block rate limits on short-term cancellations.
+/// `good_til_block` must be within the short block window after the current block.
+pub fn batch_cancel(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+    order_batches: Vec<OrderBatch>,
+    good_til_block: u32,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+
+    // validate sender (must be configured trader)
+    if info.sender != state.trader {
+        return Err(ContractError::SenderIsNotTrader {
+            sender: info.sender,
+        });
+    }
+
+    // validate vault
+    let vault = load_vault(deps.storage, vault_id)?;
+
+    // validate batches
+    if order_batches.is_empty() {
+        return Err(ContractError::InvalidBatchCancel {
+            reason: "no order batches".to_string(),
+        });
+    }
+    let mut clob_pair_ids = vec![];
+    for batch in order_batches.iter() {
+        if batch.client_ids.is_empty() {
+            return Err(ContractError::InvalidBatchCancel {
+                reason: format!("no client ids for clob pair {}", batch.clob_pair_id),
+            });
+        }
+        if clob_pair_ids.contains(&batch.clob_pair_id) {
+            return Err(ContractError::InvalidBatchCancel {
+                reason: format!(
+                    "clob pair {} appears in more than one batch",
+                    batch.clob_pair_id
+                ),
+            });
+        }
+        clob_pair_ids.push(batch.clob_pair_id);
+    }
+
+    // validate the number of cancels against the batch size and block rate limits
+    let num_client_ids: usize = order_batches.iter().map(|b| b.client_ids.len()).sum();
+    let querier = DydxQuerier::new(&deps.querier);
+    let rate_limits = querier
+        .query_block_rate_limit_config()?
+        .block_rate_limit_config;
+    let max = rate_limits
+        .max_short_term_order_cancellations_per_n_blocks
+        .iter()
+        .chain(
+            rate_limits
+                .max_short_term_orders_and_cancels_per_n_blocks
+                .iter(),
+        )
+        .map(|rate_limit| rate_limit.limit as usize)
+        .fold(MAX_BATCH_CANCEL_CLIENT_IDS, usize::min);
+    if num_client_ids > max {
+        return Err(ContractError::BatchCancelTooLarge {
+            num_client_ids,
+            max,
+        });
+    }
+
+    // validate good til block
+    let current_block = env.block.height;
+    let max_block = current_block + SHORT_BLOCK_WINDOW;
+    if good_til_block as u64 <= current_block || good_til_block as u64 > max_block {
+        return Err(ContractError::InvalidGoodTilBlock {
+            good_til_block,
+            current_block,
+            max_block,
+        });
+    }
+
+    let events: Vec<Event> = order_batches
+        .iter()
+        .map(|batch| {
+            Event::new("batch_cancelled_orders")
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("clob_pair_id", batch.clob_pair_id.to_string())
+                .add_attribute(
+                    "client_ids",
+                    batch
+                        .client_ids
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                )
+                .add_attribute("good_til_block", good_til_block.to_string())
+        })
+        .collect();
+
+    let batch_cancel_msg = DydxMsg::BatchCancelV1 {
+        subaccount_number: vault.subaccount_number,
+        short_term_cancels: order_batches,
+        good_til_block,
+    };
+
+    Ok(Response::new()
+        .add_attribute("method", "batch_cancel")
+        .add_events(events)
+        .add_message(batch_cancel_msg))
+}
@@ -1,9 +1,13 @@
-pub const USDC_ID: u32 = 0;
-pub const USDC_DENOM: u32 = 6;
-pub const USDC_COIN_TYPE: &str =
-    "ibc/8E27BA2D5493AF5636760E354E46004562C46AB7EC0CC4C1CA14E9E20E2545B5";
-
 pub mod admin;
 pub mod deposit_withdraw;
 pub mod helpers;
+pub mod lifecycle;
 pub mod market_make;
+pub mod permissioned_keys;
+pub mod rewards;
+
+/// dYdX holds isolated positions in child subaccounts, numbered from 128 upwards.
+/// Lower numbers are parent subaccounts, which are cross-margined.
+pub const ISOLATED_SUBACCOUNT_START: u32 = 128;
+/// Highest subaccount number allowed by dYdX.
+pub const MAX_SUBACCOUNT_NUMBER: u32 = 128_000;
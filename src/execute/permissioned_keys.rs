@@ -0,0 +1,304 @@
+use std::str::FromStr;
+
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{from_json, to_json_binary, Binary, DepsMut, Env, Event, MessageInfo, Response};
+
+use crate::dydx::msg::{AuthenticatorType, DydxMsg, SubAuthenticatorInitData};
+use crate::dydx::proto_structs::AccountAuthenticator;
+use crate::dydx::querier::DydxQuerier;
+use crate::dydx::query::DydxQueryWrapper;
+use crate::error::ContractResult;
+use crate::{error::ContractError, state::STATE};
+
+use super::helpers::{load_vault, verify_sender_is_admin};
+
+/// Length of a compressed secp256k1 public key.
+const COMPRESSED_PUBLIC_KEY_LEN: usize = 33;
+
+/// Messages a permissioned key can sign on behalf of a vault subaccount.
+#[cw_serde]
+pub enum PermissionedMessageType {
+    PlaceOrder,
+    CancelOrder,
+    BatchCancel,
+}
+
+impl PermissionedMessageType {
+    pub fn type_url(&self) -> &'static str {
+        match self {
+            PermissionedMessageType::PlaceOrder => "/dydxprotocol.clob.MsgPlaceOrder",
+            PermissionedMessageType::CancelOrder => "/dydxprotocol.clob.MsgCancelOrder",
+            PermissionedMessageType::BatchCancel => "/dydxprotocol.clob.MsgBatchCancel",
+        }
+    }
+
+    pub fn from_type_url(type_url: &str) -> Option<Self> {
+        match type_url {
+            "/dydxprotocol.clob.MsgPlaceOrder" => Some(PermissionedMessageType::PlaceOrder),
+            "/dydxprotocol.clob.MsgCancelOrder" => Some(PermissionedMessageType::CancelOrder),
+            "/dydxprotocol.clob.MsgBatchCancel" => Some(PermissionedMessageType::BatchCancel),
+            _ => None,
+        }
+    }
+}
+
+/// An external signer that can trade a vault subaccount, as registered with x/accountplus.
+#[cw_serde]
+pub struct PermissionedKey {
+    pub authenticator_id: u64,
+    pub public_key: Binary,
+    pub subaccount_number: u32,
+    pub clob_pair_ids: Vec<u32>,
+    pub message_types: Vec<PermissionedMessageType>,
+}
+
+impl PermissionedKey {
+    /// Parses an `AllOf` authenticator registered by `add_permissioned_key`.
+    /// Returns `None` for authenticators that were not registered as a permissioned key.
+    pub fn from_authenticator(authenticator: &AccountAuthenticator) -> Option<Self> {
+        if AuthenticatorType::from_str(&authenticator.authenticator_type).ok()?
+            != AuthenticatorType::AllOf
+        {
+            return None;
+        }
+        let sub_authenticators: Vec<SubAuthenticatorInitData> =
+            from_json(&authenticator.config).ok()?;
+
+        let mut public_key = None;
+        let mut subaccount_number = None;
+        let mut clob_pair_ids = None;
+        let mut message_types = None;
+        for sub_authenticator in sub_authenticators {
+            let config = &sub_authenticator.config;
+            match sub_authenticator.authenticator_type {
+                AuthenticatorType::SignatureVerification => public_key = Some(config.clone()),
+                AuthenticatorType::SubaccountFilter => {
+                    subaccount_number = Some(std::str::from_utf8(config).ok()?.parse().ok()?)
+                }
+                AuthenticatorType::ClobPairIdFilter => {
+                    clob_pair_ids = Some(
+                        std::str::from_utf8(config)
+                            .ok()?
+                            .split(',')
+                            .map(|id| id.parse().ok())
+                            .collect::<Option<Vec<u32>>>()?,
+                    )
+                }
+                AuthenticatorType::MessageFilter => {
+                    message_types = Some(
+                        std::str::from_utf8(config)
+                            .ok()?
+                            .split(',')
+                            .map(PermissionedMessageType::from_type_url)
+                            .collect::<Option<Vec<_>>>()?,
+                    )
+                }
+                AuthenticatorType::AllOf | AuthenticatorType::AnyOf => return None,
+            }
+        }
+
+        Some(PermissionedKey {
+            authenticator_id: authenticator.id,
+            public_key: public_key?,
+            subaccount_number: subaccount_number?,
+            clob_pair_ids: clob_pair_ids?,
+            message_types: message_types?,
+        })
+    }
+}
+
+/// Builds the data of an `AllOf` authenticator that only lets `public_key` sign the given
+/// message types for the given clob pairs of a single subaccount.
+fn permissioned_key_authenticator_data(
+    public_key: &Binary,
+    subaccount_number: u32,
+    clob_pair_ids: &[u32],
+    message_types: &[PermissionedMessageType],
+) -> ContractResult<Binary> {
+    let clob_pair_ids = clob_pair_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let type_urls = message_types
+        .iter()
+        .map(|message_type| message_type.type_url())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let sub_authenticators = vec![
+        SubAuthenticatorInitData {
+            authenticator_type: AuthenticatorType::SignatureVerification,
+            config: public_key.clone(),
+        },
+        SubAuthenticatorInitData {
+            authenticator_type: AuthenticatorType::SubaccountFilter,
+            config: Binary::from(subaccount_number.to_string().into_bytes()),
+        },
+        SubAuthenticatorInitData {
+            authenticator_type: AuthenticatorType::ClobPairIdFilter,
+            config: Binary::from(clob_pair_ids.into_bytes()),
+        },
+        SubAuthenticatorInitData {
+            authenticator_type: AuthenticatorType::MessageFilter,
+            config: Binary::from(type_urls.into_bytes()),
+        },
+    ];
+    Ok(to_json_binary(&sub_authenticators)?)
+}
+
+/// Registers a permissioned key that can trade the subaccount of a vault.
+/// Can only be called by the admin.
+pub fn add_permissioned_key(
+    deps: DepsMut<DydxQueryWrapper>,
+    info: MessageInfo,
+    vault_id: u32,
+    public_key: Binary,
+    mut clob_pair_ids: Vec<u32>,
+    message_types: Vec<PermissionedMessageType>,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_admin(&info.sender, &state.admin)?;
+
+    let vault = load_vault(deps.storage, vault_id)?;
+    if public_key.len() != COMPRESSED_PUBLIC_KEY_LEN {
+        return Err(ContractError::InvalidPermissionedKey {
+            vault_id,
+            reason: format!(
+                "public key must be a {} byte compressed secp256k1 key",
+                COMPRESSED_PUBLIC_KEY_LEN
+            ),
+        });
+    }
+
+    clob_pair_ids.sort_unstable();
+    clob_pair_ids.dedup();
+    if clob_pair_ids.is_empty() {
+        return Err(ContractError::InvalidPermissionedKey {
+            vault_id,
+            reason: "at least one clob pair id is required".to_string(),
+        });
+    }
+    let querier = DydxQuerier::new(&deps.querier);
+    let clob_pairs = querier.query_all_clob_pairs()?.clob_pairs;
+    if let Some(clob_pair_id) = clob_pair_ids
+        .iter()
+        .find(|id| !clob_pairs.iter().any(|clob_pair| clob_pair.id == **id))
+    {
+        return Err(ContractError::InvalidPermissionedKey {
+            vault_id,
+            reason: format!("clob pair id {} does not exist", clob_pair_id),
+        });
+    }
+    // the subaccount of an isolated vault can only hold its own market
+    if vault.isolated {
+        let vault_clob_pair_id = querier
+            .query_perpetual_clob_details(vault.perp_id)?
+            .perpetual_clob_details
+            .clob_pair
+            .id;
+        if clob_pair_ids != [vault_clob_pair_id] {
+            return Err(ContractError::InvalidPermissionedKey {
+                vault_id,
+                reason: format!(
+                    "keys of an isolated vault can only trade clob pair id {}",
+                    vault_clob_pair_id
+                ),
+            });
+        }
+    }
+
+    let mut unique_message_types = Vec::with_capacity(message_types.len());
+    for message_type in message_types {
+        if !unique_message_types.contains(&message_type) {
+            unique_message_types.push(message_type);
+        }
+    }
+    let message_types = unique_message_types;
+    if message_types.is_empty() {
+        return Err(ContractError::InvalidPermissionedKey {
+            vault_id,
+            reason: "at least one message type is required".to_string(),
+        });
+    }
+
+    let data = permissioned_key_authenticator_data(
+        &public_key,
+        vault.subaccount_number,
+        &clob_pair_ids,
+        &message_types,
+    )?;
+
+    let event = Event::new("added_permissioned_key")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("public_key", public_key.to_base64())
+        .add_attribute(
+            "clob_pair_ids",
+            clob_pair_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+        .add_attribute(
+            "message_types",
+            message_types
+                .iter()
+                .map(|message_type| message_type.type_url())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+
+    Ok(Response::new()
+        .add_message(DydxMsg::AddAuthenticatorV1 {
+            authenticator_type: AuthenticatorType::AllOf,
+            data,
+        })
+        .add_event(event)
+        .add_attribute("method", "add_permissioned_key"))
+}
+
+/// Removes a permissioned key from the subaccount of a vault.
+/// Can only be called by the admin.
+pub fn remove_permissioned_key(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+    authenticator_id: u64,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_admin(&info.sender, &state.admin)?;
+
+    let vault = load_vault(deps.storage, vault_id)?;
+
+    // only keys scoped to this vault's subaccount can be removed through it
+    let querier = DydxQuerier::new(&deps.querier);
+    let authenticators = querier
+        .query_authenticators(env.contract.address.to_string())?
+        .account_authenticators;
+    let key_exists = authenticators
+        .iter()
+        .filter_map(PermissionedKey::from_authenticator)
+        .any(|key| {
+            key.authenticator_id == authenticator_id
+                && key.subaccount_number == vault.subaccount_number
+        });
+    if !key_exists {
+        return Err(ContractError::PermissionedKeyNotFound {
+            vault_id,
+            authenticator_id,
+        });
+    }
+
+    let event = Event::new("removed_permissioned_key")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("authenticator_id", authenticator_id.to_string());
+
+    Ok(Response::new()
+        .add_message(DydxMsg::RemoveAuthenticatorV1 {
+            id: authenticator_id,
+        })
+        .add_event(event)
+        .add_attribute("method", "remove_permissioned_key"))
+}
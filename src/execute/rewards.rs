@@ -0,0 +1,295 @@
+use cosmwasm_std::{
+    Addr, BankMsg, Coin, Decimal256, DepsMut, Env, Event, MessageInfo, Order, Response, StdError,
+    StdResult, Storage, Timestamp, Uint128, Uint256,
+};
+
+use crate::dydx::msg::DydxMsg;
+use crate::dydx::query::DydxQueryWrapper;
+use crate::error::{ContractError, ContractResult};
+use crate::state::{
+    RewardStream, UserReward, LP_BALANCES, LP_TOKENS, REWARDS_PER_TOKEN, REWARD_STREAMS, STATE,
+    USER_REWARDS, VAULT_LP_HELD_BY_VAULTS,
+};
+
+use super::helpers::{load_vault, verify_sender_is_admin, verify_vault_is_active};
+
+/// Funds a reward stream that releases the sent coin linearly to the LP holders of a vault between `start_time` and `end_time`.
+/// Can only be called by the admin.
+pub fn fund_rewards(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+    start_time: u64,
+    end_time: u64,
+) -> ContractResult<Response<DydxMsg>> {
+    let state = STATE.load(deps.storage)?;
+    verify_sender_is_admin(&info.sender, &state.admin)?;
+    let vault = load_vault(deps.storage, vault_id)?;
+    verify_vault_is_active(&vault)?;
+
+    if info.funds.len() != 1 {
+        return Err(ContractError::CanOnlyDepositOneCointype {});
+    }
+    let funds = info.funds[0].clone();
+    if funds.amount.is_zero() {
+        return Err(ContractError::InvalidDepositAmount {
+            coin_type: funds.denom,
+            amount: funds.amount.u128(),
+        });
+    }
+    let invalid_stream = |reason: &str| ContractError::InvalidRewardStream {
+        vault_id,
+        reason: reason.to_string(),
+    };
+    if start_time < env.block.time.seconds() {
+        return Err(invalid_stream("start time is in the past"));
+    }
+    if end_time <= start_time {
+        return Err(invalid_stream("end time must be after the start time"));
+    }
+
+    // settle the active streams before the new stream is added
+    accrue_rewards(deps.storage, env.block.time, vault_id, None)?;
+    let stream_id = REWARD_STREAMS
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Descending)
+        .next()
+        .transpose()?
+        .map_or(0, |id| id + 1);
+    let stream = RewardStream {
+        denom: funds.denom.clone(),
+        amount: funds.amount,
+        start_time: Timestamp::from_seconds(start_time),
+        end_time: Timestamp::from_seconds(end_time),
+        last_update: env.block.time,
+    };
+    REWARD_STREAMS.save(deps.storage, (vault_id, stream_id), &stream)?;
+
+    let event = Event::new("funded_rewards")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("stream_id", stream_id.to_string())
+        .add_attribute("rewards", funds.to_string())
+        .add_attribute("start_time", start_time.to_string())
+        .add_attribute("end_time", end_time.to_string());
+
+    Ok(Response::new()
+        .add_event(event)
+        .add_attribute("method", "fund_rewards"))
+}
+
+/// Sends the sender all rewards accrued on their LP tokens of a vault.
+pub fn claim_rewards(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u32,
+) -> ContractResult<Response<DydxMsg>> {
+    accrue_rewards(deps.storage, env.block.time, vault_id, Some(&info.sender))?;
+
+    let denoms: Vec<String> = REWARDS_PER_TOKEN
+        .prefix(vault_id)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    let mut rewards: Vec<Coin> = vec![];
+    for denom in denoms {
+        let key = (vault_id, denom.as_str(), &info.sender);
+        let mut user_reward = USER_REWARDS
+            .may_load(deps.storage, key)?
+            .unwrap_or_default();
+        if user_reward.pending.is_zero() {
+            continue;
+        }
+        rewards.push(Coin::new(user_reward.pending.u128(), denom.clone()));
+        user_reward.pending = Uint128::zero();
+        USER_REWARDS.save(deps.storage, key, &user_reward)?;
+    }
+    if rewards.is_empty() {
+        return Err(ContractError::NoRewardsToClaim {
+            vault_id,
+            user: info.sender,
+        });
+    }
+
+    let event = Event::new("claimed_rewards")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("user", info.sender.to_string())
+        .add_attribute(
+            "rewards",
+            rewards
+                .iter()
+                .map(|coin| coin.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: rewards,
+        })
+        .add_event(event)
+        .add_attribute("method", "claim_rewards"))
+}
+
+/// Ends the reward streams of an archived vault at `now`, so they cannot release rewards to a vault that reuses its id.
+/// Returns the rewards the streams had not distributed yet, which are refunded to the admin.
+/// Accrued rewards can still be claimed.
+pub fn end_reward_streams(
+    storage: &mut dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+) -> StdResult<Vec<Coin>> {
+    accrue_rewards(storage, now, vault_id, None)?;
+    let streams: Vec<(u64, RewardStream)> = REWARD_STREAMS
+        .prefix(vault_id)
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    let mut unreleased: Vec<Coin> = vec![];
+    for (stream_id, mut stream) in streams {
+        // rewards released after the last update were not distributed, as no LP tokens earned them
+        if stream.last_update < stream.end_time {
+            let released = stream.released_at(stream.last_update);
+            let refund = stream.amount - released;
+            if !refund.is_zero() {
+                match unreleased
+                    .iter_mut()
+                    .find(|coin| coin.denom == stream.denom)
+                {
+                    Some(coin) => coin.amount += refund,
+                    None => unreleased.push(Coin::new(refund.u128(), stream.denom.clone())),
+                }
+            }
+            stream.amount = released;
+            stream.start_time = stream.start_time.min(stream.last_update);
+            stream.end_time = stream.last_update;
+            REWARD_STREAMS.save(storage, (vault_id, stream_id), &stream)?;
+        }
+    }
+    Ok(unreleased)
+}
+
+/// Brings the reward streams of a vault up to `now` and, if given, moves the rewards of a user's LP tokens to their pending rewards.
+/// Must be called before every change of the LP supply or of an LP balance, so the rewards are computed over the previous balances.
+pub fn accrue_rewards(
+    storage: &mut dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+    user_addr: Option<&Addr>,
+) -> StdResult<()> {
+    let (rewards_per_token, streams) = updated_rewards(storage, now, vault_id)?;
+    if rewards_per_token.is_empty() {
+        return Ok(());
+    }
+    for (denom, reward_per_token) in &rewards_per_token {
+        if streams.iter().any(|(_, stream)| stream.denom == *denom) {
+            REWARDS_PER_TOKEN.save(storage, (vault_id, denom), reward_per_token)?;
+        }
+    }
+    for (stream_id, stream) in streams {
+        REWARD_STREAMS.save(storage, (vault_id, stream_id), &stream)?;
+    }
+
+    let contract = STATE.load(storage)?.contract;
+    let user_addr = match user_addr.filter(|addr| **addr != contract) {
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+    let user_lp_tokens = LP_BALANCES
+        .may_load(storage, (vault_id, user_addr))?
+        .unwrap_or_default();
+    for (denom, reward_per_token) in rewards_per_token {
+        let key = (vault_id, denom.as_str(), user_addr);
+        let user_reward = USER_REWARDS.may_load(storage, key)?.unwrap_or_default();
+        if user_reward.reward_per_token_paid == reward_per_token {
+            continue;
+        }
+        let user_reward = accrued_user_reward(user_reward, reward_per_token, user_lp_tokens)?;
+        USER_REWARDS.save(storage, key, &user_reward)?;
+    }
+    Ok(())
+}
+
+/// Returns the reward per token of each denom of a vault brought up to `now`, and the reward streams that released rewards since their last update.
+/// Streams that have distributed all their rewards are skipped.
+/// While no LP tokens earn rewards the streams are not updated, so the rewards released meanwhile go to the next LP holders.
+#[allow(clippy::type_complexity)]
+pub fn updated_rewards(
+    storage: &dyn Storage,
+    now: Timestamp,
+    vault_id: u32,
+) -> StdResult<(Vec<(String, Decimal256)>, Vec<(u64, RewardStream)>)> {
+    let mut rewards_per_token: Vec<(String, Decimal256)> = REWARDS_PER_TOKEN
+        .prefix(vault_id)
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    let streams: Vec<(u64, RewardStream)> = REWARD_STREAMS
+        .prefix(vault_id)
+        .range(storage, None, None, Order::Ascending)
+        .filter(|item| {
+            item.as_ref().map_or(true, |(_, stream)| {
+                stream.last_update < stream.end_time && stream.last_update < now
+            })
+        })
+        .collect::<StdResult<_>>()?;
+    if streams.is_empty() {
+        return Ok((rewards_per_token, vec![]));
+    }
+    let eligible_supply = reward_eligible_supply(storage, vault_id)?;
+    if eligible_supply.is_zero() {
+        return Ok((rewards_per_token, vec![]));
+    }
+
+    let mut updated = vec![];
+    for (stream_id, mut stream) in streams {
+        let released = stream.released_at(now) - stream.released_at(stream.last_update);
+        if released.is_zero() {
+            continue;
+        }
+        let reward_per_token = Decimal256::from_ratio(released, eligible_supply);
+        match rewards_per_token
+            .iter_mut()
+            .find(|(denom, _)| *denom == stream.denom)
+        {
+            Some((_, total)) => *total += reward_per_token,
+            None => rewards_per_token.push((stream.denom.clone(), reward_per_token)),
+        }
+        stream.last_update = now;
+        updated.push((stream_id, stream));
+    }
+    Ok((rewards_per_token, updated))
+}
+
+/// Returns the LP tokens of a vault that earn rewards.
+/// LP tokens of queued withdrawals, held by the contract, and LP tokens held by other vaults do not earn rewards.
+pub fn reward_eligible_supply(storage: &dyn Storage, vault_id: u32) -> StdResult<Uint128> {
+    let lp_token_info = match LP_TOKENS.may_load(storage, vault_id)? {
+        Some(lp_token_info) => lp_token_info,
+        None => return Ok(Uint128::zero()),
+    };
+    let contract = STATE.load(storage)?.contract;
+    let queued = LP_BALANCES
+        .may_load(storage, (vault_id, &contract))?
+        .unwrap_or_default();
+    let held_by_vaults = VAULT_LP_HELD_BY_VAULTS
+        .may_load(storage, vault_id)?
+        .unwrap_or_default();
+    Ok(lp_token_info
+        .total_supply
+        .saturating_sub(queued)
+        .saturating_sub(held_by_vaults))
+}
+
+/// Returns the user's rewards with the rewards earned by `lp_tokens` since their last accrual added to the pending rewards.
+pub fn accrued_user_reward(
+    mut user_reward: UserReward,
+    reward_per_token: Decimal256,
+    lp_tokens: Uint128,
+) -> StdResult<UserReward> {
+    let earned =
+        Uint256::from(lp_tokens).mul_floor(reward_per_token - user_reward.reward_per_token_paid);
+    let earned = Uint128::try_from(earned).map_err(|e| StdError::generic_err(e.to_string()))?;
+    user_reward.pending += earned;
+    user_reward.reward_per_token_paid = reward_per_token;
+    Ok(user_reward)
+}
@@ -1,13 +1,13 @@
 use cosmwasm_std::{entry_point, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult};
-use cw20_base::msg::MigrateMsg;
 use dydx::{msg::DydxMsg, query::DydxQueryWrapper};
 use error::ContractResult;
-use msg::{ExecuteMsg, InstantiateMsg};
+use msg::{ExecuteMsg, InstantiateMsg, MigrateMsg};
 
 pub mod contract;
 pub mod dydx;
 pub mod error;
 pub mod execute;
+pub mod migrations;
 pub mod msg;
 pub mod query;
 pub mod state;
@@ -38,6 +38,10 @@ pub fn execute(
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn migrate(deps: DepsMut<DydxQueryWrapper>, env: Env, msg: MigrateMsg) -> StdResult<Response> {
+pub fn migrate(
+    deps: DepsMut<DydxQueryWrapper>,
+    env: Env,
+    msg: MigrateMsg,
+) -> ContractResult<Response<DydxMsg>> {
     contract::migrate(deps, env, msg)
 }
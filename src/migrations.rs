@@ -0,0 +1,95 @@
+use cosmwasm_std::{Env, Order, StdResult, Storage, Uint128};
+use semver::Version;
+
+use crate::error::{ContractError, ContractResult};
+use crate::state::{
+    Vault, VaultStatus, LEGACY_VAULTS_BY_PERP_ID, LP_SNAPSHOTS_START_HEIGHT, VAULTS,
+    VAULT_LP_HELD_BY_VAULTS, VAULT_LP_HOLDINGS,
+};
+
+/// A state migration to the storage layout of `version`.
+/// It runs when migrating from a version older than `version` to `version` or newer.
+pub struct Migration {
+    pub version: &'static str,
+    pub migrate: fn(&mut dyn Storage, &Env) -> StdResult<()>,
+}
+
+/// All state migrations. A new entry is added whenever a release changes the storage layout.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: "0.2.0",
+        migrate: migrate_vaults_to_vault_ids,
+    },
+    Migration {
+        version: "0.3.0",
+        migrate: migrate_to_0_3_0,
+    },
+];
+
+/// Parses a contract version, as stored by cw2 or set in Cargo.toml.
+pub fn parse_version(version: &str) -> ContractResult<Version> {
+    Version::parse(version).map_err(|e| ContractError::InvalidContractVersion {
+        version: version.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Returns the migrations that upgrade state written by version `from` to the layout of version `to`, in the order they must run.
+pub fn pending_migrations(from: &Version, to: &Version) -> ContractResult<Vec<&'static Migration>> {
+    let mut pending = vec![];
+    for migration in MIGRATIONS {
+        let version = parse_version(migration.version)?;
+        if &version > from && &version <= to {
+            pending.push((version, migration));
+        }
+    }
+    pending.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(pending
+        .into_iter()
+        .map(|(_, migration)| migration)
+        .collect())
+}
+
+/// Vaults used to be keyed by perp_id and trade from the subaccount with the same number.
+fn migrate_vaults_to_vault_ids(storage: &mut dyn Storage, _env: &Env) -> StdResult<()> {
+    let legacy_perp_ids = LEGACY_VAULTS_BY_PERP_ID
+        .keys(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<u32>>>()?;
+    for perp_id in legacy_perp_ids {
+        let vault = Vault {
+            vault_id: perp_id,
+            perp_id,
+            subaccount_number: perp_id,
+            isolated: false,
+            status: VaultStatus::Active,
+        };
+        VAULTS.save(storage, perp_id, &vault)?;
+        LEGACY_VAULTS_BY_PERP_ID.remove(storage, perp_id);
+    }
+    Ok(())
+}
+
+/// LP balances and token infos moved onto snapshot maps with the same primary keys, so existing balances are read as is.
+/// Changes are recorded from the migration onwards, so snapshots are only available from the migration height.
+fn migrate_lp_balances_to_snapshots(storage: &mut dyn Storage, env: &Env) -> StdResult<()> {
+    LP_SNAPSHOTS_START_HEIGHT.save(storage, &env.block.height)
+}
+
+/// Version 0.3.0 snapshots LP balances and counts the LP tokens of each vault held by other vaults.
+fn migrate_to_0_3_0(storage: &mut dyn Storage, env: &Env) -> StdResult<()> {
+    migrate_lp_balances_to_snapshots(storage, env)?;
+    count_vault_lp_held_by_vaults(storage)
+}
+
+/// Counts, per issuing vault, the LP tokens held by other vaults from `VAULT_LP_HOLDINGS` into `VAULT_LP_HELD_BY_VAULTS`.
+fn count_vault_lp_held_by_vaults(storage: &mut dyn Storage) -> StdResult<()> {
+    let holdings = VAULT_LP_HOLDINGS
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<((u32, u32), Uint128)>>>()?;
+    for ((_, issuer), amount) in holdings {
+        VAULT_LP_HELD_BY_VAULTS.update(storage, issuer, |held| -> StdResult<_> {
+            Ok(held.unwrap_or_default() + amount)
+        })?;
+    }
+    Ok(())
+}
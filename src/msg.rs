@@ -1,15 +1,46 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Addr, CustomQuery, SignedDecimal, SignedDecimal256, Uint128};
+use cosmwasm_std::{
+    Addr, Binary, Coin, CustomQuery, Decimal, Int128, SignedDecimal, SignedDecimal256, Timestamp,
+    Uint128,
+};
 
 use crate::{
-    dydx::{proto_structs::Subaccount, query::LiquidityTiersResponse},
-    execute::market_make::NewOrder,
+    dydx::{
+        msg::{DydxMsgEncoding, OrderBatch},
+        proto_structs::Subaccount,
+        query::{
+            AllClobPairsResponse, AllPerpetualsResponse, AssetResponse,
+            BlockRateLimitConfigurationResponse, LiquidityTiersResponse, MarketParamResponse,
+            PerpetualFeeParamsResponse,
+        },
+    },
+    execute::{
+        market_make::NewOrder,
+        permissioned_keys::{PermissionedKey, PermissionedMessageType},
+    },
+    state::{
+        Collateral, InstantWithdrawalConfig, RewardStream, SharePriceCheckpoint, Vault,
+        VaultConfig, WithdrawalRateLimit,
+    },
 };
 
 #[cw_serde]
 #[serde(rename_all = "snake_case")]
 pub struct InstantiateMsg {
     pub owner: String,
+    /// Defaults to custom bindings.
+    pub msg_encoding: Option<DydxMsgEncoding>,
+    /// Validated against the dYdX asset with the same asset id.
+    pub collateral: Collateral,
+}
+
+/// Optional overrides applied after the state migrations have run.
+#[cw_serde]
+#[derive(Default)]
+pub struct MigrateMsg {
+    pub new_admin: Option<String>,
+    pub new_trader: Option<String>,
+    pub msg_encoding: Option<DydxMsgEncoding>,
 }
 
 #[cw_serde]
@@ -17,18 +48,106 @@ pub struct InstantiateMsg {
 pub enum QueryMsg {
     #[returns(TraderResponse)]
     Trader,
+    /// The collateral that vaults accept and are valued in.
+    #[returns(CollateralResponse)]
+    Collateral,
     #[returns(LpTokenBalanceResponse)]
-    UserLpTokens { perp_id: u32, user: String },
+    UserLpTokens { vault_id: u32, user: String },
+    /// A user's LP token balance at the start of block `height`.
+    #[returns(BalanceAtHeightResponse)]
+    BalanceAtHeight {
+        vault_id: u32,
+        user: String,
+        height: u64,
+    },
+    /// The LP token supply of a vault at the start of block `height`.
+    #[returns(TotalSupplyAtHeightResponse)]
+    TotalSupplyAtHeight { vault_id: u32, height: u64 },
     #[returns(VaultsResponse)]
     Vaults,
+    #[returns(VaultResponse)]
+    Vault { vault_id: u32 },
     #[returns(VaultOwnershipResponse)]
-    VaultOwnership { perp_id: u32, depositor: String },
+    VaultOwnership { vault_id: u32, depositor: String },
+    /// The value of a vault split into its components, with its share price and leverage.
+    #[returns(VaultSummaryResponse)]
+    VaultSummary { vault_id: u32 },
+    /// Paginates over the summaries of all vaults. `start_after` is a vault id.
+    #[returns(AllVaultSummariesResponse)]
+    AllVaultSummaries {
+        start_after: Option<u32>,
+        limit: Option<u32>,
+    },
+    /// The LP tokens that a deposit of `amount` collateral quantums would mint.
+    #[returns(SimulateDepositResponse)]
+    SimulateDeposit { vault_id: u32, amount: Uint128 },
+    /// The LP tokens that a withdrawal request of `user` would lock and their current USDC value.
+    #[returns(SimulateWithdrawalResponse)]
+    SimulateWithdrawal {
+        vault_id: u32,
+        user: String,
+        amount: WithdrawalAmount,
+    },
+    /// The LP tokens, queued withdrawals and lockups of a user in every vault they hold, with their USDC values.
+    #[returns(UserPortfolioResponse)]
+    UserPortfolio { user: String },
+    /// Paginates over the share price checkpoints of a vault, oldest first. `start_after` is a timestamp in seconds.
+    #[returns(SharePriceHistoryResponse)]
+    SharePriceHistory {
+        vault_id: u32,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// The annualized return of a vault over the last `window_seconds`, computed from its share price checkpoints.
+    #[returns(VaultAprResponse)]
+    VaultApr { vault_id: u32, window_seconds: u64 },
+    /// The cost basis and realized and unrealized PnL of a user's LP tokens in a vault.
+    #[returns(UserPnlResponse)]
+    UserPnl { vault_id: u32, user: String },
+    /// The deposits attributed to a referrer across all vaults and their share of the performance fees.
+    #[returns(ReferralStatsResponse)]
+    ReferralStats { referrer: String },
+    /// The reward streams funded for the LP holders of a vault.
+    #[returns(RewardStreamsResponse)]
+    RewardStreams { vault_id: u32 },
+    /// The rewards a user could claim from a vault now.
+    #[returns(PendingRewardsResponse)]
+    PendingRewards { vault_id: u32, user: String },
+    /// Paginates over the withdrawal queue of a vault. `start_after` is a queue position.
     #[returns(WithdrawalsResponse)]
-    Withdrawals { perp_id: u32 },
+    Withdrawals {
+        vault_id: u32,
+        start_after: Option<u32>,
+        limit: Option<u32>,
+    },
+    /// Returns the withdrawal requests owned by a user, either in one vault or across all vaults.
+    #[returns(WithdrawalsResponse)]
+    UserWithdrawals { user: String, vault_id: Option<u32> },
     #[returns(DydxSubaccountResponse)]
     DydxSubaccount { owner: String, number: u32 },
     #[returns(LiquidityTiersResponse)]
     LiquidityTiers,
+    #[returns(AssetResponse)]
+    Asset { id: u32 },
+    #[returns(MarketParamResponse)]
+    MarketParam { id: u32 },
+    #[returns(AllPerpetualsResponse)]
+    AllPerpetuals,
+    #[returns(AllClobPairsResponse)]
+    AllClobPairs,
+    #[returns(PerpetualFeeParamsResponse)]
+    PerpetualFeeParams,
+    #[returns(BlockRateLimitConfigurationResponse)]
+    BlockRateLimitConfiguration,
+    #[returns(VaultConfigResponse)]
+    VaultConfig { vault_id: u32 },
+    #[returns(UnlockScheduleResponse)]
+    UnlockSchedule { vault_id: u32, user: String },
+    #[returns(WithdrawalCapacityResponse)]
+    WithdrawalCapacity { vault_id: u32 },
+    /// Lists the permissioned keys that can trade the subaccount of a vault.
+    #[returns(PermissionedKeysResponse)]
+    PermissionedKeys { vault_id: u32 },
 }
 
 impl CustomQuery for QueryMsg {}
@@ -39,35 +158,106 @@ pub enum ExecuteMsg {
         new_trader: String,
     },
     CreateVault {
+        vault_id: u32,
         perp_id: u32,
+        /// Contract-owned subaccount the vault trades from. Defaults to `vault_id`.
+        subaccount_number: Option<u32>,
+    },
+    UpdateVaultConfig {
+        vault_id: u32,
+        lockup_seconds: Option<u64>,
+        instant_withdrawals: Option<InstantWithdrawalConfig>,
+        withdrawal_rate_limit: Option<WithdrawalRateLimit>,
+        share_price_interval_seconds: Option<u64>,
+    },
+    RebalanceVaults {
+        from_vault_id: u32,
+        to_vault_id: u32,
+        amount: u64,
     },
     ModifyVaultFee {
-        perp_id: u32,
+        vault_id: u32,
     },
     CollectFeesFromVault {
-        perp_id: u32,
+        vault_id: u32,
     },
     DepositIntoVault {
-        perp_id: u32,
+        vault_id: u32,
+        /// Partner that brought the depositor. Only recorded on the depositor's first deposit into the vault.
+        referrer: Option<String>,
     },
     RequestWithdrawal {
-        perp_id: u32,
+        vault_id: u32,
         usdc_amount: u64,
+        /// Address that receives the withdrawn USDC. Defaults to the sender.
+        recipient: Option<String>,
     },
     CancelWithdrawalRequests {
-        perp_id: u32,
+        vault_id: u32,
     },
     ProcessWithdrawals {
-        perp_id: u32,
+        vault_id: u32,
         max_num_withdrawals: u32,
     },
     MarketMake {
-        subaccount_number: u32,
+        vault_id: u32,
         clob_pair_id: u32,
         new_orders: Vec<NewOrder>,
         cancel_client_ids: Vec<u32>,
         cancel_good_til_block_time: u32,
     },
+    BatchCancel {
+        vault_id: u32,
+        order_batches: Vec<OrderBatch>,
+        good_til_block: u32,
+    },
+    /// Winds down a vault whose perp market is in final settlement. Can be called by anyone.
+    /// Pays out at most `limit` LP holders per call, so it must be called until the vault is `Settled`.
+    SettleVault {
+        vault_id: u32,
+        limit: Option<u32>,
+    },
+    /// Stops deposits and trading on a vault with a flat perp position and lets LP holders redeem pro-rata.
+    /// Cancels the given long-term orders and removes the vault's permissioned keys.
+    CloseVault {
+        vault_id: u32,
+        cancel_client_ids: Vec<u32>,
+        cancel_good_til_block_time: u32,
+    },
+    /// Removes the state of a closed or settled vault once its LP supply is zero.
+    /// Removes at most `limit` per-user entries per call, so it must be called until the vault is archived.
+    ArchiveVault {
+        vault_id: u32,
+        limit: Option<u32>,
+    },
+    AddPermissionedKey {
+        vault_id: u32,
+        /// Compressed secp256k1 public key of the signer.
+        public_key: Binary,
+        clob_pair_ids: Vec<u32>,
+        message_types: Vec<PermissionedMessageType>,
+    },
+    RemovePermissionedKey {
+        vault_id: u32,
+        authenticator_id: u64,
+    },
+    /// Streams the sent native coin to the LP holders of a vault between `start_time` and `end_time`, in unix seconds.
+    /// Can only be called by the admin.
+    FundRewards {
+        vault_id: u32,
+        start_time: u64,
+        end_time: u64,
+    },
+    /// Sends the sender the rewards accrued on their LP tokens of a vault.
+    ClaimRewards {
+        vault_id: u32,
+    },
+    /// Sets the share of the performance fees of referred deposits owed to a referrer, or clears it.
+    /// Can only be called by the admin.
+    SetReferralFeeShare {
+        referrer: String,
+        fee_share: Option<Decimal>,
+    },
 }
 
 #[cw_serde]
@@ -75,10 +265,20 @@ pub struct TraderResponse {
     pub trader: Addr,
 }
 
+#[cw_serde]
+pub struct CollateralResponse {
+    pub collateral: Collateral,
+}
+
 #[cw_serde]
 pub struct WithdrawalResponse {
+    pub vault_id: u32,
+    /// Position in the vault's withdrawal queue, starting at 0 for the next request to be processed.
+    pub position: u32,
+    pub owner: Addr,
     pub recipient_addr: Addr,
     pub lp_tokens: Uint128,
+    /// Estimated USDC that would be paid out if the request was processed now.
     pub usdc_equivalent: SignedDecimal256,
 }
 
@@ -89,8 +289,12 @@ pub struct WithdrawalsResponse {
 
 #[cw_serde]
 pub struct VaultsResponse {
-    /// perp ids
-    pub vaults: Vec<u32>,
+    pub vaults: Vec<Vault>,
+}
+
+#[cw_serde]
+pub struct VaultResponse {
+    pub vault: Vault,
 }
 
 #[cw_serde]
@@ -99,10 +303,199 @@ pub struct VaultOwnershipResponse {
     pub subaccount_number: u32,
     pub asset_usdc_value: SignedDecimal,
     pub perp_usdc_value: SignedDecimal,
+    /// Value of the LP tokens of other vaults held by this vault after rebalancing.
+    pub lp_holdings_usdc_value: SignedDecimal,
     pub depositor_lp_tokens: Uint128,
     pub outstanding_lp_tokens: Uint128,
 }
 
+#[cw_serde]
+pub struct VaultSummaryResponse {
+    pub vault: Vault,
+    /// Value that LP tokens are minted and redeemed at: collateral, perp value and LP holdings.
+    pub nav: SignedDecimal,
+    pub collateral_usdc_value: SignedDecimal,
+    /// Mark value of the perp position at the oracle price.
+    pub perp_usdc_value: SignedDecimal,
+    /// Value of the LP tokens of other vaults held by this vault after rebalancing.
+    pub lp_holdings_usdc_value: SignedDecimal,
+    /// Funding that dYdX has not settled into the collateral yet. Not included in the nav.
+    pub unrealized_funding: SignedDecimal,
+    /// Share of the nav owed to queued withdrawal requests.
+    pub pending_withdrawals_usdc_value: SignedDecimal,
+    pub pending_withdrawal_lp_tokens: Uint128,
+    pub lp_supply: Uint128,
+    /// USDC value of one LP token.
+    pub price_per_share: SignedDecimal,
+    /// Perp value over the margin of the leverage checks. Not set if the margin is not positive.
+    pub leverage: Option<Decimal>,
+    /// Size of the perp position in base units, negative for shorts.
+    pub open_interest: SignedDecimal,
+    /// Oracle price used to value the perp position.
+    pub oracle_price: SignedDecimal,
+}
+
+#[cw_serde]
+pub struct AllVaultSummariesResponse {
+    pub summaries: Vec<VaultSummaryResponse>,
+}
+
+/// A user's position in a single vault. USDC values are at the current vault value.
+#[cw_serde]
+pub struct UserVaultPosition {
+    pub vault_id: u32,
+    /// LP tokens held by the user, excluding LP tokens in queued withdrawals.
+    pub lp_tokens: Uint128,
+    /// Fraction of the vault's LP token supply held by the user.
+    pub share_of_vault: Decimal,
+    pub usdc_value: SignedDecimal,
+    pub queued_withdrawal_lp_tokens: Uint128,
+    pub queued_withdrawals_usdc_value: SignedDecimal,
+    pub locked_lp_tokens: Uint128,
+    pub unlocked_lp_tokens: Uint128,
+    /// When the next locked LP tokens unlock. Not set if no LP tokens are locked.
+    pub next_unlock_time: Option<Timestamp>,
+}
+
+#[cw_serde]
+pub struct UserPortfolioResponse {
+    pub user: Addr,
+    /// Vaults where the user holds LP tokens or has queued withdrawals, ordered by vault id.
+    pub positions: Vec<UserVaultPosition>,
+    pub total_usdc_value: SignedDecimal,
+    pub total_queued_withdrawals_usdc_value: SignedDecimal,
+}
+
+#[cw_serde]
+pub struct SharePriceHistoryResponse {
+    pub vault_id: u32,
+    pub checkpoints: Vec<SharePriceCheckpoint>,
+}
+
+/// The APR is not compounded and is based on a 365 day year.
+/// If the history is shorter than the window, it is computed from the oldest checkpoint.
+/// Not set if the vault has fewer than two checkpoints or the share price started non-positive.
+#[cw_serde]
+pub struct VaultAprResponse {
+    pub vault_id: u32,
+    pub window_seconds: u64,
+    pub start: Option<SharePriceCheckpoint>,
+    pub end: Option<SharePriceCheckpoint>,
+    pub apr: Option<SignedDecimal>,
+}
+
+/// Amounts are in collateral quantums. LP tokens include the LP tokens of withdrawal requests owned by the user.
+#[cw_serde]
+pub struct UserPnlResponse {
+    pub vault_id: u32,
+    pub user: Addr,
+    pub deposited_usdc: Uint128,
+    pub withdrawn_usdc: Uint128,
+    /// LP tokens with a recorded cost.
+    pub lp_tokens: Uint128,
+    /// LP tokens without a recorded cost, minted before cost bases were tracked.
+    pub untracked_lp_tokens: Uint128,
+    /// Average cost of `lp_tokens`.
+    pub cost_basis_usdc: Uint128,
+    /// Value of `lp_tokens` at the current vault value.
+    pub usdc_value: Uint128,
+    pub realized_pnl: Int128,
+    /// `usdc_value` minus `cost_basis_usdc`.
+    pub unrealized_pnl: Int128,
+}
+
+#[cw_serde]
+pub struct ReferralStatsResponse {
+    pub referrer: Addr,
+    pub referred_depositors: u32,
+    /// Collateral quantums deposited by the referred depositors.
+    pub deposit_volume: Uint128,
+    pub fee_share: Option<Decimal>,
+}
+
+#[cw_serde]
+pub struct RewardStreamResponse {
+    pub stream_id: u64,
+    pub stream: RewardStream,
+    /// Rewards released by the stream up to now.
+    pub released: Uint128,
+}
+
+#[cw_serde]
+pub struct RewardStreamsResponse {
+    pub vault_id: u32,
+    pub streams: Vec<RewardStreamResponse>,
+}
+
+#[cw_serde]
+pub struct PendingRewardsResponse {
+    pub vault_id: u32,
+    pub user: Addr,
+    pub rewards: Vec<Coin>,
+}
+
+#[cw_serde]
+pub enum WithdrawalAmount {
+    /// USDC quantums, as passed to `request_withdrawal`. 0 withdraws all unlocked LP tokens.
+    UsdcAmount(u64),
+    LpTokens(Uint128),
+}
+
+#[cw_serde]
+pub struct SimulateDepositResponse {
+    pub vault_id: u32,
+    pub usdc_amount: Uint128,
+    pub lp_tokens: Uint128,
+}
+
+#[cw_serde]
+pub struct SimulateWithdrawalResponse {
+    pub vault_id: u32,
+    pub lp_tokens: Uint128,
+    /// USDC quantums the LP tokens are worth at the current vault value.
+    /// Queued withdrawals are paid at the vault value when they are processed.
+    pub usdc_amount: Uint128,
+}
+
+#[cw_serde]
+pub struct VaultConfigResponse {
+    pub vault_id: u32,
+    pub config: VaultConfig,
+}
+
+#[cw_serde]
+pub struct LpTokenUnlock {
+    pub lp_tokens: Uint128,
+    pub unlock_time: Timestamp,
+}
+
+#[cw_serde]
+pub struct UnlockScheduleResponse {
+    pub vault_id: u32,
+    pub lockup_seconds: u64,
+    pub unlocked_lp_tokens: Uint128,
+    pub locked_lp_tokens: Uint128,
+    /// Locked LP tokens ordered by unlock time.
+    pub unlocks: Vec<LpTokenUnlock>,
+}
+
+/// Outflow amounts are in USDC quantums. A `None` capacity means the outflow is not limited.
+#[cw_serde]
+pub struct WithdrawalCapacityResponse {
+    pub vault_id: u32,
+    pub withdrawal_rate_limit: Option<WithdrawalRateLimit>,
+    pub max_outflow: Option<Uint128>,
+    pub processed_outflow: Uint128,
+    pub remaining_capacity: Option<Uint128>,
+    pub remaining_instant_withdrawal_capacity: Option<Uint128>,
+}
+
+#[cw_serde]
+pub struct PermissionedKeysResponse {
+    pub vault_id: u32,
+    pub keys: Vec<PermissionedKey>,
+}
+
 #[cw_serde]
 pub struct DydxSubaccountResponse {
     pub subaccount: Subaccount,
@@ -110,10 +503,25 @@ pub struct DydxSubaccountResponse {
 
 #[cw_serde]
 pub struct LpTokenBalanceResponse {
-    pub perp_id: u32,
+    pub vault_id: u32,
     pub balance: Uint128,
 }
 
+/// The LP tokens of queued withdrawals are held by the contract and not included in the user's balance.
+#[cw_serde]
+pub struct BalanceAtHeightResponse {
+    pub vault_id: u32,
+    pub height: u64,
+    pub balance: Uint128,
+}
+
+#[cw_serde]
+pub struct TotalSupplyAtHeightResponse {
+    pub vault_id: u32,
+    pub height: u64,
+    pub total_supply: Uint128,
+}
+
 #[cw_serde]
 pub struct TokenInfoResponse {
     pub name: String,
@@ -126,7 +534,7 @@ pub struct TokenInfoResponse {
 mod tests {
     use crate::{
         dydx::{
-            msg::{DydxMsg, OrderConditionType, OrderSide, OrderTimeInForce},
+            msg::{DydxMsg, OrderBatch, OrderConditionType, OrderSide, OrderTimeInForce},
             proto_structs::SubaccountId,
         },
         execute::market_make::NewOrder,
@@ -184,8 +592,9 @@ mod tests {
     #[test]
     fn example_serialize_request_withdrawal() {
         let msg = ExecuteMsg::RequestWithdrawal {
-            perp_id: 0,
+            vault_id: 0,
             usdc_amount: 100,
+            recipient: None,
         };
 
         let serialized_msg = serde_json::to_string(&msg).unwrap();
@@ -195,7 +604,7 @@ mod tests {
     #[test]
     fn example_serialize_process_withdrawals() {
         let msg = ExecuteMsg::ProcessWithdrawals {
-            perp_id: 0,
+            vault_id: 0,
             max_num_withdrawals: 1,
         };
 
@@ -206,7 +615,7 @@ mod tests {
     #[test]
     fn example_serialize_market_make_place_order() {
         let msg = ExecuteMsg::MarketMake {
-            subaccount_number: 0,
+            vault_id: 0,
             clob_pair_id: 0,
             new_orders: vec![NewOrder {
                 client_id: 101,
@@ -230,7 +639,7 @@ mod tests {
     #[test]
     fn example_serialize_market_make_cancel_order() {
         let msg = ExecuteMsg::MarketMake {
-            subaccount_number: 0,
+            vault_id: 0,
             clob_pair_id: 0,
             new_orders: vec![],
             cancel_client_ids: vec![101],
@@ -241,9 +650,27 @@ mod tests {
         println!("{}", serialized_msg);
     }
 
+    #[test]
+    fn example_serialize_batch_cancel() {
+        let msg = ExecuteMsg::BatchCancel {
+            vault_id: 0,
+            order_batches: vec![OrderBatch {
+                clob_pair_id: 0,
+                client_ids: vec![101, 102],
+            }],
+            good_til_block: 123,
+        };
+
+        let serialized_msg = serde_json::to_string(&msg).unwrap();
+        assert!(
+            serialized_msg
+                == r#"{"batch_cancel":{"vault_id":0,"order_batches":[{"clob_pair_id":0,"client_ids":[101,102]}],"good_til_block":123}}"#
+        );
+    }
+
     #[test]
     fn example_serialize_cancel_withdrawals() {
-        let msg = ExecuteMsg::CancelWithdrawalRequests { perp_id: 0 };
+        let msg = ExecuteMsg::CancelWithdrawalRequests { vault_id: 0 };
 
         let serialized_msg = serde_json::to_string(&msg).unwrap();
         println!("{}", serialized_msg);
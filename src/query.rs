@@ -1,18 +1,52 @@
 use crate::{
     dydx::{
         querier::DydxQuerier,
-        query::{DydxQueryWrapper, LiquidityTiersResponse, PerpetualClobDetailsResponse},
+        query::{
+            AllClobPairsResponse, AllPerpetualsResponse, AssetResponse,
+            BlockRateLimitConfigurationResponse, DydxQueryWrapper, LiquidityTiersResponse,
+            MarketParamResponse, PerpetualClobDetailsResponse, PerpetualFeeParamsResponse,
+        },
     },
     error::{ContractError, ContractResult},
-    execute::{USDC_DENOM, USDC_ID},
+    execute::{
+        deposit_withdraw::withdrawal_lp_tokens,
+        helpers::{
+            active_deposit_locks, current_instant_outflow_window, decimal_to_native_round_down,
+            load_vault, lp_tokens_for_deposit, lp_tokens_usdc_value, remaining_withdrawal_capacity,
+            signed_quantums, vault_margin, verify_lp_tokens_unlocked,
+            verify_vault_accepts_deposits,
+        },
+        permissioned_keys::PermissionedKey,
+        rewards::{accrued_user_reward, updated_rewards},
+    },
     msg::{
-        DydxSubaccountResponse, LpTokenBalanceResponse, TokenInfoResponse, TraderResponse,
-        VaultOwnershipResponse, VaultsResponse, WithdrawalResponse, WithdrawalsResponse,
+        AllVaultSummariesResponse, BalanceAtHeightResponse, CollateralResponse,
+        DydxSubaccountResponse, LpTokenBalanceResponse, LpTokenUnlock, PendingRewardsResponse,
+        PermissionedKeysResponse, ReferralStatsResponse, RewardStreamResponse,
+        RewardStreamsResponse, SharePriceHistoryResponse, SimulateDepositResponse,
+        SimulateWithdrawalResponse, TokenInfoResponse, TotalSupplyAtHeightResponse, TraderResponse,
+        UnlockScheduleResponse, UserPnlResponse, UserPortfolioResponse, UserVaultPosition,
+        VaultAprResponse, VaultConfigResponse, VaultOwnershipResponse, VaultResponse,
+        VaultSummaryResponse, VaultsResponse, WithdrawalAmount, WithdrawalCapacityResponse,
+        WithdrawalResponse, WithdrawalsResponse,
+    },
+    state::{
+        Vault, WithdrawalRequest, COST_BASES, LP_BALANCES, LP_SNAPSHOTS_START_HEIGHT, LP_TOKENS,
+        REFERRER_STATS, REWARD_STREAMS, SHARE_PRICE_CHECKPOINTS, STATE, USER_REWARDS, VAULTS,
+        VAULT_CONFIGS, VAULT_LP_HOLDINGS, WITHDRAWAL_QUEUES,
     },
-    state::{LP_BALANCES, LP_TOKENS, STATE, VAULTS_BY_PERP_ID, WITHDRAWAL_QUEUES},
 };
-use cosmwasm_std::{Deps, Int256, Order, SignedDecimal, SignedDecimal256, StdResult};
-use num_traits::{Signed, ToPrimitive};
+use cosmwasm_std::{
+    Coin, Decimal, Deps, Env, Int256, Order, SignedDecimal, SignedDecimal256, StdError, StdResult,
+    Uint128,
+};
+use cw_storage_plus::Bound;
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+/// dYdX funding indices are in parts per million.
+const FUNDING_INDEX_DECIMALS: u32 = 6;
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
 
 pub fn perp_clob_details(
     deps: Deps<DydxQueryWrapper>,
@@ -27,6 +61,41 @@ pub fn liquidity_tiers(deps: Deps<DydxQueryWrapper>) -> StdResult<LiquidityTiers
     querier.query_liquidity_tiers()
 }
 
+pub fn asset(deps: Deps<DydxQueryWrapper>, asset_id: u32) -> StdResult<AssetResponse> {
+    let querier = DydxQuerier::new(&deps.querier);
+    querier.query_asset(asset_id)
+}
+
+pub fn market_param(
+    deps: Deps<DydxQueryWrapper>,
+    market_id: u32,
+) -> StdResult<MarketParamResponse> {
+    let querier = DydxQuerier::new(&deps.querier);
+    querier.query_market_param(market_id)
+}
+
+pub fn all_perpetuals(deps: Deps<DydxQueryWrapper>) -> StdResult<AllPerpetualsResponse> {
+    let querier = DydxQuerier::new(&deps.querier);
+    querier.query_all_perpetuals()
+}
+
+pub fn all_clob_pairs(deps: Deps<DydxQueryWrapper>) -> StdResult<AllClobPairsResponse> {
+    let querier = DydxQuerier::new(&deps.querier);
+    querier.query_all_clob_pairs()
+}
+
+pub fn perpetual_fee_params(deps: Deps<DydxQueryWrapper>) -> StdResult<PerpetualFeeParamsResponse> {
+    let querier = DydxQuerier::new(&deps.querier);
+    querier.query_perpetual_fee_params()
+}
+
+pub fn block_rate_limit_config(
+    deps: Deps<DydxQueryWrapper>,
+) -> StdResult<BlockRateLimitConfigurationResponse> {
+    let querier = DydxQuerier::new(&deps.querier);
+    querier.query_block_rate_limit_config()
+}
+
 pub fn trader(deps: Deps<DydxQueryWrapper>) -> StdResult<TraderResponse> {
     let state = STATE.load(deps.storage)?;
     Ok(TraderResponse {
@@ -34,63 +103,725 @@ pub fn trader(deps: Deps<DydxQueryWrapper>) -> StdResult<TraderResponse> {
     })
 }
 
-pub fn withdrawals(deps: Deps<DydxQueryWrapper>, perp_id: u32) -> StdResult<WithdrawalsResponse> {
-    let q = WITHDRAWAL_QUEUES
-        .may_load(deps.storage, perp_id)?
-        .ok_or(ContractError::MissingWithdrawalQueue { perp_id })
-        .unwrap();
-    let pos = query_dydx_position(deps, perp_id).unwrap();
-    let subaccount_value = pos.asset_usdc_value + pos.perp_usdc_value;
+pub fn collateral(deps: Deps<DydxQueryWrapper>) -> StdResult<CollateralResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(CollateralResponse {
+        collateral: state.collateral,
+    })
+}
 
-    let lp_token_info = lp_token_info(deps, perp_id)?;
-    let outstanding_lp_tokens = Int256::try_from(lp_token_info.total_supply).unwrap();
+/// Queries a page of the withdrawal queue for the vault with the provided `vault_id`.
+pub fn withdrawals(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    start_after: Option<u32>,
+    limit: Option<u32>,
+) -> StdResult<WithdrawalsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|p| p as usize + 1).unwrap_or_default();
 
-    let withdrawals: Vec<WithdrawalResponse> = q
+    let queue = load_withdrawal_queue(deps, vault_id)?;
+    let page: Vec<(usize, WithdrawalRequest)> = queue
         .into_iter()
-        .map(|w| {
-            let lp_fraction = SignedDecimal256::from_ratio(w.lp_tokens, outstanding_lp_tokens);
+        .enumerate()
+        .skip(start)
+        .take(limit)
+        .collect();
 
-            WithdrawalResponse {
-                recipient_addr: w.recipient_addr,
-                lp_tokens: w.lp_tokens,
-                usdc_equivalent: SignedDecimal256::from(subaccount_value) * lp_fraction,
+    Ok(WithdrawalsResponse {
+        withdrawal_queue: withdrawal_responses(deps, vault_id, page)?,
+    })
+}
+
+/// Queries the withdrawal requests owned by `user`, in the vault with the provided `vault_id` or in all vaults.
+pub fn user_withdrawals(
+    deps: Deps<DydxQueryWrapper>,
+    user: String,
+    vault_id: Option<u32>,
+) -> StdResult<WithdrawalsResponse> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let vault_ids = match vault_id {
+        Some(vault_id) => vec![vault_id],
+        None => VAULTS
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<u32>>>()?,
+    };
+
+    let mut withdrawal_queue = vec![];
+    for vault_id in vault_ids {
+        let requests: Vec<(usize, WithdrawalRequest)> = load_withdrawal_queue(deps, vault_id)?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, w)| w.owner() == user_addr)
+            .collect();
+        if !requests.is_empty() {
+            withdrawal_queue.extend(withdrawal_responses(deps, vault_id, requests)?);
+        }
+    }
+    Ok(WithdrawalsResponse { withdrawal_queue })
+}
+
+/// Queries a user's position in every vault where they hold LP tokens or have queued withdrawals.
+/// `LP_BALANCES` is keyed by vault first, so the user's balance is loaded from each vault.
+pub fn user_portfolio(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    user: String,
+) -> StdResult<UserPortfolioResponse> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let vault_ids = VAULTS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<u32>>>()?;
+    let to_signed = |value: Decimal| {
+        SignedDecimal::try_from(value).map_err(|e| StdError::generic_err(e.to_string()))
+    };
+
+    let mut positions = vec![];
+    for vault_id in vault_ids {
+        let lp_tokens = LP_BALANCES
+            .may_load(deps.storage, (vault_id, &user_addr))?
+            .unwrap_or_default();
+        let queued_withdrawal_lp_tokens: Uint128 = load_withdrawal_queue(deps, vault_id)?
+            .iter()
+            .filter(|w| w.owner() == user_addr)
+            .map(|w| w.lp_tokens)
+            .sum();
+        if lp_tokens.is_zero() && queued_withdrawal_lp_tokens.is_zero() {
+            continue;
+        }
+
+        let pos = query_dydx_position(deps, vault_id)?;
+        let nav = vault_usdc_value(deps, vault_id, &pos)?;
+        let outstanding_lp_tokens = lp_token_info(deps, vault_id)?.total_supply;
+        let share_of_vault = Decimal::from_ratio(lp_tokens, outstanding_lp_tokens);
+        let queued_fraction =
+            Decimal::from_ratio(queued_withdrawal_lp_tokens, outstanding_lp_tokens);
+
+        let (lockup_seconds, locks) =
+            active_deposit_locks(deps.storage, env.block.time, vault_id, &user_addr)?;
+        let locked_lp_tokens: Uint128 = locks.iter().map(|lock| lock.lp_tokens).sum();
+
+        positions.push(UserVaultPosition {
+            vault_id,
+            lp_tokens,
+            share_of_vault,
+            usdc_value: nav * to_signed(share_of_vault)?,
+            queued_withdrawal_lp_tokens,
+            queued_withdrawals_usdc_value: nav * to_signed(queued_fraction)?,
+            locked_lp_tokens,
+            unlocked_lp_tokens: lp_tokens.saturating_sub(locked_lp_tokens),
+            next_unlock_time: locks
+                .first()
+                .map(|lock| lock.deposited_at.plus_seconds(lockup_seconds)),
+        });
+    }
+
+    Ok(UserPortfolioResponse {
+        user: user_addr,
+        total_usdc_value: positions.iter().map(|p| p.usdc_value).sum(),
+        total_queued_withdrawals_usdc_value: positions
+            .iter()
+            .map(|p| p.queued_withdrawals_usdc_value)
+            .sum(),
+        positions,
+    })
+}
+
+/// Paginates over the share price checkpoints of a vault, oldest first. `start_after` is a timestamp in seconds.
+pub fn share_price_history(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<SharePriceHistoryResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let checkpoints = SHARE_PRICE_CHECKPOINTS
+        .prefix(vault_id)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, checkpoint)| checkpoint))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(SharePriceHistoryResponse {
+        vault_id,
+        checkpoints,
+    })
+}
+
+/// Queries the annualized return of a vault from its share price checkpoints.
+/// The window ends at the latest checkpoint and starts at the latest checkpoint at least `window_seconds` before it.
+pub fn vault_apr(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    window_seconds: u64,
+) -> StdResult<VaultAprResponse> {
+    if window_seconds == 0 {
+        return Err(ContractError::InvalidAprWindow {}.into());
+    }
+    load_vault(deps.storage, vault_id)?;
+
+    let checkpoints = SHARE_PRICE_CHECKPOINTS.prefix(vault_id);
+    let end = checkpoints
+        .range(deps.storage, None, None, Order::Descending)
+        .next()
+        .transpose()?
+        .map(|(_, checkpoint)| checkpoint);
+    let start = match &end {
+        Some(end) => {
+            let window_start = end.time.seconds().saturating_sub(window_seconds);
+            let before_window = checkpoints
+                .range(
+                    deps.storage,
+                    None,
+                    Some(Bound::inclusive(window_start)),
+                    Order::Descending,
+                )
+                .next()
+                .transpose()?;
+            // fall back to the oldest checkpoint if the history is shorter than the window
+            match before_window {
+                Some(checkpoint) => Some(checkpoint),
+                None => checkpoints
+                    .range(deps.storage, None, None, Order::Ascending)
+                    .next()
+                    .transpose()?,
             }
+            .map(|(_, checkpoint)| checkpoint)
+        }
+        None => None,
+    };
+
+    let apr = match (&start, &end) {
+        (Some(start), Some(end))
+            if end.time > start.time && start.share_price > SignedDecimal::zero() =>
+        {
+            let elapsed_seconds = end.time.seconds() - start.time.seconds();
+            let period_return = end.share_price / start.share_price - SignedDecimal::one();
+            Some(
+                period_return
+                    .checked_mul(SignedDecimal::from_ratio(SECONDS_PER_YEAR, elapsed_seconds))
+                    .map_err(|e| StdError::generic_err(e.to_string()))?,
+            )
+        }
+        _ => None,
+    };
+
+    Ok(VaultAprResponse {
+        vault_id,
+        window_seconds,
+        start,
+        end,
+        apr,
+    })
+}
+
+/// Queries the cost basis of a user's LP tokens in a vault and the PnL on them.
+pub fn user_pnl(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    user: String,
+) -> StdResult<UserPnlResponse> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let cost_basis = COST_BASES
+        .may_load(deps.storage, (vault_id, &user_addr))?
+        .unwrap_or_default();
+    let balance = LP_BALANCES
+        .may_load(deps.storage, (vault_id, &user_addr))?
+        .unwrap_or_default();
+    let queued_lp_tokens: Uint128 = load_withdrawal_queue(deps, vault_id)?
+        .iter()
+        .filter(|w| w.owner() == user_addr)
+        .map(|w| w.lp_tokens)
+        .sum();
+
+    let usdc_value = if cost_basis.lp_tokens.is_zero() {
+        Uint128::zero()
+    } else {
+        let pos = query_dydx_position(deps, vault_id)?;
+        let nav = vault_usdc_value(deps, vault_id, &pos)?;
+        let outstanding_lp_tokens = lp_token_info(deps, vault_id)?.total_supply;
+        let lp_fraction = Decimal::from_ratio(cost_basis.lp_tokens, outstanding_lp_tokens);
+        if nav > SignedDecimal::zero() {
+            let collateral_decimals = STATE.load(deps.storage)?.collateral.decimals;
+            decimal_to_native_round_down(
+                nav.abs_diff(SignedDecimal::zero()) * lp_fraction,
+                collateral_decimals,
+            )
+            .map_err(|e| StdError::generic_err(e.to_string()))?
+        } else {
+            Uint128::zero()
+        }
+    };
+
+    Ok(UserPnlResponse {
+        vault_id,
+        user: user_addr,
+        deposited_usdc: cost_basis.deposited_usdc,
+        withdrawn_usdc: cost_basis.withdrawn_usdc,
+        lp_tokens: cost_basis.lp_tokens,
+        untracked_lp_tokens: (balance + queued_lp_tokens).saturating_sub(cost_basis.lp_tokens),
+        cost_basis_usdc: cost_basis.cost_usdc,
+        usdc_value,
+        realized_pnl: cost_basis.realized_pnl,
+        unrealized_pnl: signed_quantums(usdc_value) - signed_quantums(cost_basis.cost_usdc),
+    })
+}
+
+/// Queries the deposits attributed to a referrer and their share of the performance fees.
+pub fn referral_stats(
+    deps: Deps<DydxQueryWrapper>,
+    referrer: String,
+) -> StdResult<ReferralStatsResponse> {
+    let referrer_addr = deps.api.addr_validate(&referrer)?;
+    let stats = REFERRER_STATS
+        .may_load(deps.storage, &referrer_addr)?
+        .unwrap_or_default();
+    Ok(ReferralStatsResponse {
+        referrer: referrer_addr,
+        referred_depositors: stats.referred_depositors,
+        deposit_volume: stats.deposit_volume,
+        fee_share: stats.fee_share,
+    })
+}
+
+/// Queries the reward streams of a vault, with the rewards each has released so far.
+pub fn reward_streams(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    vault_id: u32,
+) -> StdResult<RewardStreamsResponse> {
+    let streams = REWARD_STREAMS
+        .prefix(vault_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            item.map(|(stream_id, stream)| RewardStreamResponse {
+                stream_id,
+                released: stream.released_at(env.block.time),
+                stream,
+            })
         })
-        .collect();
-    Ok(WithdrawalsResponse {
-        withdrawal_queue: withdrawals,
+        .collect::<StdResult<_>>()?;
+    Ok(RewardStreamsResponse { vault_id, streams })
+}
+
+/// Queries the rewards a user has accrued on their LP tokens of a vault, including rewards released since the last LP balance change.
+pub fn pending_rewards(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    vault_id: u32,
+    user: String,
+) -> StdResult<PendingRewardsResponse> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let lp_tokens = if user_addr == STATE.load(deps.storage)?.contract {
+        Uint128::zero()
+    } else {
+        LP_BALANCES
+            .may_load(deps.storage, (vault_id, &user_addr))?
+            .unwrap_or_default()
+    };
+
+    let (rewards_per_token, _) = updated_rewards(deps.storage, env.block.time, vault_id)?;
+    let mut rewards: Vec<Coin> = vec![];
+    for (denom, reward_per_token) in rewards_per_token {
+        let user_reward = USER_REWARDS
+            .may_load(deps.storage, (vault_id, denom.as_str(), &user_addr))?
+            .unwrap_or_default();
+        let pending = accrued_user_reward(user_reward, reward_per_token, lp_tokens)?.pending;
+        if !pending.is_zero() {
+            rewards.push(Coin::new(pending.u128(), denom));
+        }
+    }
+
+    Ok(PendingRewardsResponse {
+        vault_id,
+        user: user_addr,
+        rewards,
     })
 }
 
-/// Queries all existing vaults and returns the `perp_id` of the underlying dYdX market.
+fn load_withdrawal_queue(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+) -> StdResult<Vec<WithdrawalRequest>> {
+    Ok(WITHDRAWAL_QUEUES
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingWithdrawalQueue { vault_id })?)
+}
+
+/// Values queued withdrawal requests at the current value of the vault's subaccount.
+fn withdrawal_responses(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    requests: Vec<(usize, WithdrawalRequest)>,
+) -> StdResult<Vec<WithdrawalResponse>> {
+    if requests.is_empty() {
+        return Ok(vec![]);
+    }
+    let pos = query_dydx_position(deps, vault_id)?;
+    let subaccount_value = SignedDecimal256::from(vault_usdc_value(deps, vault_id, &pos)?);
+    let outstanding_lp_tokens = Int256::from(lp_token_info(deps, vault_id)?.total_supply);
+
+    requests
+        .into_iter()
+        .map(|(position, w)| {
+            let lp_fraction =
+                SignedDecimal256::checked_from_ratio(w.lp_tokens, outstanding_lp_tokens)
+                    .map_err(|e| StdError::generic_err(e.to_string()))?;
+            Ok(WithdrawalResponse {
+                vault_id,
+                position: position as u32,
+                owner: w.owner().clone(),
+                recipient_addr: w.recipient_addr,
+                lp_tokens: w.lp_tokens,
+                usdc_equivalent: subaccount_value * lp_fraction,
+            })
+        })
+        .collect()
+}
+
+/// Queries all existing vaults.
 pub fn vaults(deps: Deps<DydxQueryWrapper>) -> StdResult<VaultsResponse> {
-    let vault = VAULTS_BY_PERP_ID.keys(deps.storage, None, None, Order::Ascending);
-    let vaults: Vec<u32> = vault.map(|i| i.unwrap()).collect();
+    let vaults = VAULTS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, vault)| vault))
+        .collect::<StdResult<Vec<Vault>>>()?;
     Ok(VaultsResponse { vaults })
 }
 
-/// Queries a depositor's share of the vault with the provided `perp_id`.
+/// Queries the market and subaccount of the vault with the provided `vault_id`.
+pub fn vault(deps: Deps<DydxQueryWrapper>, vault_id: u32) -> StdResult<VaultResponse> {
+    let vault = load_vault(deps.storage, vault_id)?;
+    Ok(VaultResponse { vault })
+}
+
+/// Queries a depositor's share of the vault with the provided `vault_id`.
 pub fn vault_ownership(
     deps: Deps<DydxQueryWrapper>,
-    perp_id: u32,
+    vault_id: u32,
     depositor: String,
 ) -> StdResult<VaultOwnershipResponse> {
     let state = STATE.load(deps.storage)?;
-    let pos = query_dydx_position(deps, perp_id).unwrap();
+    let vault = load_vault(deps.storage, vault_id)?;
+    let pos = query_dydx_position(deps, vault_id)?;
+    let lp_holdings_usdc_value = vault_lp_holdings_value(deps, vault_id)?;
 
-    let raw_depositor_balance = lp_balance(deps, perp_id, depositor)?;
-    let lp_token_info = lp_token_info(deps, perp_id)?;
+    let raw_depositor_balance = lp_balance(deps, vault_id, depositor)?;
+    let lp_token_info = lp_token_info(deps, vault_id)?;
 
     Ok(VaultOwnershipResponse {
         subaccount_owner: state.contract.to_string(),
-        subaccount_number: perp_id,
+        subaccount_number: vault.subaccount_number,
         asset_usdc_value: pos.asset_usdc_value,
         perp_usdc_value: pos.perp_usdc_value,
+        lp_holdings_usdc_value,
         depositor_lp_tokens: raw_depositor_balance.balance,
         outstanding_lp_tokens: lp_token_info.total_supply,
     })
 }
 
+/// Queries the value of a vault and its components, together with its share price and leverage.
+pub fn vault_summary(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+) -> StdResult<VaultSummaryResponse> {
+    let vault = load_vault(deps.storage, vault_id)?;
+    let pos = query_dydx_position(deps, vault_id)?;
+    let lp_holdings_usdc_value = vault_lp_holdings_value(deps, vault_id)?;
+    let nav = pos.asset_usdc_value + pos.perp_usdc_value + lp_holdings_usdc_value;
+    let lp_token_info = lp_token_info(deps, vault_id)?;
+    let to_signed = |value: Decimal| {
+        SignedDecimal::try_from(value).map_err(|e| StdError::generic_err(e.to_string()))
+    };
+
+    let pending_withdrawal_lp_tokens: Uint128 = load_withdrawal_queue(deps, vault_id)?
+        .iter()
+        .map(|w| w.lp_tokens)
+        .sum();
+    let (pending_withdrawals_usdc_value, price_per_share) = if lp_token_info.total_supply.is_zero()
+    {
+        // the first deposit mints one LP token per unit of collateral
+        (SignedDecimal::zero(), SignedDecimal::one())
+    } else {
+        let pending_fraction = to_signed(Decimal::from_ratio(
+            pending_withdrawal_lp_tokens,
+            lp_token_info.total_supply,
+        ))?;
+        let lp_supply =
+            Decimal::from_atomics(lp_token_info.total_supply, lp_token_info.decimals as u32)
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+        (nav * pending_fraction, nav / to_signed(lp_supply)?)
+    };
+
+    let margin = vault_margin(&vault, &pos);
+    let leverage = if margin > SignedDecimal::zero() {
+        Some(
+            pos.perp_usdc_value.abs_diff(SignedDecimal::zero())
+                / margin.abs_diff(SignedDecimal::zero()),
+        )
+    } else {
+        None
+    };
+
+    Ok(VaultSummaryResponse {
+        vault,
+        nav,
+        collateral_usdc_value: pos.asset_usdc_value,
+        perp_usdc_value: pos.perp_usdc_value,
+        lp_holdings_usdc_value,
+        unrealized_funding: pos.unsettled_funding,
+        pending_withdrawals_usdc_value,
+        pending_withdrawal_lp_tokens,
+        lp_supply: lp_token_info.total_supply,
+        price_per_share,
+        leverage,
+        open_interest: pos.perp_size,
+        oracle_price: pos.oracle_price,
+    })
+}
+
+/// Paginates over the summaries of all vaults. `start_after` is a vault id.
+pub fn all_vault_summaries(
+    deps: Deps<DydxQueryWrapper>,
+    start_after: Option<u32>,
+    limit: Option<u32>,
+) -> StdResult<AllVaultSummariesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let summaries = VAULTS
+        .keys(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|vault_id| vault_summary(deps, vault_id?))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(AllVaultSummariesResponse { summaries })
+}
+
+/// Simulates a deposit of `amount` collateral quantums with the same math as `deposit_into_vault`.
+pub fn simulate_deposit(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    amount: Uint128,
+) -> StdResult<SimulateDepositResponse> {
+    let state = STATE.load(deps.storage)?;
+    if amount.is_zero() {
+        return Err(ContractError::InvalidDepositAmount {
+            coin_type: state.collateral.denom,
+            amount: amount.u128(),
+        }
+        .into());
+    }
+    let vault = load_vault(deps.storage, vault_id)?;
+    verify_vault_accepts_deposits(&DydxQuerier::new(&deps.querier), &vault)?;
+
+    let pos = query_dydx_position(deps, vault_id)?;
+    let vault_value = vault_usdc_value(deps, vault_id, &pos)?.abs_diff(SignedDecimal::zero());
+    let lp_token_info = lp_token_info(deps, vault_id)?;
+    let outstanding_lp_tokens =
+        Decimal::from_atomics(lp_token_info.total_supply, lp_token_info.decimals as u32)
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
+    let lp_tokens = lp_tokens_for_deposit(
+        amount,
+        state.collateral.decimals,
+        vault_value,
+        outstanding_lp_tokens,
+        lp_token_info.decimals as u32,
+    );
+
+    Ok(SimulateDepositResponse {
+        vault_id,
+        usdc_amount: amount,
+        lp_tokens,
+    })
+}
+
+/// Simulates a withdrawal request of `user` with the same math as `request_withdrawal`.
+/// The withdrawal can also be given in LP tokens, which must be held and unlocked.
+pub fn simulate_withdrawal(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    vault_id: u32,
+    user: String,
+    amount: WithdrawalAmount,
+) -> StdResult<SimulateWithdrawalResponse> {
+    let state = STATE.load(deps.storage)?;
+    let user_addr = deps.api.addr_validate(&user)?;
+    load_vault(deps.storage, vault_id)?;
+
+    let lp_tokens = match amount {
+        WithdrawalAmount::UsdcAmount(usdc_amount) => {
+            withdrawal_lp_tokens(deps, &env, vault_id, &user_addr, usdc_amount)?
+        }
+        WithdrawalAmount::LpTokens(lp_tokens) => {
+            let balance = lp_balance(deps, vault_id, user)?.balance;
+            if lp_tokens > balance {
+                return Err(ContractError::InsufficientLpTokens {
+                    vault_id,
+                    requested: lp_tokens,
+                    balance,
+                }
+                .into());
+            }
+            verify_lp_tokens_unlocked(
+                deps.storage,
+                &env,
+                vault_id,
+                &user_addr,
+                balance,
+                lp_tokens,
+            )?;
+            lp_tokens
+        }
+    };
+
+    let pos = query_dydx_position(deps, vault_id)?;
+    let vault_value = vault_usdc_value(deps, vault_id, &pos)?.abs_diff(SignedDecimal::zero());
+    let lp_token_info = lp_token_info(deps, vault_id)?;
+    let usdc_amount = if lp_token_info.total_supply.is_zero() {
+        Uint128::zero()
+    } else {
+        let outstanding_lp_tokens =
+            Decimal::from_atomics(lp_token_info.total_supply, lp_token_info.decimals as u32)
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+        let usdc_value = lp_tokens_usdc_value(
+            lp_tokens,
+            lp_token_info.decimals as u32,
+            outstanding_lp_tokens,
+            vault_value,
+        );
+        decimal_to_native_round_down(usdc_value, state.collateral.decimals)
+            .map_err(|e| StdError::generic_err(e.to_string()))?
+    };
+
+    Ok(SimulateWithdrawalResponse {
+        vault_id,
+        lp_tokens,
+        usdc_amount,
+    })
+}
+
+/// Queries the config of the vault with the provided `vault_id`.
+pub fn vault_config(deps: Deps<DydxQueryWrapper>, vault_id: u32) -> StdResult<VaultConfigResponse> {
+    if !VAULTS.has(deps.storage, vault_id) {
+        return Err(ContractError::VaultNotInitialized { vault_id }.into());
+    }
+    let config = VAULT_CONFIGS
+        .may_load(deps.storage, vault_id)?
+        .unwrap_or_default();
+    Ok(VaultConfigResponse { vault_id, config })
+}
+
+/// Queries when a user's LP tokens for the specified perp market unlock.
+/// LP tokens are locked for the vault lockup period after each deposit.
+pub fn unlock_schedule(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    vault_id: u32,
+    user: String,
+) -> StdResult<UnlockScheduleResponse> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let balance = LP_BALANCES
+        .may_load(deps.storage, (vault_id, &user_addr))?
+        .unwrap_or_default();
+    let (lockup_seconds, locks) =
+        active_deposit_locks(deps.storage, env.block.time, vault_id, &user_addr)?;
+
+    let locked_lp_tokens: Uint128 = locks.iter().map(|lock| lock.lp_tokens).sum();
+    let unlocks = locks
+        .into_iter()
+        .map(|lock| LpTokenUnlock {
+            lp_tokens: lock.lp_tokens,
+            unlock_time: lock.deposited_at.plus_seconds(lockup_seconds),
+        })
+        .collect();
+
+    Ok(UnlockScheduleResponse {
+        vault_id,
+        lockup_seconds,
+        unlocked_lp_tokens: balance.saturating_sub(locked_lp_tokens),
+        locked_lp_tokens,
+        unlocks,
+    })
+}
+
+/// Queries how much USDC can still be withdrawn from the vault in the current rate limit windows.
+pub fn withdrawal_capacity(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    vault_id: u32,
+) -> StdResult<WithdrawalCapacityResponse> {
+    let config = vault_config(deps, vault_id)?.config;
+
+    let (max_outflow, processed_outflow, remaining_capacity) = match &config.withdrawal_rate_limit {
+        Some(rate_limit) => {
+            let pos = query_dydx_position(deps, vault_id)?;
+            let subaccount_value =
+                vault_usdc_value(deps, vault_id, &pos)?.abs_diff(SignedDecimal::zero());
+            let (max_outflow, processed_outflow, remaining_capacity) =
+                remaining_withdrawal_capacity(
+                    deps.storage,
+                    env.block.time,
+                    vault_id,
+                    rate_limit,
+                    subaccount_value,
+                    STATE.load(deps.storage)?.collateral.decimals,
+                )?;
+            (
+                Some(max_outflow),
+                processed_outflow,
+                Some(remaining_capacity),
+            )
+        }
+        None => (None, Uint128::zero(), None),
+    };
+
+    let remaining_instant_withdrawal_capacity = match &config.instant_withdrawals {
+        Some(instant_config) => {
+            let window = current_instant_outflow_window(
+                deps.storage,
+                env.block.time,
+                vault_id,
+                instant_config,
+            )?;
+            Some(
+                instant_config
+                    .max_outflow_per_window
+                    .saturating_sub(window.usdc_outflow),
+            )
+        }
+        None => None,
+    };
+
+    Ok(WithdrawalCapacityResponse {
+        vault_id,
+        withdrawal_rate_limit: config.withdrawal_rate_limit,
+        max_outflow,
+        processed_outflow,
+        remaining_capacity,
+        remaining_instant_withdrawal_capacity,
+    })
+}
+
+/// Queries the permissioned keys registered on the subaccount of a vault.
+pub fn permissioned_keys(
+    deps: Deps<DydxQueryWrapper>,
+    env: Env,
+    vault_id: u32,
+) -> StdResult<PermissionedKeysResponse> {
+    let vault = load_vault(deps.storage, vault_id)?;
+
+    let querier = DydxQuerier::new(&deps.querier);
+    let keys = querier
+        .query_authenticators(env.contract.address.to_string())?
+        .account_authenticators
+        .iter()
+        .filter_map(PermissionedKey::from_authenticator)
+        .filter(|key| key.subaccount_number == vault.subaccount_number)
+        .collect();
+
+    Ok(PermissionedKeysResponse { vault_id, keys })
+}
+
 pub fn dydx_subaccount(
     deps: Deps<DydxQueryWrapper>,
     owner: String,
@@ -104,23 +835,72 @@ pub fn dydx_subaccount(
 /// Queries an address' balance of the LP token for the specified perp market.
 pub fn lp_balance(
     deps: Deps<DydxQueryWrapper>,
-    perp_id: u32,
+    vault_id: u32,
     address: String,
 ) -> StdResult<LpTokenBalanceResponse> {
     let address = deps.api.addr_validate(&address)?;
     let balance = LP_BALANCES
-        .may_load(deps.storage, (perp_id, &address))?
+        .may_load(deps.storage, (vault_id, &address))?
+        .unwrap_or_default();
+    Ok(LpTokenBalanceResponse { vault_id, balance })
+}
+
+/// Queries an address' balance of the LP token of a vault at the start of block `height`.
+pub fn balance_at_height(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    user: String,
+    height: u64,
+) -> StdResult<BalanceAtHeightResponse> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    verify_lp_snapshot_available(deps, height)?;
+    let balance = LP_BALANCES
+        .may_load_at_height(deps.storage, (vault_id, &user_addr), height)?
         .unwrap_or_default();
-    Ok(LpTokenBalanceResponse { perp_id, balance })
+    Ok(BalanceAtHeightResponse {
+        vault_id,
+        height,
+        balance,
+    })
+}
+
+/// Queries the LP token supply of a vault at the start of block `height`. Zero if the vault did not exist yet.
+pub fn total_supply_at_height(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    height: u64,
+) -> StdResult<TotalSupplyAtHeightResponse> {
+    verify_lp_snapshot_available(deps, height)?;
+    let total_supply = LP_TOKENS
+        .may_load_at_height(deps.storage, vault_id, height)?
+        .map(|info| info.total_supply)
+        .unwrap_or_default();
+    Ok(TotalSupplyAtHeightResponse {
+        vault_id,
+        height,
+        total_supply,
+    })
+}
+
+/// LP balances are only snapshotted from instantiation or the migration that introduced snapshots.
+fn verify_lp_snapshot_available(deps: Deps<DydxQueryWrapper>, height: u64) -> StdResult<()> {
+    let start_height = LP_SNAPSHOTS_START_HEIGHT.load(deps.storage)?;
+    if height < start_height {
+        return Err(ContractError::LpSnapshotNotAvailable {
+            height,
+            start_height,
+        }
+        .into());
+    }
+    Ok(())
 }
 
 /// Queries the metadata of the LP token for the specified perp market.
 /// This includes the total token supply.
-pub fn lp_token_info(deps: Deps<DydxQueryWrapper>, perp_id: u32) -> StdResult<TokenInfoResponse> {
+pub fn lp_token_info(deps: Deps<DydxQueryWrapper>, vault_id: u32) -> StdResult<TokenInfoResponse> {
     let info = LP_TOKENS
-        .may_load(deps.storage, perp_id)?
-        .ok_or(ContractError::MissingLpToken { perp_id })
-        .unwrap();
+        .may_load(deps.storage, vault_id)?
+        .ok_or(ContractError::MissingLpToken { vault_id })?;
     let res = TokenInfoResponse {
         name: info.name,
         symbol: info.symbol,
@@ -135,6 +915,13 @@ pub fn lp_token_info(deps: Deps<DydxQueryWrapper>, perp_id: u32) -> StdResult<To
 pub struct DydxPosition {
     pub asset_usdc_value: SignedDecimal,
     pub perp_usdc_value: SignedDecimal,
+    /// Size of the perp position in base units, negative for shorts.
+    pub perp_size: SignedDecimal,
+    /// Oracle price that the perp position is valued at.
+    pub oracle_price: SignedDecimal,
+    /// Funding that dYdX has not settled into the asset position yet, negative if owed by the subaccount.
+    /// Not included in the values above.
+    pub unsettled_funding: SignedDecimal,
 }
 
 /// Queries dYdX for the asset and perp values of the subaccount.
@@ -142,61 +929,94 @@ pub struct DydxPosition {
 /// Assumes that the subaccount only holds USDC and a single perp position in the provided market.
 pub fn query_dydx_position(
     deps: Deps<DydxQueryWrapper>,
-    perp_id: u32,
+    vault_id: u32,
 ) -> ContractResult<DydxPosition> {
     let state = STATE.load(deps.storage)?;
+    let vault = load_vault(deps.storage, vault_id)?;
     let querier = DydxQuerier::new(&deps.querier);
 
     // query subaccount + price state from dYdX
-    let clob_resp = querier.query_perpetual_clob_details(perp_id)?;
-    let perp_params = clob_resp.perpetual_clob_details.perpetual.params;
+    let clob_resp = querier.query_perpetual_clob_details(vault.perp_id)?;
+    let perpetual = clob_resp.perpetual_clob_details.perpetual;
+    let perp_params = perpetual.params;
     let market_price_resp = querier.query_market_price(perp_params.market_id)?;
-    let subaccount_resp = querier.query_subaccount(state.contract.to_string(), perp_id)?;
+    let subaccount_resp =
+        querier.query_subaccount(state.contract.to_string(), vault.subaccount_number)?;
     let subaccount = subaccount_resp.subaccount;
 
     if market_price_resp.market_price.exponent > 0 {
         return Err(ContractError::InvalidPriceExponent {
             exponent: market_price_resp.market_price.exponent,
-            perp_id,
+            perp_id: vault.perp_id,
         });
     };
-    let price_exponent = (-1 * market_price_resp.market_price.exponent) as u32;
-    let price =
-        SignedDecimal::from_atomics(market_price_resp.market_price.price, price_exponent).unwrap();
+    let price_exponent = (-market_price_resp.market_price.exponent) as u32;
+    let price = SignedDecimal::from_atomics(market_price_resp.market_price.price, price_exponent)
+        .map_err(|e| StdError::generic_err(e.to_string()))?;
 
     let usdc_position = subaccount
         .asset_positions
         .iter()
-        .find(|p| p.asset_id == USDC_ID);
+        .find(|p| p.asset_id == state.collateral.asset_id);
     let asset_usdc_value = match usdc_position {
-        Some(p) => {
-            let quantums: i128 = p.quantums.to_big_int().to_i128().unwrap_or(0);
-            SignedDecimal::from_atomics(quantums, USDC_DENOM).unwrap()
-        }
+        Some(p) => p
+            .quantums
+            .to_signed_decimal(state.collateral.decimals)
+            .map_err(|e| StdError::generic_err(e.to_string()))?,
         None => SignedDecimal::zero(),
     };
 
     if perp_params.atomic_resolution > 0 {
         return Err(ContractError::InvalidPerpExponent {
             exponent: perp_params.atomic_resolution,
-            perp_id,
+            perp_id: vault.perp_id,
         });
     };
-    let perp_exponent = (-1 * perp_params.atomic_resolution) as u32;
+    let perp_exponent = (-perp_params.atomic_resolution) as u32;
+    // isolated subaccounts can only hold their own market, anything else means the vault cannot be valued
+    if vault.isolated
+        && subaccount
+            .perpetual_positions
+            .iter()
+            .any(|p| p.perpetual_id != vault.perp_id)
+    {
+        return Err(ContractError::VaultSubaccountHasMoreThanOnePerpPosition { vault_id });
+    }
     let perp_position = subaccount
         .perpetual_positions
         .iter()
-        .find(|p| p.perpetual_id == perp_id);
+        .find(|p| p.perpetual_id == vault.perp_id);
+    let (perp_size, unsettled_funding) = match perp_position {
+        Some(p) => {
+            let size = p
+                .quantums
+                .to_signed_decimal(perp_exponent)
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+            // dYdX funding indices are in parts per million of a quote quantum per base quantum
+            let funding = -((&perpetual.funding_index - &p.funding_index) * &p.quantums);
+            let funding = funding
+                .to_signed_decimal(FUNDING_INDEX_DECIMALS + state.collateral.decimals)
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+            (size, funding)
+        }
+        None => (SignedDecimal::zero(), SignedDecimal::zero()),
+    };
     let perp_usdc_value = match perp_position {
         Some(p) => {
-            let big_int = p.quantums.to_big_int();
-            let is_neg = big_int.is_negative();
-            let quantums: i128 = big_int.to_i128().unwrap_or(0);
-            let position = SignedDecimal::from_atomics(quantums, perp_exponent).unwrap();
-            if is_neg {
-                position * price * SignedDecimal::negative_one()
+            // value the position with 256 bit precision so that large positions error instead of truncating
+            let position = p
+                .quantums
+                .to_signed_decimal_256(perp_exponent)
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+            let value = position
+                .checked_mul(SignedDecimal256::from(price))
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+            let value =
+                SignedDecimal::try_from(value).map_err(|e| StdError::generic_err(e.to_string()))?;
+            if p.quantums.is_negative() {
+                value * SignedDecimal::negative_one()
             } else {
-                position * price
+                value
             }
         }
         None => SignedDecimal::zero(),
@@ -205,7 +1025,44 @@ pub fn query_dydx_position(
     let position = DydxPosition {
         asset_usdc_value,
         perp_usdc_value,
+        perp_size,
+        oracle_price: price,
+        unsettled_funding,
     };
 
     Ok(position)
 }
+
+/// Returns the USDC value of a vault: the value of its dYdX subaccount position
+/// plus the value of the LP tokens it holds in other vaults after rebalancing.
+pub fn vault_usdc_value(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+    pos: &DydxPosition,
+) -> ContractResult<SignedDecimal> {
+    Ok(pos.asset_usdc_value + pos.perp_usdc_value + vault_lp_holdings_value(deps, vault_id)?)
+}
+
+/// Returns the USDC value of the LP tokens that a vault holds in other vaults.
+/// Rebalancing guarantees that the issuing vaults do not hold LP tokens themselves,
+/// so they are valued by their dYdX subaccount alone.
+pub fn vault_lp_holdings_value(
+    deps: Deps<DydxQueryWrapper>,
+    vault_id: u32,
+) -> ContractResult<SignedDecimal> {
+    let holdings = VAULT_LP_HOLDINGS
+        .prefix(vault_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<(u32, Uint128)>>>()?;
+
+    let mut value = SignedDecimal::zero();
+    for (issuer_vault_id, lp_tokens) in holdings {
+        let pos = query_dydx_position(deps, issuer_vault_id)?;
+        let outstanding_lp_tokens = lp_token_info(deps, issuer_vault_id)?.total_supply;
+        let lp_fraction =
+            SignedDecimal::try_from(Decimal::from_ratio(lp_tokens, outstanding_lp_tokens))
+                .map_err(|e| StdError::generic_err(e.to_string()))?;
+        value += (pos.asset_usdc_value + pos.perp_usdc_value) * lp_fraction;
+    }
+    Ok(value)
+}
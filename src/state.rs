@@ -1,28 +1,312 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Decimal, Decimal256, Int128, SignedDecimal, Timestamp, Uint128};
 use cw20_base::state::TokenInfo;
-use cw_storage_plus::{Item, Map};
+use cw_storage_plus::{Item, Map, SnapshotMap, Strategy};
+
+use crate::dydx::msg::DydxMsgEncoding;
+
+/// The dYdX asset that users deposit and that vault values are denominated in.
+#[cw_serde]
+pub struct Collateral {
+    /// Bank denom of the collateral coin.
+    pub denom: String,
+    /// dYdX asset id of the collateral.
+    pub asset_id: u32,
+    /// Decimals of the collateral. One base unit of the denom must equal one dYdX quantum of the asset.
+    pub decimals: u32,
+}
+
+impl Collateral {
+    /// Mainnet USDC, the collateral of contracts instantiated before the collateral was configurable.
+    pub fn mainnet_usdc() -> Self {
+        Collateral {
+            denom: "ibc/8E27BA2D5493AF5636760E354E46004562C46AB7EC0CC4C1CA14E9E20E2545B5"
+                .to_string(),
+            asset_id: 0,
+            decimals: 6,
+        }
+    }
+}
+
+/// A vault trades a single dYdX perp market from its own contract-owned subaccount.
+/// A market can have several vaults, e.g. with different strategies or risk profiles.
+#[cw_serde]
+pub struct Vault {
+    pub vault_id: u32,
+    pub perp_id: u32,
+    pub subaccount_number: u32,
+    /// Whether the perp market is isolated. Isolated vaults trade from a child subaccount that only holds their market.
+    #[serde(default)]
+    pub isolated: bool,
+    #[serde(default)]
+    pub status: VaultStatus,
+}
+
+/// Where a vault is in its lifecycle. Only active vaults accept deposits and trade.
+#[cw_serde]
+#[derive(Default)]
+pub enum VaultStatus {
+    #[default]
+    Active,
+    /// The perp market is in final settlement. The vault waits for dYdX to close its position before paying out its LP holders.
+    Settling,
+    /// All LP holders were paid out after final settlement.
+    Settled,
+    /// Closed by the trader with a flat position. LP holders redeem their tokens pro-rata until the vault can be archived.
+    Closed,
+}
 
 #[cw_serde]
 pub struct State {
     pub admin: Addr,
     pub trader: Addr,
     pub contract: Addr,
+    #[serde(default)]
+    pub msg_encoding: DydxMsgEncoding,
+    #[serde(default = "Collateral::mainnet_usdc")]
+    pub collateral: Collateral,
+}
+
+/// Per-vault parameters that can be tuned by the trader after the vault is created.
+#[cw_serde]
+pub struct VaultConfig {
+    /// Minimum number of seconds LP tokens must be held after a deposit before they can be withdrawn.
+    #[serde(default)]
+    pub lockup_seconds: u64,
+    /// Allows small withdrawals to skip the withdrawal queue. Disabled if not set.
+    #[serde(default)]
+    pub instant_withdrawals: Option<InstantWithdrawalConfig>,
+    /// Limits how much `process_withdrawals` can pay out. Unlimited if not set.
+    #[serde(default)]
+    pub withdrawal_rate_limit: Option<WithdrawalRateLimit>,
+    /// Minimum number of seconds between two share price checkpoints of the vault.
+    #[serde(default = "VaultConfig::default_share_price_interval_seconds")]
+    pub share_price_interval_seconds: u64,
+}
+
+impl VaultConfig {
+    /// Share prices are checkpointed at most hourly unless configured otherwise.
+    pub fn default_share_price_interval_seconds() -> u64 {
+        3_600
+    }
+}
+
+impl Default for VaultConfig {
+    fn default() -> Self {
+        VaultConfig {
+            lockup_seconds: 0,
+            instant_withdrawals: None,
+            withdrawal_rate_limit: None,
+            share_price_interval_seconds: VaultConfig::default_share_price_interval_seconds(),
+        }
+    }
+}
+
+/// The largest withdrawal that can be settled instantly.
+#[cw_serde]
+pub enum InstantWithdrawalBuffer {
+    /// A fraction of the vault's USDC value.
+    NavFraction(Decimal),
+    /// A fixed amount of USDC quantums.
+    Usdc(Uint128),
+}
+
+#[cw_serde]
+pub struct InstantWithdrawalConfig {
+    pub buffer: InstantWithdrawalBuffer,
+    /// Maximum USDC quantums that can be withdrawn instantly per window.
+    pub max_outflow_per_window: Uint128,
+    /// Length of a window in seconds. Windows are aligned to multiples of this value, e.g. 86400 for UTC days.
+    pub window_seconds: u64,
+}
+
+/// Limits the share of the vault value that processed withdrawals can pay out over a rolling window.
+#[cw_serde]
+pub struct WithdrawalRateLimit {
+    /// Maximum fraction of the vault value, measured at the start of the window, that can be paid out per window.
+    pub max_nav_fraction: Decimal,
+    /// Length of the rolling window in seconds.
+    pub window_seconds: u64,
+}
+
+/// USDC quantums paid out at a point in time.
+#[cw_serde]
+pub struct Outflow {
+    pub time: Timestamp,
+    pub usdc: Uint128,
+}
+
+/// USDC paid out during a window.
+#[cw_serde]
+pub struct OutflowWindow {
+    pub window_start: Timestamp,
+    pub usdc_outflow: Uint128,
+}
+
+/// The share price of a vault at a point in time, recorded to track the vault's performance.
+#[cw_serde]
+pub struct SharePriceCheckpoint {
+    pub time: Timestamp,
+    /// Vault value per LP token.
+    pub share_price: SignedDecimal,
+    pub nav: SignedDecimal,
+    pub lp_supply: Uint128,
+}
+
+/// Native coins funded by the admin and released linearly to the LP holders of a vault between `start_time` and `end_time`.
+#[cw_serde]
+pub struct RewardStream {
+    pub denom: String,
+    pub amount: Uint128,
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    /// Rewards released up to this time are added to the reward per token of the denom.
+    pub last_update: Timestamp,
+}
+
+impl RewardStream {
+    /// Returns the rewards released by the stream up to `time`.
+    pub fn released_at(&self, time: Timestamp) -> Uint128 {
+        if time <= self.start_time {
+            Uint128::zero()
+        } else if time >= self.end_time {
+            self.amount
+        } else {
+            self.amount.multiply_ratio(
+                time.seconds() - self.start_time.seconds(),
+                self.end_time.seconds() - self.start_time.seconds(),
+            )
+        }
+    }
+}
+
+/// The payout of a vault in final settlement, frozen on the first payout so every page is paid at the same rate.
+#[cw_serde]
+pub struct Settlement {
+    /// USDC quantums in the vault subaccount once the perp position was closed.
+    pub available_quantums: Uint128,
+    /// LP token supply once the perp position was closed.
+    pub total_supply: Uint128,
+    pub num_payouts: u32,
+}
+
+/// A user's rewards in a single denom.
+#[cw_serde]
+#[derive(Default)]
+pub struct UserReward {
+    /// The reward per token of the denom when the user's rewards were last accrued.
+    pub reward_per_token_paid: Decimal256,
+    /// Accrued rewards that were not claimed yet.
+    pub pending: Uint128,
+}
+
+/// LP tokens minted by a single deposit, used to enforce the vault lockup.
+#[cw_serde]
+pub struct DepositLock {
+    pub lp_tokens: Uint128,
+    pub deposited_at: Timestamp,
+}
+
+/// Deposits attributed to a referrer across all vaults.
+#[cw_serde]
+#[derive(Default)]
+pub struct ReferrerStats {
+    /// Number of depositors whose first deposit into a vault named the referrer.
+    pub referred_depositors: u32,
+    /// Collateral quantums deposited by the referred depositors, including their later deposits.
+    pub deposit_volume: Uint128,
+    /// Share of the performance fees of referred deposits owed to the referrer, set by the admin.
+    pub fee_share: Option<Decimal>,
+}
+
+/// Average-cost accounting of a user's LP tokens in a vault. Amounts are in collateral quantums.
+#[cw_serde]
+#[derive(Default)]
+pub struct CostBasis {
+    /// LP tokens with a recorded cost, including the LP tokens of withdrawal requests owned by the user.
+    pub lp_tokens: Uint128,
+    /// Collateral paid for `lp_tokens`.
+    pub cost_usdc: Uint128,
+    pub deposited_usdc: Uint128,
+    pub withdrawn_usdc: Uint128,
+    /// Withdrawn collateral minus the cost of the LP tokens that were burnt for it.
+    pub realized_pnl: Int128,
 }
 
 #[cw_serde]
 pub struct WithdrawalRequest {
     pub recipient_addr: Addr,
     pub lp_tokens: Uint128,
+    /// The address that requested the withdrawal and can cancel it.
+    /// Not set for requests queued before recipients could be specified, in which case the recipient is the owner.
+    #[serde(default)]
+    pub owner: Option<Addr>,
+}
+
+impl WithdrawalRequest {
+    pub fn owner(&self) -> &Addr {
+        self.owner.as_ref().unwrap_or(&self.recipient_addr)
+    }
 }
 
-/// A map of tracks LP tokens and their metadata Keyed by perp_id.
-pub const LP_TOKENS: Map<u32, TokenInfo> = Map::new("lp_tokens");
-/// A map that tracks user balances of LP tokens. Keyed by a tuple of (perp_id, Addr) and values are the raw LP token amount.
-pub const LP_BALANCES: Map<(u32, &Addr), Uint128> = Map::new("balance");
-/// A map that tracks the existence of perp markets.
-pub const VAULTS_BY_PERP_ID: Map<u32, bool> = Map::new("vaults_by_perp_id");
+/// A map of tracks LP tokens and their metadata Keyed by vault_id.
+/// Snapshotted on every change so the total supply can be queried at past heights.
+pub const LP_TOKENS: SnapshotMap<u32, TokenInfo> = SnapshotMap::new(
+    "lp_tokens",
+    "lp_tokens__checkpoints",
+    "lp_tokens__changelog",
+    Strategy::EveryBlock,
+);
+/// A map that tracks user balances of LP tokens. Keyed by a tuple of (vault_id, Addr) and values are the raw LP token amount.
+/// Snapshotted on every change so balances can be queried at past heights.
+pub const LP_BALANCES: SnapshotMap<(u32, &Addr), Uint128> = SnapshotMap::new(
+    "balance",
+    "balance__checkpoints",
+    "balance__changelog",
+    Strategy::EveryBlock,
+);
+/// The block height from which LP balance and supply snapshots are available.
+/// Set at instantiation, or at the migration that started snapshotting the LP balances.
+pub const LP_SNAPSHOTS_START_HEIGHT: Item<u64> = Item::new("lp_snapshots_start_height");
+/// A map of vaults keyed by vault_id.
+pub const VAULTS: Map<u32, Vault> = Map::new("vaults");
+/// Vaults created before vault ids existed. They were keyed by their perp_id and traded from the subaccount with the same number.
+/// Only read during migration.
+pub const LEGACY_VAULTS_BY_PERP_ID: Map<u32, bool> = Map::new("vaults_by_perp_id");
 /// A struct containing permissioned addresses for the smart contract.
 pub const STATE: Item<State> = Item::new("state");
 /// A map of withdrawals requests for each market. Withdrawal requests are a FIFO queue.
 pub const WITHDRAWAL_QUEUES: Map<u32, Vec<WithdrawalRequest>> = Map::new("withdrawal_queues");
+/// A map of vault configs keyed by vault_id.
+pub const VAULT_CONFIGS: Map<u32, VaultConfig> = Map::new("vault_configs");
+/// A map of deposits that may still be locked. Keyed by a tuple of (vault_id, Addr) and ordered from oldest to newest.
+pub const DEPOSIT_LOCKS: Map<(u32, &Addr), Vec<DepositLock>> = Map::new("deposit_locks");
+/// A map of instant withdrawal outflows in the current window. Keyed by vault_id.
+pub const INSTANT_WITHDRAWAL_OUTFLOWS: Map<u32, OutflowWindow> =
+    Map::new("instant_withdrawal_outflows");
+/// A map of withdrawals processed in the current rolling window, ordered from oldest to newest. Keyed by vault_id.
+pub const PROCESSED_WITHDRAWAL_OUTFLOWS: Map<u32, Vec<Outflow>> =
+    Map::new("processed_withdrawal_outflows");
+/// A map of LP tokens that a vault holds in other vaults after rebalancing. Keyed by (holder vault_id, issuer vault_id).
+/// These tokens count towards the issuer's LP token supply but have no entry in `LP_BALANCES`.
+pub const VAULT_LP_HOLDINGS: Map<(u32, u32), Uint128> = Map::new("vault_lp_holdings");
+/// A map of the LP tokens of a vault held by other vaults, the sum of its `VAULT_LP_HOLDINGS`. Keyed by issuer vault_id.
+pub const VAULT_LP_HELD_BY_VAULTS: Map<u32, Uint128> = Map::new("vault_lp_held_by_vaults");
+/// A map of the cost basis of users' LP tokens. Keyed by a tuple of (vault_id, Addr).
+/// LP tokens minted before cost bases were tracked have no recorded cost.
+pub const COST_BASES: Map<(u32, &Addr), CostBasis> = Map::new("cost_bases");
+/// A map of share price checkpoints. Keyed by a tuple of (vault_id, checkpoint time in seconds).
+pub const SHARE_PRICE_CHECKPOINTS: Map<(u32, u64), SharePriceCheckpoint> =
+    Map::new("share_price_checkpoints");
+/// A map of the payouts of vaults that are being settled. Keyed by vault_id.
+pub const SETTLEMENTS: Map<u32, Settlement> = Map::new("settlements");
+/// A map of reward streams. Keyed by a tuple of (vault_id, stream_id).
+pub const REWARD_STREAMS: Map<(u32, u64), RewardStream> = Map::new("reward_streams");
+/// A map of the rewards released per LP token of a vault by all its streams of a denom. Keyed by a tuple of (vault_id, denom).
+pub const REWARDS_PER_TOKEN: Map<(u32, &str), Decimal256> = Map::new("rewards_per_token");
+/// A map of users' rewards. Keyed by a tuple of (vault_id, denom, Addr).
+pub const USER_REWARDS: Map<(u32, &str, &Addr), UserReward> = Map::new("user_rewards");
+/// A map of the referrers of depositors. Keyed by a tuple of (vault_id, depositor).
+pub const REFERRALS: Map<(u32, &Addr), Addr> = Map::new("referrals");
+/// A map of referrers to the deposits attributed to them.
+pub const REFERRER_STATS: Map<&Addr, ReferrerStats> = Map::new("referrer_stats");
@@ -4,20 +4,26 @@ mod utils;
 mod tests {
     use crate::utils::{
         fetch_response_events, instantiate_contract_with_trader_and_vault, mint_native, test_setup,
-        TEST_CONTRACT_ADDR,
+        TEST_CONTRACT_ADDR, USDC_COIN_TYPE,
     };
-    use cosmwasm_std::{Coin, SignedDecimal, SignedDecimal256, Uint128};
-    use cw_multi_test::Executor;
+    use cosmwasm_std::{Coin, Decimal, Int128, SignedDecimal, SignedDecimal256, Uint128};
+    use cw_multi_test::{next_block, Executor};
     use elixir_dydx_integration::{
         dydx::{proto_structs::PerpetualPosition, serializable_int::SerializableInt},
         error::ContractError,
-        execute::USDC_COIN_TYPE,
         msg::{
-            DydxSubaccountResponse, ExecuteMsg, LpTokenBalanceResponse, QueryMsg,
-            VaultOwnershipResponse, WithdrawalsResponse,
+            AllVaultSummariesResponse, BalanceAtHeightResponse, DydxSubaccountResponse, ExecuteMsg,
+            LpTokenBalanceResponse, PendingRewardsResponse, QueryMsg, ReferralStatsResponse,
+            RewardStreamsResponse, SharePriceHistoryResponse, SimulateDepositResponse,
+            SimulateWithdrawalResponse, TotalSupplyAtHeightResponse, UnlockScheduleResponse,
+            UserPnlResponse, UserPortfolioResponse, VaultAprResponse, VaultOwnershipResponse,
+            VaultSummaryResponse, WithdrawalAmount, WithdrawalCapacityResponse,
+            WithdrawalsResponse,
         },
+        state::{InstantWithdrawalBuffer, InstantWithdrawalConfig, WithdrawalRateLimit},
     };
     use num_bigint::BigInt;
+    use std::str::FromStr;
 
     #[test]
     fn can_mint_coin() {
@@ -63,7 +69,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -86,7 +95,7 @@ mod tests {
         let subaccount_id = subaccount.id.unwrap();
 
         assert!(subaccount_id.number == 0);
-        assert!(subaccount_id.owner == TEST_CONTRACT_ADDR.to_string());
+        assert!(subaccount_id.owner == TEST_CONTRACT_ADDR);
         assert!(subaccount.asset_positions.len() == 1);
         assert!(subaccount.asset_positions[0].asset_id == 0);
         assert!(subaccount.asset_positions[0].quantums.i == deposit_amount.into());
@@ -96,12 +105,12 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::VaultOwnership {
-                    perp_id: 0,
+                    vault_id: 0,
                     depositor: user2.to_string(),
                 },
             )
             .unwrap();
-        assert!(vault_resp.subaccount_owner == TEST_CONTRACT_ADDR.to_string());
+        assert!(vault_resp.subaccount_owner == TEST_CONTRACT_ADDR);
         assert!(vault_resp.subaccount_number == 0);
         assert!(vault_resp.asset_usdc_value == SignedDecimal::one());
         assert!(vault_resp.perp_usdc_value == SignedDecimal::zero());
@@ -117,7 +126,7 @@ mod tests {
             deposit_events[0].attributes[1].value
                 == "cosmwasm1vqjarrly327529599rcc4qhzvhwe34pp5uyy4gylvxe5zupeqx3sg08lap"
         );
-        assert!(deposit_events[0].attributes[2].key == "perp_id");
+        assert!(deposit_events[0].attributes[2].key == "vault_id");
         assert!(deposit_events[0].attributes[2].value == "0");
         assert!(deposit_events[0].attributes[3].key == "usdc_amount");
         assert!(deposit_events[0].attributes[3].value == "1000000");
@@ -147,7 +156,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -184,7 +196,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: "ibc/1234".to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -221,7 +236,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: "ibc/1234".to_string(),
                     amount: Uint128::new(0),
@@ -258,7 +276,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -271,7 +292,7 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::UserLpTokens {
-                    perp_id: 0,
+                    vault_id: 0,
                     user: user2.to_string(),
                 },
             )
@@ -281,7 +302,7 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::UserLpTokens {
-                    perp_id: 0,
+                    vault_id: 0,
                     user: TEST_CONTRACT_ADDR.to_string(),
                 },
             )
@@ -294,8 +315,9 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::RequestWithdrawal {
-                    perp_id: 0,
+                    vault_id: 0,
                     usdc_amount: withdraw_amount as u64,
+                    recipient: None,
                 },
                 &[],
             )
@@ -303,7 +325,14 @@ mod tests {
 
         let q_resp: WithdrawalsResponse = app
             .wrap()
-            .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
             .unwrap();
 
         let user_lp_after: LpTokenBalanceResponse = app
@@ -311,7 +340,7 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::UserLpTokens {
-                    perp_id: 0,
+                    vault_id: 0,
                     user: user2.to_string(),
                 },
             )
@@ -321,7 +350,7 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::UserLpTokens {
-                    perp_id: 0,
+                    vault_id: 0,
                     user: TEST_CONTRACT_ADDR.to_string(),
                 },
             )
@@ -346,18 +375,20 @@ mod tests {
         );
         assert!(withdraw_events.len() == 1);
         assert!(withdraw_events[0].ty == "wasm-new_withdrawal_request");
-        assert!(withdraw_events[0].attributes.len() == 5);
+        assert!(withdraw_events[0].attributes.len() == 6);
         assert!(withdraw_events[0].attributes[1].key == "withdrawer");
         assert!(
             withdraw_events[0].attributes[1].value
                 == "cosmwasm1vqjarrly327529599rcc4qhzvhwe34pp5uyy4gylvxe5zupeqx3sg08lap"
         );
-        assert!(withdraw_events[0].attributes[2].key == "perp_id");
+        assert!(withdraw_events[0].attributes[2].key == "vault_id");
         assert!(withdraw_events[0].attributes[2].value == "0");
         assert!(withdraw_events[0].attributes[3].key == "usdc_amount");
         assert!(withdraw_events[0].attributes[3].value == "1000");
         assert!(withdraw_events[0].attributes[4].key == "transferred_lp_tokens");
         assert!(withdraw_events[0].attributes[4].value == "1000");
+        assert!(withdraw_events[0].attributes[5].key == "recipient");
+        assert!(withdraw_events[0].attributes[5].value == user2.as_str());
     }
 
     // #[test]
@@ -391,7 +422,10 @@ mod tests {
     //             .execute_contract(
     //                 user.clone(),
     //                 app_addr.clone(),
-    //                 &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+    //                 &ExecuteMsg::DepositIntoVault {
+    //                     vault_id: 0,
+    //                     referrer: None,
+    //                 },
     //                 &[Coin {
     //                     denom: USDC_COIN_TYPE.to_string(),
     //                     amount: Uint128::new(deposit_amount),
@@ -404,7 +438,7 @@ mod tests {
     //                 user.clone(),
     //                 app_addr.clone(),
     //                 &ExecuteMsg::RequestWithdrawal {
-    //                     perp_id: 0,
+    //                     vault_id: 0,
     //                     usdc_amount: withdraw_amount as u64,
     //                 },
     //                 &[],
@@ -414,7 +448,7 @@ mod tests {
 
     //     let q_resp: WithdrawalsResponse = app
     //         .wrap()
-    //         .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+    //         .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { vault_id: 0, start_after: None, limit: None })
     //         .unwrap();
 
     //     let withdrawal_queue = q_resp.withdrawal_queue;
@@ -455,7 +489,7 @@ mod tests {
         );
 
         // mint, deposit and request withdraw for all users
-        for user in vec![user2.clone(), user3.clone(), user4.clone()] {
+        for user in [user2.clone(), user3.clone(), user4.clone()] {
             mint_native(
                 &mut app,
                 user.to_string(),
@@ -467,7 +501,10 @@ mod tests {
                 .execute_contract(
                     user.clone(),
                     app_addr.clone(),
-                    &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
                     &[Coin {
                         denom: USDC_COIN_TYPE.to_string(),
                         amount: Uint128::new(deposit_amount),
@@ -480,8 +517,9 @@ mod tests {
                     user.clone(),
                     app_addr.clone(),
                     &ExecuteMsg::RequestWithdrawal {
-                        perp_id: 0,
+                        vault_id: 0,
                         usdc_amount: withdraw_amount as u64,
+                        recipient: None,
                     },
                     &[],
                 )
@@ -490,7 +528,14 @@ mod tests {
 
         let q_resp: WithdrawalsResponse = app
             .wrap()
-            .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
             .unwrap();
 
         let withdrawal_queue = q_resp.withdrawal_queue;
@@ -506,14 +551,21 @@ mod tests {
             .execute_contract(
                 user3.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::CancelWithdrawalRequests { perp_id: 0 },
+                &ExecuteMsg::CancelWithdrawalRequests { vault_id: 0 },
                 &[],
             )
             .unwrap();
 
         let q_resp: WithdrawalsResponse = app
             .wrap()
-            .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
             .unwrap();
 
         let user3_lp: LpTokenBalanceResponse = app
@@ -521,7 +573,7 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::UserLpTokens {
-                    perp_id: 0,
+                    vault_id: 0,
                     user: user3.to_string(),
                 },
             )
@@ -537,14 +589,21 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::CancelWithdrawalRequests { perp_id: 0 },
+                &ExecuteMsg::CancelWithdrawalRequests { vault_id: 0 },
                 &[],
             )
             .unwrap();
 
         let q_resp: WithdrawalsResponse = app
             .wrap()
-            .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
             .unwrap();
 
         let withdrawal_queue = q_resp.withdrawal_queue;
@@ -563,7 +622,7 @@ mod tests {
             cancel_withdraw_events[0].attributes[1].value
                 == "cosmwasm1vqjarrly327529599rcc4qhzvhwe34pp5uyy4gylvxe5zupeqx3sg08lap"
         );
-        assert!(cancel_withdraw_events[0].attributes[2].key == "perp_id");
+        assert!(cancel_withdraw_events[0].attributes[2].key == "vault_id");
         assert!(cancel_withdraw_events[0].attributes[2].value == "0");
         assert!(cancel_withdraw_events[0].attributes[3].key == "restored_lp_tokens");
         assert!(cancel_withdraw_events[0].attributes[3].value == "1000");
@@ -598,7 +657,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -611,8 +673,9 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::RequestWithdrawal {
-                    perp_id: 0,
+                    vault_id: 0,
                     usdc_amount: 0u64,
+                    recipient: None,
                 },
                 &[],
             )
@@ -623,7 +686,7 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::ProcessWithdrawals {
-                    perp_id: 0,
+                    vault_id: 0,
                     max_num_withdrawals: 1,
                 },
                 &[],
@@ -650,7 +713,7 @@ mod tests {
         );
 
         // mint, deposit and request withdraw for all users
-        for user in vec![user2.clone(), user3.clone(), user4.clone()] {
+        for user in [user2.clone(), user3.clone(), user4.clone()] {
             mint_native(
                 &mut app,
                 user.to_string(),
@@ -662,7 +725,10 @@ mod tests {
                 .execute_contract(
                     user.clone(),
                     app_addr.clone(),
-                    &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
                     &[Coin {
                         denom: USDC_COIN_TYPE.to_string(),
                         amount: Uint128::new(deposit_amount),
@@ -675,8 +741,9 @@ mod tests {
                     user.clone(),
                     app_addr.clone(),
                     &ExecuteMsg::RequestWithdrawal {
-                        perp_id: 0,
+                        vault_id: 0,
                         usdc_amount: withdraw_amount as u64,
+                        recipient: None,
                     },
                     &[],
                 )
@@ -685,7 +752,14 @@ mod tests {
 
         let q_resp: WithdrawalsResponse = app
             .wrap()
-            .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
             .unwrap();
 
         let withdrawal_queue = q_resp.withdrawal_queue;
@@ -696,7 +770,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::ProcessWithdrawals {
-                    perp_id: 0,
+                    vault_id: 0,
                     max_num_withdrawals: 8,
                 },
                 &[],
@@ -705,19 +779,26 @@ mod tests {
 
         let q_resp: WithdrawalsResponse = app
             .wrap()
-            .query_wasm_smart(app_addr.clone(), &QueryMsg::Withdrawals { perp_id: 0 })
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
             .unwrap();
 
         let withdrawal_queue = q_resp.withdrawal_queue;
-        assert!(withdrawal_queue.len() == 0);
+        assert!(withdrawal_queue.is_empty());
 
-        for user in vec![user2.clone(), user3.clone(), user4.clone()] {
+        for user in [user2.clone(), user3.clone(), user4.clone()] {
             let lp: LpTokenBalanceResponse = app
                 .wrap()
                 .query_wasm_smart(
                     app_addr.clone(),
                     &QueryMsg::UserLpTokens {
-                        perp_id: 0,
+                        vault_id: 0,
                         user: user.to_string(),
                     },
                 )
@@ -736,39 +817,41 @@ mod tests {
         );
         assert!(processed_withdraw_events.len() == 3);
         assert!(processed_withdraw_events[0].ty == "wasm-processed_withdrawal");
-        assert!(processed_withdraw_events[0].attributes.len() == 5);
+        assert!(processed_withdraw_events[0].attributes.len() == 6);
         assert!(processed_withdraw_events[0].attributes[1].key == "recipient");
         assert!(
             processed_withdraw_events[0].attributes[1].value
                 == "cosmwasm1vqjarrly327529599rcc4qhzvhwe34pp5uyy4gylvxe5zupeqx3sg08lap"
         );
-        assert!(processed_withdraw_events[0].attributes[2].key == "perp_id");
+        assert!(processed_withdraw_events[0].attributes[2].key == "vault_id");
         assert!(processed_withdraw_events[0].attributes[2].value == "0");
         assert!(processed_withdraw_events[0].attributes[3].key == "withdrawn_usdc");
         assert!(processed_withdraw_events[0].attributes[3].value == "1000");
         assert!(processed_withdraw_events[0].attributes[4].key == "burnt_lp_tokens");
         assert!(processed_withdraw_events[0].attributes[4].value == "1000");
+        assert!(processed_withdraw_events[0].attributes[5].key == "realized_pnl");
+        assert!(processed_withdraw_events[0].attributes[5].value == "0");
 
-        assert!(processed_withdraw_events[1].attributes.len() == 5);
+        assert!(processed_withdraw_events[1].attributes.len() == 6);
         assert!(processed_withdraw_events[1].attributes[1].key == "recipient");
         assert!(
             processed_withdraw_events[1].attributes[1].value
                 == "cosmwasm1tps04uptd0rzy2a94jjjx4s0pcmyenvtv7lwfph730muq82f9n9s2w0guk"
         );
-        assert!(processed_withdraw_events[1].attributes[2].key == "perp_id");
+        assert!(processed_withdraw_events[1].attributes[2].key == "vault_id");
         assert!(processed_withdraw_events[1].attributes[2].value == "0");
         assert!(processed_withdraw_events[1].attributes[3].key == "withdrawn_usdc");
         assert!(processed_withdraw_events[1].attributes[3].value == "999");
         assert!(processed_withdraw_events[1].attributes[4].key == "burnt_lp_tokens");
         assert!(processed_withdraw_events[1].attributes[4].value == "1000");
 
-        assert!(processed_withdraw_events[2].attributes.len() == 5);
+        assert!(processed_withdraw_events[2].attributes.len() == 6);
         assert!(processed_withdraw_events[2].attributes[1].key == "recipient");
         assert!(
             processed_withdraw_events[2].attributes[1].value
                 == "cosmwasm12f57lxqdu3upnw3azs6q73n9yckyr6fnmjfvrgna6hgpkpr6eq8qf0fk2p"
         );
-        assert!(processed_withdraw_events[2].attributes[2].key == "perp_id");
+        assert!(processed_withdraw_events[2].attributes[2].key == "vault_id");
         assert!(processed_withdraw_events[2].attributes[2].value == "0");
         assert!(processed_withdraw_events[2].attributes[3].key == "withdrawn_usdc");
         assert!(processed_withdraw_events[2].attributes[3].value == "999");
@@ -803,7 +886,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -816,8 +902,9 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::RequestWithdrawal {
-                    perp_id: 0,
+                    vault_id: 0,
                     usdc_amount: 0u64,
+                    recipient: None,
                 },
                 &[],
             )
@@ -828,7 +915,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::ProcessWithdrawals {
-                    perp_id: 0,
+                    vault_id: 0,
                     max_num_withdrawals: 1,
                 },
                 &[],
@@ -850,7 +937,7 @@ mod tests {
         let subaccount_id = subaccount.id.unwrap();
 
         assert!(subaccount_id.number == 0);
-        assert!(subaccount_id.owner == TEST_CONTRACT_ADDR.to_string());
+        assert!(subaccount_id.owner == TEST_CONTRACT_ADDR);
         assert!(subaccount.asset_positions.len() == 1);
         assert!(subaccount.asset_positions[0].asset_id == 0);
         assert!(subaccount.asset_positions[0].quantums.i == 0.into());
@@ -860,12 +947,12 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::VaultOwnership {
-                    perp_id: 0,
+                    vault_id: 0,
                     depositor: user1.to_string(),
                 },
             )
             .unwrap();
-        assert!(vault_resp.subaccount_owner == TEST_CONTRACT_ADDR.to_string());
+        assert!(vault_resp.subaccount_owner == TEST_CONTRACT_ADDR);
         assert!(vault_resp.subaccount_number == 0);
         assert!(vault_resp.asset_usdc_value == SignedDecimal::zero());
         assert!(vault_resp.perp_usdc_value == SignedDecimal::zero());
@@ -875,7 +962,7 @@ mod tests {
 
     #[test]
     #[should_panic(
-        expected = "Could not find LP tokens with perp_id: 0 for cosmwasm1tps04uptd0rzy2a94jjjx4s0pcmyenvtv7lwfph730muq82f9n9s2w0guk"
+        expected = "Could not find LP tokens with vault_id: 0 for cosmwasm1tps04uptd0rzy2a94jjjx4s0pcmyenvtv7lwfph730muq82f9n9s2w0guk"
     )]
     fn only_users_with_deposits_can_request_withdrawal_from_vault() {
         let (mut app, code_id, users) = test_setup();
@@ -905,7 +992,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -918,8 +1008,9 @@ mod tests {
                 user3.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::RequestWithdrawal {
-                    perp_id: 0,
+                    vault_id: 0,
                     usdc_amount: withdraw_amount as u64,
+                    recipient: None,
                 },
                 &[],
             )
@@ -953,7 +1044,10 @@ mod tests {
             .execute_contract(
                 user2.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -976,8 +1070,9 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::RequestWithdrawal {
-                    perp_id: 0,
+                    vault_id: 0,
                     usdc_amount: 1u64,
+                    recipient: None,
                 },
                 &[],
             )
@@ -988,7 +1083,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::ProcessWithdrawals {
-                    perp_id: 0,
+                    vault_id: 0,
                     max_num_withdrawals: 1,
                 },
                 &[],
@@ -1000,12 +1095,12 @@ mod tests {
             .query_wasm_smart(
                 app_addr.clone(),
                 &QueryMsg::VaultOwnership {
-                    perp_id: 0,
+                    vault_id: 0,
                     depositor: user1.to_string(),
                 },
             )
             .unwrap();
-        assert!(vault_resp.subaccount_owner == TEST_CONTRACT_ADDR.to_string());
+        assert!(vault_resp.subaccount_owner == TEST_CONTRACT_ADDR);
         assert!(vault_resp.subaccount_number == 0);
         assert!(vault_resp.asset_usdc_value == SignedDecimal::from_atomics(5i128, 1).unwrap());
         assert!(
@@ -1019,8 +1114,9 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::RequestWithdrawal {
-                    perp_id: 0,
+                    vault_id: 0,
                     usdc_amount: 6u64,
+                    recipient: None,
                 },
                 &[],
             )
@@ -1030,7 +1126,7 @@ mod tests {
             user1.clone(),
             app_addr.clone(),
             &ExecuteMsg::ProcessWithdrawals {
-                perp_id: 0,
+                vault_id: 0,
                 max_num_withdrawals: 1,
             },
             &[],
@@ -1043,10 +1139,2211 @@ mod tests {
         {
             assert_eq!(
                 error,
-                &ContractError::WithdrawalWouldIncreaseLeverageTooMuch { perp_id: 0 }
+                &ContractError::WithdrawalWouldIncreaseLeverageTooMuch { vault_id: 0 }
             );
         } else {
             panic!("Expected ContractError::WithdrawalWouldIncreaseLeverageTooMuch");
         }
     }
+
+    #[test]
+    fn deposits_are_locked_for_the_vault_lockup() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+        let lockup_seconds = 3_600;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: Some(lockup_seconds),
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+        let unlock_time = app.block_info().time.plus_seconds(lockup_seconds);
+
+        let schedule: UnlockScheduleResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UnlockSchedule {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(schedule.lockup_seconds == lockup_seconds);
+        assert!(schedule.locked_lp_tokens == Uint128::new(deposit_amount));
+        assert!(schedule.unlocked_lp_tokens == Uint128::zero());
+        assert!(schedule.unlocks.len() == 1);
+        assert!(schedule.unlocks[0].lp_tokens == Uint128::new(deposit_amount));
+        assert!(schedule.unlocks[0].unlock_time == unlock_time);
+
+        let withdraw_response = app.execute_contract(
+            user2.clone(),
+            app_addr.clone(),
+            &ExecuteMsg::RequestWithdrawal {
+                vault_id: 0,
+                usdc_amount: 0,
+                recipient: None,
+            },
+            &[],
+        );
+        assert_eq!(
+            withdraw_response
+                .unwrap_err()
+                .downcast_ref::<ContractError>()
+                .unwrap(),
+            &ContractError::LpTokensLocked {
+                vault_id: 0,
+                requested: Uint128::one(),
+                unlocked: Uint128::zero(),
+                unlock_time: unlock_time.seconds(),
+            }
+        );
+
+        app.update_block(|block| block.time = block.time.plus_seconds(lockup_seconds));
+
+        let schedule: UnlockScheduleResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UnlockSchedule {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(schedule.locked_lp_tokens == Uint128::zero());
+        assert!(schedule.unlocked_lp_tokens == Uint128::new(deposit_amount));
+        assert!(schedule.unlocks.is_empty());
+
+        let _withdraw_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 0,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 1);
+        assert!(q_resp.withdrawal_queue[0].lp_tokens == Uint128::new(deposit_amount));
+    }
+
+    #[test]
+    fn only_unlocked_lp_tokens_can_be_withdrawn() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+        let lockup_seconds = 3_600;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            2 * deposit_amount,
+        );
+
+        // the first deposit is made before the lockup is configured
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: Some(lockup_seconds),
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+        let unlock_time = app.block_info().time.plus_seconds(lockup_seconds);
+
+        // withdrawing more than the unlocked tokens fails
+        let withdraw_response = app.execute_contract(
+            user2.clone(),
+            app_addr.clone(),
+            &ExecuteMsg::RequestWithdrawal {
+                vault_id: 0,
+                usdc_amount: (deposit_amount + 1) as u64,
+                recipient: None,
+            },
+            &[],
+        );
+        assert_eq!(
+            withdraw_response
+                .unwrap_err()
+                .downcast_ref::<ContractError>()
+                .unwrap(),
+            &ContractError::LpTokensLocked {
+                vault_id: 0,
+                requested: Uint128::new(deposit_amount + 1),
+                unlocked: Uint128::new(deposit_amount),
+                unlock_time: unlock_time.seconds(),
+            }
+        );
+
+        // withdrawing everything only withdraws the unlocked tokens
+        let _withdraw_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 0,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 1);
+        assert!(q_resp.withdrawal_queue[0].lp_tokens == Uint128::new(deposit_amount));
+
+        let user_lp: LpTokenBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserLpTokens {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(user_lp.balance == Uint128::new(deposit_amount));
+    }
+
+    #[test]
+    fn withdrawals_can_be_paid_to_a_different_recipient() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let deposit_amount = 1_000_000;
+        let withdraw_amount = 1_000u128;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        let invalid_recipient_response = app.execute_contract(
+            user2.clone(),
+            app_addr.clone(),
+            &ExecuteMsg::RequestWithdrawal {
+                vault_id: 0,
+                usdc_amount: withdraw_amount as u64,
+                recipient: Some("Invalid".to_string()),
+            },
+            &[],
+        );
+        assert_eq!(
+            invalid_recipient_response
+                .unwrap_err()
+                .downcast_ref::<ContractError>()
+                .unwrap(),
+            &ContractError::InvalidAddress {
+                addr: "Invalid".to_string()
+            }
+        );
+
+        let _request_withdraw_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: withdraw_amount as u64,
+                    recipient: Some(user3.to_string()),
+                },
+                &[],
+            )
+            .unwrap();
+
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 1);
+        assert!(q_resp.withdrawal_queue[0].owner == user2);
+        assert!(q_resp.withdrawal_queue[0].recipient_addr == user3);
+
+        // the recipient does not own the request, so cancelling is a no-op
+        let _cancel_response = app
+            .execute_contract(
+                user3.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CancelWithdrawalRequests { vault_id: 0 },
+                &[],
+            )
+            .unwrap();
+
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 1);
+
+        let process_withdrawal_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ProcessWithdrawals {
+                    vault_id: 0,
+                    max_num_withdrawals: 1,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let processed_withdraw_events = fetch_response_events(
+            &process_withdrawal_response,
+            "processed_withdrawal".to_string(),
+        );
+        assert!(processed_withdraw_events.len() == 1);
+        assert!(processed_withdraw_events[0].attributes[1].key == "recipient");
+        assert!(processed_withdraw_events[0].attributes[1].value == user3.as_str());
+
+        let user_lp: LpTokenBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserLpTokens {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(user_lp.balance == Uint128::new(deposit_amount - withdraw_amount));
+    }
+
+    #[test]
+    fn withdrawal_queue_can_be_paginated_and_filtered_by_user() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let deposit_amount = 1_000_000;
+        let withdraw_amount = 1_000u128;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        for user in [user2.clone(), user3.clone()] {
+            mint_native(
+                &mut app,
+                user.to_string(),
+                USDC_COIN_TYPE.to_string(),
+                deposit_amount,
+            );
+
+            let _deposit_response = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
+                    &[Coin {
+                        denom: USDC_COIN_TYPE.to_string(),
+                        amount: Uint128::new(deposit_amount),
+                    }],
+                )
+                .unwrap();
+        }
+
+        // queue: user2, user3, user2
+        for user in [user2.clone(), user3.clone(), user2.clone()] {
+            let _request_withdraw_response = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::RequestWithdrawal {
+                        vault_id: 0,
+                        usdc_amount: withdraw_amount as u64,
+                        recipient: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+        }
+
+        let first_page: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: Some(2),
+                },
+            )
+            .unwrap();
+        assert!(first_page.withdrawal_queue.len() == 2);
+        assert!(first_page.withdrawal_queue[0].position == 0);
+        assert!(first_page.withdrawal_queue[0].owner == user2);
+        assert!(first_page.withdrawal_queue[1].position == 1);
+        assert!(first_page.withdrawal_queue[1].owner == user3);
+
+        let second_page: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: Some(1),
+                    limit: Some(2),
+                },
+            )
+            .unwrap();
+        assert!(second_page.withdrawal_queue.len() == 1);
+        assert!(second_page.withdrawal_queue[0].position == 2);
+        assert!(second_page.withdrawal_queue[0].owner == user2);
+
+        let user_withdrawals: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserWithdrawals {
+                    user: user2.to_string(),
+                    vault_id: None,
+                },
+            )
+            .unwrap();
+        assert!(user_withdrawals.withdrawal_queue.len() == 2);
+        assert!(user_withdrawals.withdrawal_queue[0].vault_id == 0);
+        assert!(user_withdrawals.withdrawal_queue[0].position == 0);
+        assert!(user_withdrawals.withdrawal_queue[1].position == 2);
+        for withdrawal in user_withdrawals.withdrawal_queue {
+            assert!(withdrawal.owner == user2);
+            assert!(withdrawal.lp_tokens == Uint128::new(withdraw_amount));
+            assert!(
+                withdrawal.usdc_equivalent == SignedDecimal256::from_atomics(1i128, 3).unwrap()
+            );
+        }
+
+        let user_withdrawals: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserWithdrawals {
+                    user: user1.to_string(),
+                    vault_id: Some(0),
+                },
+            )
+            .unwrap();
+        assert!(user_withdrawals.withdrawal_queue.is_empty());
+
+        // missing vaults return an error instead of panicking
+        let missing_vault_resp: Result<WithdrawalsResponse, _> = app.wrap().query_wasm_smart(
+            app_addr.clone(),
+            &QueryMsg::Withdrawals {
+                vault_id: 5,
+                start_after: None,
+                limit: None,
+            },
+        );
+        assert!(missing_vault_resp
+            .unwrap_err()
+            .to_string()
+            .contains("could not find withdrawal_queue for vault with vault_id: 5"));
+    }
+
+    #[test]
+    fn small_withdrawals_are_settled_instantly_within_the_buffer() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: None,
+                    instant_withdrawals: Some(InstantWithdrawalConfig {
+                        buffer: InstantWithdrawalBuffer::Usdc(Uint128::new(5_000)),
+                        max_outflow_per_window: Uint128::new(5_500),
+                        window_seconds: 86_400,
+                    }),
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        // fits in the buffer and the window cap, so it is paid out right away
+        let instant_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 1_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let instant_events =
+            fetch_response_events(&instant_response, "instant_withdrawal".to_string());
+        assert!(instant_events.len() == 1);
+        assert!(instant_events[0].attributes[4].key == "withdrawn_usdc");
+        assert!(instant_events[0].attributes[4].value == "1000");
+        assert!(instant_events[0].attributes[5].key == "burnt_lp_tokens");
+        assert!(instant_events[0].attributes[5].value == "1000");
+
+        let vault_resp: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 0,
+                    depositor: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(vault_resp.asset_usdc_value == SignedDecimal::from_atomics(999i128, 3).unwrap());
+        assert!(vault_resp.depositor_lp_tokens == Uint128::new(deposit_amount - 1_000));
+        assert!(vault_resp.outstanding_lp_tokens == Uint128::new(deposit_amount - 1_000));
+
+        // larger than the buffer, or over the remaining window cap, so both are queued
+        for usdc_amount in [5_001, 5_000] {
+            let queued_response = app
+                .execute_contract(
+                    user2.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::RequestWithdrawal {
+                        vault_id: 0,
+                        usdc_amount,
+                        recipient: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+            assert!(
+                fetch_response_events(&queued_response, "instant_withdrawal".to_string())
+                    .is_empty()
+            );
+        }
+
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 2);
+
+        // the cap resets in the next window
+        app.update_block(|block| block.time = block.time.plus_seconds(86_400));
+
+        let instant_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 5_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let instant_events =
+            fetch_response_events(&instant_response, "instant_withdrawal".to_string());
+        assert!(instant_events.len() == 1);
+        assert!(instant_events[0].attributes[4].value == "5000");
+    }
+
+    #[test]
+    fn processed_withdrawals_are_rate_limited_per_window() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: None,
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: Some(WithdrawalRateLimit {
+                        max_nav_fraction: Decimal::percent(10),
+                        window_seconds: 86_400,
+                    }),
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        for _ in 0..3 {
+            let _withdrawal_response = app
+                .execute_contract(
+                    user2.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::RequestWithdrawal {
+                        vault_id: 0,
+                        usdc_amount: 40_000,
+                        recipient: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+        }
+
+        // only two withdrawals fit in 10% of the vault value
+        // (40_000 and 38_400 USDC quantums, since the ownership fraction is taken of the remaining value)
+        let process_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ProcessWithdrawals {
+                    vault_id: 0,
+                    max_num_withdrawals: 3,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let processed_events =
+            fetch_response_events(&process_response, "processed_withdrawal".to_string());
+        assert!(processed_events.len() == 2);
+        let limited_events =
+            fetch_response_events(&process_response, "withdrawals_rate_limited".to_string());
+        assert!(limited_events.len() == 1);
+        assert!(limited_events[0].attributes[2].key == "remaining_capacity");
+        assert!(limited_events[0].attributes[2].value == "21600");
+
+        let capacity_resp: WithdrawalCapacityResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::WithdrawalCapacity { vault_id: 0 },
+            )
+            .unwrap();
+        assert!(capacity_resp.max_outflow == Some(Uint128::new(100_000)));
+        assert!(capacity_resp.processed_outflow == Uint128::new(78_400));
+        assert!(capacity_resp.remaining_capacity == Some(Uint128::new(21_600)));
+        assert!(capacity_resp
+            .remaining_instant_withdrawal_capacity
+            .is_none());
+
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 1);
+
+        // the processed outflows leave the window
+        app.update_block(|block| block.time = block.time.plus_seconds(86_400));
+
+        let process_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ProcessWithdrawals {
+                    vault_id: 0,
+                    max_num_withdrawals: 3,
+                },
+                &[],
+            )
+            .unwrap();
+        let processed_events =
+            fetch_response_events(&process_response, "processed_withdrawal".to_string());
+        assert!(processed_events.len() == 1);
+
+        let capacity_resp: WithdrawalCapacityResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::WithdrawalCapacity { vault_id: 0 },
+            )
+            .unwrap();
+        assert!(capacity_resp.processed_outflow == Uint128::new(40_069));
+        assert!(capacity_resp.remaining_capacity == Some(Uint128::new(52_091)));
+
+        // instant withdrawals count against the same window
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: None,
+                    instant_withdrawals: Some(InstantWithdrawalConfig {
+                        buffer: InstantWithdrawalBuffer::Usdc(Uint128::new(100_000)),
+                        max_outflow_per_window: Uint128::new(100_000),
+                        window_seconds: 86_400,
+                    }),
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let instant_withdrawal = |app: &mut crate::utils::ElixirTestApp, usdc_amount: u64| {
+            let response = app
+                .execute_contract(
+                    user2.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::RequestWithdrawal {
+                        vault_id: 0,
+                        usdc_amount,
+                        recipient: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+            fetch_response_events(&response, "instant_withdrawal".to_string()).len() == 1
+        };
+        assert!(instant_withdrawal(&mut app, 50_000));
+
+        let capacity_resp: WithdrawalCapacityResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::WithdrawalCapacity { vault_id: 0 },
+            )
+            .unwrap();
+        assert!(capacity_resp.processed_outflow == Uint128::new(90_069));
+        assert!(capacity_resp.remaining_capacity == Some(Uint128::new(2_091)));
+
+        // the instant withdrawal that exceeds the remaining capacity is queued instead
+        assert!(!instant_withdrawal(&mut app, 10_000));
+        let q_resp: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr,
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(q_resp.withdrawal_queue.len() == 1);
+    }
+
+    #[test]
+    fn oversized_positions_fail_valuation_instead_of_truncating() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        // does not fit an i128 and used to be valued as zero
+        let perp_quantums = BigInt::from(1) << 250;
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(perp_quantums),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<VaultOwnershipResponse>(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 0,
+                    depositor: user2.to_string(),
+                },
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Error converting SerializableInt to SignedDecimal256"));
+
+        mint_native(&mut app, user2.to_string(), USDC_COIN_TYPE.to_string(), 100);
+        let err = app
+            .execute_contract(
+                user2,
+                app_addr,
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(100),
+                }],
+            )
+            .unwrap_err();
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("Error converting SerializableInt to SignedDecimal256"));
+    }
+
+    #[test]
+    fn vault_summary_splits_nav_and_computes_share_price() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        for (user, amount) in [(&user2, 1_000_000), (&user3, 3_000_000)] {
+            mint_native(
+                &mut app,
+                user.to_string(),
+                USDC_COIN_TYPE.to_string(),
+                amount,
+            );
+            let _deposit = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
+                    &[Coin {
+                        denom: USDC_COIN_TYPE.to_string(),
+                        amount: Uint128::new(amount),
+                    }],
+                )
+                .unwrap();
+        }
+        let _request = app
+            .execute_contract(
+                user3.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 0,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        // short 0.00001 BTC whose funding has not been settled since the index was 2 ppm higher
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new((-100_000).into()),
+                funding_index: SerializableInt::new(2_000_000.into()),
+            },
+        );
+
+        let summary: VaultSummaryResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::VaultSummary { vault_id: 0 })
+            .unwrap();
+        assert!(summary.vault.vault_id == 0);
+        assert!(summary.collateral_usdc_value == SignedDecimal::from_str("4").unwrap());
+        assert!(summary.perp_usdc_value == SignedDecimal::from_str("0.6038418054").unwrap());
+        assert!(summary.lp_holdings_usdc_value == SignedDecimal::zero());
+        assert!(summary.nav == SignedDecimal::from_str("4.6038418054").unwrap());
+        assert!(summary.unrealized_funding == SignedDecimal::from_str("-0.2").unwrap());
+        assert!(summary.pending_withdrawal_lp_tokens == Uint128::new(3_000_000));
+        assert!(
+            summary.pending_withdrawals_usdc_value
+                == SignedDecimal::from_str("3.45288135405").unwrap()
+        );
+        assert!(summary.lp_supply == Uint128::new(4_000_000));
+        assert!(summary.price_per_share == SignedDecimal::from_str("1.15096045135").unwrap());
+        assert!(summary.leverage == Some(Decimal::from_str("0.15096045135").unwrap()));
+        assert!(summary.open_interest == SignedDecimal::from_str("-0.00001").unwrap());
+        assert!(summary.oracle_price == SignedDecimal::from_str("60384.18054").unwrap());
+
+        let _create_vault = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let summaries: AllVaultSummariesResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::AllVaultSummaries {
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(summaries.summaries.len() == 2);
+        assert!(summaries.summaries[0] == summary);
+        let empty_vault = &summaries.summaries[1];
+        assert!(empty_vault.vault.vault_id == 1);
+        assert!(empty_vault.nav == SignedDecimal::zero());
+        assert!(empty_vault.price_per_share == SignedDecimal::one());
+        assert!(empty_vault.leverage.is_none());
+
+        let summaries: AllVaultSummariesResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::AllVaultSummaries {
+                    start_after: Some(0),
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(summaries.summaries.len() == 1);
+        assert!(summaries.summaries[0].vault.vault_id == 1);
+        let summaries: AllVaultSummariesResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr,
+                &QueryMsg::AllVaultSummaries {
+                    start_after: None,
+                    limit: Some(1),
+                },
+            )
+            .unwrap();
+        assert!(summaries.summaries.len() == 1);
+        assert!(summaries.summaries[0].vault.vault_id == 0);
+    }
+
+    #[test]
+    fn simulations_match_deposits_and_withdrawal_requests() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let event_attribute = |response: &cw_multi_test::AppResponse, event: &str, key: &str| {
+            fetch_response_events(response, event.to_string())[0]
+                .attributes
+                .iter()
+                .find(|attr| attr.key == key)
+                .unwrap()
+                .value
+                .clone()
+        };
+        let deposit = |app: &mut crate::utils::ElixirTestApp, user: &cosmwasm_std::Addr, amount| {
+            mint_native(app, user.to_string(), USDC_COIN_TYPE.to_string(), amount);
+            app.execute_contract(
+                user.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(amount),
+                }],
+            )
+            .unwrap()
+        };
+
+        // the first deposit mints one LP token per quantum
+        let simulated: SimulateDepositResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::SimulateDeposit {
+                    vault_id: 0,
+                    amount: Uint128::new(1_000_000),
+                },
+            )
+            .unwrap();
+        assert!(simulated.lp_tokens == Uint128::new(1_000_000));
+        let response = deposit(&mut app, &user2, 1_000_000);
+        assert!(
+            event_attribute(&response, "new_deposit", "minted_lp_tokens")
+                == simulated.lp_tokens.to_string()
+        );
+
+        // later deposits mint at the share price, which the perp position moves
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(100_000.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+        let simulated: SimulateDepositResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::SimulateDeposit {
+                    vault_id: 0,
+                    amount: Uint128::new(3_000_000),
+                },
+            )
+            .unwrap();
+        assert!(simulated.lp_tokens < Uint128::new(3_000_000));
+        let response = deposit(&mut app, &user3, 3_000_000);
+        assert!(
+            event_attribute(&response, "new_deposit", "minted_lp_tokens")
+                == simulated.lp_tokens.to_string()
+        );
+
+        let simulated: SimulateWithdrawalResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::SimulateWithdrawal {
+                    vault_id: 0,
+                    user: user3.to_string(),
+                    amount: WithdrawalAmount::UsdcAmount(1_000_000),
+                },
+            )
+            .unwrap();
+        assert!(
+            simulated.usdc_amount >= Uint128::new(999_999)
+                && simulated.usdc_amount <= Uint128::new(1_000_001)
+        );
+        let by_lp_tokens: SimulateWithdrawalResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::SimulateWithdrawal {
+                    vault_id: 0,
+                    user: user3.to_string(),
+                    amount: WithdrawalAmount::LpTokens(simulated.lp_tokens),
+                },
+            )
+            .unwrap();
+        assert!(by_lp_tokens == simulated);
+        let response = app
+            .execute_contract(
+                user3.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 1_000_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        assert!(
+            event_attribute(&response, "new_withdrawal_request", "transferred_lp_tokens")
+                == simulated.lp_tokens.to_string()
+        );
+
+        // simulations fail like the real messages would
+        let err = app
+            .wrap()
+            .query_wasm_smart::<SimulateWithdrawalResponse>(
+                app_addr.clone(),
+                &QueryMsg::SimulateWithdrawal {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                    amount: WithdrawalAmount::LpTokens(Uint128::new(1_000_001)),
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains(
+            &ContractError::InsufficientLpTokens {
+                vault_id: 0,
+                requested: Uint128::new(1_000_001),
+                balance: Uint128::new(1_000_000),
+            }
+            .to_string()
+        ));
+        let err = app
+            .wrap()
+            .query_wasm_smart::<SimulateWithdrawalResponse>(
+                app_addr.clone(),
+                &QueryMsg::SimulateWithdrawal {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                    amount: WithdrawalAmount::UsdcAmount(10_000_000),
+                },
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Tried to withdraw an invalid amount"));
+        app.router().custom.sudo_start_final_settlement(0);
+        let err = app
+            .wrap()
+            .query_wasm_smart::<SimulateDepositResponse>(
+                app_addr,
+                &QueryMsg::SimulateDeposit {
+                    vault_id: 0,
+                    amount: Uint128::new(1_000_000),
+                },
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&ContractError::MarketInFinalSettlement { vault_id: 0 }.to_string()));
+    }
+
+    #[test]
+    fn user_portfolio_covers_every_vault_held() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let user4 = users[4].clone();
+        let lockup_seconds = 3_600;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let _create_vault = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 1,
+                    lockup_seconds: Some(lockup_seconds),
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        for (user, vault_id, amount) in [
+            (&user2, 0, 1_000_000),
+            (&user3, 0, 3_000_000),
+            (&user2, 1, 2_000_000),
+        ] {
+            mint_native(
+                &mut app,
+                user.to_string(),
+                USDC_COIN_TYPE.to_string(),
+                amount,
+            );
+            let _deposit_response = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id,
+                        referrer: None,
+                    },
+                    &[Coin {
+                        denom: USDC_COIN_TYPE.to_string(),
+                        amount: Uint128::new(amount),
+                    }],
+                )
+                .unwrap();
+        }
+        let unlock_time = app.block_info().time.plus_seconds(lockup_seconds);
+        let _withdrawal_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 500_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let portfolio: UserPortfolioResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserPortfolio {
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(portfolio.user == user2);
+        assert!(portfolio.positions.len() == 2);
+
+        let position = &portfolio.positions[0];
+        assert!(position.vault_id == 0);
+        assert!(position.lp_tokens == Uint128::new(500_000));
+        assert!(position.share_of_vault == Decimal::from_str("0.125").unwrap());
+        assert!(position.usdc_value == SignedDecimal::from_str("0.5").unwrap());
+        assert!(position.queued_withdrawal_lp_tokens == Uint128::new(500_000));
+        assert!(position.queued_withdrawals_usdc_value == SignedDecimal::from_str("0.5").unwrap());
+        assert!(position.locked_lp_tokens.is_zero());
+        assert!(position.unlocked_lp_tokens == Uint128::new(500_000));
+        assert!(position.next_unlock_time.is_none());
+
+        let position = &portfolio.positions[1];
+        assert!(position.vault_id == 1);
+        assert!(position.lp_tokens == Uint128::new(2_000_000));
+        assert!(position.share_of_vault == Decimal::one());
+        assert!(position.usdc_value == SignedDecimal::from_str("2").unwrap());
+        assert!(position.queued_withdrawal_lp_tokens.is_zero());
+        assert!(position.locked_lp_tokens == Uint128::new(2_000_000));
+        assert!(position.unlocked_lp_tokens.is_zero());
+        assert!(position.next_unlock_time == Some(unlock_time));
+
+        assert!(portfolio.total_usdc_value == SignedDecimal::from_str("2.5").unwrap());
+        assert!(
+            portfolio.total_queued_withdrawals_usdc_value
+                == SignedDecimal::from_str("0.5").unwrap()
+        );
+
+        let portfolio: UserPortfolioResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr,
+                &QueryMsg::UserPortfolio {
+                    user: user4.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(portfolio.positions.is_empty());
+        assert!(portfolio.total_usdc_value.is_zero());
+    }
+
+    #[test]
+    fn user_pnl_tracks_cost_basis_through_withdrawals() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+        let user_pnl = |app: &crate::utils::ElixirTestApp| -> UserPnlResponse {
+            app.wrap()
+                .query_wasm_smart(
+                    app_addr.clone(),
+                    &QueryMsg::UserPnl {
+                        vault_id: 0,
+                        user: user2.to_string(),
+                    },
+                )
+                .unwrap()
+        };
+
+        let pnl = user_pnl(&app);
+        assert!(pnl.deposited_usdc == Uint128::new(deposit_amount));
+        assert!(pnl.lp_tokens == Uint128::new(deposit_amount));
+        assert!(pnl.untracked_lp_tokens.is_zero());
+        assert!(pnl.cost_basis_usdc == Uint128::new(deposit_amount));
+        assert!(pnl.usdc_value == Uint128::new(deposit_amount));
+        assert!(pnl.realized_pnl.is_zero());
+        assert!(pnl.unrealized_pnl.is_zero());
+
+        // the perp position raises the vault value
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(100_000.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+        let pnl = user_pnl(&app);
+        assert!(pnl.usdc_value == Uint128::new(1_603_841));
+        assert!(pnl.unrealized_pnl == Int128::new(603_841));
+
+        // queued LP tokens keep their cost until the withdrawal is processed
+        let _withdrawal_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 300_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let pnl = user_pnl(&app);
+        assert!(pnl.lp_tokens == Uint128::new(deposit_amount));
+        assert!(pnl.realized_pnl.is_zero());
+
+        let process_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ProcessWithdrawals {
+                    vault_id: 0,
+                    max_num_withdrawals: 1,
+                },
+                &[],
+            )
+            .unwrap();
+        let event =
+            &fetch_response_events(&process_response, "processed_withdrawal".to_string())[0];
+        let attribute = |key: &str| {
+            event
+                .attributes
+                .iter()
+                .find(|attr| attr.key == key)
+                .unwrap()
+                .value
+                .clone()
+        };
+        let withdrawn_usdc = Uint128::from_str(&attribute("withdrawn_usdc")).unwrap();
+        let burnt_lp_tokens = Uint128::from_str(&attribute("burnt_lp_tokens")).unwrap();
+
+        // burnt LP tokens are removed at their average cost of one quantum each
+        let pnl = user_pnl(&app);
+        assert!(pnl.withdrawn_usdc == withdrawn_usdc);
+        assert!(pnl.lp_tokens == Uint128::new(deposit_amount) - burnt_lp_tokens);
+        assert!(pnl.cost_basis_usdc == pnl.lp_tokens);
+        assert!(
+            pnl.realized_pnl
+                == Int128::new(withdrawn_usdc.u128() as i128 - burnt_lp_tokens.u128() as i128)
+        );
+        assert!(attribute("realized_pnl") == pnl.realized_pnl.to_string());
+        assert!(pnl.realized_pnl > Int128::zero());
+        assert!(pnl.unrealized_pnl > Int128::zero());
+    }
+
+    #[test]
+    fn share_price_checkpoints_track_vault_apr() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let interval_seconds = 3_600;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: None,
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: Some(interval_seconds),
+                },
+                &[],
+            )
+            .unwrap();
+        let deposit = |app: &mut crate::utils::ElixirTestApp, user: &cosmwasm_std::Addr| {
+            mint_native(app, user.to_string(), USDC_COIN_TYPE.to_string(), 1_000_000);
+            app.execute_contract(
+                user.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(1_000_000),
+                }],
+            )
+            .unwrap();
+        };
+        let market_make = |app: &mut crate::utils::ElixirTestApp| {
+            app.execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::MarketMake {
+                    vault_id: 0,
+                    clob_pair_id: 0,
+                    new_orders: vec![],
+                    cancel_client_ids: vec![],
+                    cancel_good_til_block_time: 0,
+                },
+                &[],
+            )
+            .unwrap();
+        };
+        let history = |app: &crate::utils::ElixirTestApp, start_after| {
+            app.wrap()
+                .query_wasm_smart::<SharePriceHistoryResponse>(
+                    app_addr.clone(),
+                    &QueryMsg::SharePriceHistory {
+                        vault_id: 0,
+                        start_after,
+                        limit: None,
+                    },
+                )
+                .unwrap()
+                .checkpoints
+        };
+
+        // an empty vault has no share price to checkpoint
+        deposit(&mut app, &user2);
+        assert!(history(&app, None).is_empty());
+
+        app.update_block(|block| block.time = block.time.plus_seconds(100));
+        let start_time = app.block_info().time;
+        deposit(&mut app, &user3);
+        let checkpoints = history(&app, None);
+        assert!(checkpoints.len() == 1);
+        assert!(checkpoints[0].time == start_time);
+        assert!(checkpoints[0].share_price == SignedDecimal::one());
+        assert!(checkpoints[0].lp_supply == Uint128::new(1_000_000));
+
+        // the perp position raises the share price, which is checkpointed at most once per interval
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(100_000.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+        app.update_block(|block| block.time = block.time.plus_seconds(interval_seconds / 2));
+        market_make(&mut app);
+        assert!(history(&app, None).len() == 1);
+
+        app.update_block(|block| block.time = block.time.plus_seconds(interval_seconds / 2));
+        market_make(&mut app);
+        let checkpoints = history(&app, Some(start_time.seconds()));
+        assert!(checkpoints.len() == 1);
+        assert!(checkpoints[0].time == start_time.plus_seconds(interval_seconds));
+        assert!(checkpoints[0].share_price == SignedDecimal::from_str("1.3019209027").unwrap());
+        assert!(checkpoints[0].nav == SignedDecimal::from_str("2.6038418054").unwrap());
+
+        let apr: VaultAprResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultApr {
+                    vault_id: 0,
+                    window_seconds: interval_seconds,
+                },
+            )
+            .unwrap();
+        assert!(apr.start.unwrap().time == start_time);
+        assert!(apr.end.unwrap().time == start_time.plus_seconds(interval_seconds));
+        // a 30.19% return over an hour, annualized over 8760 hours
+        assert!(apr.apr == Some(SignedDecimal::from_str("2644.827107652").unwrap()));
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<VaultAprResponse>(
+                app_addr.clone(),
+                &QueryMsg::VaultApr {
+                    vault_id: 0,
+                    window_seconds: 0,
+                },
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&ContractError::InvalidAprWindow {}.to_string()));
+
+        // instant withdrawals checkpoint the share price before paying out
+        let _config_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: None,
+                    instant_withdrawals: Some(InstantWithdrawalConfig {
+                        buffer: InstantWithdrawalBuffer::Usdc(Uint128::new(100_000)),
+                        max_outflow_per_window: Uint128::new(100_000),
+                        window_seconds: 86_400,
+                    }),
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(interval_seconds));
+        let withdrawal_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 10_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        assert!(
+            fetch_response_events(&withdrawal_response, "instant_withdrawal".to_string()).len()
+                == 1
+        );
+        let checkpoints = history(
+            &app,
+            Some(start_time.plus_seconds(interval_seconds).seconds()),
+        );
+        assert!(checkpoints.len() == 1);
+        assert!(checkpoints[0].time == app.block_info().time);
+        assert!(checkpoints[0].lp_supply == Uint128::new(2_000_000));
+    }
+
+    #[test]
+    fn lp_balances_and_supply_are_snapshotted_per_block() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let deposit = |app: &mut crate::utils::ElixirTestApp, user: &cosmwasm_std::Addr, amount| {
+            mint_native(app, user.to_string(), USDC_COIN_TYPE.to_string(), amount);
+            app.execute_contract(
+                user.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(amount),
+                }],
+            )
+            .unwrap();
+        };
+        let balance_at = |app: &crate::utils::ElixirTestApp, height| {
+            app.wrap()
+                .query_wasm_smart::<BalanceAtHeightResponse>(
+                    app_addr.clone(),
+                    &QueryMsg::BalanceAtHeight {
+                        vault_id: 0,
+                        user: user2.to_string(),
+                        height,
+                    },
+                )
+                .unwrap()
+                .balance
+        };
+        let supply_at = |app: &crate::utils::ElixirTestApp, height| {
+            app.wrap()
+                .query_wasm_smart::<TotalSupplyAtHeightResponse>(
+                    app_addr.clone(),
+                    &QueryMsg::TotalSupplyAtHeight {
+                        vault_id: 0,
+                        height,
+                    },
+                )
+                .unwrap()
+                .total_supply
+        };
+
+        let deposit_height = app.block_info().height;
+        deposit(&mut app, &user2, 1_000_000);
+        app.update_block(next_block);
+        deposit(&mut app, &user3, 2_000_000);
+        app.update_block(next_block);
+        let withdrawal_height = app.block_info().height;
+        let _withdrawal_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 500_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let _process_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ProcessWithdrawals {
+                    vault_id: 0,
+                    max_num_withdrawals: 1,
+                },
+                &[],
+            )
+            .unwrap();
+        app.update_block(next_block);
+
+        // snapshots are taken at the start of each block
+        assert!(balance_at(&app, deposit_height).is_zero());
+        assert!(supply_at(&app, deposit_height).is_zero());
+        assert!(balance_at(&app, deposit_height + 1) == Uint128::new(1_000_000));
+        assert!(supply_at(&app, deposit_height + 1) == Uint128::new(1_000_000));
+        assert!(balance_at(&app, withdrawal_height) == Uint128::new(1_000_000));
+        // the second deposit mints one LP token less as the deposit math rounds down
+        assert!(supply_at(&app, withdrawal_height) == Uint128::new(2_999_999));
+        let height = app.block_info().height;
+        let lp_tokens: LpTokenBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserLpTokens {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(balance_at(&app, height) == lp_tokens.balance);
+        assert!(
+            supply_at(&app, height)
+                == Uint128::new(2_999_999) - (Uint128::new(1_000_000) - lp_tokens.balance)
+        );
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<TotalSupplyAtHeightResponse>(
+                app_addr,
+                &QueryMsg::TotalSupplyAtHeight {
+                    vault_id: 0,
+                    height: deposit_height - 1,
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains(
+            &ContractError::LpSnapshotNotAvailable {
+                height: deposit_height - 1,
+                start_height: deposit_height,
+            }
+            .to_string()
+        ));
+    }
+
+    #[test]
+    fn rewards_accrue_per_lp_token_and_can_be_claimed() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let reward_denom = "adydx";
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let deposit = |app: &mut crate::utils::ElixirTestApp, user: &cosmwasm_std::Addr, amount| {
+            mint_native(app, user.to_string(), USDC_COIN_TYPE.to_string(), amount);
+            app.execute_contract(
+                user.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(amount),
+                }],
+            )
+            .unwrap();
+        };
+        let pending_rewards = |app: &crate::utils::ElixirTestApp, user: &cosmwasm_std::Addr| {
+            app.wrap()
+                .query_wasm_smart::<PendingRewardsResponse>(
+                    app_addr.clone(),
+                    &QueryMsg::PendingRewards {
+                        vault_id: 0,
+                        user: user.to_string(),
+                    },
+                )
+                .unwrap()
+                .rewards
+        };
+
+        deposit(&mut app, &user2, 1_000_000);
+
+        // only the admin can fund rewards
+        let start_time = app.block_info().time.seconds();
+        let fund_msg = ExecuteMsg::FundRewards {
+            vault_id: 0,
+            start_time,
+            end_time: start_time + 100,
+        };
+        mint_native(&mut app, user1.to_string(), reward_denom.to_string(), 1_000);
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &fund_msg,
+                &[Coin::new(1_000, reward_denom)],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ContractError>().unwrap(),
+            &ContractError::SenderIsNotAdmin {
+                sender: user1.clone()
+            }
+        );
+        mint_native(&mut app, owner.to_string(), reward_denom.to_string(), 1_000);
+        let fund_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &fund_msg,
+                &[Coin::new(1_000, reward_denom)],
+            )
+            .unwrap();
+        let events = fetch_response_events(&fund_response, "funded_rewards".to_string());
+        assert!(events.len() == 1);
+
+        // user2 holds all LP tokens for the first half of the stream
+        app.update_block(|block| block.time = block.time.plus_seconds(50));
+        assert!(pending_rewards(&app, &user2) == vec![Coin::new(500, reward_denom)]);
+
+        // user3 deposits the same amount and shares the second half
+        deposit(&mut app, &user3, 1_000_000);
+        app.update_block(|block| block.time = block.time.plus_seconds(50));
+        assert!(pending_rewards(&app, &user2) == vec![Coin::new(750, reward_denom)]);
+        assert!(pending_rewards(&app, &user3) == vec![Coin::new(250, reward_denom)]);
+
+        let streams: RewardStreamsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::RewardStreams { vault_id: 0 })
+            .unwrap();
+        assert!(streams.streams.len() == 1);
+        assert!(streams.streams[0].released == Uint128::new(1_000));
+
+        // user2 queues their LP tokens after the stream has ended, which does not change their rewards
+        app.execute_contract(
+            user2.clone(),
+            app_addr.clone(),
+            &ExecuteMsg::RequestWithdrawal {
+                vault_id: 0,
+                usdc_amount: 500_000,
+                recipient: None,
+            },
+            &[],
+        )
+        .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(100));
+        assert!(pending_rewards(&app, &user2) == vec![Coin::new(750, reward_denom)]);
+
+        let claim_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ClaimRewards { vault_id: 0 },
+                &[],
+            )
+            .unwrap();
+        let events = fetch_response_events(&claim_response, "claimed_rewards".to_string());
+        assert!(events.len() == 1);
+        let balance = app
+            .wrap()
+            .query_balance(user2.to_string(), reward_denom)
+            .unwrap();
+        assert!(balance.amount == Uint128::new(750));
+        assert!(pending_rewards(&app, &user2).is_empty());
+
+        // the ended stream is no longer updated
+        let streams: RewardStreamsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::RewardStreams { vault_id: 0 })
+            .unwrap();
+        let stream = &streams.streams[0].stream;
+        assert!(
+            stream.last_update >= stream.end_time
+                && stream.last_update < stream.end_time.plus_seconds(1)
+        );
+
+        let err = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ClaimRewards { vault_id: 0 },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ContractError>().unwrap(),
+            &ContractError::NoRewardsToClaim {
+                vault_id: 0,
+                user: user2.clone()
+            }
+        );
+    }
+
+    #[test]
+    fn rewards_released_without_eligible_lp_tokens_roll_over() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let reward_denom = "adydx";
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let start_time = app.block_info().time.seconds();
+        mint_native(&mut app, owner.to_string(), reward_denom.to_string(), 1_000);
+        app.execute_contract(
+            owner.clone(),
+            app_addr.clone(),
+            &ExecuteMsg::FundRewards {
+                vault_id: 0,
+                start_time,
+                end_time: start_time + 100,
+            },
+            &[Coin::new(1_000, reward_denom)],
+        )
+        .unwrap();
+
+        // the first half of the stream is released before anyone holds LP tokens
+        app.update_block(|block| block.time = block.time.plus_seconds(50));
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            1_000_000,
+        );
+        app.execute_contract(
+            user2.clone(),
+            app_addr.clone(),
+            &ExecuteMsg::DepositIntoVault {
+                vault_id: 0,
+                referrer: None,
+            },
+            &[Coin {
+                denom: USDC_COIN_TYPE.to_string(),
+                amount: Uint128::new(1_000_000),
+            }],
+        )
+        .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(50));
+
+        let pending: PendingRewardsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::PendingRewards {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(pending.rewards == vec![Coin::new(1_000, reward_denom)]);
+    }
+
+    #[test]
+    fn referrals_are_recorded_on_first_deposit() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let referrer = users[4].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let deposit = |app: &mut crate::utils::ElixirTestApp,
+                       user: &cosmwasm_std::Addr,
+                       amount,
+                       referrer: Option<&cosmwasm_std::Addr>| {
+            mint_native(app, user.to_string(), USDC_COIN_TYPE.to_string(), amount);
+            app.execute_contract(
+                user.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: referrer.map(|r| r.to_string()),
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(amount),
+                }],
+            )
+        };
+        let referral_stats = |app: &crate::utils::ElixirTestApp| -> ReferralStatsResponse {
+            app.wrap()
+                .query_wasm_smart(
+                    app_addr.clone(),
+                    &QueryMsg::ReferralStats {
+                        referrer: referrer.to_string(),
+                    },
+                )
+                .unwrap()
+        };
+
+        // depositors cannot refer themselves
+        let err = deposit(&mut app, &user2, 1_000_000, Some(&user2)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ContractError>().unwrap(),
+            &ContractError::SelfReferral {
+                referrer: user2.clone()
+            }
+        );
+
+        // the referrer of the first deposit is recorded
+        let deposit_response = deposit(&mut app, &user2, 1_000_000, Some(&referrer)).unwrap();
+        let deposit_events = fetch_response_events(&deposit_response, "new_deposit".to_string());
+        assert!(deposit_events[0]
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "referrer" && attr.value == referrer.as_str()));
+
+        // later deposits are attributed to the recorded referrer
+        deposit(&mut app, &user2, 500_000, Some(&user3)).unwrap();
+        deposit(&mut app, &user2, 500_000, None).unwrap();
+
+        // a referrer named after the first deposit is ignored
+        deposit(&mut app, &user3, 1_000_000, None).unwrap();
+        let deposit_response = deposit(&mut app, &user3, 1_000_000, Some(&referrer)).unwrap();
+        let deposit_events = fetch_response_events(&deposit_response, "new_deposit".to_string());
+        assert!(!deposit_events[0]
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "referrer"));
+
+        let stats = referral_stats(&app);
+        assert!(stats.referred_depositors == 1);
+        assert!(stats.deposit_volume == Uint128::new(2_000_000));
+        assert!(stats.fee_share.is_none());
+        let user3_stats: ReferralStatsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::ReferralStats {
+                    referrer: user3.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(user3_stats.referred_depositors == 0);
+        assert!(user3_stats.deposit_volume.is_zero());
+
+        // only the admin can set the fee share of a referrer, which is at most 1
+        let set_fee_share = |app: &mut crate::utils::ElixirTestApp,
+                             sender: &cosmwasm_std::Addr,
+                             fee_share: Decimal| {
+            app.execute_contract(
+                sender.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::SetReferralFeeShare {
+                    referrer: referrer.to_string(),
+                    fee_share: Some(fee_share),
+                },
+                &[],
+            )
+        };
+        let err = set_fee_share(&mut app, &user1, Decimal::percent(10)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ContractError>().unwrap(),
+            &ContractError::SenderIsNotAdmin {
+                sender: user1.clone()
+            }
+        );
+        let err = set_fee_share(&mut app, &owner, Decimal::percent(101)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ContractError>().unwrap(),
+            &ContractError::InvalidReferralFeeShare {
+                fee_share: "1.01".to_string()
+            }
+        );
+        set_fee_share(&mut app, &owner, Decimal::percent(10)).unwrap();
+
+        let stats = referral_stats(&app);
+        assert!(stats.fee_share == Some(Decimal::percent(10)));
+        assert!(stats.deposit_volume == Uint128::new(2_000_000));
+    }
 }
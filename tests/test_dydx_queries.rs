@@ -0,0 +1,105 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+    use elixir_dydx_integration::{
+        dydx::{
+            proto_structs::{Metadata, PerpetualClobMetadata, PerpetualMarketType},
+            query::{
+                AllClobPairsResponse, AllPerpetualsResponse, AssetResponse,
+                BlockRateLimitConfigurationResponse, MarketParamResponse,
+                PerpetualFeeParamsResponse,
+            },
+        },
+        msg::QueryMsg,
+    };
+
+    use crate::utils::{instantiate_contract, test_setup, USDC_COIN_TYPE, USDC_DENOM, USDC_ID};
+
+    #[test]
+    fn can_query_asset_and_market_param() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+
+        let app_addr = instantiate_contract(&mut app, code_id, owner.clone());
+
+        let asset_resp: AssetResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Asset { id: USDC_ID })
+            .unwrap();
+        assert!(asset_resp.asset.id == USDC_ID);
+        assert!(asset_resp.asset.symbol == "USDC");
+        assert!(asset_resp.asset.denom == USDC_COIN_TYPE);
+        assert!(asset_resp.asset.atomic_resolution == -(USDC_DENOM as i32));
+
+        let market_param_resp: MarketParamResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::MarketParam { id: 0 })
+            .unwrap();
+        assert!(market_param_resp.market_param.pair == "BTC-USD");
+        assert!(market_param_resp.market_param.exponent == -5);
+        assert!(market_param_resp.market_param.min_price_change_ppm == 1000);
+
+        let err = app
+            .wrap()
+            .query_wasm_smart::<AssetResponse>(app_addr, &QueryMsg::Asset { id: 1 })
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only USDC with asset id: 0 is supported for testing"));
+    }
+
+    #[test]
+    fn can_query_all_perpetuals_and_clob_pairs() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+
+        let app_addr = instantiate_contract(&mut app, code_id, owner.clone());
+
+        let perpetuals_resp: AllPerpetualsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::AllPerpetuals {})
+            .unwrap();
+        assert!(perpetuals_resp.perpetuals.len() == 3);
+        assert!(perpetuals_resp.perpetuals[0].params.ticker == "BTC-USD");
+        assert!(perpetuals_resp.perpetuals[0].params.atomic_resolution == -10);
+        assert!(perpetuals_resp.perpetuals[1].params.ticker == "ETH-USD");
+        assert!(perpetuals_resp.perpetuals[2].params.market_type == PerpetualMarketType::Isolated);
+
+        let clob_pairs_resp: AllClobPairsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr, &QueryMsg::AllClobPairs {})
+            .unwrap();
+        assert!(clob_pairs_resp.clob_pairs.len() == 3);
+        assert!(
+            clob_pairs_resp.clob_pairs[0].metadata
+                == Metadata::PerpetualClobMetadata(PerpetualClobMetadata { perpetual_id: 0 })
+        );
+        assert!(clob_pairs_resp.clob_pairs[0].subticks_per_tick == 100000);
+    }
+
+    #[test]
+    fn can_query_fee_tiers_and_block_rate_limits() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+
+        let app_addr = instantiate_contract(&mut app, code_id, owner.clone());
+
+        let fee_resp: PerpetualFeeParamsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::PerpetualFeeParams {})
+            .unwrap();
+        assert!(fee_resp.params.tiers.len() == 2);
+        assert!(fee_resp.params.tiers[1].maker_fee_ppm == -110);
+
+        let rate_limit_resp: BlockRateLimitConfigurationResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr, &QueryMsg::BlockRateLimitConfiguration {})
+            .unwrap();
+        let config = rate_limit_resp.block_rate_limit_config;
+        assert!(config.max_short_term_orders_per_n_blocks.is_empty());
+        assert!(config.max_stateful_orders_per_n_blocks.len() == 2);
+        assert!(config.max_stateful_orders_per_n_blocks[1].num_blocks == 100);
+        assert!(config.max_short_term_orders_and_cancels_per_n_blocks[0].limit == 400);
+    }
+}
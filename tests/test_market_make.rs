@@ -6,22 +6,24 @@ mod tests {
     use cw_multi_test::Executor;
     use elixir_dydx_integration::{
         dydx::{
-            msg::{OrderSide, OrderTimeInForce},
+            msg::{OrderBatch, OrderSide, OrderTimeInForce},
             proto_structs::PerpetualPosition,
             serializable_int::SerializableInt,
         },
         error::ContractError,
-        execute::{market_make::NewOrder, USDC_COIN_TYPE},
+        execute::market_make::NewOrder,
         msg::ExecuteMsg,
     };
     use num_bigint::BigInt;
 
     use crate::utils::{
-        fetch_response_events, instantiate_contract_with_trader_and_vault, mint_native, test_setup,
+        fetch_attributes, fetch_response_events, instantiate_contract_with_trader_and_vault,
+        mint_native, test_setup, USDC_COIN_TYPE,
     };
 
     const CLIENT_ID: u32 = 101;
     const CLOB_PAIR_ID: u32 = 0;
+    const VAULT_ID: u32 = 0;
     const SUBACCOUNT_NUMBER: u32 = 0;
     const BLOCK_TIME: u32 = 1720791702;
 
@@ -64,7 +66,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -77,7 +82,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order()],
                     cancel_client_ids: vec![],
@@ -112,7 +117,7 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order()],
                     cancel_client_ids: vec![],
@@ -148,7 +153,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -161,7 +169,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order()],
                     cancel_client_ids: vec![],
@@ -178,7 +186,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![],
                     cancel_client_ids: vec![CLIENT_ID],
@@ -220,7 +228,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -233,7 +244,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order()],
                     cancel_client_ids: vec![],
@@ -250,7 +261,7 @@ mod tests {
                 user2.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![],
                     cancel_client_ids: vec![CLIENT_ID],
@@ -286,7 +297,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -303,7 +317,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders,
                     cancel_client_ids: vec![],
@@ -350,7 +364,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -368,7 +385,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders,
                     cancel_client_ids: vec![],
@@ -404,7 +421,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -421,7 +441,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders,
                     cancel_client_ids: vec![],
@@ -446,7 +466,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![],
                     cancel_client_ids: vec![CLIENT_ID, CLIENT_ID + 1],
@@ -493,7 +513,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -510,7 +533,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders,
                     cancel_client_ids: vec![],
@@ -530,7 +553,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders,
                     cancel_client_ids: vec![],
@@ -550,7 +573,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders,
                     cancel_client_ids: vec![],
@@ -565,7 +588,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![],
                     cancel_client_ids: vec![
@@ -609,7 +632,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -621,7 +647,7 @@ mod tests {
             user1.clone(),
             app_addr.clone(),
             &ExecuteMsg::MarketMake {
-                subaccount_number: SUBACCOUNT_NUMBER,
+                vault_id: VAULT_ID,
                 clob_pair_id: CLOB_PAIR_ID,
                 new_orders: vec![new_order()],
                 cancel_client_ids: vec![],
@@ -635,7 +661,7 @@ mod tests {
         if let Some(error) = place_response.unwrap_err().downcast_ref::<ContractError>() {
             assert_eq!(
                 error,
-                &ContractError::NewOrdersWouldIncreaseLeverageTooMuch { perp_id: 0 }
+                &ContractError::NewOrdersWouldIncreaseLeverageTooMuch { vault_id: 0 }
             );
         } else {
             panic!("Expected ContractError::NewOrderWouldIncreaseLeverageTooMuch");
@@ -646,7 +672,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(1),
@@ -659,7 +688,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order()],
                     cancel_client_ids: vec![],
@@ -706,7 +735,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -720,7 +752,7 @@ mod tests {
             user1.clone(),
             app_addr.clone(),
             &ExecuteMsg::MarketMake {
-                subaccount_number: SUBACCOUNT_NUMBER,
+                vault_id: VAULT_ID,
                 clob_pair_id: CLOB_PAIR_ID,
                 new_orders: vec![new_order.clone()],
                 cancel_client_ids: vec![],
@@ -734,7 +766,7 @@ mod tests {
         if let Some(error) = place_response.unwrap_err().downcast_ref::<ContractError>() {
             assert_eq!(
                 error,
-                &ContractError::NewOrdersWouldIncreaseLeverageTooMuch { perp_id: 0 }
+                &ContractError::NewOrdersWouldIncreaseLeverageTooMuch { vault_id: 0 }
             );
         } else {
             panic!("Expected ContractError::NewOrderWouldIncreaseLeverageTooMuch");
@@ -747,7 +779,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order],
                     cancel_client_ids: vec![],
@@ -784,7 +816,10 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::DepositIntoVault { perp_id: 0 },
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
                 &[Coin {
                     denom: USDC_COIN_TYPE.to_string(),
                     amount: Uint128::new(deposit_amount),
@@ -792,10 +827,7 @@ mod tests {
             )
             .unwrap();
 
-        let mut orders1 = vec![new_order(), new_order(), new_order(), new_order()];
         let mut orders2 = vec![new_order()];
-        orders1[1].client_id += 1;
-        orders1[2].client_id += 2;
         orders2[0].client_id += 3;
 
         let _place_response = app
@@ -803,7 +835,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: vec![new_order()],
                     cancel_client_ids: vec![],
@@ -820,7 +852,7 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::MarketMake {
-                    subaccount_number: SUBACCOUNT_NUMBER,
+                    vault_id: VAULT_ID,
                     clob_pair_id: CLOB_PAIR_ID,
                     new_orders: orders2,
                     cancel_client_ids: vec![CLIENT_ID, CLIENT_ID + 1, CLIENT_ID + 2],
@@ -848,7 +880,7 @@ mod tests {
         assert!(place_events.len() == 1);
         assert!(place_events[0].ty == "wasm-placed_order");
         assert!(place_events[0].attributes.len() == 7);
-        assert!(place_events[0].attributes[1].key == "perp_id");
+        assert!(place_events[0].attributes[1].key == "vault_id");
         assert!(place_events[0].attributes[1].value == "0");
         assert!(place_events[0].attributes[2].key == "client_id");
         assert!(place_events[0].attributes[2].value == "104");
@@ -865,7 +897,7 @@ mod tests {
             fetch_response_events(&place2_response, "cancelled_order".to_string());
         assert!(cancelled_events.len() == 3);
         assert!(cancelled_events[0].attributes.len() == 5);
-        assert!(cancelled_events[0].attributes[1].key == "perp_id");
+        assert!(cancelled_events[0].attributes[1].key == "vault_id");
         assert!(cancelled_events[0].attributes[1].value == "0");
         assert!(cancelled_events[0].attributes[2].key == "client_id");
         assert!(cancelled_events[0].attributes[2].value == "101");
@@ -874,7 +906,7 @@ mod tests {
         assert!(cancelled_events[0].attributes[4].key == "cancel_good_til_block_time");
         assert!(cancelled_events[0].attributes[4].value == "0");
 
-        assert!(cancelled_events[1].attributes[1].key == "perp_id");
+        assert!(cancelled_events[1].attributes[1].key == "vault_id");
         assert!(cancelled_events[1].attributes[1].value == "0");
         assert!(cancelled_events[1].attributes[2].key == "client_id");
         assert!(cancelled_events[1].attributes[2].value == "102");
@@ -883,7 +915,7 @@ mod tests {
         assert!(cancelled_events[1].attributes[4].key == "cancel_good_til_block_time");
         assert!(cancelled_events[1].attributes[4].value == "0");
 
-        assert!(cancelled_events[2].attributes[1].key == "perp_id");
+        assert!(cancelled_events[2].attributes[1].key == "vault_id");
         assert!(cancelled_events[2].attributes[1].value == "0");
         assert!(cancelled_events[2].attributes[2].key == "client_id");
         assert!(cancelled_events[2].attributes[2].value == "103");
@@ -892,4 +924,292 @@ mod tests {
         assert!(cancelled_events[2].attributes[4].key == "cancel_good_til_block_time");
         assert!(cancelled_events[2].attributes[4].value == "0");
     }
+
+    #[test]
+    fn trader_can_batch_cancel_orders() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let deposit_amount = 10_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        mint_native(
+            &mut app,
+            user1.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+
+        let _deposit_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        let mut new_orders = vec![new_order(), new_order(), new_order()];
+        new_orders[1].client_id += 1;
+        new_orders[2].client_id += 2;
+
+        let _place_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::MarketMake {
+                    vault_id: VAULT_ID,
+                    clob_pair_id: CLOB_PAIR_ID,
+                    new_orders,
+                    cancel_client_ids: vec![],
+                    cancel_good_til_block_time: 0,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let good_til_block = app.block_info().height as u32 + 5;
+        let cancel_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::BatchCancel {
+                    vault_id: VAULT_ID,
+                    order_batches: vec![OrderBatch {
+                        clob_pair_id: CLOB_PAIR_ID,
+                        client_ids: vec![CLIENT_ID, CLIENT_ID + 2],
+                    }],
+                    good_til_block,
+                },
+                &[],
+            )
+            .unwrap();
+
+        assert!(!app.router().custom.has_order(SUBACCOUNT_NUMBER, CLIENT_ID));
+        assert!(app
+            .router()
+            .custom
+            .has_order(SUBACCOUNT_NUMBER, CLIENT_ID + 1));
+        assert!(!app
+            .router()
+            .custom
+            .has_order(SUBACCOUNT_NUMBER, CLIENT_ID + 2));
+
+        let method_attributes = fetch_attributes(&cancel_response, "method".to_string());
+        assert!(method_attributes[0].value == "batch_cancel");
+        let cancel_events =
+            fetch_response_events(&cancel_response, "batch_cancelled_orders".to_string());
+        assert!(cancel_events.len() == 1);
+        assert!(cancel_events[0].attributes[3].key == "client_ids");
+        assert!(cancel_events[0].attributes[3].value == "101,103");
+    }
+
+    #[test]
+    fn batch_cancel_is_limited_by_chain_config() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let height = app.block_info().height as u32;
+
+        // the mocked chain allows 20 short-term cancellations per block
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::BatchCancel {
+                    vault_id: VAULT_ID,
+                    order_batches: vec![
+                        OrderBatch {
+                            clob_pair_id: CLOB_PAIR_ID,
+                            client_ids: (0..15).collect(),
+                        },
+                        OrderBatch {
+                            clob_pair_id: CLOB_PAIR_ID + 1,
+                            client_ids: (0..6).collect(),
+                        },
+                    ],
+                    good_til_block: height + 5,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::BatchCancelTooLarge {
+                    num_client_ids: 21,
+                    max: 20
+                })
+        );
+
+        // good til block must be within the short block window
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::BatchCancel {
+                    vault_id: VAULT_ID,
+                    order_batches: vec![OrderBatch {
+                        clob_pair_id: CLOB_PAIR_ID,
+                        client_ids: vec![CLIENT_ID],
+                    }],
+                    good_til_block: height + 21,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidGoodTilBlock {
+                    good_til_block: height + 21,
+                    current_block: height as u64,
+                    max_block: height as u64 + 20,
+                })
+        );
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::BatchCancel {
+                    vault_id: VAULT_ID,
+                    order_batches: vec![OrderBatch {
+                        clob_pair_id: CLOB_PAIR_ID,
+                        client_ids: vec![CLIENT_ID],
+                    }],
+                    good_til_block: height + 5,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::SenderIsNotTrader {
+                    sender: owner.clone()
+                })
+        );
+    }
+
+    #[test]
+    fn isolated_vaults_are_margined_by_their_subaccount_value() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let isolated_vault_id = 1;
+        let sol_clob_pair_id = 2;
+        let deposit_amount = 1_000_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let _cv = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: isolated_vault_id,
+                    perp_id: 2,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        mint_native(
+            &mut app,
+            user1.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+        let _deposit_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: isolated_vault_id,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        // 6 SOL at $150 is a $900 long in the isolated subaccount
+        app.router().custom.sudo_add_perp_position(
+            128,
+            PerpetualPosition {
+                perpetual_id: 2,
+                quantums: SerializableInt::new(60_000_000.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+
+        // a $500 bid fits in the $1900 subaccount value, even though it exceeds the $1000 asset position
+        let _mm = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::MarketMake {
+                    vault_id: isolated_vault_id,
+                    clob_pair_id: sol_clob_pair_id,
+                    new_orders: vec![NewOrder {
+                        quantums: 500_000_000,
+                        ..new_order()
+                    }],
+                    cancel_client_ids: vec![],
+                    cancel_good_til_block_time: 0,
+                },
+                &[],
+            )
+            .unwrap();
+        assert!(app.router().custom.has_order(128, CLIENT_ID));
+
+        let err = app
+            .execute_contract(
+                user1,
+                app_addr,
+                &ExecuteMsg::MarketMake {
+                    vault_id: isolated_vault_id,
+                    clob_pair_id: sol_clob_pair_id,
+                    new_orders: vec![NewOrder {
+                        client_id: CLIENT_ID + 1,
+                        quantums: 1_100_000_000,
+                        ..new_order()
+                    }],
+                    cancel_client_ids: vec![],
+                    cancel_good_til_block_time: 0,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::NewOrdersWouldIncreaseLeverageTooMuch {
+                    vault_id: isolated_vault_id
+                })
+        );
+    }
 }
@@ -0,0 +1,429 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::{to_json_vec, Addr, Coin, Uint128};
+    use cw_multi_test::{next_block, Executor};
+    use cw_storage_plus::Map;
+    use elixir_dydx_integration::{
+        dydx::msg::DydxMsgEncoding,
+        error::ContractError,
+        migrations::{parse_version, pending_migrations, MIGRATIONS},
+        msg::{
+            BalanceAtHeightResponse, CollateralResponse, ExecuteMsg, InstantiateMsg, MigrateMsg,
+            QueryMsg, TraderResponse, VaultResponse, WithdrawalsResponse,
+        },
+        state::{
+            Collateral, Vault, VaultStatus, LEGACY_VAULTS_BY_PERP_ID, LP_SNAPSHOTS_START_HEIGHT,
+            VAULTS, VAULT_CONFIGS, VAULT_LP_HELD_BY_VAULTS, VAULT_LP_HOLDINGS,
+        },
+    };
+    use serde_json::json;
+
+    use crate::utils::{
+        fetch_attributes, mint_native, test_setup, usdc_collateral, ElixirTestApp, USDC_COIN_TYPE,
+    };
+
+    const CONTRACT_NAME: &str = env!("CARGO_PKG_NAME");
+    const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+    /// Instantiates the contract with the owner as its wasm admin, a vault on perp 0 and a queued withdrawal of user2.
+    fn instantiate_migratable_contract(
+        app: &mut ElixirTestApp,
+        code_id: u64,
+        users: &[Addr],
+    ) -> Addr {
+        let owner = users[0].clone();
+        let trader = users[1].clone();
+        let user2 = users[2].clone();
+        let app_addr = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &InstantiateMsg {
+                    owner: owner.to_string(),
+                    msg_encoding: None,
+                    collateral: usdc_collateral(),
+                },
+                &[],
+                "Contract",
+                Some(owner.to_string()),
+            )
+            .unwrap();
+        let _set_trader = app
+            .execute_contract(
+                owner,
+                app_addr.clone(),
+                &ExecuteMsg::SetTrader {
+                    new_trader: trader.to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+        let _create_vault = app
+            .execute_contract(
+                trader,
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 0,
+                    perp_id: 0,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+        mint_native(
+            app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            1_000_000,
+        );
+        let _deposit = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(1_000_000),
+                }],
+            )
+            .unwrap();
+        let _request = app
+            .execute_contract(
+                user2,
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 500_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        app_addr
+    }
+
+    /// Rewrites the contract storage into the layout of version 0.1.0:
+    /// vaults keyed by perp id, a state without encoding and collateral, withdrawal requests without owners
+    /// and no LP snapshot start height.
+    fn write_0_1_0_state(app: &mut ElixirTestApp, app_addr: &Addr, users: &[Addr]) {
+        let mut storage = app.contract_storage_mut(app_addr);
+        cw2::set_contract_version(&mut *storage, CONTRACT_NAME, "0.1.0").unwrap();
+        storage.set(
+            b"state",
+            &to_json_vec(&json!({
+                "admin": users[0],
+                "trader": users[1],
+                "contract": app_addr,
+            }))
+            .unwrap(),
+        );
+        VAULTS.remove(&mut *storage, 0);
+        VAULT_CONFIGS.remove(&mut *storage, 0);
+        LP_SNAPSHOTS_START_HEIGHT.remove(&mut *storage);
+        LEGACY_VAULTS_BY_PERP_ID
+            .save(&mut *storage, 0, &true)
+            .unwrap();
+
+        let withdrawal_queues: Map<u32, Vec<serde_json::Value>> = Map::new("withdrawal_queues");
+        let queue = withdrawal_queues.load(&*storage, 0).unwrap();
+        let legacy_queue = queue
+            .into_iter()
+            .map(|request| {
+                json!({
+                    "recipient_addr": request["recipient_addr"],
+                    "lp_tokens": request["lp_tokens"],
+                })
+            })
+            .collect::<Vec<_>>();
+        withdrawal_queues
+            .save(&mut *storage, 0, &legacy_queue)
+            .unwrap();
+    }
+
+    fn set_stored_version(app: &mut ElixirTestApp, app_addr: &Addr, contract: &str, version: &str) {
+        let mut storage = app.contract_storage_mut(app_addr);
+        cw2::set_contract_version(&mut *storage, contract, version).unwrap();
+    }
+
+    #[test]
+    fn migrates_state_from_0_1_0() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+
+        let app_addr = instantiate_migratable_contract(&mut app, code_id, &users);
+        write_0_1_0_state(&mut app, &app_addr, &users);
+        app.update_block(next_block);
+        let err = app
+            .wrap()
+            .query_wasm_smart::<VaultResponse>(app_addr.clone(), &QueryMsg::Vault { vault_id: 0 })
+            .unwrap_err();
+        assert!(err.to_string().contains("not initialized"));
+
+        let migrate_response = app
+            .migrate_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &MigrateMsg {
+                    new_admin: Some(user3.to_string()),
+                    new_trader: Some(user3.to_string()),
+                    msg_encoding: None,
+                },
+                code_id,
+            )
+            .unwrap();
+        let migrations = fetch_attributes(&migrate_response, "migrations".to_string());
+        assert!(migrations.len() == 1);
+        assert!(migrations[0].value == "0.2.0,0.3.0");
+        let admin = fetch_attributes(&migrate_response, "admin".to_string());
+        assert!(admin[0].value == user3.as_str());
+
+        let version = cw2::query_contract_info(&app.wrap(), app_addr.clone()).unwrap();
+        assert!(version.contract == CONTRACT_NAME);
+        assert!(version.version == CONTRACT_VERSION);
+
+        let vault_resp: VaultResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vault { vault_id: 0 })
+            .unwrap();
+        assert!(
+            vault_resp.vault
+                == Vault {
+                    vault_id: 0,
+                    perp_id: 0,
+                    subaccount_number: 0,
+                    isolated: false,
+                    status: VaultStatus::Active,
+                }
+        );
+        let trader: TraderResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Trader {})
+            .unwrap();
+        assert!(trader.trader == user3);
+        let collateral: CollateralResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Collateral {})
+            .unwrap();
+        assert!(collateral.collateral == Collateral::mainnet_usdc());
+        let withdrawals: WithdrawalsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::Withdrawals {
+                    vault_id: 0,
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(withdrawals.withdrawal_queue.len() == 1);
+        assert!(withdrawals.withdrawal_queue[0].owner == user2);
+        assert!(withdrawals.withdrawal_queue[0].recipient_addr == user2);
+
+        // existing LP balances are snapshotted from the migration height
+        let migration_height = app.block_info().height;
+        let balance: BalanceAtHeightResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::BalanceAtHeight {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                    height: migration_height,
+                },
+            )
+            .unwrap();
+        assert!(balance.balance == Uint128::new(500_000));
+        let err = app
+            .wrap()
+            .query_wasm_smart::<BalanceAtHeightResponse>(
+                app_addr.clone(),
+                &QueryMsg::BalanceAtHeight {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                    height: migration_height - 1,
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains(
+            &ContractError::LpSnapshotNotAvailable {
+                height: migration_height - 1,
+                start_height: migration_height,
+            }
+            .to_string()
+        ));
+
+        // the migrated vault keeps working with the new trader
+        let _create_vault = app
+            .execute_contract(
+                user3,
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        // the same version cannot be migrated again
+        let err = app
+            .migrate_contract(owner, app_addr, &MigrateMsg::default(), code_id)
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotMigrate {
+                    from_version: CONTRACT_VERSION.to_string(),
+                    to_version: CONTRACT_VERSION.to_string(),
+                    reason: "can only upgrade from an older version".to_string()
+                })
+        );
+    }
+
+    #[test]
+    fn migration_counts_vault_lp_held_by_vaults() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+
+        let app_addr = instantiate_migratable_contract(&mut app, code_id, &users);
+        {
+            let mut storage = app.contract_storage_mut(&app_addr);
+            for (holder, issuer, amount) in [(1, 0, 100_u128), (2, 0, 50), (0, 1, 30)] {
+                VAULT_LP_HOLDINGS
+                    .save(&mut *storage, (holder, issuer), &Uint128::new(amount))
+                    .unwrap();
+            }
+        }
+        set_stored_version(&mut app, &app_addr, CONTRACT_NAME, "0.2.0");
+        app.migrate_contract(owner, app_addr.clone(), &MigrateMsg::default(), code_id)
+            .unwrap();
+
+        for (issuer, held) in [(0, 150), (1, 30)] {
+            let held_by_vaults = VAULT_LP_HELD_BY_VAULTS
+                .query(&app.wrap(), app_addr.clone(), issuer)
+                .unwrap();
+            assert!(held_by_vaults == Some(Uint128::new(held)));
+        }
+    }
+
+    #[test]
+    fn migration_overrides_are_optional() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let trader = users[1].clone();
+
+        let app_addr = instantiate_migratable_contract(&mut app, code_id, &users);
+        set_stored_version(&mut app, &app_addr, CONTRACT_NAME, "0.1.9");
+        let migrate_response = app
+            .migrate_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &MigrateMsg {
+                    new_admin: None,
+                    new_trader: None,
+                    msg_encoding: Some(DydxMsgEncoding::Stargate),
+                },
+                code_id,
+            )
+            .unwrap();
+        let admin = fetch_attributes(&migrate_response, "admin".to_string());
+        assert!(admin[0].value == owner.as_str());
+        let msg_encoding = fetch_attributes(&migrate_response, "msg_encoding".to_string());
+        assert!(msg_encoding[0].value == DydxMsgEncoding::Stargate.to_string());
+        let trader_resp: TraderResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr, &QueryMsg::Trader {})
+            .unwrap();
+        assert!(trader_resp.trader == trader);
+    }
+
+    #[test]
+    fn migration_compares_versions_as_semver() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+
+        let app_addr = instantiate_migratable_contract(&mut app, code_id, &users);
+
+        // "0.10.0" sorts before "0.2.0" as a string, but is newer
+        set_stored_version(&mut app, &app_addr, CONTRACT_NAME, "0.10.0");
+        let err = app
+            .migrate_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &MigrateMsg::default(),
+                code_id,
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotMigrate {
+                    from_version: "0.10.0".to_string(),
+                    to_version: CONTRACT_VERSION.to_string(),
+                    reason: "can only upgrade from an older version".to_string()
+                })
+        );
+
+        set_stored_version(&mut app, &app_addr, CONTRACT_NAME, "latest");
+        let err = app
+            .migrate_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &MigrateMsg::default(),
+                code_id,
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ContractError>(),
+            Some(ContractError::InvalidContractVersion { version, .. }) if version == "latest"
+        ));
+
+        set_stored_version(&mut app, &app_addr, "crates.io:cw20-base", "0.1.0");
+        let err = app
+            .migrate_contract(owner, app_addr, &MigrateMsg::default(), code_id)
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotMigrate {
+                    from_version: "0.1.0".to_string(),
+                    to_version: CONTRACT_VERSION.to_string(),
+                    reason: "can only upgrade from the same contract type, not crates.io:cw20-base"
+                        .to_string()
+                })
+        );
+    }
+
+    #[test]
+    fn migrations_are_registered_in_version_order() {
+        let contract_version = parse_version(CONTRACT_VERSION).unwrap();
+        let versions = MIGRATIONS
+            .iter()
+            .map(|migration| parse_version(migration.version).unwrap())
+            .collect::<Vec<_>>();
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(versions.iter().all(|version| *version <= contract_version));
+
+        let pending = pending_migrations(&parse_version("0.1.0").unwrap(), &contract_version)
+            .unwrap()
+            .iter()
+            .map(|migration| migration.version)
+            .collect::<Vec<_>>();
+        assert!(pending == vec!["0.2.0", "0.3.0"]);
+        let pending = pending_migrations(&parse_version("0.2.0").unwrap(), &contract_version)
+            .unwrap()
+            .iter()
+            .map(|migration| migration.version)
+            .collect::<Vec<_>>();
+        assert!(pending == vec!["0.3.0"]);
+        let pending =
+            pending_migrations(&parse_version("0.3.0").unwrap(), &contract_version).unwrap();
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::Binary;
+    use cw_multi_test::Executor;
+    use elixir_dydx_integration::{
+        error::ContractError,
+        execute::permissioned_keys::PermissionedMessageType,
+        msg::{ExecuteMsg, PermissionedKeysResponse, QueryMsg},
+    };
+
+    use crate::utils::{
+        fetch_attributes, fetch_response_events, instantiate_contract_with_trader_and_vault,
+        test_setup,
+    };
+
+    fn test_public_key(seed: u8) -> Binary {
+        let mut key = vec![seed; 33];
+        key[0] = 0x02;
+        Binary::from(key)
+    }
+
+    #[test]
+    fn admin_can_add_and_remove_permissioned_keys() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let _cv1 = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let add_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::AddPermissionedKey {
+                    vault_id: 0,
+                    public_key: test_public_key(1),
+                    clob_pair_ids: vec![0],
+                    message_types: vec![
+                        PermissionedMessageType::PlaceOrder,
+                        PermissionedMessageType::CancelOrder,
+                    ],
+                },
+                &[],
+            )
+            .unwrap();
+        let _add_eth_key = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::AddPermissionedKey {
+                    vault_id: 1,
+                    public_key: test_public_key(2),
+                    clob_pair_ids: vec![1, 0, 1],
+                    message_types: vec![PermissionedMessageType::BatchCancel],
+                },
+                &[],
+            )
+            .unwrap();
+
+        let method_attributes = fetch_attributes(&add_response, "method".to_string());
+        assert!(method_attributes.len() == 1);
+        assert!(method_attributes[0].value == "add_permissioned_key");
+        let add_events = fetch_response_events(&add_response, "added_permissioned_key".to_string());
+        assert!(add_events.len() == 1);
+        assert!(add_events[0].attributes[1].value == "0");
+        assert!(add_events[0].attributes[2].value == test_public_key(1).to_base64());
+        assert!(add_events[0].attributes[3].value == "0");
+        assert!(
+            add_events[0].attributes[4].value
+                == "/dydxprotocol.clob.MsgPlaceOrder,/dydxprotocol.clob.MsgCancelOrder"
+        );
+
+        let btc_keys: PermissionedKeysResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::PermissionedKeys { vault_id: 0 },
+            )
+            .unwrap();
+        assert!(btc_keys.keys.len() == 1);
+        assert!(btc_keys.keys[0].authenticator_id == 0);
+        assert!(btc_keys.keys[0].public_key == test_public_key(1));
+        assert!(btc_keys.keys[0].subaccount_number == 0);
+        assert!(btc_keys.keys[0].clob_pair_ids == vec![0]);
+        assert!(
+            btc_keys.keys[0].message_types
+                == vec![
+                    PermissionedMessageType::PlaceOrder,
+                    PermissionedMessageType::CancelOrder
+                ]
+        );
+
+        let eth_keys: PermissionedKeysResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::PermissionedKeys { vault_id: 1 },
+            )
+            .unwrap();
+        assert!(eth_keys.keys.len() == 1);
+        assert!(eth_keys.keys[0].authenticator_id == 1);
+        assert!(eth_keys.keys[0].clob_pair_ids == vec![0, 1]);
+        assert!(eth_keys.keys[0].message_types == vec![PermissionedMessageType::BatchCancel]);
+
+        // keys can only be removed through the vault they are scoped to
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RemovePermissionedKey {
+                    vault_id: 0,
+                    authenticator_id: 1,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::PermissionedKeyNotFound {
+                    vault_id: 0,
+                    authenticator_id: 1
+                })
+        );
+
+        let remove_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RemovePermissionedKey {
+                    vault_id: 0,
+                    authenticator_id: 0,
+                },
+                &[],
+            )
+            .unwrap();
+        let remove_events =
+            fetch_response_events(&remove_response, "removed_permissioned_key".to_string());
+        assert!(remove_events.len() == 1);
+        assert!(remove_events[0].attributes[1].value == "0");
+        assert!(remove_events[0].attributes[2].value == "0");
+
+        let btc_keys: PermissionedKeysResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::PermissionedKeys { vault_id: 0 },
+            )
+            .unwrap();
+        assert!(btc_keys.keys.is_empty());
+        let eth_keys: PermissionedKeysResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr, &QueryMsg::PermissionedKeys { vault_id: 1 })
+            .unwrap();
+        assert!(eth_keys.keys.len() == 1);
+    }
+
+    #[test]
+    fn permissioned_keys_are_validated() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let add_key = |public_key: Binary,
+                       vault_id: u32,
+                       clob_pair_ids: Vec<u32>,
+                       message_types: Vec<PermissionedMessageType>| {
+            ExecuteMsg::AddPermissionedKey {
+                vault_id,
+                public_key,
+                clob_pair_ids,
+                message_types,
+            }
+        };
+
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &add_key(
+                    test_public_key(1),
+                    0,
+                    vec![0],
+                    vec![PermissionedMessageType::PlaceOrder],
+                ),
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::SenderIsNotAdmin {
+                    sender: user1.clone()
+                })
+        );
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &add_key(
+                    test_public_key(1),
+                    1,
+                    vec![1],
+                    vec![PermissionedMessageType::PlaceOrder],
+                ),
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::VaultNotInitialized { vault_id: 1 })
+        );
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &add_key(
+                    Binary::from(vec![2; 20]),
+                    0,
+                    vec![0],
+                    vec![PermissionedMessageType::PlaceOrder],
+                ),
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidPermissionedKey {
+                    vault_id: 0,
+                    reason: "public key must be a 33 byte compressed secp256k1 key".to_string()
+                })
+        );
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &add_key(
+                    test_public_key(1),
+                    0,
+                    vec![],
+                    vec![PermissionedMessageType::PlaceOrder],
+                ),
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidPermissionedKey {
+                    vault_id: 0,
+                    reason: "at least one clob pair id is required".to_string()
+                })
+        );
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &add_key(
+                    test_public_key(1),
+                    0,
+                    vec![0, 7],
+                    vec![PermissionedMessageType::PlaceOrder],
+                ),
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidPermissionedKey {
+                    vault_id: 0,
+                    reason: "clob pair id 7 does not exist".to_string()
+                })
+        );
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &add_key(test_public_key(1), 0, vec![0], vec![]),
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidPermissionedKey {
+                    vault_id: 0,
+                    reason: "at least one message type is required".to_string()
+                })
+        );
+
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RemovePermissionedKey {
+                    vault_id: 0,
+                    authenticator_id: 0,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::SenderIsNotAdmin { sender: user1 })
+        );
+
+        let err = app
+            .execute_contract(
+                owner,
+                app_addr,
+                &ExecuteMsg::RemovePermissionedKey {
+                    vault_id: 0,
+                    authenticator_id: 0,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::PermissionedKeyNotFound {
+                    vault_id: 0,
+                    authenticator_id: 0
+                })
+        );
+    }
+}
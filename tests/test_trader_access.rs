@@ -2,9 +2,18 @@ mod utils;
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::{fetch_attributes, fetch_response_events, instantiate_contract, test_setup};
+    use crate::utils::{
+        fetch_attributes, fetch_response_events, instantiate_contract, mint_native, test_setup,
+        usdc_collateral, USDC_COIN_TYPE,
+    };
+    use cosmwasm_std::Coin;
     use cw_multi_test::Executor;
-    use elixir_dydx_integration::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, TraderResponse};
+    use elixir_dydx_integration::{
+        dydx::msg::DydxMsgEncoding,
+        error::ContractError,
+        msg::{CollateralResponse, ExecuteMsg, InstantiateMsg, QueryMsg, TraderResponse},
+        state::Collateral,
+    };
 
     #[test]
     fn can_instantiate_contract() {
@@ -20,6 +29,123 @@ mod tests {
         assert_eq!(resp, TraderResponse { trader: owner });
     }
 
+    #[test]
+    fn instantiation_validates_collateral() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+
+        let mut instantiate = |collateral: Collateral| {
+            app.instantiate_contract(
+                code_id,
+                owner.clone(),
+                &InstantiateMsg {
+                    owner: owner.to_string(),
+                    msg_encoding: None,
+                    collateral,
+                },
+                &[],
+                "Contract",
+                None,
+            )
+        };
+
+        let err = instantiate(Collateral {
+            denom: "uusdc".to_string(),
+            ..usdc_collateral()
+        })
+        .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidCollateral {
+                    reason: format!("asset id 0 has denom {}, not uusdc", USDC_COIN_TYPE)
+                })
+        );
+
+        let err = instantiate(Collateral {
+            decimals: 18,
+            ..usdc_collateral()
+        })
+        .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidCollateral {
+                    reason: "18 decimals do not match the atomic resolution -6 and denom exponent -6 of asset id 0".to_string()
+                })
+        );
+
+        let err = instantiate(Collateral {
+            asset_id: 1,
+            ..usdc_collateral()
+        })
+        .unwrap_err();
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("only USDC with asset id: 0 is supported for testing"));
+
+        let app_addr = instantiate(usdc_collateral()).unwrap();
+        let resp: CollateralResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr, &QueryMsg::Collateral {})
+            .unwrap();
+        assert!(resp.collateral == usdc_collateral());
+    }
+
+    #[test]
+    fn can_instantiate_contract_with_stargate_encoding() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = app
+            .instantiate_contract(
+                code_id,
+                owner.clone(),
+                &InstantiateMsg {
+                    owner: owner.to_string(),
+                    msg_encoding: Some(DydxMsgEncoding::Stargate),
+                    collateral: usdc_collateral(),
+                },
+                &[],
+                "Contract",
+                None,
+            )
+            .unwrap();
+        let _create_vault = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 0,
+                    perp_id: 0,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        // the test app rejects all stargate messages, so the deposit fails with the encoded message
+        mint_native(&mut app, user1.to_string(), USDC_COIN_TYPE.to_string(), 100);
+        let err = app
+            .execute_contract(
+                user1,
+                app_addr,
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: 100u128.into(),
+                }],
+            )
+            .unwrap_err();
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("/dydxprotocol.sending.MsgDepositToSubaccount"));
+    }
+
     #[test]
     #[should_panic(
         expected = "Provided owner: cosmwasm1pgzph9rze2j2xxavx4n7pdhxlkgsq7rak245x0vk7mgh3j4le6gqmlwcfu does not match the sender"
@@ -35,6 +161,8 @@ mod tests {
                 owner.clone(),
                 &InstantiateMsg {
                     owner: user1.to_string(),
+                    msg_encoding: None,
+                    collateral: usdc_collateral(),
                 },
                 &[],
                 "Contract",
@@ -80,9 +208,9 @@ mod tests {
 
         assert!(trader_added_events[0].attributes.len() == 3);
         assert!(trader_added_events[0].attributes[1].key == "old");
-        assert!(trader_added_events[0].attributes[1].value == owner.to_string());
+        assert!(trader_added_events[0].attributes[1].value == owner);
         assert!(trader_added_events[0].attributes[2].key == "new");
-        assert!(trader_added_events[0].attributes[2].value == user1.to_string());
+        assert!(trader_added_events[0].attributes[2].value == user1);
     }
 
     #[test]
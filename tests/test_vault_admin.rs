@@ -2,10 +2,23 @@ mod utils;
 
 #[cfg(test)]
 mod tests {
+    use cosmwasm_std::{Coin, Decimal, SignedDecimal, Uint128};
     use cw_multi_test::Executor;
-    use elixir_dydx_integration::msg::{ExecuteMsg, QueryMsg, TraderResponse, VaultsResponse};
+    use elixir_dydx_integration::{
+        dydx::{proto_structs::PerpetualPosition, serializable_int::SerializableInt},
+        error::ContractError,
+        msg::{
+            ExecuteMsg, QueryMsg, TraderResponse, VaultConfigResponse, VaultOwnershipResponse,
+            VaultResponse, VaultsResponse,
+        },
+        state::{InstantWithdrawalBuffer, InstantWithdrawalConfig, Vault, VaultStatus},
+    };
+    use num_bigint::BigInt;
 
-    use crate::utils::{fetch_attributes, fetch_response_events, instantiate_contract, test_setup};
+    use crate::utils::{
+        fetch_attributes, fetch_response_events, instantiate_contract,
+        instantiate_contract_with_trader_and_vault, mint_native, test_setup, USDC_COIN_TYPE,
+    };
 
     #[test]
     fn trader_can_create_vault() {
@@ -30,7 +43,11 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::CreateVault { perp_id: 1 },
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
                 &[],
             )
             .unwrap();
@@ -47,7 +64,16 @@ mod tests {
 
         assert!(trader_resp.trader == user1);
         assert!(vault_resp.vaults.len() == 1);
-        assert!(vault_resp.vaults[0] == 1);
+        assert!(
+            vault_resp.vaults[0]
+                == Vault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: 1,
+                    isolated: false,
+                    status: VaultStatus::Active
+                }
+        );
 
         let trader_events = fetch_response_events(&set_response, "new_trader".to_string());
         assert!(trader_events.len() == 1);
@@ -72,13 +98,19 @@ mod tests {
         let vault_events = fetch_response_events(&create_vault_response, "new_vault".to_string());
         assert!(vault_events.len() == 1);
         assert!(vault_events[0].ty == "wasm-new_vault");
-        assert!(vault_events[0].attributes.len() == 4);
-        assert!(vault_events[0].attributes[1].key == "perp_id");
+        assert!(vault_events[0].attributes.len() == 7);
+        assert!(vault_events[0].attributes[1].key == "vault_id");
         assert!(vault_events[0].attributes[1].value == "1");
-        assert!(vault_events[0].attributes[2].key == "lp_name");
-        assert!(vault_events[0].attributes[2].value == "Elixir LP Token: dYdX-1");
-        assert!(vault_events[0].attributes[3].key == "lp_symbol");
-        assert!(vault_events[0].attributes[3].value == "ELXR-LP-dYdX-1");
+        assert!(vault_events[0].attributes[2].key == "perp_id");
+        assert!(vault_events[0].attributes[2].value == "1");
+        assert!(vault_events[0].attributes[3].key == "subaccount_number");
+        assert!(vault_events[0].attributes[3].value == "1");
+        assert!(vault_events[0].attributes[4].key == "isolated");
+        assert!(vault_events[0].attributes[4].value == "false");
+        assert!(vault_events[0].attributes[5].key == "lp_name");
+        assert!(vault_events[0].attributes[5].value == "Elixir LP Token: dYdX-1");
+        assert!(vault_events[0].attributes[6].key == "lp_symbol");
+        assert!(vault_events[0].attributes[6].value == "ELXR-LP-dYdX-1");
     }
 
     #[test]
@@ -104,7 +136,11 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::CreateVault { perp_id: 1 },
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
                 &[],
             )
             .unwrap();
@@ -113,7 +149,11 @@ mod tests {
             .execute_contract(
                 user1.clone(),
                 app_addr.clone(),
-                &ExecuteMsg::CreateVault { perp_id: 2 },
+                &ExecuteMsg::CreateVault {
+                    vault_id: 2,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
                 &[],
             )
             .unwrap();
@@ -124,12 +164,106 @@ mod tests {
             .unwrap();
 
         assert!(vault_resp.vaults.len() == 2);
-        assert!(vault_resp.vaults[0] == 1);
-        assert!(vault_resp.vaults[1] == 2);
+        assert!(vault_resp.vaults[0].vault_id == 1);
+        assert!(vault_resp.vaults[1].vault_id == 2);
+        // both vaults trade the same market from their own subaccounts
+        assert!(vault_resp.vaults[0].perp_id == vault_resp.vaults[1].perp_id);
+        assert!(vault_resp.vaults[1].subaccount_number == 2);
     }
 
     #[test]
-    #[should_panic(expected = "Vault already initialized for perp_id: 1")]
+    fn vaults_on_the_same_market_need_distinct_subaccounts() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 0,
+                    subaccount_number: Some(0),
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::SubaccountAlreadyUsed {
+                    subaccount_number: 0,
+                    vault_id: 0
+                })
+        );
+
+        let _cv = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 0,
+                    subaccount_number: Some(5),
+                },
+                &[],
+            )
+            .unwrap();
+
+        let vault_resp: VaultResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vault { vault_id: 1 })
+            .unwrap();
+        assert!(
+            vault_resp.vault
+                == Vault {
+                    vault_id: 1,
+                    perp_id: 0,
+                    subaccount_number: 5,
+                    isolated: false,
+                    status: VaultStatus::Active
+                }
+        );
+        let ownership: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 1,
+                    depositor: user1.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(ownership.subaccount_number == 5);
+
+        // the perp market must exist
+        let err = app
+            .execute_contract(
+                user1,
+                app_addr,
+                &ExecuteMsg::CreateVault {
+                    vault_id: 2,
+                    perp_id: 7,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidMarket { perp_id: 7 })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Vault already initialized for vault_id: 1")]
     fn trader_cannot_create_multiple_vaults_with_same_market_id() {
         let (mut app, code_id, users) = test_setup();
         let owner = users[0].clone();
@@ -154,7 +288,9 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::CreateVault {
+                    vault_id: same_market_id,
                     perp_id: same_market_id,
+                    subaccount_number: None,
                 },
                 &[],
             )
@@ -165,7 +301,9 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::CreateVault {
+                    vault_id: same_market_id,
                     perp_id: same_market_id,
+                    subaccount_number: None,
                 },
                 &[],
             )
@@ -189,10 +327,607 @@ mod tests {
                 user1.clone(),
                 app_addr.clone(),
                 &ExecuteMsg::CreateVault {
+                    vault_id: same_market_id,
                     perp_id: same_market_id,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn trader_can_update_vault_config() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let config_resp: VaultConfigResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::VaultConfig { vault_id: 0 })
+            .unwrap();
+        assert!(config_resp.config.lockup_seconds == 0);
+
+        let update_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: Some(86_400),
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
                 },
                 &[],
             )
             .unwrap();
+
+        let config_resp: VaultConfigResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::VaultConfig { vault_id: 0 })
+            .unwrap();
+        assert!(config_resp.vault_id == 0);
+        assert!(config_resp.config.lockup_seconds == 86_400);
+
+        let config_events =
+            fetch_response_events(&update_response, "updated_vault_config".to_string());
+        assert!(config_events.len() == 1);
+        assert!(config_events[0].attributes[1].key == "vault_id");
+        assert!(config_events[0].attributes[1].value == "0");
+        assert!(config_events[0].attributes[2].key == "lockup_seconds");
+        assert!(config_events[0].attributes[2].value == "86400");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "cosmwasm1fsgzj6t7udv8zhf6zj32mkqhcjcpv52yph5qsdcl0qt94jgdckqs2g053y is not the trader"
+    )]
+    fn need_permissions_to_update_vault_config() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let _update_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: Some(86_400),
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Invalid config for vault with vault_id: 0: instant withdrawal buffer cannot exceed the vault value"
+    )]
+    fn instant_withdrawal_buffer_cannot_exceed_vault_value() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        let _update_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: None,
+                    instant_withdrawals: Some(InstantWithdrawalConfig {
+                        buffer: InstantWithdrawalBuffer::NavFraction(Decimal::percent(101)),
+                        max_outflow_per_window: Uint128::new(1_000_000),
+                        window_seconds: 86_400,
+                    }),
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn admin_can_rebalance_vaults() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let _cv1 = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        // vault 0 is paid in LP tokens of vault 1
+        let rebalance_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 0,
+                    to_vault_id: 1,
+                    amount: 200_000,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let method_attributes = fetch_attributes(&rebalance_response, "method".to_string());
+        assert!(method_attributes[0].value == "rebalance_vaults");
+        let rebalance_events =
+            fetch_response_events(&rebalance_response, "rebalanced_vaults".to_string());
+        assert!(rebalance_events.len() == 1);
+        assert!(rebalance_events[0].attributes[4].key == "redeemed_lp_tokens");
+        assert!(rebalance_events[0].attributes[4].value == "0");
+        assert!(rebalance_events[0].attributes[5].key == "minted_lp_tokens");
+        assert!(rebalance_events[0].attributes[5].value == "200000");
+
+        let vault_resp: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 0,
+                    depositor: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(vault_resp.asset_usdc_value == SignedDecimal::percent(80));
+        assert!(vault_resp.lp_holdings_usdc_value == SignedDecimal::percent(20));
+        assert!(vault_resp.depositor_lp_tokens == Uint128::new(deposit_amount));
+        assert!(vault_resp.outstanding_lp_tokens == Uint128::new(deposit_amount));
+
+        let vault_resp: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 1,
+                    depositor: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(vault_resp.subaccount_number == 1);
+        assert!(vault_resp.asset_usdc_value == SignedDecimal::percent(20));
+        assert!(vault_resp.outstanding_lp_tokens == Uint128::new(200_000));
+
+        // moving USDC back redeems the LP tokens of vault 1 held by vault 0
+        let rebalance_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 1,
+                    to_vault_id: 0,
+                    amount: 50_000,
+                },
+                &[],
+            )
+            .unwrap();
+        let rebalance_events =
+            fetch_response_events(&rebalance_response, "rebalanced_vaults".to_string());
+        assert!(rebalance_events[0].attributes[4].value == "50000");
+        assert!(rebalance_events[0].attributes[5].value == "0");
+
+        let vault_resp: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 0,
+                    depositor: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(vault_resp.asset_usdc_value == SignedDecimal::percent(85));
+        assert!(vault_resp.lp_holdings_usdc_value == SignedDecimal::percent(15));
+
+        let vault_resp: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr,
+                &QueryMsg::VaultOwnership {
+                    vault_id: 1,
+                    depositor: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(vault_resp.asset_usdc_value == SignedDecimal::percent(15));
+        assert!(vault_resp.outstanding_lp_tokens == Uint128::new(150_000));
+    }
+
+    #[test]
+    fn rebalance_respects_leverage_and_holding_limits() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        for vault_id in [1, 2] {
+            let _cv = app
+                .execute_contract(
+                    user1.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::CreateVault {
+                        vault_id,
+                        perp_id: 1,
+                        subaccount_number: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+        }
+
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 0,
+                    to_vault_id: 1,
+                    amount: 2_000_000,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::RebalanceWouldIncreaseLeverageTooMuch { vault_id: 0 })
+        );
+
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 0,
+                    to_vault_id: 1,
+                    amount: 100_000,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::SenderIsNotAdmin {
+                    sender: user1.clone()
+                })
+        );
+
+        let _rebalance_response = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 0,
+                    to_vault_id: 1,
+                    amount: 100_000,
+                },
+                &[],
+            )
+            .unwrap();
+
+        // vault 1 LP tokens are held by vault 0, so vault 1 cannot be paid in LP tokens of vault 2
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 1,
+                    to_vault_id: 2,
+                    amount: 10_000,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidRebalance {
+                    from_vault_id: 1,
+                    to_vault_id: 2,
+                    reason: "the source vault LP tokens are held by another vault".to_string(),
+                })
+        );
+    }
+
+    #[test]
+    fn rebalance_cannot_mint_lp_tokens_of_settling_vaults() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let deposit_amount = 1_000_000;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        let _cv = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 1,
+                    perp_id: 1,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+        mint_native(
+            &mut app,
+            user2.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            deposit_amount,
+        );
+        let _deposit_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(deposit_amount),
+                }],
+            )
+            .unwrap();
+
+        // vault 1 waits in settlement for dYdX to close its position
+        let vault_resp: VaultResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vault { vault_id: 1 })
+            .unwrap();
+        app.router().custom.sudo_add_perp_position(
+            vault_resp.vault.subaccount_number,
+            PerpetualPosition {
+                perpetual_id: 1,
+                quantums: SerializableInt::new(1_000.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+        app.router().custom.sudo_start_final_settlement(1);
+        let _settle = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::SettleVault {
+                    vault_id: 1,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap();
+
+        let err = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RebalanceVaults {
+                    from_vault_id: 0,
+                    to_vault_id: 1,
+                    amount: 100_000,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::VaultNotActive {
+                    vault_id: 1,
+                    status: VaultStatus::Settling
+                })
+        );
+    }
+
+    #[test]
+    fn trader_can_create_vaults_on_isolated_markets() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let sol_perp_id = 2;
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+
+        // isolated vaults are allocated the first unused child subaccount
+        for vault_id in [1, 2] {
+            let create_vault_response = app
+                .execute_contract(
+                    user1.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::CreateVault {
+                        vault_id,
+                        perp_id: sol_perp_id,
+                        subaccount_number: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+            let vault_events =
+                fetch_response_events(&create_vault_response, "new_vault".to_string());
+            assert!(vault_events[0].attributes[4].key == "isolated");
+            assert!(vault_events[0].attributes[4].value == "true");
+        }
+        let vault_resp: VaultsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vaults {})
+            .unwrap();
+        assert!(!vault_resp.vaults[0].isolated);
+        assert!(
+            vault_resp.vaults[1]
+                == Vault {
+                    vault_id: 1,
+                    perp_id: sol_perp_id,
+                    subaccount_number: 128,
+                    isolated: true,
+                    status: VaultStatus::Active
+                }
+        );
+        assert!(vault_resp.vaults[2].subaccount_number == 129);
+
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 3,
+                    perp_id: sol_perp_id,
+                    subaccount_number: Some(3),
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidSubaccountNumber {
+                    subaccount_number: 3,
+                    perp_id: sol_perp_id,
+                    reason: "isolated markets must be traded from a child subaccount".to_string()
+                })
+        );
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::CreateVault {
+                    vault_id: 3,
+                    perp_id: 1,
+                    subaccount_number: Some(130),
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::InvalidSubaccountNumber {
+                    subaccount_number: 130,
+                    perp_id: 1,
+                    reason: "cross markets must be traded from a parent subaccount".to_string()
+                })
+        );
+
+        // an isolated subaccount holding another market cannot be valued
+        app.router().custom.sudo_add_perp_position(
+            128,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(165_604.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+        let err = app
+            .wrap()
+            .query_wasm_smart::<VaultOwnershipResponse>(
+                app_addr,
+                &QueryMsg::VaultOwnership {
+                    vault_id: 1,
+                    depositor: user1.to_string(),
+                },
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("The subaccount for vault with vault_id: 1 has more that one perp position"));
     }
 }
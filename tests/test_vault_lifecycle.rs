@@ -0,0 +1,758 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::{Binary, Coin, Uint128};
+    use cw_multi_test::Executor;
+    use elixir_dydx_integration::{
+        dydx::msg::{OrderSide, OrderTimeInForce},
+        dydx::{proto_structs::PerpetualPosition, serializable_int::SerializableInt},
+        error::ContractError,
+        execute::{market_make::NewOrder, permissioned_keys::PermissionedMessageType},
+        msg::{
+            ExecuteMsg, LpTokenBalanceResponse, PermissionedKeysResponse, QueryMsg,
+            VaultOwnershipResponse, VaultResponse, VaultsResponse,
+        },
+        state::VaultStatus,
+    };
+    use num_bigint::BigInt;
+
+    use crate::utils::{
+        fetch_response_events, instantiate_contract_with_trader_and_vault, mint_native, test_setup,
+        USDC_COIN_TYPE,
+    };
+
+    #[test]
+    fn vault_settles_and_pays_out_lp_holders_after_final_settlement() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        for (user, amount) in [(&user2, 1_000_000), (&user3, 3_000_000)] {
+            mint_native(
+                &mut app,
+                user.to_string(),
+                USDC_COIN_TYPE.to_string(),
+                amount * 2,
+            );
+            let _deposit = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
+                    &[Coin {
+                        denom: USDC_COIN_TYPE.to_string(),
+                        amount: Uint128::new(amount),
+                    }],
+                )
+                .unwrap();
+        }
+        let _request = app
+            .execute_contract(
+                user3.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 1_000_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(165_604.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+
+        let err = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::SettleVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotSettleVault {
+                    vault_id: 0,
+                    reason: "the perp market is not in final settlement".to_string()
+                })
+        );
+
+        app.router().custom.sudo_start_final_settlement(0);
+        let deposit = |app: &mut crate::utils::ElixirTestApp| {
+            app.execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(1_000_000),
+                }],
+            )
+            .unwrap_err()
+        };
+        let err = deposit(&mut app);
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::MarketInFinalSettlement { vault_id: 0 })
+        );
+
+        // anyone can start the settlement, which waits for dYdX to close the position
+        let settling_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::SettleVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let settling_events =
+            fetch_response_events(&settling_response, "settling_vault".to_string());
+        assert!(settling_events.len() == 1);
+        let vault_resp: VaultResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vault { vault_id: 0 })
+            .unwrap();
+        assert!(vault_resp.vault.status == VaultStatus::Settling);
+
+        let err = deposit(&mut app);
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::VaultNotActive {
+                    vault_id: 0,
+                    status: VaultStatus::Settling
+                })
+        );
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::MarketMake {
+                    vault_id: 0,
+                    clob_pair_id: 0,
+                    new_orders: vec![NewOrder {
+                        client_id: 101,
+                        side: OrderSide::Sell,
+                        quantums: 1000000,
+                        subticks: 100000,
+                        good_til_block_time: 1720791702,
+                        time_in_force: OrderTimeInForce::Unspecified,
+                        reduce_only: true,
+                        client_metadata: 0,
+                        conditional_order_trigger_subticks: 0,
+                    }],
+                    cancel_client_ids: vec![],
+                    cancel_good_til_block_time: 0,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::VaultNotActive {
+                    vault_id: 0,
+                    status: VaultStatus::Settling
+                })
+        );
+
+        // once the position is closed, LP holders and queued withdrawals are paid out pro-rata
+        app.router().custom.sudo_close_perp_positions(0);
+        let settled_response = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::SettleVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let payout_events =
+            fetch_response_events(&settled_response, "settlement_payout".to_string());
+        let mut payouts: Vec<(String, String)> = payout_events
+            .iter()
+            .map(|event| {
+                (
+                    event.attributes[2].value.clone(),
+                    event.attributes[4].value.clone(),
+                )
+            })
+            .collect();
+        payouts.sort();
+        let mut expected = vec![
+            (user2.to_string(), "1000000".to_string()),
+            (user3.to_string(), "2000000".to_string()),
+            (user3.to_string(), "1000000".to_string()),
+        ];
+        expected.sort();
+        assert!(payouts == expected);
+
+        let vault_resp: VaultResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vault { vault_id: 0 })
+            .unwrap();
+        assert!(vault_resp.vault.status == VaultStatus::Settled);
+        let ownership: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 0,
+                    depositor: user3.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(ownership.outstanding_lp_tokens.is_zero());
+        let balance: LpTokenBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::UserLpTokens {
+                    vault_id: 0,
+                    user: user2.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(balance.balance.is_zero());
+
+        let err = app
+            .execute_contract(
+                user2,
+                app_addr,
+                &ExecuteMsg::SettleVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotSettleVault {
+                    vault_id: 0,
+                    reason: "the vault is already settled".to_string()
+                })
+        );
+    }
+
+    #[test]
+    fn vault_settlement_pays_out_lp_holders_across_calls() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        for (user, amount) in [(&user2, 1_000_000), (&user3, 3_000_000)] {
+            mint_native(
+                &mut app,
+                user.to_string(),
+                USDC_COIN_TYPE.to_string(),
+                amount,
+            );
+            let _deposit = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
+                    &[Coin {
+                        denom: USDC_COIN_TYPE.to_string(),
+                        amount: Uint128::new(amount),
+                    }],
+                )
+                .unwrap();
+        }
+        let _request = app
+            .execute_contract(
+                user3.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 1_000_000,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        app.router().custom.sudo_start_final_settlement(0);
+
+        // each call pays out a single LP holder, and the last one settles the vault
+        let settle_msg = ExecuteMsg::SettleVault {
+            vault_id: 0,
+            limit: Some(1),
+        };
+        let mut payouts = vec![];
+        for (page, expected_status) in [
+            VaultStatus::Settling,
+            VaultStatus::Settling,
+            VaultStatus::Settled,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            // no USDC can leave the vault between pages
+            if page > 0 {
+                for user in [&user2, &user3] {
+                    let err = app
+                        .execute_contract(
+                            user.clone(),
+                            app_addr.clone(),
+                            &ExecuteMsg::RequestWithdrawal {
+                                vault_id: 0,
+                                usdc_amount: 0,
+                                recipient: None,
+                            },
+                            &[],
+                        )
+                        .unwrap_err();
+                    assert!(
+                        err.downcast_ref::<ContractError>()
+                            == Some(&ContractError::VaultNotActive {
+                                vault_id: 0,
+                                status: VaultStatus::Settling
+                            })
+                    );
+                }
+                let err = app
+                    .execute_contract(
+                        user1.clone(),
+                        app_addr.clone(),
+                        &ExecuteMsg::ProcessWithdrawals {
+                            vault_id: 0,
+                            max_num_withdrawals: 1,
+                        },
+                        &[],
+                    )
+                    .unwrap_err();
+                assert!(
+                    err.downcast_ref::<ContractError>()
+                        == Some(&ContractError::VaultNotActive {
+                            vault_id: 0,
+                            status: VaultStatus::Settling
+                        })
+                );
+            }
+            let response = app
+                .execute_contract(user2.clone(), app_addr.clone(), &settle_msg, &[])
+                .unwrap();
+            let payout_events = fetch_response_events(&response, "settlement_payout".to_string());
+            assert!(payout_events.len() == 1);
+            payouts.push((
+                payout_events[0].attributes[2].value.clone(),
+                payout_events[0].attributes[4].value.clone(),
+            ));
+            let vault_resp: VaultResponse = app
+                .wrap()
+                .query_wasm_smart(app_addr.clone(), &QueryMsg::Vault { vault_id: 0 })
+                .unwrap();
+            assert!(vault_resp.vault.status == expected_status);
+        }
+        payouts.sort();
+        let mut expected = vec![
+            (user2.to_string(), "1000000".to_string()),
+            (user3.to_string(), "2000000".to_string()),
+            (user3.to_string(), "1000000".to_string()),
+        ];
+        expected.sort();
+        assert!(payouts == expected);
+
+        let ownership: VaultOwnershipResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::VaultOwnership {
+                    vault_id: 0,
+                    depositor: user3.to_string(),
+                },
+            )
+            .unwrap();
+        assert!(ownership.outstanding_lp_tokens.is_zero());
+    }
+
+    #[test]
+    fn trader_can_close_and_archive_vaults() {
+        let (mut app, code_id, users) = test_setup();
+        let owner = users[0].clone();
+        let user1 = users[1].clone();
+        let user2 = users[2].clone();
+        let user3 = users[3].clone();
+        let user4 = users[4].clone();
+
+        let app_addr = instantiate_contract_with_trader_and_vault(
+            &mut app,
+            code_id,
+            owner.clone(),
+            user1.clone(),
+        );
+        for (user, amount) in [(&user2, 1_000_000), (&user3, 3_000_000)] {
+            mint_native(
+                &mut app,
+                user.to_string(),
+                USDC_COIN_TYPE.to_string(),
+                amount * 2,
+            );
+            let _deposit = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::DepositIntoVault {
+                        vault_id: 0,
+                        referrer: None,
+                    },
+                    &[Coin {
+                        denom: USDC_COIN_TYPE.to_string(),
+                        amount: Uint128::new(amount),
+                    }],
+                )
+                .unwrap();
+        }
+        // a queued withdrawal is returned to its owner on close
+        let _queue_withdrawal = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::RequestWithdrawal {
+                    vault_id: 0,
+                    usdc_amount: 0,
+                    recipient: None,
+                },
+                &[],
+            )
+            .unwrap();
+        // a deposit that is still locked can be redeemed once the vault is closed
+        let _config = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::UpdateVaultConfig {
+                    vault_id: 0,
+                    lockup_seconds: Some(3_600),
+                    instant_withdrawals: None,
+                    withdrawal_rate_limit: None,
+                    share_price_interval_seconds: None,
+                },
+                &[],
+            )
+            .unwrap();
+        mint_native(
+            &mut app,
+            user4.to_string(),
+            USDC_COIN_TYPE.to_string(),
+            2_000_000,
+        );
+        let _deposit = app
+            .execute_contract(
+                user4.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(2_000_000),
+                }],
+            )
+            .unwrap();
+        let _place_order = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::MarketMake {
+                    vault_id: 0,
+                    clob_pair_id: 0,
+                    new_orders: vec![NewOrder {
+                        client_id: 101,
+                        side: OrderSide::Buy,
+                        quantums: 1000000,
+                        subticks: 100000,
+                        good_til_block_time: 1720791702,
+                        time_in_force: OrderTimeInForce::Unspecified,
+                        reduce_only: false,
+                        client_metadata: 0,
+                        conditional_order_trigger_subticks: 0,
+                    }],
+                    cancel_client_ids: vec![],
+                    cancel_good_til_block_time: 0,
+                },
+                &[],
+            )
+            .unwrap();
+        let _add_key = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::AddPermissionedKey {
+                    vault_id: 0,
+                    public_key: Binary::from([2; 33]),
+                    clob_pair_ids: vec![0],
+                    message_types: vec![PermissionedMessageType::PlaceOrder],
+                },
+                &[],
+            )
+            .unwrap();
+        assert!(app.router().custom.has_order(0, 101));
+
+        let close_msg = ExecuteMsg::CloseVault {
+            vault_id: 0,
+            cancel_client_ids: vec![101],
+            cancel_good_til_block_time: 1720791702,
+        };
+        let err = app
+            .execute_contract(user2.clone(), app_addr.clone(), &close_msg, &[])
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::SenderIsNotTrader {
+                    sender: user2.clone()
+                })
+        );
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ArchiveVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotArchiveVault {
+                    vault_id: 0,
+                    reason: "only closed or settled vaults can be archived".to_string()
+                })
+        );
+
+        // the position must be flat before closing
+        app.router().custom.sudo_add_perp_position(
+            0,
+            PerpetualPosition {
+                perpetual_id: 0,
+                quantums: SerializableInt::new(165_604.into()),
+                funding_index: SerializableInt::new(BigInt::ZERO),
+            },
+        );
+        let err = app
+            .execute_contract(user1.clone(), app_addr.clone(), &close_msg, &[])
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotCloseVault {
+                    vault_id: 0,
+                    reason: "the perp position is not flat".to_string()
+                })
+        );
+        app.router().custom.sudo_close_perp_positions(0);
+
+        // a reward stream that has not started yet is refunded to the admin on archive
+        let start_time = app.block_info().time.seconds() + 1_000;
+        mint_native(&mut app, owner.to_string(), "adydx".to_string(), 1_000);
+        let _fund = app
+            .execute_contract(
+                owner.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::FundRewards {
+                    vault_id: 0,
+                    start_time,
+                    end_time: start_time + 100,
+                },
+                &[Coin::new(1_000, "adydx")],
+            )
+            .unwrap();
+
+        let close_response = app
+            .execute_contract(user1.clone(), app_addr.clone(), &close_msg, &[])
+            .unwrap();
+        assert!(fetch_response_events(&close_response, "closed_vault".to_string()).len() == 1);
+        assert!(fetch_response_events(&close_response, "cancelled_order".to_string()).len() == 1);
+        assert!(
+            fetch_response_events(&close_response, "removed_permissioned_key".to_string()).len()
+                == 1
+        );
+        let refunds =
+            fetch_response_events(&close_response, "cancelled_withdrawal_requests".to_string());
+        assert!(refunds.len() == 1);
+        assert!(refunds[0]
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "restored_lp_tokens" && attr.value == "1000000"));
+        assert!(!app.router().custom.has_order(0, 101));
+        let keys: PermissionedKeysResponse = app
+            .wrap()
+            .query_wasm_smart(
+                app_addr.clone(),
+                &QueryMsg::PermissionedKeys { vault_id: 0 },
+            )
+            .unwrap();
+        assert!(keys.keys.is_empty());
+        let vaults: VaultsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vaults {})
+            .unwrap();
+        assert!(vaults.vaults.len() == 1);
+        assert!(vaults.vaults[0].status == VaultStatus::Closed);
+
+        let err = app
+            .execute_contract(
+                user2.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::DepositIntoVault {
+                    vault_id: 0,
+                    referrer: None,
+                },
+                &[Coin {
+                    denom: USDC_COIN_TYPE.to_string(),
+                    amount: Uint128::new(1_000_000),
+                }],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::VaultNotActive {
+                    vault_id: 0,
+                    status: VaultStatus::Closed
+                })
+        );
+
+        // withdrawals are redeemed immediately and pro-rata
+        let redeem = |app: &mut crate::utils::ElixirTestApp, user: &cosmwasm_std::Addr| {
+            let response = app
+                .execute_contract(
+                    user.clone(),
+                    app_addr.clone(),
+                    &ExecuteMsg::RequestWithdrawal {
+                        vault_id: 0,
+                        usdc_amount: 0,
+                        recipient: None,
+                    },
+                    &[],
+                )
+                .unwrap();
+            let events = fetch_response_events(&response, "closed_vault_redemption".to_string());
+            assert!(events.len() == 1);
+            events[0].attributes[4].value.clone()
+        };
+        assert!(redeem(&mut app, &user2) == "1000000");
+        let err = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ArchiveVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<ContractError>()
+                == Some(&ContractError::CannotArchiveVault {
+                    vault_id: 0,
+                    reason: "LP tokens are still outstanding".to_string()
+                })
+        );
+        assert!(redeem(&mut app, &user3) == "3000000");
+        assert!(redeem(&mut app, &user4) == "2000000");
+
+        // archiving removes the per-user state in pages before the vault is gone
+        let archive_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ArchiveVault {
+                    vault_id: 0,
+                    limit: Some(1),
+                },
+                &[],
+            )
+            .unwrap();
+        assert!(fetch_response_events(&archive_response, "archiving_vault".to_string()).len() == 1);
+        assert!(fetch_response_events(&archive_response, "archived_vault".to_string()).is_empty());
+        let vaults: VaultsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vaults {})
+            .unwrap();
+        assert!(vaults.vaults.len() == 1);
+
+        let archive_response = app
+            .execute_contract(
+                user1.clone(),
+                app_addr.clone(),
+                &ExecuteMsg::ArchiveVault {
+                    vault_id: 0,
+                    limit: None,
+                },
+                &[],
+            )
+            .unwrap();
+        let archive_events = fetch_response_events(&archive_response, "archived_vault".to_string());
+        assert!(archive_events.len() == 1);
+        assert!(archive_events[0]
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "refunded_rewards" && attr.value == "1000adydx"));
+        let balance = app
+            .wrap()
+            .query_balance(owner.to_string(), "adydx")
+            .unwrap();
+        assert!(balance.amount == Uint128::new(1_000));
+        let vaults: VaultsResponse = app
+            .wrap()
+            .query_wasm_smart(app_addr.clone(), &QueryMsg::Vaults {})
+            .unwrap();
+        assert!(vaults.vaults.is_empty());
+
+        // the vault id and subaccount can be reused
+        let _create = app
+            .execute_contract(
+                user1,
+                app_addr,
+                &ExecuteMsg::CreateVault {
+                    vault_id: 0,
+                    perp_id: 0,
+                    subaccount_number: None,
+                },
+                &[],
+            )
+            .unwrap();
+    }
+}
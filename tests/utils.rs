@@ -21,18 +21,35 @@ use elixir_dydx_integration::{
     dydx::{
         msg::DydxMsg,
         proto_structs::{
-            ClobPair, MarketPrice, Metadata, Perpetual, PerpetualClobDetails,
-            PerpetualClobMetadata, PerpetualMarketType, PerpetualParams, Status, Subaccount,
+            AccountAuthenticator, Asset, BlockRateLimitConfiguration, ClobPair, MarketParam,
+            MarketPrice, MaxPerNBlocksRateLimit, Metadata, Perpetual, PerpetualClobDetails,
+            PerpetualClobMetadata, PerpetualFeeParams, PerpetualFeeTier, PerpetualMarketType,
+            PerpetualParams, Status, Subaccount,
         },
         serializable_int::SerializableInt,
     },
     msg::{ExecuteMsg, InstantiateMsg},
+    state::Collateral,
 };
 use num_bigint::BigInt;
 use num_traits::Zero;
 use serde::de::DeserializeOwned;
 
 pub const TEST_CONTRACT_ADDR: &str = "contract0";
+pub const USDC_ID: u32 = 0;
+pub const USDC_DENOM: u32 = 6;
+pub const USDC_COIN_TYPE: &str =
+    "ibc/8E27BA2D5493AF5636760E354E46004562C46AB7EC0CC4C1CA14E9E20E2545B5";
+
+/// The collateral that the mock dYdX asset 0 matches.
+#[allow(dead_code)]
+pub fn usdc_collateral() -> Collateral {
+    Collateral {
+        denom: USDC_COIN_TYPE.to_string(),
+        asset_id: USDC_ID,
+        decimals: USDC_DENOM,
+    }
+}
 
 pub type ElixirTestApp = App<
     BankKeeper,
@@ -52,7 +69,8 @@ pub fn test_setup() -> (ElixirTestApp, u64, Vec<Addr>) {
         elixir_dydx_integration::contract::execute,
         elixir_dydx_integration::contract::instantiate,
         elixir_dydx_integration::contract::query,
-    );
+    )
+    .with_migrate(elixir_dydx_integration::contract::migrate);
     let b: Box<dyn Contract<DydxMsg, DydxQueryWrapper>> = Box::new(contract);
 
     let test_dydx = TestDydx::new();
@@ -60,18 +78,11 @@ pub fn test_setup() -> (ElixirTestApp, u64, Vec<Addr>) {
     let app_builder = AppBuilder::new_custom();
 
     let mut app = app_builder.with_custom(test_dydx).build(|router, _, _| {
-        router.custom.mock_subaccounts.borrow_mut().insert(
-            0,
-            Subaccount {
-                id: Some(SubaccountId {
-                    owner: TEST_CONTRACT_ADDR.to_string(),
-                    number: 0,
-                }),
-                asset_positions: vec![],
-                perpetual_positions: vec![],
-                margin_enabled: true,
-            },
-        );
+        router
+            .custom
+            .mock_subaccounts
+            .borrow_mut()
+            .insert(0, empty_subaccount(0));
     });
     let code_id = app.store_code(b);
 
@@ -92,6 +103,8 @@ pub fn instantiate_contract(app: &mut ElixirTestApp, code_id: u64, owner: Addr)
         owner.clone(),
         &InstantiateMsg {
             owner: owner.to_string(),
+            msg_encoding: None,
+            collateral: usdc_collateral(),
         },
         &[],
         "Contract",
@@ -113,6 +126,8 @@ pub fn instantiate_contract_with_trader_and_vault(
             owner.clone(),
             &InstantiateMsg {
                 owner: owner.to_string(),
+                msg_encoding: None,
+                collateral: usdc_collateral(),
             },
             &[],
             "Contract",
@@ -135,7 +150,11 @@ pub fn instantiate_contract_with_trader_and_vault(
         .execute_contract(
             trader.clone(),
             app_addr.clone(),
-            &ExecuteMsg::CreateVault { perp_id: 0 },
+            &ExecuteMsg::CreateVault {
+                vault_id: 0,
+                perp_id: 0,
+                subaccount_number: None,
+            },
             &[],
         )
         .unwrap();
@@ -159,8 +178,8 @@ pub fn fetch_attributes(resp: &AppResponse, key: String) -> Vec<Attribute> {
     let wasm = resp.events.iter().find(|ev| ev.ty == "wasm").unwrap();
     wasm.attributes
         .iter()
+        .filter(|&attr| attr.key == key)
         .cloned()
-        .filter(|attr| attr.key == key)
         .collect()
 }
 
@@ -168,17 +187,76 @@ pub fn fetch_attributes(resp: &AppResponse, key: String) -> Vec<Attribute> {
 pub fn fetch_response_events(resp: &AppResponse, event_name: String) -> Vec<Event> {
     resp.events
         .iter()
+        .filter(|&ev| ev.ty == format!("wasm-{event_name}"))
         .cloned()
-        .filter(|ev| ev.ty == format!("wasm-{event_name}"))
         .collect()
 }
 
+/// The perpetuals supported by the mock: BTC-USD with id 0, ETH-USD with id 1 and the isolated SOL-USD with id 2.
+/// Perpetual, market and clob pair ids are the same.
+fn mock_perpetual(id: u32) -> Perpetual {
+    let (ticker, atomic_resolution, market_type) = match id {
+        0 => ("BTC-USD", -10, PerpetualMarketType::Cross),
+        1 => ("ETH-USD", -9, PerpetualMarketType::Cross),
+        _ => ("SOL-USD", -7, PerpetualMarketType::Isolated),
+    };
+    Perpetual {
+        params: PerpetualParams {
+            id,
+            ticker: ticker.to_string(),
+            market_id: id,
+            atomic_resolution,
+            default_funding_ppm: 0,
+            liquidity_tier: 0,
+            market_type,
+        },
+        funding_index: SerializableInt::new(BigInt::zero()),
+        open_interest: SerializableInt::new(BigInt::zero()),
+    }
+}
+
+/// The clob pair of [mock_perpetual].
+fn mock_clob_pair(id: u32) -> ClobPair {
+    ClobPair {
+        id,
+        metadata: Metadata::PerpetualClobMetadata(PerpetualClobMetadata { perpetual_id: id }),
+        step_base_quantums: 1000000,
+        subticks_per_tick: 100000,
+        quantum_conversion_exponent: -9,
+        status: Status::Active,
+    }
+}
+
+fn empty_subaccount(number: u32) -> Subaccount {
+    Subaccount {
+        id: Some(SubaccountId {
+            owner: TEST_CONTRACT_ADDR.to_string(),
+            number,
+        }),
+        asset_positions: vec![],
+        perpetual_positions: vec![],
+        margin_enabled: true,
+    }
+}
+
 pub struct TestDydx {
     pub bank: BankKeeper,
     /// Mock meant to mimic subaccount state on dYdX chain
     mock_subaccounts: RefCell<HashMap<u32, Subaccount>>,
     /// Mock of orders for a subaccount. Keyed on subaccount number, value is client order id
     mock_orders: RefCell<HashMap<u32, Vec<u32>>>,
+    /// Mock of the authenticators registered for the contract account
+    mock_authenticators: RefCell<Vec<AccountAuthenticator>>,
+    /// Id of the next authenticator added, incremented on every add like x/accountplus
+    next_authenticator_id: RefCell<u64>,
+    /// Clob pairs in final settlement
+    final_settlement_clob_pairs: RefCell<Vec<u32>>,
+}
+
+impl Default for TestDydx {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TestDydx {
@@ -187,6 +265,38 @@ impl TestDydx {
             bank: BankKeeper::new(),
             mock_orders: RefCell::new(HashMap::new()),
             mock_subaccounts: RefCell::new(HashMap::new()),
+            mock_authenticators: RefCell::new(vec![]),
+            next_authenticator_id: RefCell::new(0),
+            final_settlement_clob_pairs: RefCell::new(vec![]),
+        }
+    }
+
+    /// Returns the clob pair of [mock_perpetual] with its current status
+    fn clob_pair(&self, id: u32) -> ClobPair {
+        let mut clob_pair = mock_clob_pair(id);
+        if self.final_settlement_clob_pairs.borrow().contains(&id) {
+            clob_pair.status = Status::FinalSettlement;
+        }
+        clob_pair
+    }
+
+    /// Moves a clob pair to final settlement
+    #[allow(dead_code)]
+    pub fn sudo_start_final_settlement(&self, clob_pair_id: u32) {
+        self.final_settlement_clob_pairs
+            .borrow_mut()
+            .push(clob_pair_id);
+    }
+
+    /// Closes all perp positions of the contract owned subaccount, like final settlement does
+    #[allow(dead_code)]
+    pub fn sudo_close_perp_positions(&self, subaccount_number: u32) {
+        if let Some(subaccount) = self
+            .mock_subaccounts
+            .borrow_mut()
+            .get_mut(&subaccount_number)
+        {
+            subaccount.perpetual_positions.clear();
         }
     }
 
@@ -204,10 +314,52 @@ impl TestDydx {
     #[allow(dead_code)]
     pub fn sudo_add_perp_position(&self, subaccount_number: u32, position: PerpetualPosition) {
         let mut accounts = self.mock_subaccounts.borrow_mut();
-        let mut subaccount = accounts.get_mut(&subaccount_number).unwrap().clone();
+        let subaccount = accounts
+            .entry(subaccount_number)
+            .or_insert_with(|| empty_subaccount(subaccount_number));
         subaccount.perpetual_positions.push(position);
+    }
 
-        accounts.insert(subaccount_number, subaccount);
+    /// Adds USDC quantums to a contract owned subaccount, creating the subaccount if needed
+    fn add_usdc(&self, subaccount_number: u32, quantums: BigInt) -> AnyResult<()> {
+        let mut account_map = self.mock_subaccounts.borrow_mut();
+        let subaccount = account_map
+            .entry(subaccount_number)
+            .or_insert_with(|| empty_subaccount(subaccount_number));
+
+        if subaccount.asset_positions.is_empty() {
+            subaccount.asset_positions.push(AssetPosition {
+                asset_id: 0,
+                quantums: SerializableInt::new(quantums),
+                index: 0,
+            })
+        } else if subaccount.asset_positions.len() == 1 {
+            let current_amount = subaccount.asset_positions[0].quantums.clone();
+            let new_amount = SerializableInt::new(current_amount.i.checked_add(&quantums).unwrap());
+            subaccount.asset_positions[0].quantums = new_amount;
+        } else {
+            bail!("subaccount should only have USDC asset");
+        }
+        Ok(())
+    }
+
+    /// Removes USDC quantums from a contract owned subaccount
+    fn remove_usdc(&self, subaccount_number: u32, quantums: BigInt) -> AnyResult<()> {
+        let mut account_map = self.mock_subaccounts.borrow_mut();
+        let Some(subaccount) = account_map.get_mut(&subaccount_number) else {
+            bail!("tryingto withdraw from an unsupported subaccount");
+        };
+
+        if subaccount.asset_positions.is_empty() {
+            bail!("tryingto withdraw without any deposits");
+        } else if subaccount.asset_positions.len() == 1 {
+            let current_amount = subaccount.asset_positions[0].quantums.clone();
+            let new_amount = SerializableInt::new(current_amount.i.checked_sub(&quantums).unwrap());
+            subaccount.asset_positions[0].quantums = new_amount;
+        } else {
+            bail!("subaccount should only have USDC asset");
+        }
+        Ok(())
     }
 }
 
@@ -237,7 +389,7 @@ impl Module for TestDydx {
                 quantums,
             } => {
                 println!("DepositToSubaccount");
-                if recipient.number != 0 || recipient.owner != TEST_CONTRACT_ADDR.to_string() {
+                if recipient.owner != TEST_CONTRACT_ADDR {
                     bail!("tryingto deposit for an unsupported subaccount");
                 }
 
@@ -245,25 +397,7 @@ impl Module for TestDydx {
                     bail!("tryingto deposit something other than USDC");
                 }
 
-                let mut account_map = self.mock_subaccounts.borrow_mut();
-
-                let subaccount = account_map.get_mut(&0).unwrap();
-
-                if subaccount.asset_positions.len() == 0 {
-                    subaccount.asset_positions.push(AssetPosition {
-                        asset_id,
-                        quantums: SerializableInt::new(quantums.into()),
-                        index: 0,
-                    })
-                } else if subaccount.asset_positions.len() == 1 {
-                    let current_amount = subaccount.asset_positions[0].quantums.clone();
-                    let new_amount = SerializableInt::new(
-                        current_amount.i.checked_add(&quantums.into()).unwrap(),
-                    );
-                    subaccount.asset_positions[0].quantums = new_amount;
-                } else {
-                    bail!("subaccount should only have USDC asset");
-                }
+                self.add_usdc(recipient.number, quantums.into())?;
                 Ok(AppResponse::default())
             }
             DydxMsg::WithdrawFromSubaccountV1 {
@@ -273,31 +407,14 @@ impl Module for TestDydx {
                 quantums,
             } => {
                 println!("WithdrawFromSubaccountV1");
-                if subaccount_number != 0 {
-                    bail!("tryingto withdraw from an unsupported subaccount");
-                }
-                if recipient == TEST_CONTRACT_ADDR.to_string() {
+                if recipient == TEST_CONTRACT_ADDR {
                     bail!("tryingto withdraw to the smart contract");
                 }
                 if asset_id != 0 {
                     bail!("tryingto withdraw something other than USDC");
                 }
 
-                let mut account_map = self.mock_subaccounts.borrow_mut();
-
-                let subaccount = account_map.get_mut(&0).unwrap();
-
-                if subaccount.asset_positions.len() == 0 {
-                    bail!("tryingto withdraw without any deposits");
-                } else if subaccount.asset_positions.len() == 1 {
-                    let current_amount = subaccount.asset_positions[0].quantums.clone();
-                    let new_amount = SerializableInt::new(
-                        current_amount.i.checked_sub(&quantums.into()).unwrap(),
-                    );
-                    subaccount.asset_positions[0].quantums = new_amount;
-                } else {
-                    bail!("subaccount should only have USDC asset");
-                }
+                self.remove_usdc(subaccount_number, quantums.into())?;
                 Ok(AppResponse::default())
             }
             DydxMsg::PlaceOrderV1 {
@@ -342,6 +459,68 @@ impl Module for TestDydx {
 
                 Ok(AppResponse::default())
             }
+            DydxMsg::BatchCancelV1 {
+                subaccount_number,
+                short_term_cancels,
+                good_til_block: _,
+            } => {
+                println!("BatchCancelV1");
+
+                let mut order_map = self.mock_orders.borrow_mut();
+                let order_client_ids = order_map.entry(subaccount_number).or_insert(vec![]);
+                for batch in short_term_cancels {
+                    order_client_ids.retain(|x| !batch.client_ids.contains(x));
+                }
+
+                Ok(AppResponse::default())
+            }
+            DydxMsg::TransferV1 {
+                sender,
+                recipient,
+                asset_id,
+                amount,
+            } => {
+                println!("TransferV1");
+                if sender.owner != TEST_CONTRACT_ADDR || recipient.owner != TEST_CONTRACT_ADDR {
+                    bail!("tryingto transfer between unsupported subaccounts");
+                }
+                if asset_id != 0 {
+                    bail!("tryingto transfer something other than USDC");
+                }
+
+                self.remove_usdc(sender.number, amount.into())?;
+                self.add_usdc(recipient.number, amount.into())?;
+                Ok(AppResponse::default())
+            }
+            DydxMsg::AddAuthenticatorV1 {
+                authenticator_type,
+                data,
+            } => {
+                println!("AddAuthenticatorV1");
+
+                let mut next_id = self.next_authenticator_id.borrow_mut();
+                self.mock_authenticators
+                    .borrow_mut()
+                    .push(AccountAuthenticator {
+                        id: *next_id,
+                        authenticator_type: authenticator_type.to_string(),
+                        config: data,
+                    });
+                *next_id += 1;
+
+                Ok(AppResponse::default())
+            }
+            DydxMsg::RemoveAuthenticatorV1 { id } => {
+                println!("RemoveAuthenticatorV1");
+
+                let mut authenticators = self.mock_authenticators.borrow_mut();
+                if !authenticators.iter().any(|a| a.id == id) {
+                    bail!("authenticator {} does not exist", id);
+                }
+                authenticators.retain(|a| a.id != id);
+
+                Ok(AppResponse::default())
+            }
             _ => panic!("unknown message"),
         }
     }
@@ -357,58 +536,144 @@ impl Module for TestDydx {
         match request.query_data {
             DydxQuery::MarketPrice { id } => {
                 println!("{:?}", "MarketPrice");
-                if id != 0 {
-                    bail!("only market with id: 0 is supported for testing");
-                }
+                let price = match id {
+                    0 => 6038418054,
+                    1 => 300000000,
+                    2 => 15000000,
+                    _ => bail!("only markets with id: 0, 1 and 2 are supported for testing"),
+                };
                 Ok(to_json_binary(&MarketPrice {
                     id,
                     exponent: -5,
-                    price: 6038418054,
+                    price,
                 })?)
             }
             DydxQuery::Subaccount { owner, number } => {
                 println!("Subaccount {} {}", owner, number);
-                if number != 0 || owner != TEST_CONTRACT_ADDR.to_string() {
+                if owner != TEST_CONTRACT_ADDR {
                     bail!("tryingto query for an unsupported subaccount");
                 }
 
-                let subaccount = self.mock_subaccounts.borrow().get(&number).unwrap().clone();
+                let subaccount = self
+                    .mock_subaccounts
+                    .borrow()
+                    .get(&number)
+                    .cloned()
+                    .unwrap_or_else(|| empty_subaccount(number));
                 Ok(to_json_binary(&subaccount)?)
             }
             DydxQuery::PerpetualClobDetails { id } => {
                 println!("{:?}", "PerpetualClobDetails");
-                if id != 0 {
-                    bail!("only market with id: 0 is supported for testing");
+                if id > 2 {
+                    bail!("only markets with id: 0, 1 and 2 are supported for testing");
                 }
                 Ok(to_json_binary(&PerpetualClobDetails {
-                    perpetual: Perpetual {
-                        params: PerpetualParams {
-                            id,
-                            ticker: "BTC-USD".to_string(),
-                            market_id: 0,
-                            atomic_resolution: -10,
-                            default_funding_ppm: 0,
-                            liquidity_tier: 0,
-                            market_type: PerpetualMarketType::Cross,
-                        },
-                        funding_index: SerializableInt::new(BigInt::zero()),
-                        open_interest: SerializableInt::new(BigInt::zero()),
-                    },
-                    clob_pair: ClobPair {
-                        id,
-                        metadata: Metadata::PerpetualClobMetadata(PerpetualClobMetadata {
-                            perpetual_id: 0,
-                        }),
-                        step_base_quantums: 1000000,
-                        subticks_per_tick: 100000,
-                        quantum_conversion_exponent: -9,
-                        status: Status::Active,
-                    },
+                    perpetual: mock_perpetual(id),
+                    clob_pair: self.clob_pair(id),
                 })?)
             }
             DydxQuery::LiquidityTiers => {
                 unimplemented!()
             }
+            DydxQuery::Asset { id } => {
+                println!("{:?}", "Asset");
+                if id != 0 {
+                    bail!("only USDC with asset id: 0 is supported for testing");
+                }
+                Ok(to_json_binary(&Asset {
+                    id,
+                    symbol: "USDC".to_string(),
+                    denom: USDC_COIN_TYPE.to_string(),
+                    denom_exponent: -6,
+                    has_market: false,
+                    market_id: 0,
+                    atomic_resolution: -6,
+                })?)
+            }
+            DydxQuery::MarketParam { id } => {
+                println!("{:?}", "MarketParam");
+                if id != 0 {
+                    bail!("only market with id: 0 is supported for testing");
+                }
+                Ok(to_json_binary(&MarketParam {
+                    id,
+                    pair: "BTC-USD".to_string(),
+                    exponent: -5,
+                    min_exchanges: 3,
+                    min_price_change_ppm: 1000,
+                    exchange_config_json: "{}".to_string(),
+                })?)
+            }
+            DydxQuery::AllPerpetuals => {
+                println!("{:?}", "AllPerpetuals");
+                Ok(to_json_binary(&vec![
+                    mock_perpetual(0),
+                    mock_perpetual(1),
+                    mock_perpetual(2),
+                ])?)
+            }
+            DydxQuery::AllClobPairs => {
+                println!("{:?}", "AllClobPairs");
+                Ok(to_json_binary(&vec![
+                    self.clob_pair(0),
+                    self.clob_pair(1),
+                    self.clob_pair(2),
+                ])?)
+            }
+            DydxQuery::PerpetualFeeParams => {
+                println!("{:?}", "PerpetualFeeParams");
+                Ok(to_json_binary(&PerpetualFeeParams {
+                    tiers: vec![
+                        PerpetualFeeTier {
+                            name: "1".to_string(),
+                            absolute_volume_requirement: 0,
+                            total_volume_share_requirement_ppm: 0,
+                            maker_volume_share_requirement_ppm: 0,
+                            maker_fee_ppm: 100,
+                            taker_fee_ppm: 500,
+                        },
+                        PerpetualFeeTier {
+                            name: "2".to_string(),
+                            absolute_volume_requirement: 1_000_000_000_000,
+                            total_volume_share_requirement_ppm: 0,
+                            maker_volume_share_requirement_ppm: 0,
+                            maker_fee_ppm: -110,
+                            taker_fee_ppm: 250,
+                        },
+                    ],
+                })?)
+            }
+            DydxQuery::BlockRateLimitConfiguration => {
+                println!("{:?}", "BlockRateLimitConfiguration");
+                Ok(to_json_binary(&BlockRateLimitConfiguration {
+                    max_short_term_orders_per_n_blocks: vec![],
+                    max_stateful_orders_per_n_blocks: vec![
+                        MaxPerNBlocksRateLimit {
+                            num_blocks: 1,
+                            limit: 2,
+                        },
+                        MaxPerNBlocksRateLimit {
+                            num_blocks: 100,
+                            limit: 20,
+                        },
+                    ],
+                    max_short_term_order_cancellations_per_n_blocks: vec![MaxPerNBlocksRateLimit {
+                        num_blocks: 1,
+                        limit: 20,
+                    }],
+                    max_short_term_orders_and_cancels_per_n_blocks: vec![MaxPerNBlocksRateLimit {
+                        num_blocks: 1,
+                        limit: 400,
+                    }],
+                })?)
+            }
+            DydxQuery::Authenticators { account } => {
+                println!("{:?}", "Authenticators");
+                if account != TEST_CONTRACT_ADDR {
+                    return Ok(to_json_binary::<Vec<AccountAuthenticator>>(&vec![])?);
+                }
+                Ok(to_json_binary(&*self.mock_authenticators.borrow())?)
+            }
         }
     }
 